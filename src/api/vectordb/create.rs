@@ -1,27 +1,43 @@
 use actix_web::{web, HttpResponse};
 
 use crate::{
-    api_service::init_vector_store,
-    models::rpc::{CreateVectorDb, RPCResponseBody},
+    api_service::{init_vector_store, VectorStoreConfig},
+    models::{
+        common::WaCustomError,
+        rpc::{CreateVectorDb, RPCResponseBody},
+    },
 };
 
 // Route: `/vectordb/createdb`
 pub(crate) async fn create(web::Json(body): web::Json<CreateVectorDb>) -> HttpResponse {
-    // Define the parameters for init_vector_store
-    let name = body.vector_db_name;
-    let size = body.dimensions as usize;
-    let lower_bound = body.min_val;
-    let upper_bound = body.max_val;
-    // ---------------------------
-    // -- TODO Maximum cache level
-    // ---------------------------
-    let max_cache_level = 5;
+    let mut config = VectorStoreConfig::new(body.vector_db_name, body.dimensions as usize);
+    // Either bound falls back to the default range when left out
+    if body.min_val.is_some() || body.max_val.is_some() {
+        config = config.bounds(body.min_val.unwrap_or(-1.0), body.max_val.unwrap_or(1.0));
+    }
+    match (
+        body.num_subspaces,
+        body.num_centroids,
+        body.training_vectors,
+    ) {
+        (None, None, None) => {}
+        (Some(num_subspaces), Some(num_centroids), Some(training_vectors)) => {
+            config = config.product_quantization(num_subspaces, num_centroids, training_vectors);
+        }
+        _ => {
+            return HttpResponse::BadRequest().body(
+                "Product quantization needs num_subspaces, num_centroids and training_vectors",
+            )
+        }
+    }
 
-    // Call init_vector_store using web::block
-    let result = init_vector_store(name, size, lower_bound, upper_bound, max_cache_level).await;
+    let result = init_vector_store(config).await;
 
     match result {
         Ok(_) => HttpResponse::Ok().json(RPCResponseBody::RespCreateVectorDb { result: true }),
+        Err(e @ WaCustomError::AlreadyExists(_)) => {
+            HttpResponse::Conflict().body(format!("Error: {}", e))
+        }
         Err(e) => HttpResponse::NotAcceptable().body(format!("Error: {}", e)),
     }
 }
@@ -1,6 +1,7 @@
 use crate::{
     api_service::fetch_vector_neighbors,
     models::{
+        common::WaCustomError,
         rpc::{FetchNeighbors, RPCResponseBody, Vector, VectorIdValue},
         types::{get_app_env, VectorId},
     },
@@ -23,29 +24,22 @@ pub(crate) async fn fetch(web::Json(body): web::Json<FetchNeighbors>) -> HttpRes
     };
     let fvid = VectorId::from(body.vector_id);
 
-    let result = fetch_vector_neighbors(vec_store.clone(), fvid).await;
+    let (id, neighbors) = match fetch_vector_neighbors(vec_store.clone(), fvid).await {
+        Ok(result) => result,
+        Err(err @ WaCustomError::NotFound(_)) => {
+            return HttpResponse::NotFound().body(err.to_string())
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
 
-    let mut xx: Vec<Option<RPCResponseBody>> = result
-        .iter()
-        .map(|res_item| {
-            res_item.as_ref().map(|(vect, neig)| {
-                let nvid = VectorIdValue::from(vect.clone());
-                let response_data = RPCResponseBody::RespFetchNeighbors {
-                    neighbors: neig
-                        .iter()
-                        .map(|(vid, x)| (VectorIdValue::from(vid.clone()), *x))
-                        .collect(),
-                    vector: Vector {
-                        id: nvid,
-                        values: vec![],
-                    },
-                };
-                response_data
-            })
-        })
-        .collect();
-    // Filter out any None values (optional)
-    xx.retain(|x| x.is_some());
-    let rs: Vec<RPCResponseBody> = xx.into_iter().map(|x| x.unwrap()).collect();
-    HttpResponse::Ok().json(rs)
+    HttpResponse::Ok().json(RPCResponseBody::RespFetchNeighbors {
+        neighbors: neighbors
+            .into_iter()
+            .map(|(vid, score)| (VectorIdValue::from(vid), score))
+            .collect(),
+        vector: Vector {
+            id: VectorIdValue::from(id),
+            values: vec![],
+        },
+    })
 }
@@ -1,46 +1,296 @@
+use crate::distance::DistanceFunction;
+use crate::models::cache_loader::NodeRegistry;
 use crate::models::common::*;
-use crate::models::custom_buffered_writer::CustomBufferedWriter;
+use crate::models::custom_buffered_writer::{CustomBufferedWriter, BULK_BUFFER_SIZE};
 use crate::models::file_persist::*;
+use crate::models::index_reader::IndexFileReader;
 use crate::models::lazy_load::*;
 use crate::models::meta_persist::*;
+use crate::models::meta_store::get_u32;
 use crate::models::rpc::VectorIdValue;
+use crate::models::store_file::{MemoryFile, StoreFile};
 use crate::models::types::*;
-use crate::models::user::Statistics;
-use crate::quantization::{Quantization, StorageType};
+use crate::models::versioning::VersionHash;
+use crate::quantization::{
+    product::ProductQuantization, scalar::ScalarQuantization, Quantization, StorageType,
+};
+use crate::storage::Storage;
 use crate::vector_store::*;
 use actix_web::web;
 use cosdata::config_loader::Config;
-use lmdb::{DatabaseFlags, Transaction};
-use rand::Rng;
+use futures::Stream;
+use half::f16;
+use lmdb::{DatabaseFlags, Environment};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rayon::iter::IndexedParallelIterator;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
-use std::array::TryFromSliceError;
 use std::cell::RefCell;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
 use std::rc::Rc;
-use std::sync::{atomic::AtomicBool, Arc};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize},
+    Arc,
+};
 
-pub async fn init_vector_store(
+// Options for `init_vector_store`. Only the name and dimension are required, the rest
+// start from the same defaults the create endpoint uses
+#[derive(Debug, Clone)]
+pub struct VectorStoreConfig {
     name: String,
     size: usize,
     lower_bound: Option<f32>,
     upper_bound: Option<f32>,
     max_cache_level: u8,
-) -> Result<(), WaCustomError> {
-    if name.is_empty() {
+    max_neighbors: usize,
+    distance_metric: DistanceMetric,
+    quantization_metric: QuantizationMetric,
+    storage_type: StorageType,
+    training_vectors: Option<Vec<Vec<f32>>>,
+    seed: Option<u64>,
+    levels_factor: Option<f64>,
+    min_explore_similarity: Option<f32>,
+    neighbor_selection: NeighborSelection,
+    persist_mode: PersistMode,
+    keep_versions: Option<usize>,
+    overwrite: bool,
+}
+
+impl VectorStoreConfig {
+    pub fn new(name: String, size: usize) -> Self {
+        VectorStoreConfig {
+            name,
+            size,
+            lower_bound: None,
+            upper_bound: None,
+            // ---------------------------
+            // -- TODO Maximum cache level
+            // ---------------------------
+            max_cache_level: 5,
+            max_neighbors: 20,
+            distance_metric: DistanceMetric::Cosine,
+            quantization_metric: QuantizationMetric::Scalar,
+            storage_type: StorageType::UnsignedByte,
+            training_vectors: None,
+            seed: None,
+            levels_factor: None,
+            min_explore_similarity: None,
+            neighbor_selection: NeighborSelection::Simple,
+            persist_mode: PersistMode::WriteThrough,
+            keep_versions: None,
+            overwrite: false,
+        }
+    }
+
+    // Range the root vector is drawn from, [-1, 1] unless set
+    pub fn bounds(mut self, lower_bound: f32, upper_bound: f32) -> Self {
+        self.lower_bound = Some(lower_bound);
+        self.upper_bound = Some(upper_bound);
+        self
+    }
+
+    pub fn max_cache_level(mut self, max_cache_level: u8) -> Self {
+        self.max_cache_level = max_cache_level;
+        self
+    }
+
+    pub fn max_neighbors(mut self, max_neighbors: usize) -> Self {
+        self.max_neighbors = max_neighbors;
+        self
+    }
+
+    pub fn distance_metric(mut self, distance_metric: DistanceMetric) -> Self {
+        self.distance_metric = distance_metric;
+        self
+    }
+
+    pub fn quantization_metric(mut self, quantization_metric: QuantizationMetric) -> Self {
+        self.quantization_metric = quantization_metric;
+        self
+    }
+
+    pub fn storage_type(mut self, storage_type: StorageType) -> Self {
+        self.storage_type = storage_type;
+        self
+    }
+
+    // Splits each vector into `num_subspaces` equal parts, each snapped to one of
+    // `num_centroids` entries of a codebook trained on `training_vectors`
+    pub fn product_quantization(
+        mut self,
+        num_subspaces: usize,
+        num_centroids: usize,
+        training_vectors: Vec<Vec<f32>>,
+    ) -> Self {
+        self.quantization_metric =
+            QuantizationMetric::Product(ProductQuantization::new(num_subspaces, num_centroids));
+        self.training_vectors = Some(training_vectors);
+        self
+    }
+
+    // Required by product quantization, which trains its codebook on them
+    pub fn training_vectors(mut self, training_vectors: Vec<Vec<f32>>) -> Self {
+        self.training_vectors = Some(training_vectors);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn levels_factor(mut self, levels_factor: f64) -> Self {
+        self.levels_factor = Some(levels_factor);
+        self
+    }
+
+    // Searches skip expanding neighbors that score worse than this below the top
+    // level, trading a little recall for fewer visited nodes. Each node still
+    // expands its best neighbor
+    pub fn min_explore_similarity(mut self, min_explore_similarity: f32) -> Self {
+        self.min_explore_similarity = Some(min_explore_similarity);
+        self
+    }
+
+    pub fn neighbor_selection(mut self, neighbor_selection: NeighborSelection) -> Self {
+        self.neighbor_selection = neighbor_selection;
+        self
+    }
+
+    pub fn persist_mode(mut self, persist_mode: PersistMode) -> Self {
+        self.persist_mode = persist_mode;
+        self
+    }
+
+    // Index files of all but the `keep_versions` newest versions are removed after
+    // each commit, so older versions can no longer be searched
+    pub fn keep_versions(mut self, keep_versions: usize) -> Self {
+        self.keep_versions = Some(keep_versions);
+        self
+    }
+
+    // Replace a store that already has this name instead of failing with
+    // `AlreadyExists`. The old store's files and vectors are discarded
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+}
+
+pub async fn init_vector_store(config: VectorStoreConfig) -> Result<(), WaCustomError> {
+    let vec_store = create_vector_store(config, false)?;
+    let ain_env = get_app_env().map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    ain_env
+        .vector_store_map
+        .insert(vec_store.database_name.clone(), vec_store);
+    Ok(())
+}
+
+// Creates a store that never touches the disk. Its props and raw embeddings are kept
+// in memory and it writes no index files, only its metadata DB sits in a temporary
+// directory that goes with the store. It isn't registered with the app, so the
+// returned handle is the only way to reach it
+pub async fn init_vector_store_in_memory(
+    config: VectorStoreConfig,
+) -> Result<Arc<VectorStore>, WaCustomError> {
+    create_vector_store(config, true)
+}
+
+fn create_vector_store(
+    config: VectorStoreConfig,
+    in_memory: bool,
+) -> Result<Arc<VectorStore>, WaCustomError> {
+    let VectorStoreConfig {
+        name,
+        size,
+        lower_bound,
+        upper_bound,
+        max_cache_level,
+        max_neighbors,
+        distance_metric,
+        quantization_metric,
+        storage_type,
+        training_vectors,
+        seed,
+        levels_factor,
+        min_explore_similarity,
+        neighbor_selection,
+        persist_mode,
+        keep_versions,
+        overwrite,
+    } = config;
+
+    // The name doubles as the store's directory name, and a `:` ends it in the keys
+    // of its ids, where store `a` would otherwise see the ids of store `a:b`
+    if name.is_empty() || name.contains(['/', '\\', ':']) || name == "." || name == ".." {
+        return Err(WaCustomError::InvalidParams);
+    }
+    // Each level holds roughly `1 / levels_factor` of the nodes of the one below it
+    let levels_factor = levels_factor.unwrap_or(DEFAULT_LEVELS_FACTOR);
+    if levels_factor <= 1.0 || !levels_factor.is_finite() {
         return Err(WaCustomError::InvalidParams);
     }
+    if let StorageType::SubByte(resolution) = storage_type {
+        if resolution == 0 || resolution > MAX_SUB_BYTE_RESOLUTION {
+            return Err(WaCustomError::InvalidParams);
+        }
+    }
+    // Otherwise every search of the store would fail
+    if !distance_metric.supports(storage_type) {
+        return Err(WaCustomError::InvalidParams);
+    }
+
+    let (lmdb, memory) = if in_memory {
+        let (env, lmdb_dir) = open_temp_env()?;
+        let memory = MemoryBacking {
+            raw_embeddings: MemoryFile::new(),
+            _lmdb_dir: Arc::new(lmdb_dir),
+        };
+        (open_meta_db(env)?, Some(memory))
+    } else {
+        // Checked before touching any files, which the new store would otherwise clobber
+        let ain_env = get_app_env().map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        let lmdb = open_meta_db(ain_env.persist.clone())?;
+        if !overwrite
+            && (ain_env.vector_store_map.contains_key(&name)
+                || vector_store_meta_exists(&lmdb, &name)?)
+        {
+            return Err(WaCustomError::AlreadyExists(name));
+        }
+        (lmdb, None)
+    };
 
-    let quantization_metric = Arc::new(QuantizationMetric::Scalar);
-    let storage_type = StorageType::UnsignedByte;
+    let mut quantization_metric = quantization_metric;
+    if let QuantizationMetric::Product(product) = &quantization_metric {
+        // Codes are stored a byte each, and every subspace has the same width
+        if product.num_subspaces == 0
+            || size % product.num_subspaces != 0
+            || product.num_centroids == 0
+            || product.num_centroids > u8::MAX as usize + 1
+        {
+            return Err(WaCustomError::InvalidParams);
+        }
+        // Product quantization can't encode anything without a trained codebook
+        match training_vectors {
+            Some(vectors) if !vectors.is_empty() => quantization_metric.train(&vectors)?,
+            _ => return Err(WaCustomError::InvalidParams),
+        }
+    }
+    let quantization_metric = Arc::new(quantization_metric);
 
-    let min = lower_bound.unwrap_or(-1.0);
-    let max = upper_bound.unwrap_or(1.0);
+    let min = lower_bound.unwrap_or(DEFAULT_BOUNDS.0);
+    let max = upper_bound.unwrap_or(DEFAULT_BOUNDS.1);
+    // A seed makes the root vector, and so the index layout, reproducible
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
     let vec = (0..size)
         .map(|_| {
-            let mut rng = rand::thread_rng();
-
             let random_number: f32 = rng.gen_range(min..max);
             random_number
         })
@@ -50,37 +300,35 @@ pub async fn init_vector_store(
     let exec_queue_nodes: ExecQueueUpdate = Item::new(Vec::new());
     let vector_list = Arc::new(quantization_metric.quantize(&vec, storage_type));
 
-    // Note that setting .write(true).append(true) has the same effect
-    // as setting only .append(true)
-    let prop_file = Arc::new(
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("prop.data")
-            .expect("Failed to open file for writing"),
-    );
-
-    let ver_file = Rc::new(RefCell::new(
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("0.index")
-            .expect("Failed to open file for writing"),
-    ));
-
-    let mut writer =
-        CustomBufferedWriter::new(ver_file.clone()).expect("Failed opening custom buffer");
+    // An overwritten store's files are discarded, the new one starts from empty files
+    let base_path = vector_store_dir(&name)?;
+    let prop_file = if in_memory {
+        Arc::new(StoreFile::Memory(MemoryFile::new()))
+    } else {
+        if base_path.exists() {
+            std::fs::remove_dir_all(&base_path)?;
+        }
+        std::fs::create_dir_all(&base_path)?;
+        Arc::new(StoreFile::Disk(open_prop_file(&base_path)?))
+    };
 
     let mut root: LazyItemRef<MergedNode> = LazyItemRef::new_invalid();
     let mut prev: LazyItemRef<MergedNode> = LazyItemRef::new_invalid();
 
+    // Every level shares the root's prop, so it only needs writing once
+    let mut root_prop = NodeProp {
+        id: vec_hash.clone(),
+        value: vector_list.clone(),
+        location: None,
+        metadata: None,
+        namespace: None,
+        exact: None,
+    };
+    root_prop.location = Some(write_prop_to_file(&root_prop, prop_file.handle()));
+    let prop = Arc::new(root_prop);
+
     let mut nodes = Vec::new();
     for l in 0..=max_cache_level {
-        let prop = Arc::new(NodeProp {
-            id: vec_hash.clone(),
-            value: vector_list.clone(),
-            location: Some((0, 0)),
-        });
         let mut current_node = Item::new(MergedNode {
             version_id: 0, // Initialize with appropriate version ID
             hnsw_level: l as u8,
@@ -105,34 +353,216 @@ pub async fn init_vector_store(
 
         if l == 0 {
             root = nn.clone();
-            let prop_location = write_prop_to_file(&prop, &prop_file);
-            current_node.get().set_prop_ready(prop);
         }
         nodes.push(nn.clone());
         // println!("sssss: {:?}", nn);
     }
 
-    for (l, nn) in nodes.iter_mut().enumerate() {
-        match persist_node_update_loc(&mut writer, nn.item.clone()) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Failed node persist (init): {}", e);
-            }
-        };
-    }
+    if !in_memory {
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(base_path.join("0.index"))
+            .expect("Failed to open file for writing");
+        write_index_header(&mut index_file)?;
+        let ver_file = Rc::new(RefCell::new(index_file));
+
+        let mut writer = CustomBufferedWriter::with_capacity(ver_file.clone(), BULK_BUFFER_SIZE)
+            .expect("Failed opening custom buffer");
 
-    writer
-        .flush()
-        .expect("Final Custom Buffered Writer flush failed ");
+        for (l, nn) in nodes.iter_mut().enumerate() {
+            match persist_node_update_loc(&mut writer, nn.item.clone()) {
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("Failed node persist (init): {}", e);
+                }
+            };
+        }
+
+        writer
+            .finish()
+            .expect("Final Custom Buffered Writer flush failed ");
+    }
     // ---------------------------
     // -- TODO level entry ratio
     // ---------------------------
-    let factor_levels = 10.0;
-    let lp = Arc::new(generate_tuples(factor_levels).into_iter().rev().collect());
+    let lp = Arc::new(generate_tuples(levels_factor).into_iter().rev().collect());
+    clear_embedding_ids(&lmdb, &name)?;
+    clear_indexing_counters(&lmdb, &name)?;
+    clear_vector_count(&lmdb, &name)?;
+
+    let meta = VectorStoreMeta {
+        name: name.clone(),
+        size,
+        max_cache_level,
+        max_neighbors,
+        distance_metric: distance_metric.clone(),
+        quantization_metric: (*quantization_metric).clone(),
+        storage_type,
+        root_offset: root.item.clone().get().get_offset().unwrap_or(0),
+        levels_factor,
+        bounds: (min, max),
+        min_explore_similarity,
+        neighbor_selection,
+        persist_mode,
+        keep_versions,
+    };
+    // There's nothing to reload a store in memory from
+    if !in_memory {
+        store_vector_store_meta(&lmdb, &meta)?;
+    }
+
+    let vec_store = Arc::new(VectorStore::new(VectorStoreParts {
+        exec_queue_nodes,
+        max_cache_level,
+        max_neighbors,
+        database_name: name.clone(),
+        base_path,
+        root_vec: root,
+        levels_prob: lp,
+        prop_file,
+        lmdb,
+        current_version: Item::new(None),
+        quantization_metric,
+        distance_metric,
+        storage_type,
+        bounds: (min, max),
+        min_explore_similarity,
+        neighbor_selection,
+        persist_mode,
+        keep_versions,
+        memory,
+    }));
+
+    let result = store_current_version(
+        &vec_store.lmdb,
+        &vec_store.database_name,
+        "main".to_string(),
+        0,
+    );
+    let version_hash = result.expect("Failed to get VersionHash");
+    vec_store.set_current_version(Some(version_hash));
+    // An overwritten store numbers its versions from the start again
+    clear_reserved_versions(&vec_store.lmdb, &vec_store.database_name)?;
+
+    Ok(vec_store)
+}
+
+// Rebuilds every store recorded in the metadata DB that isn't already in memory
+pub fn load_vector_stores() -> Result<(), WaCustomError> {
+    let ain_env = get_app_env().map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    let lmdb = open_meta_db(ain_env.persist.clone())?;
+
+    for meta in retrieve_vector_store_metas(&lmdb)? {
+        if ain_env.vector_store_map.contains_key(&meta.name) {
+            continue;
+        }
+        match load_vector_store(meta, lmdb.clone()) {
+            Ok(vec_store) => {
+                ain_env
+                    .vector_store_map
+                    .insert(vec_store.database_name.clone(), vec_store);
+            }
+            Err(e) => {
+                eprintln!("Failed to restore vector store: {}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Names of the stores the app has loaded, sorted. Stores created with
+// `init_vector_store_in_memory` aren't registered, so they're left out
+pub fn list_stores() -> Result<Vec<String>, WaCustomError> {
     let ain_env = get_app_env().map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    let mut names: Vec<String> = ain_env
+        .vector_store_map
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect();
+    names.sort();
+    Ok(names)
+}
 
-    let denv = ain_env.persist.clone();
+fn load_vector_store(
+    meta: VectorStoreMeta,
+    lmdb: MetaDb,
+) -> Result<Arc<VectorStore>, WaCustomError> {
+    let base_path = vector_store_dir(&meta.name)?;
+    recover_incomplete_transaction(&lmdb, &meta.name, &base_path)?;
+    let prop_file = Arc::new(StoreFile::Disk(open_prop_file(&base_path)?));
 
+    // Reloaded as of the latest commit, a store that never committed still has the
+    // graph it was created with
+    let current_version = retrieve_current_version(&lmdb, &meta.name).ok();
+    let version_root = match &current_version {
+        Some(current) if current.version != 0 => retrieve_version_root(&lmdb, &meta.name, current)?,
+        _ => VersionRoot {
+            version: 0,
+            file_version: 0,
+            root_offset: meta.root_offset,
+            relocations: None,
+        },
+    };
+    let mut index_file = OpenOptions::new()
+        .read(true)
+        .open(index_file_path(&base_path, version_root.file_version))?;
+    check_index_header(&mut index_file)?;
+    let moved = read_relocations(&mut index_file, version_root.relocations)?;
+
+    let root_offset = version_root.root_offset;
+    let cache = Arc::new(NodeRegistry::new(1000, index_file).with_relocations(moved.clone()));
+    let root_node = read_node_from_file(root_offset, cache.clone())
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    if let Some(location) = root_node.get_prop_location() {
+        root_node.set_prop_ready(Arc::new(read_prop_from_file(location, prop_file.handle())?));
+    }
+    let root = LazyItemRef::new(root_node);
+    root.set_offset(Some(root_offset));
+    cache.link_loaded(root.item.clone().get());
+
+    let lp = Arc::new(
+        generate_tuples(meta.levels_factor)
+            .into_iter()
+            .rev()
+            .collect(),
+    );
+
+    let vec_store = Arc::new(
+        VectorStore::new(VectorStoreParts {
+            exec_queue_nodes: Item::new(Vec::new()),
+            max_cache_level: meta.max_cache_level,
+            max_neighbors: meta.max_neighbors,
+            database_name: meta.name,
+            base_path,
+            root_vec: root,
+            levels_prob: lp,
+            prop_file,
+            lmdb,
+            current_version: Item::new(current_version),
+            quantization_metric: Arc::new(meta.quantization_metric),
+            distance_metric: meta.distance_metric,
+            storage_type: meta.storage_type,
+            bounds: meta.bounds,
+            min_explore_similarity: meta.min_explore_similarity,
+            neighbor_selection: meta.neighbor_selection,
+            persist_mode: meta.persist_mode,
+            keep_versions: meta.keep_versions,
+            memory: None,
+        })
+        .with_index_log(IndexLog {
+            file_version: version_root.file_version,
+            relocations: version_root.relocations,
+            moved,
+        }),
+    );
+    register_loaded_nodes(&vec_store)?;
+
+    Ok(vec_store)
+}
+
+fn open_meta_db(denv: Arc<Environment>) -> Result<MetaDb, WaCustomError> {
     let metadata_db = denv
         .create_db(Some("metadata"), DatabaseFlags::empty())
         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
@@ -141,98 +571,172 @@ pub async fn init_vector_store(
         .create_db(Some("embeddings"), DatabaseFlags::empty())
         .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
 
-    let vec_store = Arc::new(VectorStore::new(
-        exec_queue_nodes,
-        max_cache_level,
-        name.clone(),
-        root,
-        lp,
-        (size / 32) as usize,
-        prop_file,
-        MetaDb {
-            env: denv.clone(),
-            metadata_db: Arc::new(metadata_db.clone()),
-            embeddings_db: Arc::new(embeddings_db),
-        },
-        Item::new(None),
-        Arc::new(QuantizationMetric::Scalar),
-        Arc::new(DistanceMetric::Cosine),
-        StorageType::UnsignedByte,
-    ));
-    ain_env
-        .vector_store_map
-        .insert(name.clone(), vec_store.clone());
+    Ok(MetaDb {
+        env: denv,
+        metadata_db: Arc::new(metadata_db),
+        embeddings_db: Arc::new(embeddings_db),
+        write_txns: Arc::new(AtomicUsize::new(0)),
+    })
+}
 
-    let result = store_current_version(vec_store.clone(), "main".to_string(), 0);
-    let version_hash = result.expect("Failed to get VersionHash");
-    vec_store.set_current_version(Some(version_hash));
+// Note that setting .write(true).append(true) has the same effect
+// as setting only .append(true)
+fn open_prop_file(base_path: &Path) -> Result<File, WaCustomError> {
+    OpenOptions::new()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(base_path.join("prop.data"))
+        .map_err(WaCustomError::from)
+}
 
-    Ok(())
+// Something in an upload batch that would stop a vector from being stored as given
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadProblem {
+    // Dimensions after quantization, which pads sub-byte vectors to whole bytes
+    DimensionMismatch { expected: usize, actual: usize },
+    // Appears earlier in the same batch
+    DuplicateId,
+    // Position of the first NaN or infinite value
+    NonFiniteValue(usize),
+    // Position of the first value the store's storage type can't represent
+    OutOfRange(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadIssue {
+    pub id: VectorId,
+    pub problem: UploadProblem,
+}
+
+// Runs the per-vector checks of an upload without storing anything, reporting every
+// vector that fails one of them. Nothing is read from or written to LMDB, so ids
+// already in the store are only caught by the upload itself
+pub fn validate_upload(
+    vec_store: &VectorStore,
+    vecxx: &[(VectorIdValue, Vec<f32>)],
+) -> Result<Vec<UploadIssue>, WaCustomError> {
+    let Some(mut root) = vec_store.root_vec.get_data() else {
+        return Err(WaCustomError::NodeError(
+            "Root node is not loaded".to_string(),
+        ));
+    };
+    let expected = get_or_load_prop(root.get(), vec_store)?.value.dim();
+    let (min, max) = representable_range(vec_store.storage_type);
+
+    let mut seen = HashSet::new();
+    let mut issues = Vec::new();
+    for (id, vec) in vecxx {
+        let id = convert_value(id.clone());
+        let problem = if !seen.insert(id.clone()) {
+            Some(UploadProblem::DuplicateId)
+        } else if let Some(index) = vec.iter().position(|value| !value.is_finite()) {
+            Some(UploadProblem::NonFiniteValue(index))
+        } else if let Some(index) = vec.iter().position(|value| *value < min || *value > max) {
+            Some(UploadProblem::OutOfRange(index))
+        } else {
+            let actual = vec_store.quantize(vec).dim();
+            (actual != expected).then_some(UploadProblem::DimensionMismatch { expected, actual })
+        };
+        if let Some(problem) = problem {
+            issues.push(UploadIssue { id, problem });
+        }
+    }
+
+    Ok(issues)
+}
+
+// Values outside these are clamped or saturated by the scalar quantizer
+fn representable_range(storage_type: StorageType) -> (f32, f32) {
+    match storage_type {
+        StorageType::UnsignedByte => (0.0, 1.0),
+        StorageType::SubByte(_) => (-1.0, 1.0),
+        StorageType::HalfPrecisionFP => (f16::MIN.to_f32(), f16::MAX.to_f32()),
+        StorageType::Float32 => (f32::MIN, f32::MAX),
+    }
 }
 
+// Vectors `run_upload` inserts per LMDB write transaction
+const UPLOAD_BATCH_SIZE: usize = 64;
+
 pub fn run_upload(
     vec_store: Arc<VectorStore>,
     vecxx: Vec<(VectorIdValue, Vec<f32>)>,
     config: web::Data<Config>,
 ) -> () {
-    vecxx.into_par_iter().for_each(|(id, vec)| {
-        let hash_vec = convert_value(id);
-        let storage = vec_store
-            .quantization_metric
-            .quantize(&vec, vec_store.storage_type);
-        let vec_emb = VectorEmbedding {
-            raw_vec: Arc::new(storage),
-            hash_vec,
-        };
-
-        insert_embedding(vec_store.clone(), &vec_emb).expect("Failed to inert embedding to LMDB");
-    });
+    run_upload_in_namespace(vec_store, None, vecxx, config)
+}
 
-    let env = vec_store.lmdb.env.clone();
-    let metadata_db = vec_store.lmdb.metadata_db.clone();
+// Like `run_upload` for clients holding f64 data. Each value is rounded to the nearest
+// f32 here, before quantization, so the result is the same as uploading the values
+// already converted with `as f32`. That drops precision past about seven significant
+// digits, which quantization discards anyway, and values beyond the f32 range become
+// infinite, so keep them within the store's bounds
+pub fn run_upload_f64(
+    vec_store: Arc<VectorStore>,
+    vecxx: Vec<(VectorIdValue, Vec<f64>)>,
+    config: web::Data<Config>,
+) -> () {
+    let vecxx = vecxx
+        .into_iter()
+        .map(|(id, vec)| (id, vec.into_iter().map(|value| value as f32).collect()))
+        .collect();
+    run_upload(vec_store, vecxx, config)
+}
 
-    let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+// Like `run_upload`, but the vectors go into `namespace`. Their ids only need to be
+// unique there, the same id may be taken in other namespaces
+pub fn run_upload_in_namespace(
+    vec_store: Arc<VectorStore>,
+    namespace: Option<String>,
+    vecxx: Vec<(VectorIdValue, Vec<f32>)>,
+    config: web::Data<Config>,
+) -> () {
+    // Each batch goes into LMDB in one write transaction
+    vecxx
+        .into_par_iter()
+        .chunks(UPLOAD_BATCH_SIZE)
+        .for_each(|batch| {
+            let embs: Vec<VectorEmbedding> = batch
+                .into_iter()
+                .map(|(id, vec)| VectorEmbedding {
+                    raw_vec: Arc::new(vec_store.quantize(&vec)),
+                    hash_vec: convert_value(id),
+                    namespace: namespace.clone(),
+                    exact: vec_store.exact_copy(&vec),
+                })
+                .collect();
 
-    let count_unindexed = txn
-        .get(*metadata_db, &"count_unindexed")
-        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))
-        .and_then(|bytes| {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            Ok(u32::from_le_bytes(bytes))
-        })
-        .expect("Failed to retrieve `count_unindexed`");
+            let results = insert_embeddings(&vec_store, &embs)
+                .unwrap_or_else(|e| panic!("Failed to inert embeddings to LMDB: {}", e));
+            for result in results {
+                match result {
+                    Ok(()) => (),
+                    Err(WaCustomError::DuplicateId(id)) => {
+                        eprintln!("Skipping vector {}: id already exists", id);
+                    }
+                    Err(e) => panic!("Failed to inert embedding to LMDB: {}", e),
+                }
+            }
+        });
 
-    txn.abort();
+    let count_unindexed = get_u32(
+        &vec_store.lmdb,
+        &indexing_counter_key("count_unindexed", &vec_store.database_name),
+    )
+    .expect("Failed to retrieve `count_unindexed`");
 
     if count_unindexed >= config.threshold {
         index_embeddings(vec_store.clone(), config.batch_size).expect("Failed to index embeddings");
     }
 
-    // Update version
-    let ver = vec_store
-        .get_current_version()
-        .expect("No current version found");
-    let new_ver = ver.version + 1;
-
-    // Create new version file
-    let ver_file = Rc::new(RefCell::new(
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(format!("{}.index", new_ver))
-            .map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to open new version file: {}", e))
-            })
-            .unwrap(),
-    ));
-
-    let mut writer =
-        CustomBufferedWriter::new(ver_file.clone()).expect("Failed opening custom buffer");
+    // Left for an explicit `commit`
+    if vec_store.persist_mode == PersistMode::Deferred {
+        return;
+    }
 
     println!("run_upload 333");
-    match auto_commit_transaction(vec_store.clone(), &mut writer) {
+    match commit_new_version(vec_store.clone()) {
         Ok(_) => (),
         Err(e) => {
             eprintln!("Failed node persist(nbr1): {}", e);
@@ -240,46 +744,3211 @@ pub fn run_upload(
     };
 }
 
+// Deletes every vector in `ids` in parallel and commits the result as one version.
+// Each id gets its own result, in the same order, so a missing id fails with
+// `NotFound` without holding back the others. If the commit fails, the deletions that
+// went through report its error instead
+pub fn delete_embeddings(
+    vec_store: Arc<VectorStore>,
+    ids: Vec<VectorId>,
+) -> Vec<Result<(), WaCustomError>> {
+    let mut results: Vec<_> = ids
+        .into_par_iter()
+        .map(|id| delete_embedding(vec_store.clone(), &id))
+        .collect();
+
+    if results.iter().any(|result| result.is_ok()) {
+        if let Err(e) = commit_new_version(vec_store) {
+            for result in results.iter_mut().filter(|result| result.is_ok()) {
+                *result = Err(e.clone());
+            }
+        }
+    }
+    results
+}
+
+// Indexes everything uploaded so far, whether or not the threshold was reached, and
+// commits it as a new version
+pub fn commit(
+    vec_store: Arc<VectorStore>,
+    config: web::Data<Config>,
+) -> Result<VersionHash, WaCustomError> {
+    index_embeddings(vec_store.clone(), config.batch_size)?;
+    commit_new_version(vec_store)
+}
+
+fn commit_new_version(vec_store: Arc<VectorStore>) -> Result<VersionHash, WaCustomError> {
+    if vec_store.memory.is_some() {
+        return commit_in_memory(vec_store);
+    }
+    auto_commit_transaction(vec_store)
+}
+
 pub async fn ann_vector_query(
     vec_store: Arc<VectorStore>,
     query: Vec<f32>,
-) -> Result<Option<Vec<(VectorId, f32)>>, WaCustomError> {
-    let vector_store = vec_store.clone();
-    let vec_hash = VectorId::Str("query".to_string());
-    let root = &vector_store.root_vec;
-    let vector_list = vector_store
-        .quantization_metric
-        .quantize(&query, vector_store.storage_type);
+) -> Result<Option<Vec<ScoredResult>>, WaCustomError> {
+    let storage = quantize_query(&vec_store, &query)?;
+    ann_vector_query_storage(vec_store, Arc::new(storage), usize::MAX).await
+}
 
-    let vec_emb = VectorEmbedding {
-        raw_vec: Arc::new(vector_list.clone()),
-        hash_vec: vec_hash.clone(),
-    };
+// Returns the top `k` matches in `namespace`, `None` being the default one. Like
+// `ann_vector_query_filtered`, the search widens past the candidates it ends on when
+// too few of them are in the namespace
+pub async fn ann_vector_query_in_namespace(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+    k: usize,
+    namespace: Option<&str>,
+) -> Result<Vec<ScoredResult>, WaCustomError> {
+    search_matching(vec_store, &query, k, |node_prop| {
+        node_prop.namespace.as_deref() == namespace
+    })
+}
 
-    let results = ann_search(
-        vec_store.clone(),
-        vec_emb,
-        root.item.clone().get().clone(),
-        vec_store.max_cache_level.try_into().unwrap(),
-    )?;
-    let output = remove_duplicates_and_filter(results);
+// Like `ann_vector_query`, but searches with `ctx` instead of the store's defaults
+// and leaves its counters filled in
+pub fn ann_vector_query_with_context(
+    vec_store: Arc<VectorStore>,
+    query: &[f32],
+    ctx: &mut SearchContext,
+) -> Result<Option<Vec<ScoredResult>>, WaCustomError> {
+    let storage = quantize_query(&vec_store, query)?;
+    let results = search_query_storage_with_context(vec_store.clone(), Arc::new(storage), ctx)?;
+    Ok(remove_duplicates_and_filter(
+        results,
+        &vec_store.get_distance_metric(),
+    ))
+}
+
+// Like `ann_vector_query`, and with `explain` set also hands back the path the search
+// took. Left unset, nothing is recorded
+pub async fn ann_vector_query_explained(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+    explain: bool,
+) -> Result<(Option<Vec<ScoredResult>>, Option<SearchTrace>), WaCustomError> {
+    let mut ctx = SearchContext::for_store(&vec_store);
+    if explain {
+        ctx.trace = Some(SearchTrace::default());
+    }
+    let results = ann_vector_query_with_context(vec_store, &query, &mut ctx)?;
+    Ok((results, ctx.trace))
+}
+
+// Matches buffered in a query stream before the search waits on the consumer
+const STREAM_BUFFER: usize = 64;
+
+// Streams the matches of `ann_vector_query` best first. The search runs on the
+// blocking pool and feeds a bounded channel, so a slow consumer holds back the
+// sender instead of the whole result set piling up. The graph descent only ranks
+// its candidates once it's done, so sending starts after that to keep the order
+pub fn ann_vector_query_stream(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+) -> impl Stream<Item = Result<ScoredResult, WaCustomError>> {
+    let (sender, receiver) = async_channel::bounded(STREAM_BUFFER);
+
+    tokio::task::spawn_blocking(move || {
+        let ranked = search_query(vec_store.clone(), &query).map(|results| {
+            remove_duplicates_and_filter(results, &vec_store.get_distance_metric())
+                .unwrap_or_default()
+        });
+        match ranked {
+            Ok(results) => {
+                for result in results {
+                    // The receiver was dropped, nobody wants the rest
+                    if sender.send_blocking(Ok(result)).is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = sender.send_blocking(Err(e));
+            }
+        }
+    });
+
+    receiver
+}
+
+// Searches with a vector that's already quantized, e.g. one fetched from the store,
+// returning at most `k` matches. It must use the store's storage type
+pub async fn ann_vector_query_storage(
+    vec_store: Arc<VectorStore>,
+    storage: Arc<Storage>,
+    k: usize,
+) -> Result<Option<Vec<ScoredResult>>, WaCustomError> {
+    if storage.storage_type() != vec_store.storage_type {
+        return Err(WaCustomError::QuantizationMismatch);
+    }
+    let results = search_query_storage(vec_store.clone(), storage)?;
+    Ok(remove_duplicates_and_filter_top_k(
+        results,
+        &vec_store.get_distance_metric(),
+        k,
+    ))
+}
+
+// Searches every query in parallel and returns the top `k` matches of each, in the
+// same order as `queries`. Threads each clone their own handles to the shared graph,
+// so the store and its node cache are never accessed through the same `Item`
+pub async fn ann_vector_query_batch(
+    vec_store: Arc<VectorStore>,
+    queries: Vec<Vec<f32>>,
+    k: usize,
+) -> Result<Vec<Vec<ScoredResult>>, WaCustomError> {
+    queries
+        .into_par_iter()
+        .map(|query| {
+            let results = search_query(vec_store.clone(), &query)?;
+            Ok(
+                remove_duplicates_and_filter_top_k(results, &vec_store.get_distance_metric(), k)
+                    .unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+// Like `ann_vector_query`, but hands back the full prop of every match so callers
+// don't need a second fetch for the stored vector
+pub async fn ann_vector_query_with_props(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+) -> Result<Vec<(Arc<NodeProp>, f32)>, WaCustomError> {
+    let results = search_query(vec_store.clone(), &query)?.unwrap_or_default();
+
+    let mut seen = HashSet::new();
+    let mut output = Vec::new();
+    for (lazy_item, similarity) in results {
+        let Some(mut node) = lazy_item.get_data() else {
+            continue;
+        };
+        let node_prop = get_or_load_prop(node.get(), &vec_store)?;
+        // Skip the root placeholder
+        if node_prop.id == VectorId::Int(-1) {
+            continue;
+        }
+        if seen.insert(node_prop.namespaced_id()) {
+            output.push((node_prop, similarity));
+        }
+    }
+    output.sort_by(|a, b| vec_store.get_distance_metric().compare_scores(a.1, b.1));
     Ok(output)
 }
 
-pub async fn fetch_vector_neighbors(
+// Like `ann_vector_query`, but the best `rerank_k` candidates by quantized score are
+// scored again before the top `k` are kept. The second pass compares the query as given
+// with each candidate's vector as it was inserted, so neither the rounding of the
+// query nor that of the stored vectors skews the order. Props that predate exact
+// vectors are decoded back to floats instead. With a `Float32` store both passes agree
+pub async fn ann_vector_query_reranked(
     vec_store: Arc<VectorStore>,
-    vector_id: VectorId,
-) -> Vec<Option<(VectorId, Vec<(VectorId, f32)>)>> {
-    let results = vector_fetch(vec_store.clone(), vector_id);
-    return results.expect("Failed fetching vector neighbors");
+    query: Vec<f32>,
+    k: usize,
+    rerank_k: usize,
+) -> Result<Vec<ScoredResult>, WaCustomError> {
+    let metric = &vec_store.get_distance_metric();
+    let candidates = ann_vector_query_with_props(vec_store.clone(), query.clone()).await?;
+    let exact_query = ScalarQuantization.quantize(&query, StorageType::Float32);
+
+    let mut output = Vec::with_capacity(rerank_k.min(candidates.len()));
+    for (node_prop, _) in candidates.into_iter().take(rerank_k) {
+        let exact = node_prop.exact.clone().unwrap_or_else(|| {
+            let mut decoded = node_prop.value.to_f32_vec();
+            // Sub-byte vectors come back with their padding
+            decoded.truncate(query.len());
+            decoded
+        });
+        let candidate = ScalarQuantization.quantize(&exact, StorageType::Float32);
+        output.push(ScoredResult {
+            id: node_prop.id.clone(),
+            score: metric.calculate(&exact_query, &candidate)?,
+            metric: metric.clone(),
+            namespace: node_prop.namespace.clone(),
+        });
+    }
+    output.sort_by(|a, b| metric.compare_scores(a.score, b.score));
+    output.truncate(k);
+    Ok(output)
+}
+
+// Runs the same descent as `ann_vector_query`, but instead of the top results keeps
+// every match scoring at least as well as `threshold` under the store's metric, so
+// at least it for similarities and at most it for distances
+pub async fn ann_range_query(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+    threshold: f32,
+) -> Result<Vec<ScoredResult>, WaCustomError> {
+    let metric = &vec_store.get_distance_metric();
+    let results = search_query(vec_store.clone(), &query)?;
+    let output = remove_duplicates_and_filter(results, metric)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|result| metric.compare_scores(result.score, threshold).is_le())
+        .collect();
+    Ok(output)
 }
 
-fn calculate_statistics(_: &[i32]) -> Option<Statistics> {
-    // Placeholder for calculating statistics
-    None
+// Candidates checked per requested match before a filtered query gives up
+const FILTER_OVERFETCH: usize = 10;
+
+// Returns the top `k` matches whose metadata holds every key-value pair in `filter`
+pub async fn ann_vector_query_filtered(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+    k: usize,
+    filter: HashMap<String, String>,
+) -> Result<Vec<ScoredResult>, WaCustomError> {
+    search_matching(vec_store, &query, k, |node_prop| {
+        matches_filter(node_prop, &filter)
+    })
 }
 
-fn vector_knn(vs: &Vec<f32>, vecs: &Vec<f32>) -> Vec<(i8, i8, String, f64)> {
-    // Placeholder for vector KNN
-    vec![]
+// Returns the top `k` matches whose prop passes `keep`. The descent is the same as an
+// unfiltered query, `keep` only applies to the candidates it ends on. When too few of
+// those pass, the search widens to their neighbors until `k * FILTER_OVERFETCH` more
+// have been checked
+fn search_matching(
+    vec_store: Arc<VectorStore>,
+    query: &[f32],
+    k: usize,
+    keep: impl Fn(&NodeProp) -> bool,
+) -> Result<Vec<ScoredResult>, WaCustomError> {
+    let storage = Arc::new(quantize_query(&vec_store, query)?);
+    let results = search_query_storage(vec_store.clone(), storage.clone())?.unwrap_or_default();
+    let budget = results
+        .len()
+        .saturating_add(k.saturating_mul(FILTER_OVERFETCH));
+
+    let mut queue: VecDeque<_> = results
+        .into_iter()
+        .map(|(lazy_item, similarity)| (lazy_item, Some(similarity)))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut output = Vec::new();
+    while let Some((lazy_item, similarity)) = queue.pop_front() {
+        let Some(mut node_arc) = lazy_item.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+        let node_prop = get_or_load_prop(node, &vec_store)?;
+        if !seen.insert(node_prop.namespaced_id()) {
+            continue;
+        }
+
+        // Skip the root placeholder
+        if node_prop.id != VectorId::Int(-1) && keep(&node_prop) {
+            let similarity = match similarity {
+                Some(similarity) => similarity,
+                None => vec_store
+                    .get_distance_metric()
+                    .calculate(&storage, &node_prop.value)?,
+            };
+            output.push(ScoredResult {
+                id: node_prop.id.clone(),
+                score: similarity,
+                metric: vec_store.get_distance_metric(),
+                namespace: node_prop.namespace.clone(),
+            });
+        }
+
+        if output.len() < k && seen.len() < budget {
+            queue.extend(node.neighbors.iter().map(|nbr| (nbr.1, None)));
+        }
+    }
+
+    output.sort_by(|a, b| {
+        vec_store
+            .get_distance_metric()
+            .compare_scores(a.score, b.score)
+    });
+    output.truncate(k);
+    Ok(output)
+}
+
+fn matches_filter(node_prop: &NodeProp, filter: &HashMap<String, String>) -> bool {
+    filter.iter().all(|(key, value)| {
+        node_prop
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(key))
+            == Some(value)
+    })
+}
+
+// Searches the graph as it was when `version` was committed
+pub async fn ann_vector_query_at(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+    version: VersionHash,
+) -> Result<Option<Vec<ScoredResult>>, WaCustomError> {
+    let version_root = retrieve_version_root(&vec_store.lmdb, &vec_store.database_name, &version)?;
+    let index_file = IndexFileReader::open(&vec_store.index_file_path(version_root.file_version))?;
+    query_version_file(vec_store, &query, version_root, index_file)
+}
+
+// The file stays open until the search is done, so it can't be removed in the middle
+fn query_version_file(
+    vec_store: Arc<VectorStore>,
+    query: &[f32],
+    version_root: VersionRoot,
+    mut index_file: IndexFileReader,
+) -> Result<Option<Vec<ScoredResult>>, WaCustomError> {
+    check_index_header(&mut index_file)?;
+    // Commits after this version's are in the file too, but not in its tables
+    let moved = read_relocations(&mut index_file, version_root.relocations)?;
+
+    let root_offset = version_root.root_offset;
+    let cache = Arc::new(NodeRegistry::new(1000, index_file).with_relocations(moved));
+    let root_node = read_node_from_file(root_offset, cache.clone())
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    let root = LazyItemRef::new(root_node);
+    root.set_offset(Some(root_offset));
+    cache.link_loaded(root.item.clone().get());
+
+    let mut versioned_store = (*vec_store).clone();
+    versioned_store.root_vec = root;
+
+    let results = search_query(Arc::new(versioned_store), query)?;
+    Ok(remove_duplicates_and_filter(
+        results,
+        &vec_store.get_distance_metric(),
+    ))
+}
+
+fn search_query(
+    vec_store: Arc<VectorStore>,
+    query: &[f32],
+) -> Result<Option<ScoredNodes>, WaCustomError> {
+    let vector_list = quantize_query(&vec_store, query)?;
+    search_query_storage(vec_store, Arc::new(vector_list))
+}
+
+// Quantizes a query with the store's settings. An empty query has nothing to compare,
+// and an all-zero one has no direction, which leaves cosine undefined
+fn quantize_query(vec_store: &VectorStore, query: &[f32]) -> Result<Storage, WaCustomError> {
+    if query.is_empty() {
+        return Err(WaCustomError::InvalidParams);
+    }
+    let needs_direction = matches!(
+        vec_store.get_distance_metric(),
+        DistanceMetric::Cosine
+            | DistanceMetric::NormalizedDotProduct
+            | DistanceMetric::Weighted { .. }
+    );
+    if needs_direction && query.iter().all(|&x| x == 0.0) {
+        return Err(WaCustomError::InvalidParams);
+    }
+    Ok(vec_store.quantize(query))
+}
+
+fn search_query_storage(
+    vec_store: Arc<VectorStore>,
+    query: Arc<Storage>,
+) -> Result<Option<ScoredNodes>, WaCustomError> {
+    let mut ctx = SearchContext::for_store(&vec_store);
+    search_query_storage_with_context(vec_store, query, &mut ctx)
+}
+
+fn search_query_storage_with_context(
+    vec_store: Arc<VectorStore>,
+    query: Arc<Storage>,
+    ctx: &mut SearchContext,
+) -> Result<Option<ScoredNodes>, WaCustomError> {
+    let vec_hash = VectorId::Str("query".to_string());
+    let root = &vec_store.root_vec;
+    // A store whose root never got persisted has nothing to search
+    if root.is_invalid() {
+        return Ok(None);
+    }
+
+    let vec_emb = VectorEmbedding {
+        raw_vec: query,
+        hash_vec: vec_hash,
+        namespace: None,
+        exact: None,
+    };
+
+    ann_search_with_context(
+        vec_store.clone(),
+        vec_emb,
+        root.item.clone().get().clone(),
+        max_search_level(&vec_store)?,
+        ctx,
+    )
+}
+
+fn max_search_level(vec_store: &VectorStore) -> Result<i8, WaCustomError> {
+    i8::try_from(vec_store.max_cache_level).map_err(|_| {
+        WaCustomError::SearchError(format!(
+            "Max cache level {} is out of range",
+            vec_store.max_cache_level
+        ))
+    })
+}
+
+// Descends from the root and from up to `num_entry_points - 1` other top level nodes
+// picked at random, then merges the candidates. More entry points cost more work but
+// reach parts of the graph a single descent can miss
+pub async fn ann_vector_query_multi_probe(
+    vec_store: Arc<VectorStore>,
+    query: Vec<f32>,
+    num_entry_points: usize,
+) -> Result<Option<Vec<ScoredResult>>, WaCustomError> {
+    if num_entry_points == 0 {
+        return Err(WaCustomError::InvalidParams);
+    }
+    let root = &vec_store.root_vec;
+    if root.is_invalid() {
+        return Ok(None);
+    }
+
+    let max_level = max_search_level(&vec_store)?;
+
+    // The top level root's neighbors are the other nodes on the top level
+    let root_item = root.item.clone().get().clone();
+    let mut entry_points = vec![root_item.clone()];
+    if let Some(mut root_node) = entry_at_level(root_item, max_level).get_data() {
+        let top_level: Vec<_> = root_node
+            .get()
+            .neighbors
+            .iter()
+            .map(|nbr| nbr.1)
+            .filter(|nbr| nbr.get_data().is_some())
+            .collect();
+        entry_points.extend(
+            top_level
+                .choose_multiple(&mut rand::thread_rng(), num_entry_points - 1)
+                .cloned(),
+        );
+    }
+
+    let vec_emb = VectorEmbedding {
+        raw_vec: Arc::new(quantize_query(&vec_store, &query)?),
+        hash_vec: VectorId::Str("query".to_string()),
+        namespace: None,
+        exact: None,
+    };
+
+    let mut candidates = Vec::new();
+    for entry_point in entry_points {
+        if let Some(results) =
+            ann_search(vec_store.clone(), vec_emb.clone(), entry_point, max_level)?
+        {
+            candidates.extend(results);
+        }
+    }
+
+    Ok(remove_duplicates_and_filter(
+        Some(candidates),
+        &vec_store.get_distance_metric(),
+    ))
+}
+
+// The level 0 neighbors of `vector_id` with their stored similarities, or
+// `NotFound` if the store doesn't hold it
+pub async fn fetch_vector_neighbors(
+    vec_store: Arc<VectorStore>,
+    vector_id: VectorId,
+) -> Result<(VectorId, Vec<(VectorId, f32)>), WaCustomError> {
+    vector_fetch(vec_store.clone(), vector_id)
+}
+
+// Like `fetch_vector_neighbors` for several ids at once, fetched in parallel through
+// the same store and so the same prop cache. Results come back in the order of `ids`,
+// with `None` for an id the store doesn't hold
+pub async fn fetch_vector_neighbors_batch(
+    vec_store: Arc<VectorStore>,
+    ids: Vec<VectorId>,
+) -> Result<Vec<(VectorId, Option<Vec<(VectorId, f32)>>)>, WaCustomError> {
+    ids.into_par_iter()
+        .map(|id| match vector_fetch(vec_store.clone(), id.clone()) {
+            Ok((id, neighbors)) => Ok((id, Some(neighbors))),
+            Err(WaCustomError::NotFound(_)) => Ok((id, None)),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+// Reports the size and shape of a store's index. The vector total comes from LMDB,
+// the per-level counts and degree from walking the loaded graph
+pub fn calculate_statistics(vec_store: &VectorStore) -> Result<StoreStats, WaCustomError> {
+    let levels = level_counts(vec_store)?;
+    let nodes: usize = levels.iter().map(|(nodes, _)| nodes).sum();
+    let links: usize = levels.iter().map(|(_, links)| links).sum();
+    let average_degree = if nodes == 0 {
+        0.0
+    } else {
+        links as f32 / nodes as f32
+    };
+
+    Ok(StoreStats {
+        total_vectors: vector_count(vec_store)?,
+        nodes_per_level: levels.into_iter().map(|(nodes, _)| nodes).collect(),
+        average_degree,
+        cache_hit_rate: vec_store.prop_cache.hit_rate(),
+        current_version: vec_store.get_current_version(),
+    })
+}
+
+fn vector_knn(vs: &Vec<f32>, vecs: &Vec<f32>) -> Vec<(i8, i8, String, f64)> {
+    // Placeholder for vector KNN
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::index_reader::remove_index_file;
+    use crate::models::versioning::VersionHasher;
+    use cosdata::config_loader::Server;
+
+    fn test_config(threshold: u32) -> web::Data<Config> {
+        web::Data::new(Config {
+            server: Server {
+                host: "127.0.0.1".to_string(),
+                port: "8443".to_string(),
+            },
+            threshold,
+            batch_size: 10,
+            lmdb_map_size: 1073741824,
+            data_dir: DEFAULT_DATA_DIR.into(),
+        })
+    }
+
+    // The options most test stores share, replacing any store left by an earlier run
+    fn store_config(name: &str) -> VectorStoreConfig {
+        VectorStoreConfig::new(name.to_string(), 4)
+            .overwrite(true)
+            .bounds(0.0, 1.0)
+            .max_cache_level(2)
+    }
+
+    // Creates the store on disk and returns it as handlers find it, by name
+    async fn test_store(config: VectorStoreConfig) -> Arc<VectorStore> {
+        let name = config.name.clone();
+        init_vector_store(config).await.unwrap();
+        get_app_env()
+            .unwrap()
+            .vector_store_map
+            .get(&name)
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn test_store_config_defaults() {
+        let config = VectorStoreConfig::new("test_store_config".to_string(), 8);
+        assert_eq!(config.name, "test_store_config");
+        assert_eq!(config.size, 8);
+        assert_eq!((config.lower_bound, config.upper_bound), (None, None));
+        assert_eq!(config.max_cache_level, 5);
+        assert_eq!(config.max_neighbors, 20);
+        assert_eq!(config.distance_metric, DistanceMetric::Cosine);
+        assert!(matches!(
+            config.quantization_metric,
+            QuantizationMetric::Scalar
+        ));
+        assert_eq!(config.storage_type, StorageType::UnsignedByte);
+        assert!(config.training_vectors.is_none());
+        assert_eq!(config.seed, None);
+        assert_eq!(config.levels_factor, None);
+        assert!(!config.overwrite);
+
+        // Setting one option leaves the others alone
+        let config = config.max_neighbors(8).bounds(0.0, 1.0);
+        assert_eq!(config.max_neighbors, 8);
+        assert_eq!(
+            (config.lower_bound, config.upper_bound),
+            (Some(0.0), Some(1.0))
+        );
+        assert_eq!(config.max_cache_level, 5);
+    }
+
+    #[tokio::test]
+    async fn test_dot_product_store_ranking() {
+        let name = "test_dot_product_store_ranking".to_string();
+        let vec_store = init_vector_store_in_memory(
+            store_config(&name).distance_metric(DistanceMetric::DotProduct),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            vec_store.get_distance_metric(),
+            DistanceMetric::DotProduct
+        ));
+
+        // All three point in the same direction, so cosine would rank them equally
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.1, 0.1, 0.1]),
+            (VectorIdValue::IntValue(2), vec![0.5, 0.5, 0.5, 0.5]),
+            (VectorIdValue::IntValue(3), vec![0.9, 0.9, 0.9, 0.9]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.5])
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
+        let position = |id: i32| {
+            results
+                .iter()
+                .position(|result| result.id == VectorId::Int(id))
+                .expect("vector missing from results")
+        };
+        assert!(position(3) < position(2));
+        assert!(position(2) < position(1));
+    }
+
+    #[tokio::test]
+    async fn test_sub_byte_store_needs_a_metric_for_it() {
+        let name = "test_sub_byte_store_needs_a_metric_for_it";
+        for metric in [
+            DistanceMetric::Euclidean,
+            DistanceMetric::Manhattan,
+            DistanceMetric::DotProduct,
+        ] {
+            let result = init_vector_store_in_memory(
+                store_config(name)
+                    .storage_type(StorageType::SubByte(2))
+                    .distance_metric(metric),
+            )
+            .await;
+            assert!(matches!(result, Err(WaCustomError::InvalidParams)));
+        }
+
+        let vec_store = init_vector_store_in_memory(
+            store_config(name)
+                .storage_type(StorageType::SubByte(2))
+                .distance_metric(DistanceMetric::Cosine),
+        )
+        .await
+        .unwrap();
+        let vectors = vec![(VectorIdValue::IntValue(1), vec![0.5, -0.5, 0.5, -0.5])];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let results = ann_vector_query(vec_store, vec![0.5, -0.5, 0.5, -0.5])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].id, VectorId::Int(1));
+    }
+
+    #[tokio::test]
+    async fn test_product_quantized_store() {
+        let name = "test_product_quantized_store".to_string();
+        let result = init_vector_store_in_memory(
+            store_config(&name)
+                .quantization_metric(QuantizationMetric::Product(ProductQuantization::new(2, 2))),
+        )
+        .await;
+        assert!(matches!(result, Err(WaCustomError::InvalidParams)));
+
+        let training = vec![
+            vec![0.2, 0.2, 0.8, 0.8],
+            vec![0.8, 0.8, 0.2, 0.2],
+            vec![0.2, 0.2, 0.8, 0.8],
+            vec![0.8, 0.8, 0.2, 0.2],
+        ];
+        // 3 subspaces can't split 4 dimensions evenly
+        let result = init_vector_store_in_memory(store_config(&name).product_quantization(
+            3,
+            2,
+            training.clone(),
+        ))
+        .await;
+        assert!(matches!(result, Err(WaCustomError::InvalidParams)));
+
+        let vec_store = init_vector_store_in_memory(
+            store_config(&name)
+                .quantization_metric(QuantizationMetric::Product(ProductQuantization::new(2, 2)))
+                .training_vectors(training.clone()),
+        )
+        .await
+        .unwrap();
+
+        // Inserted vectors snap to the nearest codebook entry of each subspace
+        let stored = vec_store
+            .quantization_metric
+            .quantize(&[0.25, 0.15, 0.75, 0.85], vec_store.storage_type);
+        let codebook_entry = vec_store
+            .quantization_metric
+            .quantize(&training[0], vec_store.storage_type);
+        assert_eq!(stored, codebook_entry);
+    }
+
+    #[tokio::test]
+    async fn test_reload_vector_stores() {
+        let name = "test_reload_vector_stores".to_string();
+        let vec_store = test_store(
+            store_config(&name)
+                .max_neighbors(16)
+                .distance_metric(DistanceMetric::Manhattan),
+        )
+        .await;
+        let ain_env = get_app_env().unwrap();
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4]),
+            (VectorIdValue::IntValue(2), vec![0.9, 0.8, 0.7, 0.6]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let version = vec_store.get_current_version().unwrap().version;
+        assert!(version > 0);
+
+        // Creating another store doesn't move this one's version
+        init_vector_store(store_config(&format!("{}_other", name)))
+            .await
+            .unwrap();
+
+        // Simulate a restart by dropping the in-memory store
+        ain_env.vector_store_map.remove(&name);
+        load_vector_stores().unwrap();
+
+        let vec_store = ain_env.vector_store_map.get(&name).unwrap().clone();
+        assert!(matches!(
+            vec_store.get_distance_metric(),
+            DistanceMetric::Manhattan
+        ));
+        assert_eq!(vec_store.max_cache_level, 2);
+        assert_eq!(vec_store.max_neighbors, 16);
+        assert_eq!(vec_store.bounds, (0.0, 1.0));
+        assert_eq!(vec_store.get_current_version().unwrap().version, version);
+
+        // The committed vectors come back with the store
+        let results = ann_vector_query(vec_store.clone(), vec![0.1, 0.2, 0.3, 0.4])
+            .await
+            .unwrap()
+            .unwrap();
+        let ids: HashSet<_> = results.iter().map(|result| result.id.clone()).collect();
+        assert_eq!(ids, HashSet::from([VectorId::Int(1), VectorId::Int(2)]));
+    }
+
+    #[tokio::test]
+    async fn test_query_with_props() {
+        let name = "test_query_with_props".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+
+        let target = vec![0.9, 0.1, 0.1, 0.9];
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.9, 0.9, 0.1]),
+            (VectorIdValue::IntValue(2), target.clone()),
+            (VectorIdValue::IntValue(3), vec![0.5, 0.5, 0.1, 0.1]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let results = ann_vector_query_with_props(vec_store.clone(), target.clone())
+            .await
+            .unwrap();
+        let (top, _) = results.first().expect("no results");
+        assert_eq!(top.id, VectorId::Int(2));
+        assert_eq!(
+            *top.value,
+            vec_store
+                .quantization_metric
+                .quantize(&target, vec_store.storage_type)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vector_count() {
+        let name = "test_vector_count".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+        let initial = vector_count(&vec_store).unwrap();
+
+        let vectors: Vec<_> = (0..5)
+            .map(|i| {
+                (
+                    VectorIdValue::IntValue(i),
+                    vec![0.1 * i as f32, 0.2, 0.3, 0.4],
+                )
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(u32::MAX));
+
+        assert_eq!(vector_count(&vec_store).unwrap(), initial + 5);
+    }
+
+    #[tokio::test]
+    async fn test_update_embedding() {
+        let name = "test_update_embedding".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+
+        let old_value = vec![0.9, 0.1, 0.1, 0.9];
+        let new_value = vec![0.1, 0.9, 0.9, 0.1];
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.5, 0.5, 0.1, 0.1]),
+            (VectorIdValue::IntValue(2), old_value.clone()),
+            (VectorIdValue::IntValue(3), vec![0.1, 0.1, 0.5, 0.5]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        update_embedding(vec_store.clone(), VectorId::Int(2), &new_value).unwrap();
+
+        let new_storage = vec_store
+            .quantization_metric
+            .quantize(&new_value, vec_store.storage_type);
+        let results = ann_vector_query_with_props(vec_store.clone(), new_value.clone())
+            .await
+            .unwrap();
+        let (top, _) = results.first().expect("no results");
+        assert_eq!(top.id, VectorId::Int(2));
+        assert_eq!(*top.value, new_storage);
+
+        // The old value no longer matches anything stored under the id
+        let results = ann_vector_query_with_props(vec_store.clone(), old_value.clone())
+            .await
+            .unwrap();
+        let old_storage = vec_store
+            .quantization_metric
+            .quantize(&old_value, vec_store.storage_type);
+        assert!(results.iter().all(|(prop, _)| *prop.value != old_storage));
+
+        assert!(matches!(
+            update_embedding(vec_store.clone(), VectorId::Int(42), &new_value),
+            Err(WaCustomError::NodeError(_))
+        ));
+
+        // Each replaced value is kept under the version that held it
+        let committed = commit(vec_store.clone(), test_config(0)).unwrap();
+        update_embedding(vec_store.clone(), VectorId::Int(2), &old_value).unwrap();
+        for lazy_node in find_nodes_by_id(&vec_store, &VectorId::Int(2)).unwrap() {
+            let mut node = lazy_node.get_data().unwrap();
+            assert_eq!(node.get().get_versions().len(), 2);
+            let mut previous = node
+                .get()
+                .get_version(committed.version as VersionId)
+                .and_then(|version| version.get_data())
+                .unwrap();
+            let previous_prop = get_or_load_prop(previous.get(), &vec_store).unwrap();
+            assert_eq!(*previous_prop.value, new_storage);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_embedding_refreshes_magnitude() {
+        let name = "test_update_embedding_refreshes_magnitude".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.1, 0.1, 0.1]),
+            (VectorIdValue::IntValue(2), vec![0.5, 0.1, 0.5, 0.1]),
+            (VectorIdValue::IntValue(3), vec![0.1, 0.5, 0.1, 0.5]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        // Same direction, several times the length
+        let new_value = vec![0.9, 0.9, 0.9, 0.9];
+        update_embedding(vec_store.clone(), VectorId::Int(1), &new_value).unwrap();
+
+        let results = ann_vector_query_with_props(vec_store.clone(), new_value.clone())
+            .await
+            .unwrap();
+        let (top, similarity) = results.first().expect("no results");
+        assert_eq!(top.id, VectorId::Int(1));
+        let Storage::UnsignedByte { mag, quant_vec, .. } = top.value.as_ref() else {
+            panic!("Expected UnsignedByte storage");
+        };
+        assert_eq!(*mag, mag_square_u8(quant_vec));
+        // A stale magnitude would put the vector's similarity with itself well off 1
+        assert!((similarity - 1.0).abs() < 1e-3);
+    }
+
+    #[tokio::test]
+    async fn test_query_with_unloaded_root_errors() {
+        let name = "test_query_with_unloaded_root_errors".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+
+        // Point the root at a node that was never loaded
+        vec_store.root_vec.item.clone().update(LazyItem::Valid {
+            data: None,
+            offset: Item::new(Some(42)),
+            decay_counter: 0,
+        });
+
+        let result = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.5]).await;
+        assert!(matches!(result, Err(WaCustomError::LazyLoadingError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_id_rejected() {
+        let name = "test_duplicate_id_rejected".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+
+        let emb = |value: f32| VectorEmbedding {
+            raw_vec: Arc::new(
+                vec_store
+                    .quantization_metric
+                    .quantize(&[value, 0.2, 0.3, 0.4], vec_store.storage_type),
+            ),
+            hash_vec: VectorId::Int(7),
+            namespace: None,
+            exact: None,
+        };
+        insert_embedding(vec_store.clone(), &emb(0.1)).unwrap();
+        let count = vector_count(&vec_store).unwrap();
+
+        assert!(matches!(
+            insert_embedding(vec_store.clone(), &emb(0.9)),
+            Err(WaCustomError::DuplicateId(VectorId::Int(7)))
+        ));
+        assert_eq!(vector_count(&vec_store).unwrap(), count);
+    }
+
+    #[tokio::test]
+    async fn test_range_query_threshold() {
+        let name = "test_range_query_threshold".to_string();
+        let vec_store = init_vector_store_in_memory(
+            store_config(&name).storage_type(StorageType::HalfPrecisionFP),
+        )
+        .await
+        .unwrap();
+
+        let target = vec![0.9, 0.1, 0.1, 0.9];
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.9, 0.9, 0.1]),
+            (VectorIdValue::IntValue(2), target.clone()),
+            (VectorIdValue::IntValue(3), vec![0.5, 0.5, 0.1, 0.1]),
+            (VectorIdValue::IntValue(4), vec![0.8, 0.2, 0.1, 0.8]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let loose = ann_range_query(vec_store.clone(), target.clone(), -1.0)
+            .await
+            .unwrap();
+        let tight = ann_range_query(vec_store.clone(), target.clone(), 0.95)
+            .await
+            .unwrap();
+
+        assert!(!loose.is_empty());
+        assert!(tight.len() <= loose.len());
+        for result in &tight {
+            assert!(result.score >= 0.95);
+            assert!(loose
+                .iter()
+                .any(|loose_result| loose_result.id == result.id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_empty_store() {
+        let name = "test_query_empty_store".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+
+        // Only the root placeholder exists, and it's never returned
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.5])
+            .await
+            .unwrap();
+        assert!(results.unwrap_or_default().is_empty());
+
+        // As if persisting the root had been interrupted
+        vec_store.root_vec.item.clone().update(LazyItem::Invalid);
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.5])
+            .await
+            .unwrap();
+        assert!(results.is_none());
+        let neighbors = fetch_vector_neighbors(vec_store.clone(), VectorId::Int(1)).await;
+        assert!(matches!(neighbors, Err(WaCustomError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_query_at_old_version() {
+        let name = "test_query_at_old_version".to_string();
+        let vec_store =
+            test_store(store_config(&name).storage_type(StorageType::HalfPrecisionFP)).await;
+
+        let old_vectors = vec![
+            (VectorIdValue::IntValue(501), vec![0.9, 0.1, 0.1, 0.9]),
+            (VectorIdValue::IntValue(502), vec![0.5, 0.5, 0.1, 0.1]),
+        ];
+        run_upload(vec_store.clone(), old_vectors, test_config(0));
+        let old_version = vec_store.get_current_version().unwrap();
+
+        let new_vectors = vec![
+            (VectorIdValue::IntValue(601), vec![0.1, 0.9, 0.9, 0.1]),
+            (VectorIdValue::IntValue(602), vec![0.2, 0.8, 0.9, 0.1]),
+        ];
+        run_upload(vec_store.clone(), new_vectors, test_config(0));
+
+        let query = vec![0.1, 0.9, 0.9, 0.1];
+        let results = ann_vector_query_at(vec_store.clone(), query.clone(), old_version)
+            .await
+            .unwrap()
+            .unwrap_or_default();
+        assert!(results
+            .iter()
+            .all(|result| result.id != VectorId::Int(601) && result.id != VectorId::Int(602)));
+
+        // What the old version holds scores as it does in the current one
+        let live = ann_vector_query(vec_store.clone(), query)
+            .await
+            .unwrap()
+            .unwrap();
+        for id in [501, 502] {
+            let score_in = |results: &[ScoredResult]| {
+                results
+                    .iter()
+                    .find(|result| result.id == VectorId::Int(id))
+                    .map(|result| result.score)
+            };
+            assert!(score_in(&results).is_some());
+            assert_eq!(score_in(&results), score_in(&live));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nodes_found_by_id_after_reload() {
+        let name = "test_nodes_found_by_id_after_reload".to_string();
+        let vec_store = test_store(store_config(&name)).await;
+        let mut rng = StdRng::seed_from_u64(47);
+        let vectors: Vec<(VectorIdValue, Vec<f32>)> = (0..50)
+            .map(|id| {
+                let vector = (0..4).map(|_| rng.gen_range(0.0..1.0)).collect();
+                (VectorIdValue::IntValue(id), vector)
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let ain_env = get_app_env().unwrap();
+        ain_env.vector_store_map.remove(&name);
+        load_vector_stores().unwrap();
+        let reloaded = ain_env.vector_store_map.get(&name).unwrap().clone();
+
+        // Every node the graph holds, one per level of each id, top level first
+        let nodes: usize = level_counts(&reloaded)
+            .unwrap()
+            .iter()
+            .map(|(nodes, _)| nodes)
+            .sum();
+        let mut found = 0;
+        for id in 0..50 {
+            let levels: Vec<_> = find_nodes_by_id(&reloaded, &VectorId::Int(id))
+                .unwrap()
+                .into_iter()
+                .map(|lazy_node| {
+                    let mut node_arc = lazy_node.get_data().unwrap();
+                    let node = node_arc.get();
+                    assert_eq!(
+                        get_or_load_prop(node, &reloaded).unwrap().id,
+                        VectorId::Int(id)
+                    );
+                    node.hnsw_level
+                })
+                .collect();
+            assert!(levels.windows(2).all(|pair| pair[0] > pair[1]));
+            found += levels.len();
+        }
+        assert_eq!(found, nodes);
+
+        delete_embedding(reloaded.clone(), &VectorId::Int(3)).unwrap();
+        assert!(find_nodes_by_id(&reloaded, &VectorId::Int(3))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_commit_writes_only_changed_nodes() {
+        let name = "test_commit_writes_only_changed_nodes".to_string();
+        let vec_store = test_store(store_config(&name)).await;
+
+        let mut rng = StdRng::seed_from_u64(46);
+        let vectors: Vec<(VectorIdValue, Vec<f32>)> = (0..300)
+            .map(|id| {
+                let vector = (0..4).map(|_| rng.gen_range(0.0..1.0)).collect();
+                (VectorIdValue::IntValue(id), vector)
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        // A file holding the whole graph and nothing else, which commits append to
+        let compacted = compact_index(vec_store.clone()).unwrap();
+        let path = vec_store.index_file_path(compacted.version);
+        let graph_len = std::fs::metadata(&path).unwrap().len();
+
+        let query = vec![0.95, 0.05, 0.95, 0.05];
+        update_embedding(vec_store.clone(), VectorId::Int(0), &query).unwrap();
+        let updated = commit(vec_store.clone(), test_config(0)).unwrap();
+        let written = std::fs::metadata(&path).unwrap().len() - graph_len;
+        assert!(written > 0);
+        assert!(written < graph_len / 4);
+
+        // Unchanged nodes are reached through the relocations, both by a search of
+        // the new version and by a store reloaded from it
+        let first_id = |results: Option<Vec<ScoredResult>>| results.unwrap()[0].id.clone();
+        let at_updated = ann_vector_query_at(vec_store.clone(), query.clone(), updated)
+            .await
+            .unwrap();
+        assert_eq!(first_id(at_updated), VectorId::Int(0));
+        let at_compacted = ann_vector_query_at(vec_store.clone(), query.clone(), compacted)
+            .await
+            .unwrap();
+        assert_ne!(first_id(at_compacted), VectorId::Int(0));
+
+        let ain_env = get_app_env().unwrap();
+        ain_env.vector_store_map.remove(&name);
+        load_vector_stores().unwrap();
+        let reloaded = ain_env.vector_store_map.get(&name).unwrap().clone();
+        let results = ann_vector_query(reloaded.clone(), query.clone())
+            .await
+            .unwrap();
+        assert_eq!(first_id(results), VectorId::Int(0));
+        assert_eq!(
+            level_counts(&reloaded).unwrap(),
+            level_counts(&vec_store).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_query_matches_sequential() {
+        let name = "test_batch_query_matches_sequential".to_string();
+        let vec_store = init_vector_store_in_memory(
+            store_config(&name).storage_type(StorageType::HalfPrecisionFP),
+        )
+        .await
+        .unwrap();
+
+        let vectors: Vec<_> = (0..8)
+            .map(|i| {
+                let x = i as f32 / 8.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, 0.3, 0.6])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let queries = vec![
+            vec![0.9, 0.1, 0.3, 0.6],
+            vec![0.1, 0.9, 0.3, 0.6],
+            vec![0.5, 0.5, 0.3, 0.6],
+            vec![0.2, 0.7, 0.9, 0.1],
+        ];
+        let k = 3;
+
+        let batch = ann_vector_query_batch(vec_store.clone(), queries.clone(), k)
+            .await
+            .unwrap();
+
+        assert_eq!(batch.len(), queries.len());
+        for (query, batch_results) in queries.into_iter().zip(batch) {
+            let mut sequential = ann_vector_query(vec_store.clone(), query)
+                .await
+                .unwrap()
+                .unwrap_or_default();
+            sequential.truncate(k);
+            assert_eq!(batch_results, sequential);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stores_use_separate_files() {
+        let store_a = test_store(store_config("test_separate_files_a")).await;
+        let store_b = test_store(store_config("test_separate_files_b")).await;
+
+        assert_ne!(store_a.base_path, store_b.base_path);
+        for vec_store in [&store_a, &store_b] {
+            assert!(vec_store.prop_file_path().exists());
+            assert!(vec_store.index_file_path(0).exists());
+        }
+
+        let index_len = |vec_store: &VectorStore| {
+            std::fs::metadata(vec_store.index_file_path(0))
+                .unwrap()
+                .len()
+        };
+        let created = [index_len(&store_a), index_len(&store_b)];
+
+        let vectors = vec![(VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4])];
+        run_upload(store_a.clone(), vectors, test_config(0));
+
+        assert!(index_len(&store_a) > created[0]);
+        assert_eq!(index_len(&store_b), created[1]);
+    }
+
+    #[tokio::test]
+    async fn test_seeded_root_vector_is_reproducible() {
+        let mut root_values = Vec::new();
+        for name in ["test_seeded_root_a", "test_seeded_root_b"] {
+            let vec_store = init_vector_store_in_memory(
+                VectorStoreConfig::new(name.to_string(), 16)
+                    .bounds(-1.0, 1.0)
+                    .max_cache_level(2)
+                    .storage_type(StorageType::HalfPrecisionFP)
+                    .seed(42),
+            )
+            .await
+            .unwrap();
+            let mut root = vec_store.root_vec.get_data().unwrap();
+            let prop = get_or_load_prop(root.get(), &vec_store).unwrap();
+            root_values.push(prop.value.clone());
+        }
+
+        assert_eq!(root_values[0], root_values[1]);
+    }
+
+    #[tokio::test]
+    async fn test_recover_incomplete_transaction() {
+        let name = "test_recover_incomplete_txn";
+        let vec_store = test_store(store_config(name)).await;
+
+        let vectors = vec![(VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4])];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        assert!(retrieve_open_transaction(&vec_store.lmdb, name)
+            .unwrap()
+            .is_none());
+
+        // Simulate a crash halfway through writing a version that never committed
+        let crashed_version = 1000;
+        let path = vec_store.index_file_path(crashed_version);
+        std::fs::write(&path, [0xAB; 64]).unwrap();
+        let transaction = OpenTransaction {
+            version: crashed_version,
+            file_version: crashed_version,
+            start_offset: 0,
+        };
+        store_open_transaction(&vec_store.lmdb, name, transaction).unwrap();
+
+        recover_incomplete_transaction(&vec_store.lmdb, name, &vec_store.base_path).unwrap();
+
+        assert!(!path.exists());
+        assert!(retrieve_open_transaction(&vec_store.lmdb, name)
+            .unwrap()
+            .is_none());
+        let results = ann_vector_query(vec_store.clone(), vec![0.1, 0.2, 0.3, 0.4])
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(results.iter().any(|result| result.id == VectorId::Int(1)));
+    }
+
+    #[tokio::test]
+    async fn test_levels_factor_thins_upper_levels() {
+        let stores = [
+            ("test_levels_factor_low", 4.0),
+            ("test_levels_factor_high", 20.0),
+        ];
+        let mut upper_level_counts = Vec::new();
+        for (name, factor) in stores {
+            let vec_store = init_vector_store_in_memory(store_config(name).levels_factor(factor))
+                .await
+                .unwrap();
+            let count = (0..1000)
+                .map(|i| i as f64 / 1000.0)
+                .filter(|&x| get_max_insert_level(x, vec_store.levels_prob.clone()) > 0)
+                .count();
+            upper_level_counts.push(count);
+        }
+        assert!(upper_level_counts[1] < upper_level_counts[0]);
+
+        let result = init_vector_store_in_memory(
+            store_config("test_levels_factor_invalid").levels_factor(1.0),
+        )
+        .await;
+        assert!(matches!(result, Err(WaCustomError::InvalidParams)));
+    }
+
+    #[tokio::test]
+    async fn test_commit_indexes_below_threshold() {
+        let name = "test_commit_below_threshold";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+
+        let vectors = vec![
+            (VectorIdValue::IntValue(71), vec![0.9, 0.1, 0.1, 0.1]),
+            (VectorIdValue::IntValue(72), vec![0.1, 0.9, 0.1, 0.1]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(1000));
+
+        let is_indexed = |results: Option<Vec<ScoredResult>>| {
+            results
+                .unwrap_or_default()
+                .iter()
+                .any(|result| result.id == VectorId::Int(71))
+        };
+        let before = ann_vector_query(vec_store.clone(), vec![0.9, 0.1, 0.1, 0.1])
+            .await
+            .unwrap();
+        assert!(!is_indexed(before));
+
+        let old_version = vec_store.get_current_version().unwrap();
+        let new_version = commit(vec_store.clone(), test_config(1000)).unwrap();
+        assert!(new_version.version > old_version.version);
+        assert_eq!(
+            vec_store.get_current_version().unwrap().version,
+            new_version.version
+        );
+
+        let after = ann_vector_query(vec_store.clone(), vec![0.9, 0.1, 0.1, 0.1])
+            .await
+            .unwrap();
+        assert!(is_indexed(after));
+    }
+
+    #[tokio::test]
+    async fn test_compact_index_drops_stale_nodes() {
+        let name = "test_compact_index";
+        let vec_store = test_store(store_config(name)).await;
+
+        let mut rng = StdRng::seed_from_u64(45);
+        let vectors: Vec<(VectorIdValue, Vec<f32>)> = (0..40)
+            .map(|id| {
+                let vector = (0..4).map(|_| rng.gen_range(0.0..1.0)).collect();
+                (VectorIdValue::IntValue(id), vector)
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        // Every update leaves the superseded copy of the node behind in the file
+        for id in 0..20 {
+            let vector = (0..4)
+                .map(|_| rng.gen_range(0.0..1.0))
+                .collect::<Vec<f32>>();
+            update_embedding(vec_store.clone(), VectorId::Int(id), &vector).unwrap();
+        }
+        let before = commit(vec_store.clone(), test_config(0)).unwrap();
+        let file_len = |version: &VersionHash| {
+            let version_root = retrieve_version_root(&vec_store.lmdb, name, version).unwrap();
+            std::fs::metadata(vec_store.index_file_path(version_root.file_version))
+                .unwrap()
+                .len()
+        };
+
+        let compacted = compact_index(vec_store.clone()).unwrap();
+        assert_eq!(compacted.version, before.version + 1);
+        assert_eq!(
+            vec_store.get_current_version().unwrap().version,
+            compacted.version
+        );
+        assert!(file_len(&compacted) < file_len(&before));
+
+        let query = vec![0.3, 0.6, 0.2, 0.9];
+        let live = ann_vector_query(vec_store.clone(), query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let from_compacted = ann_vector_query_at(vec_store.clone(), query, compacted)
+            .await
+            .unwrap()
+            .unwrap();
+        let ids = |results: &[ScoredResult]| {
+            results
+                .iter()
+                .map(|result| result.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert!(!from_compacted.is_empty());
+        assert_eq!(ids(&from_compacted), ids(&live));
+    }
+
+    #[tokio::test]
+    async fn test_inserted_cluster_members_are_mutual_neighbors() {
+        let name = "test_hnsw_insert_cluster";
+        let vec_store = init_vector_store_in_memory(
+            store_config(name)
+                // Room for everything inserted before the cluster, so the first member's
+                // back links aren't all evicted, leaving it unreachable for the rest
+                .max_neighbors(8),
+        )
+        .await
+        .unwrap();
+
+        let cluster = [
+            (81, vec![0.9, 0.1, 0.05, 0.05]),
+            (82, vec![0.88, 0.12, 0.06, 0.04]),
+            (83, vec![0.91, 0.08, 0.04, 0.06]),
+            (84, vec![0.89, 0.11, 0.05, 0.07]),
+        ];
+        let others = [
+            (91, vec![0.05, 0.9, 0.1, 0.05]),
+            (92, vec![0.1, 0.05, 0.9, 0.05]),
+            (93, vec![0.05, 0.1, 0.05, 0.9]),
+            (94, vec![0.05, 0.7, 0.7, 0.05]),
+            (95, vec![0.05, 0.05, 0.7, 0.7]),
+            (96, vec![0.05, 0.7, 0.05, 0.7]),
+        ];
+        // One at a time, so each insert sees the ones before it
+        for (id, vector) in others.iter().chain(cluster.iter()) {
+            let vectors = vec![(VectorIdValue::IntValue(*id), vector.clone())];
+            run_upload(vec_store.clone(), vectors, test_config(0));
+        }
+
+        // Neighbors of every vector on the bottom level
+        let mut neighbors: HashMap<VectorId, HashSet<VectorId>> = HashMap::new();
+        let mut queue = VecDeque::from([vec_store.root_vec.item.clone().get().clone()]);
+        let mut visited = HashSet::new();
+        while let Some(lazy_node) = queue.pop_front() {
+            let Some(mut node_arc) = lazy_node.get_data() else {
+                continue;
+            };
+            let node = node_arc.get();
+            let node_prop = get_or_load_prop(node, &vec_store).unwrap();
+            if node.hnsw_level != 0 || !visited.insert(node_prop.id.clone()) {
+                continue;
+            }
+            for nbr in node.neighbors.iter() {
+                let Some(mut nbr_arc) = nbr.1.get_data() else {
+                    continue;
+                };
+                let nbr_prop = get_or_load_prop(nbr_arc.get(), &vec_store).unwrap();
+                neighbors
+                    .entry(node_prop.id.clone())
+                    .or_default()
+                    .insert(nbr_prop.id.clone());
+                queue.push_back(nbr.1);
+            }
+        }
+
+        for (id, _) in &cluster {
+            let linked = &neighbors[&VectorId::Int(*id)];
+            for (other, _) in &cluster {
+                if other != id {
+                    assert!(
+                        linked.contains(&VectorId::Int(*other)),
+                        "{} is not linked to {}",
+                        id,
+                        other
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_upload_flags_bad_vector_only() {
+        let name = "test_validate_upload";
+        let vec_store = test_store(store_config(name)).await;
+        let prop_file_len = || std::fs::metadata(vec_store.prop_file_path()).unwrap().len();
+        let prop_len_before = prop_file_len();
+
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4]),
+            (VectorIdValue::IntValue(2), vec![0.5, 0.6, 0.7]),
+            (VectorIdValue::IntValue(3), vec![0.9, 0.8, 0.7, 0.6]),
+        ];
+        let issues = validate_upload(&vec_store, &vectors).unwrap();
+        assert_eq!(
+            issues,
+            vec![UploadIssue {
+                id: VectorId::Int(2),
+                problem: UploadProblem::DimensionMismatch {
+                    expected: 4,
+                    actual: 3
+                },
+            }]
+        );
+
+        assert_eq!(vector_count(&vec_store).unwrap(), 0);
+        assert_eq!(prop_file_len(), prop_len_before);
+        let results = ann_vector_query(vec_store.clone(), vec![0.1, 0.2, 0.3, 0.4])
+            .await
+            .unwrap()
+            .unwrap_or_default();
+        assert!(results.iter().all(|result| result.id != VectorId::Int(1)));
+    }
+
+    #[tokio::test]
+    async fn test_query_with_storage_matches_vector_query() {
+        let name = "test_query_with_storage";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+
+        let vectors = vec![
+            (VectorIdValue::IntValue(81), vec![0.9, 0.1, 0.1, 0.1]),
+            (VectorIdValue::IntValue(82), vec![0.1, 0.9, 0.1, 0.1]),
+            (VectorIdValue::IntValue(83), vec![0.1, 0.1, 0.9, 0.1]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let query = vec![0.8, 0.2, 0.1, 0.1];
+        let storage = Arc::new(
+            vec_store
+                .quantization_metric
+                .quantize(&query, StorageType::UnsignedByte),
+        );
+        let from_vector = ann_vector_query(vec_store.clone(), query).await.unwrap();
+        let from_storage = ann_vector_query_storage(vec_store.clone(), storage, usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(from_vector, from_storage);
+
+        let mismatched = Arc::new(
+            vec_store
+                .quantization_metric
+                .quantize(&[0.8, 0.2, 0.1, 0.1], StorageType::HalfPrecisionFP),
+        );
+        let result = ann_vector_query_storage(vec_store.clone(), mismatched, usize::MAX).await;
+        assert!(matches!(result, Err(WaCustomError::QuantizationMismatch)));
+    }
+
+    #[tokio::test]
+    async fn test_filtered_query_returns_only_matching() {
+        let name = "test_filtered_query";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+
+        let vectors: Vec<_> = (0..8)
+            .map(|i| {
+                let x = 0.1 + i as f32 * 0.1;
+                (VectorIdValue::IntValue(900 + i), vec![x, 0.5, 1.0 - x, 0.3])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let tagged: Vec<_> = (0..8).step_by(2).map(|i| VectorId::Int(900 + i)).collect();
+        for id in &tagged {
+            let metadata = HashMap::from([("group".to_string(), "even".to_string())]);
+            set_embedding_metadata(vec_store.clone(), id.clone(), metadata).unwrap();
+        }
+
+        let filter = HashMap::from([("group".to_string(), "even".to_string())]);
+        let results =
+            ann_vector_query_filtered(vec_store.clone(), vec![0.4, 0.5, 0.6, 0.3], 3, filter)
+                .await
+                .unwrap();
+
+        assert!(!results.is_empty());
+        assert!(results.len() <= 3);
+        assert!(results.iter().all(|result| tagged.contains(&result.id)));
+    }
+
+    #[tokio::test]
+    async fn test_scored_results_follow_metric_direction() {
+        let stores = [
+            ("test_scored_results_cosine", DistanceMetric::Cosine),
+            ("test_scored_results_euclidean", DistanceMetric::Euclidean),
+        ];
+        for (name, metric) in stores {
+            let vec_store = init_vector_store_in_memory(
+                store_config(name)
+                    .distance_metric(metric.clone())
+                    .storage_type(StorageType::HalfPrecisionFP),
+            )
+            .await
+            .unwrap();
+            let vectors = vec![
+                (VectorIdValue::IntValue(1), vec![0.1, 0.1, 0.1, 0.1]),
+                (VectorIdValue::IntValue(2), vec![0.5, 0.4, 0.5, 0.4]),
+                (VectorIdValue::IntValue(3), vec![0.9, 0.2, 0.8, 0.3]),
+            ];
+            run_upload(vec_store.clone(), vectors, test_config(0));
+
+            let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.5])
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(!results.is_empty());
+            assert!(results.iter().all(|result| result.metric == metric));
+            if metric.higher_is_better() {
+                assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
+            } else {
+                assert!(results.windows(2).all(|w| w[0].score <= w[1].score));
+            }
+        }
+        assert!(DistanceMetric::Cosine.higher_is_better());
+        assert!(!DistanceMetric::Euclidean.higher_is_better());
+    }
+
+    #[tokio::test]
+    async fn test_euclidean_search_keeps_closest_candidates() {
+        let name = "test_euclidean_search_keeps_closest";
+        let vec_store = init_vector_store_in_memory(
+            store_config(name)
+                .distance_metric(DistanceMetric::Euclidean)
+                .storage_type(StorageType::HalfPrecisionFP),
+        )
+        .await
+        .unwrap();
+
+        // More than a traversal step keeps, so dropping the wrong end loses the match
+        let vectors: Vec<_> = (0..20)
+            .map(|i| (VectorIdValue::IntValue(i), vec![i as f32 / 20.0; 4]))
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5; 4])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].id, VectorId::Int(10));
+    }
+
+    #[tokio::test]
+    async fn test_multi_probe_recall_at_least_single_probe() {
+        let name = "test_multi_probe_recall";
+        let vec_store = init_vector_store_in_memory(
+            store_config(name)
+                .storage_type(StorageType::HalfPrecisionFP)
+                .seed(7),
+        )
+        .await
+        .unwrap();
+
+        let dataset: Vec<(i32, Vec<f32>)> = (0..24)
+            .map(|i| {
+                let x = i as f32 / 24.0;
+                (1100 + i, vec![x, 1.0 - x, (x * 7.0).fract(), 0.5])
+            })
+            .collect();
+        let vectors = dataset
+            .iter()
+            .map(|(id, vec)| (VectorIdValue::IntValue(*id), vec.clone()))
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let k = 5;
+        let recall = |results: Option<Vec<ScoredResult>>, truth: &[VectorId]| {
+            results
+                .unwrap_or_default()
+                .iter()
+                .filter(|result| truth.contains(&result.id))
+                .count()
+        };
+        for query in [vec![0.9, 0.1, 0.3, 0.5], vec![0.2, 0.8, 0.6, 0.5]] {
+            let mut exact: Vec<_> = dataset
+                .iter()
+                .map(|(id, vec)| (VectorId::Int(*id), cosine_similarity(&query, vec)))
+                .collect();
+            exact.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+            let truth: Vec<_> = exact.into_iter().take(k).map(|(id, _)| id).collect();
+
+            let single = ann_vector_query(vec_store.clone(), query.clone())
+                .await
+                .unwrap();
+            let multi = ann_vector_query_multi_probe(vec_store.clone(), query, 4)
+                .await
+                .unwrap();
+            assert!(recall(multi, &truth) >= recall(single, &truth));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_stream_matches_batch_query() {
+        use futures::StreamExt;
+
+        let name = "test_query_stream";
+        let vec_store = init_vector_store_in_memory(
+            store_config(name).storage_type(StorageType::HalfPrecisionFP),
+        )
+        .await
+        .unwrap();
+
+        let vectors: Vec<_> = (0..10)
+            .map(|i| {
+                let x = i as f32 / 10.0;
+                (
+                    VectorIdValue::IntValue(1200 + i),
+                    vec![x, 1.0 - x, 0.4, 0.2],
+                )
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let query = vec![0.7, 0.3, 0.4, 0.2];
+        let streamed: Vec<_> = ann_vector_query_stream(vec_store.clone(), query.clone())
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let batch = ann_vector_query(vec_store.clone(), query)
+            .await
+            .unwrap()
+            .unwrap_or_default();
+
+        assert!(!streamed.is_empty());
+        assert_eq!(streamed, batch);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_fixes_quantized_ordering() {
+        let name = "test_rerank_fixes_quantized_ordering";
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new(name.to_string(), 16)
+                .overwrite(true)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2)
+                .distance_metric(DistanceMetric::Euclidean),
+        )
+        .await
+        .unwrap();
+
+        // The query sits just inside the rounding boundary of level 100 in every
+        // dimension, alternating sides. Vector 1 is one level past it in all of them,
+        // vector 2 one level the other way in a single one. Once the query rounds to
+        // level 100, vector 2 looks far closer, but it's vector 1 that really is
+        let sign = |i: usize| if i % 2 == 0 { 1.0 } else { -1.0 };
+        let level = |l: f32| l / 255.0;
+        let query: Vec<f32> = (0..16).map(|i| level(100.0 + 0.49 * sign(i))).collect();
+        let closest: Vec<f32> = (0..16).map(|i| level(100.0 + sign(i))).collect();
+        let mut rounded_closest = vec![level(100.0); 16];
+        rounded_closest[0] = level(99.0);
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), closest),
+            (VectorIdValue::IntValue(2), rounded_closest),
+            (VectorIdValue::IntValue(3), vec![level(200.0); 16]),
+            (VectorIdValue::IntValue(4), vec![level(10.0); 16]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let quantized = ann_vector_query(vec_store.clone(), query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(quantized[0].id, VectorId::Int(2));
+
+        let reranked = ann_vector_query_reranked(vec_store.clone(), query, 2, 4)
+            .await
+            .unwrap();
+        let ids: Vec<_> = reranked.iter().map(|result| result.id.clone()).collect();
+        assert_eq!(ids, vec![VectorId::Int(1), VectorId::Int(2)]);
+        assert!(reranked[0].score <= reranked[1].score);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_uses_inserted_vectors() {
+        let name = "test_rerank_uses_inserted_vectors";
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new(name.to_string(), 16)
+                .overwrite(true)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2)
+                .distance_metric(DistanceMetric::Euclidean),
+        )
+        .await
+        .unwrap();
+
+        // The query sits right on level 100. Vector 1 is just past half a level off in
+        // a single dimension, so it's stored a whole level off. Vector 2 is just short
+        // of half a level off in all of them, so it's stored as the query itself. Only
+        // the vectors as inserted show that vector 1 is the closer one
+        let level = |l: f32| l / 255.0;
+        let query = vec![level(100.0); 16];
+        let mut closest = query.clone();
+        closest[0] = level(100.51);
+        let rounded_closest = vec![level(100.49); 16];
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), closest),
+            (VectorIdValue::IntValue(2), rounded_closest),
+            (VectorIdValue::IntValue(3), vec![level(200.0); 16]),
+            (VectorIdValue::IntValue(4), vec![level(10.0); 16]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let quantized = ann_vector_query(vec_store.clone(), query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let quantized_ids: Vec<_> = quantized[..2]
+            .iter()
+            .map(|result| result.id.clone())
+            .collect();
+        assert_eq!(quantized_ids, vec![VectorId::Int(2), VectorId::Int(1)]);
+
+        let reranked = ann_vector_query_reranked(vec_store.clone(), query, 2, 4)
+            .await
+            .unwrap();
+        let ids: Vec<_> = reranked.iter().map(|result| result.id.clone()).collect();
+        assert_eq!(ids, vec![VectorId::Int(1), VectorId::Int(2)]);
+        assert!(reranked[0].score < reranked[1].score);
+    }
+
+    #[tokio::test]
+    async fn test_store_stats_match_inserts() {
+        let name = "test_store_stats_match_inserts";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+
+        let vectors: Vec<_> = (1..=6)
+            .map(|i| {
+                let x = i as f32 / 10.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, x / 2.0, 0.5])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let stats = calculate_statistics(&vec_store).unwrap();
+        assert_eq!(stats.total_vectors, 6);
+        // Every vector has a node on level 0, and upper levels only hold some of them
+        assert_eq!(stats.nodes_per_level.first().copied(), Some(6));
+        assert!(stats.nodes_per_level.windows(2).all(|w| w[0] >= w[1]));
+        assert!(stats.average_degree > 0.0);
+        assert!((0.0..=1.0).contains(&stats.cache_hit_rate));
+        let current = vec_store.get_current_version().unwrap();
+        assert_eq!(stats.current_version.unwrap().version, current.version);
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_zero_queries_rejected() {
+        let name = "test_empty_and_zero_queries_rejected";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        let vectors = vec![(VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4])];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let empty = ann_vector_query(vec_store.clone(), vec![]).await;
+        assert!(matches!(empty, Err(WaCustomError::InvalidParams)));
+
+        let zero = ann_vector_query(vec_store.clone(), vec![0.0; 4]).await;
+        assert!(matches!(zero, Err(WaCustomError::InvalidParams)));
+
+        let valid = ann_vector_query(vec_store.clone(), vec![0.1, 0.2, 0.3, 0.4]).await;
+        assert!(valid.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_delete_reports_each_id() {
+        let name = "test_bulk_delete_reports_each_id";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+
+        let vectors: Vec<_> = (1..=5)
+            .map(|i| {
+                let x = i as f32 / 10.0;
+                (VectorIdValue::IntValue(i), vec![x, 0.5, 1.0 - x, 0.3])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let version_before = vec_store.get_current_version().unwrap().version;
+
+        let results = delete_embeddings(
+            vec_store.clone(),
+            vec![VectorId::Int(2), VectorId::Int(42), VectorId::Int(4)],
+        );
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            &results[1],
+            Err(WaCustomError::NotFound(VectorId::Int(42)))
+        ));
+        assert!(results[2].is_ok());
+        // A single version for the whole batch
+        assert_eq!(
+            vec_store.get_current_version().unwrap().version,
+            version_before + 1
+        );
+        assert_eq!(vector_count(&vec_store).unwrap(), 3);
+
+        let remaining: HashSet<_> = ann_vector_query(vec_store.clone(), vec![0.3, 0.5, 0.7, 0.3])
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|result| result.id)
+            .collect();
+        assert!(!remaining.contains(&VectorId::Int(2)));
+        assert!(!remaining.contains(&VectorId::Int(4)));
+        assert!(remaining.contains(&VectorId::Int(3)));
+    }
+
+    #[tokio::test]
+    async fn test_delete_in_namespace() {
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new("test_delete_in_namespace".to_string(), 4)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2),
+        )
+        .await
+        .unwrap();
+        let value = vec![0.1, 0.4, 0.7, 0.2];
+        for namespace in ["a", "b"] {
+            run_upload_in_namespace(
+                vec_store.clone(),
+                Some(namespace.to_string()),
+                vec![(VectorIdValue::IntValue(1), value.clone())],
+                test_config(0),
+            );
+        }
+
+        // Only taken in the namespaces
+        assert!(matches!(
+            delete_embedding(vec_store.clone(), &VectorId::Int(1)),
+            Err(WaCustomError::NotFound(_))
+        ));
+        delete_embedding_in_namespace(vec_store.clone(), Some("a"), &VectorId::Int(1)).unwrap();
+        assert!(matches!(
+            delete_embedding_in_namespace(vec_store.clone(), Some("a"), &VectorId::Int(1)),
+            Err(WaCustomError::NotFound(_))
+        ));
+
+        let namespaces: HashSet<_> = ann_vector_query(vec_store.clone(), value)
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .filter(|result| result.id == VectorId::Int(1))
+            .map(|result| result.namespace)
+            .collect();
+        assert_eq!(namespaces, HashSet::from([Some("b".to_string())]));
+    }
+
+    #[tokio::test]
+    async fn test_delete_before_indexing() {
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new("test_delete_before_indexing".to_string(), 4)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2),
+        )
+        .await
+        .unwrap();
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4]),
+            (VectorIdValue::IntValue(2), vec![0.4, 0.3, 0.2, 0.1]),
+        ];
+        // Below the threshold, so neither is indexed yet
+        run_upload(vec_store.clone(), vectors, test_config(100));
+
+        delete_embedding(vec_store.clone(), &VectorId::Int(1)).unwrap();
+        assert_eq!(vector_count(&vec_store).unwrap(), 1);
+        assert!(get_embedding(vec_store.clone(), VectorId::Int(1))
+            .unwrap()
+            .is_none());
+
+        commit(vec_store.clone(), test_config(100)).unwrap();
+        let count_unindexed_key =
+            indexing_counter_key("count_unindexed", "test_delete_before_indexing");
+        assert_eq!(get_u32(&vec_store.lmdb, &count_unindexed_key).unwrap(), 0);
+        let ids: HashSet<_> = ann_vector_query(vec_store.clone(), vec![0.1, 0.2, 0.3, 0.4])
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|result| result.id)
+            .collect();
+        assert_eq!(ids, HashSet::from([VectorId::Int(2)]));
+    }
+
+    #[tokio::test]
+    async fn test_delete_keeps_neighbors_reachable() {
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new("test_delete_keeps_neighbors_reachable".to_string(), 4)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2)
+                .max_neighbors(2),
+        )
+        .await
+        .unwrap();
+        let vectors: Vec<_> = (0..30)
+            .map(|i| {
+                let x = i as f32 / 30.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, 0.5, 0.25])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let reachable = |id: i32| {
+            !find_nodes_by_id(&vec_store, &VectorId::Int(id))
+                .unwrap()
+                .is_empty()
+        };
+        let kept: Vec<_> = (0..30).filter(|&i| reachable(i)).collect();
+
+        for id in (0..30).step_by(3) {
+            delete_embedding(vec_store.clone(), &VectorId::Int(id)).unwrap();
+        }
+        for id in kept.into_iter().filter(|id| id % 3 != 0) {
+            assert!(reachable(id), "vector {} was cut off", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_init_with_same_name_fails() {
+        let name = "test_second_init_with_same_name_fails";
+        let vec_store = test_store(store_config(name)).await;
+        let ain_env = get_app_env().unwrap();
+        let vectors = vec![(VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4])];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let result = init_vector_store(VectorStoreConfig::new(name.to_string(), 8)).await;
+        assert!(matches!(result, Err(WaCustomError::AlreadyExists(n)) if n == name));
+
+        // Still the same store, with its vector
+        let current = ain_env.vector_store_map.get(name).unwrap().clone();
+        assert!(Arc::ptr_eq(&current, &vec_store));
+        assert_eq!(vector_count(&current).unwrap(), 1);
+        let results = ann_vector_query(current, vec![0.1, 0.2, 0.3, 0.4])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].id, VectorId::Int(1));
+    }
+
+    #[tokio::test]
+    async fn test_store_name_with_colon_is_rejected() {
+        let name = "test_store_name_with_colon_is_rejected";
+        let vec_store = test_store(store_config(name)).await;
+        let vectors = vec![(VectorIdValue::IntValue(1), vec![0.1, 0.2, 0.3, 0.4])];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        // Its id keys would start with `{name}:`, as those of the first store do
+        let nested = format!("{}:b", name);
+        let result = init_vector_store(store_config(&nested)).await;
+        assert!(matches!(result, Err(WaCustomError::InvalidParams)));
+        let ain_env = get_app_env().unwrap();
+        assert!(!ain_env.vector_store_map.contains_key(&nested));
+
+        assert_eq!(vector_count(&vec_store).unwrap(), 1);
+        let ids: Vec<_> = iter_vectors(vec_store.clone())
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(ids, vec![VectorId::Int(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_min_explore_similarity_prunes_search() {
+        let name = "test_min_explore_similarity_prunes_search";
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new(name.to_string(), 8)
+                .overwrite(true)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2)
+                .seed(11),
+        )
+        .await
+        .unwrap();
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let vectors = (0..80)
+            .map(|i| {
+                let vec = (0..8).map(|_| rng.gen_range(0.0..1.0)).collect();
+                (VectorIdValue::IntValue(3000 + i), vec)
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let k = 5;
+        let (mut full_visits, mut pruned_visits) = (0, 0);
+        let (mut found, mut kept) = (0, 0);
+        for _ in 0..10 {
+            let query: Vec<f32> = (0..8).map(|_| rng.gen_range(0.0..1.0)).collect();
+            let top = |results: Option<Vec<ScoredResult>>| -> Vec<VectorId> {
+                results
+                    .unwrap_or_default()
+                    .into_iter()
+                    .take(k)
+                    .map(|result| result.id)
+                    .collect()
+            };
+
+            let mut full = SearchContext::default();
+            let full_top =
+                top(ann_vector_query_with_context(vec_store.clone(), &query, &mut full).unwrap());
+            let mut pruned = SearchContext {
+                min_explore_similarity: Some(0.85),
+                ..Default::default()
+            };
+            let pruned_top =
+                top(ann_vector_query_with_context(vec_store.clone(), &query, &mut pruned).unwrap());
+
+            full_visits += full.nodes_visited;
+            pruned_visits += pruned.nodes_visited;
+            found += full_top.len();
+            kept += pruned_top.iter().filter(|id| full_top.contains(id)).count();
+        }
+
+        assert!(pruned_visits < full_visits);
+        // Most of what the full search finds is still found
+        assert!(kept as f32 >= 0.6 * found as f32);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_neighbors_returns_stored_scores() {
+        let name = "test_fetch_neighbors_returns_stored_scores";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.9, 0.1, 0.1, 0.1]),
+            (VectorIdValue::IntValue(2), vec![0.8, 0.2, 0.1, 0.1]),
+            (VectorIdValue::IntValue(3), vec![0.7, 0.1, 0.3, 0.1]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let level_0 = |id| {
+            find_nodes_by_id(&vec_store, &VectorId::Int(id))
+                .unwrap()
+                .into_iter()
+                .find(|node| node.get_data().unwrap().get().hnsw_level == 0)
+                .unwrap()
+        };
+        // Give vector 1 exactly two neighbors with known scores
+        let mut node_arc = level_0(1).get_data().unwrap();
+        let node = node_arc.get();
+        node.neighbors.clear();
+        node.neighbors.insert(EagerLazyItem(0.25, level_0(2)));
+        node.neighbors.insert(EagerLazyItem(0.75, level_0(3)));
+
+        let (id, mut neighbors) = fetch_vector_neighbors(vec_store.clone(), VectorId::Int(1))
+            .await
+            .unwrap();
+        assert_eq!(id, VectorId::Int(1));
+        neighbors.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        assert_eq!(
+            neighbors,
+            vec![(VectorId::Int(2), 0.25), (VectorId::Int(3), 0.75)]
+        );
+
+        let missing = fetch_vector_neighbors(vec_store.clone(), VectorId::Int(99)).await;
+        assert!(matches!(missing, Err(WaCustomError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_query_quantized_with_store_bounds() {
+        let name = "test_query_quantized_with_store_bounds";
+        let vec_store = init_vector_store_in_memory(
+            store_config(name).storage_type(StorageType::HalfPrecisionFP),
+        )
+        .await
+        .unwrap();
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![1.0, 0.5, 0.2, 0.0]),
+            (VectorIdValue::IntValue(2), vec![0.1, 0.2, 0.9, 0.7]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        // Past the store's bounds, the query is clamped onto the stored vector instead
+        // of saturating into a different magnitude
+        for query in [vec![1.0, 0.5, 0.2, 0.0], vec![1.4, 0.5, 0.2, -0.3]] {
+            let results = ann_vector_query(vec_store.clone(), query)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(results[0].id, VectorId::Int(1));
+            assert!((results[0].score - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_iter_vectors_yields_every_id() {
+        let name = "test_iter_vectors_yields_every_id";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+
+        // More than one batch of ids
+        let n = 300;
+        let vectors = (0..n)
+            .map(|i| {
+                let x = i as f32 / n as f32;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, 0.5, 0.25])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let mut ids = HashSet::new();
+        for entry in iter_vectors(vec_store.clone()) {
+            let (id, prop) = entry.unwrap();
+            assert_eq!(prop.id, id);
+            assert!(ids.insert(id));
+        }
+        assert_eq!(ids, (0..n).map(VectorId::Int).collect::<HashSet<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_get_embedding_by_id() {
+        let name = "test_get_embedding_by_id";
+        let vec_store =
+            init_vector_store_in_memory(store_config(name).storage_type(StorageType::Float32))
+                .await
+                .unwrap();
+        let value = vec![0.1, 0.4, 0.7, 0.2];
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), value.clone()),
+            (VectorIdValue::IntValue(2), vec![0.9, 0.3, 0.2, 0.5]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let prop = get_embedding(vec_store.clone(), VectorId::Int(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(prop.id, VectorId::Int(1));
+        assert_eq!(prop.value.to_f32_vec(), value);
+
+        assert!(get_embedding(vec_store.clone(), VectorId::Int(3))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_search_budget_truncates() {
+        let name = "test_search_budget_truncates";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        let vectors = (0..40)
+            .map(|i| {
+                let x = i as f32 / 40.0;
+                (
+                    VectorIdValue::IntValue(i),
+                    vec![x, 1.0 - x, (x * 3.0).fract(), 0.5],
+                )
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let query = [0.3, 0.7, 0.9, 0.5];
+
+        let mut tight = SearchContext {
+            max_nodes_visited: Some(3),
+            ..Default::default()
+        };
+        let partial = ann_vector_query_with_context(vec_store.clone(), &query, &mut tight)
+            .unwrap()
+            .unwrap();
+        assert!(!partial.is_empty());
+        assert!(tight.truncated);
+        assert!(tight.nodes_visited <= 3);
+
+        let mut unbounded = SearchContext::default();
+        ann_vector_query_with_context(vec_store.clone(), &query, &mut unbounded).unwrap();
+        assert!(!unbounded.truncated);
+        assert!(unbounded.nodes_visited > 3);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_inserts_and_searches() {
+        let name = "test_concurrent_inserts_and_searches";
+        let vec_store = init_vector_store_in_memory(store_config(name).max_neighbors(8))
+            .await
+            .unwrap();
+
+        let batches = 15;
+        let per_batch = 8;
+        let inserting = AtomicBool::new(true);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for batch in 0..batches {
+                    let vectors = (0..per_batch)
+                        .map(|i| {
+                            let id = batch * per_batch + i;
+                            let x = id as f32 / (batches * per_batch) as f32;
+                            (
+                                VectorIdValue::IntValue(id),
+                                vec![x, 1.0 - x, 0.5, (x * 5.0).fract()],
+                            )
+                        })
+                        .collect();
+                    run_upload(vec_store.clone(), vectors, test_config(0));
+                }
+                inserting.store(false, std::sync::atomic::Ordering::SeqCst);
+            });
+
+            for searcher in 0..3 {
+                let vec_store = vec_store.clone();
+                let inserting = &inserting;
+                scope.spawn(move || {
+                    let query = [0.2 * searcher as f32 + 0.1, 0.6, 0.5, 0.3];
+                    while inserting.load(std::sync::atomic::Ordering::SeqCst) {
+                        let mut ctx = SearchContext::default();
+                        let results =
+                            ann_vector_query_with_context(vec_store.clone(), &query, &mut ctx)
+                                .unwrap()
+                                .unwrap_or_default();
+                        let mut seen = HashSet::new();
+                        for result in &results {
+                            let VectorId::Int(id) = result.id else {
+                                panic!("Unexpected id {}", result.id);
+                            };
+                            assert!((0..batches * per_batch).contains(&id));
+                            assert!(seen.insert(id), "{} returned twice", id);
+                        }
+                        assert!(results
+                            .windows(2)
+                            .all(|pair| pair[0].score >= pair[1].score));
+                    }
+                });
+            }
+        });
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.5])
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_same_id_in_two_namespaces() {
+        let name = "test_same_id_in_two_namespaces";
+        let vec_store =
+            init_vector_store_in_memory(store_config(name).storage_type(StorageType::Float32))
+                .await
+                .unwrap();
+        let in_a = vec![0.1, 0.4, 0.7, 0.2];
+        let in_b = vec![0.9, 0.3, 0.2, 0.5];
+        run_upload_in_namespace(
+            vec_store.clone(),
+            Some("a".to_string()),
+            vec![(VectorIdValue::IntValue(1), in_a.clone())],
+            test_config(0),
+        );
+        run_upload_in_namespace(
+            vec_store.clone(),
+            Some("b".to_string()),
+            vec![(VectorIdValue::IntValue(1), in_b.clone())],
+            test_config(0),
+        );
+        run_upload(
+            vec_store.clone(),
+            vec![(VectorIdValue::IntValue(2), vec![0.5, 0.5, 0.5, 0.5])],
+            test_config(0),
+        );
+
+        let stored: HashMap<_, _> = iter_vectors(vec_store.clone())
+            .map(|entry| {
+                let (_, prop) = entry.unwrap();
+                (prop.namespaced_id(), prop.value.to_f32_vec())
+            })
+            .collect();
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[&(Some("a".to_string()), VectorId::Int(1))], in_a);
+        assert_eq!(stored[&(Some("b".to_string()), VectorId::Int(1))], in_b);
+
+        let results = ann_vector_query(vec_store.clone(), in_a.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let namespaces: HashSet<_> = results
+            .iter()
+            .filter(|result| result.id == VectorId::Int(1))
+            .map(|result| result.namespace.clone())
+            .collect();
+        assert_eq!(
+            namespaces,
+            HashSet::from([Some("a".to_string()), Some("b".to_string())])
+        );
+
+        let results = ann_vector_query_in_namespace(vec_store.clone(), in_a, 10, Some("b"))
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, VectorId::Int(1));
+        assert_eq!(results[0].namespace.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn test_namespace_query_looks_past_top_k() {
+        let name = "test_namespace_query_looks_past_top_k";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        let mut rng = StdRng::seed_from_u64(31);
+        let query = vec![0.5, 0.5, 0.5, 0.5];
+        let near: Vec<_> = (0..15)
+            .map(|id| {
+                let vector = query
+                    .iter()
+                    .map(|x| x + rng.gen_range(-0.02..0.02))
+                    .collect();
+                (VectorIdValue::IntValue(id), vector)
+            })
+            .collect();
+        run_upload(vec_store.clone(), near, test_config(0));
+        // Pointing a little away from the query, which is what cosine similarity goes by
+        let farther: Vec<_> = (15..25)
+            .map(|id| {
+                let vector = [0.6, 0.4, 0.5, 0.5]
+                    .iter()
+                    .map(|x| x + rng.gen_range(-0.02..0.02))
+                    .collect();
+                (VectorIdValue::IntValue(id), vector)
+            })
+            .collect();
+        run_upload_in_namespace(
+            vec_store.clone(),
+            Some("far".to_string()),
+            farther,
+            test_config(0),
+        );
+
+        let k = 3;
+        let top_k = ann_vector_query(vec_store.clone(), query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(top_k[..k].iter().all(|result| result.namespace.is_none()));
+
+        let results = ann_vector_query_in_namespace(vec_store.clone(), query, k, Some("far"))
+            .await
+            .unwrap();
+        assert_eq!(results.len(), k);
+        assert!(results
+            .iter()
+            .all(|result| result.namespace.as_deref() == Some("far")));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_index_format_fails_to_load() {
+        let name = "test_unknown_index_format_fails_to_load".to_string();
+        init_vector_store(store_config(&name)).await.unwrap();
+        let ain_env = get_app_env().unwrap();
+        let (_, vec_store) = ain_env.vector_store_map.remove(&name).unwrap();
+
+        let path = vec_store.index_file_path(0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[0], INDEX_FORMAT_VERSION);
+        bytes[0] = 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        let meta = retrieve_vector_store_metas(&vec_store.lmdb)
+            .unwrap()
+            .into_iter()
+            .find(|meta| meta.name == name)
+            .unwrap();
+        assert!(matches!(
+            load_vector_store(meta, vec_store.lmdb.clone()),
+            Err(WaCustomError::UnsupportedIndexFormat(0xFF))
+        ));
+
+        // The other stores still load, the broken one is left out
+        load_vector_stores().unwrap();
+        assert!(!ain_env.vector_store_map.contains_key(&name));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_creates_no_files() {
+        let name = "test_in_memory_store_creates_no_files";
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new(name.to_string(), 4)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2),
+        )
+        .await
+        .unwrap();
+        let vectors = (0..20)
+            .map(|i| {
+                let x = i as f32 / 20.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, 0.5, 0.25])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.25])
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(results.iter().any(|result| result.id == VectorId::Int(10)));
+        let prop = get_embedding(vec_store.clone(), VectorId::Int(3))
+            .unwrap()
+            .unwrap();
+        assert_eq!(prop.id, VectorId::Int(3));
+        assert_eq!(iter_vectors(vec_store.clone()).count(), 20);
+
+        assert!(!vec_store.base_path.exists());
+        assert!(!get_app_env().unwrap().vector_store_map.contains_key(name));
+        // The metadata DB's directory goes with the store
+        let lmdb_dir = vec_store
+            .memory
+            .as_ref()
+            .unwrap()
+            ._lmdb_dir
+            .path()
+            .to_owned();
+        drop(vec_store);
+        assert!(!lmdb_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_degree_histogram_counts_every_node() {
+        let name = "test_degree_histogram_counts_every_node";
+        let vec_store = init_vector_store_in_memory(store_config(name).max_neighbors(4))
+            .await
+            .unwrap();
+        let vectors = (0..12)
+            .map(|i| {
+                let x = i as f32 / 12.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, x / 2.0, 0.5])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let histogram = graph_degree_histogram(&vec_store).unwrap();
+        let levels = level_counts(&vec_store).unwrap();
+        let nodes: usize = levels.iter().map(|(nodes, _)| nodes).sum();
+        let links: usize = levels.iter().map(|(_, links)| links).sum();
+        assert_eq!(histogram.values().sum::<usize>(), nodes);
+        assert_eq!(
+            histogram
+                .iter()
+                .map(|(degree, count)| degree * count)
+                .sum::<usize>(),
+            links
+        );
+        // Neighbor sets are bounded
+        assert!(histogram.keys().all(|degree| *degree <= 4));
+    }
+
+    #[tokio::test]
+    async fn test_swapping_distance_metric_changes_ranking() {
+        let name = "test_swapping_distance_metric_changes_ranking";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        // 1 points the same way as the query, 2 sits right next to it
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.9, 0.9, 0.9, 0.9]),
+            (VectorIdValue::IntValue(2), vec![0.35, 0.25, 0.3, 0.3]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let query = vec![0.3, 0.3, 0.3, 0.3];
+
+        let results = ann_vector_query(vec_store.clone(), query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].id, VectorId::Int(1));
+
+        vec_store.set_distance_metric(DistanceMetric::Euclidean);
+        let results = ann_vector_query(vec_store.clone(), query)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].id, VectorId::Int(2));
+        assert_eq!(results[0].metric, DistanceMetric::Euclidean);
+    }
+
+    #[tokio::test]
+    async fn test_heuristic_neighbor_selection_keeps_other_clusters() {
+        let mut picked_clusters = Vec::new();
+        for selection in [NeighborSelection::Simple, NeighborSelection::Heuristic] {
+            let name = format!("test_neighbor_selection_{:?}", selection).to_lowercase();
+            let vec_store = init_vector_store_in_memory(
+                VectorStoreConfig::new(name, 4)
+                    .bounds(0.0, 1.0)
+                    .max_cache_level(2)
+                    .max_neighbors(3)
+                    .neighbor_selection(selection),
+            )
+            .await
+            .unwrap();
+            let metric = vec_store.get_distance_metric();
+            let query = vec_store.quantize(&[0.7, 0.4, 0.1, 0.1]);
+
+            // A tight cluster next to the query and a lone vector a little further off
+            let cluster = (0..5).map(|i| (0, vec![0.9, 0.2 + i as f32 * 0.01, 0.1, 0.1]));
+            let lone = std::iter::once((1, vec![0.3, 0.9, 0.1, 0.1]));
+            let candidates = cluster
+                .chain(lone)
+                .enumerate()
+                .map(|(i, (cluster, vector))| {
+                    let value = Arc::new(vec_store.quantize(&vector));
+                    let score = metric.calculate(&query, &value).unwrap();
+                    let node = MergedNode::new(0, 0);
+                    node.set_prop_ready(Arc::new(NodeProp {
+                        id: VectorId::Int(cluster * 100 + i as i32),
+                        value,
+                        location: None,
+                        metadata: None,
+                        namespace: None,
+                        exact: None,
+                    }));
+                    (LazyItem::new(node), score)
+                })
+                .collect();
+
+            let selected = select_neighbors(&vec_store, candidates).unwrap();
+            assert!(selected.len() <= 3);
+            let clusters: HashSet<i32> = selected
+                .iter()
+                .map(|(node, _)| {
+                    let node = node.get_data().unwrap().get().clone();
+                    match get_or_load_prop(&node, &vec_store).unwrap().id {
+                        VectorId::Int(id) => id / 100,
+                        _ => unreachable!(),
+                    }
+                })
+                .collect();
+            picked_clusters.push(clusters.len());
+        }
+        // Top 3 by score all come from the cluster
+        assert_eq!(picked_clusters, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_batched_insert_uses_one_transaction() {
+        let mut stores = Vec::new();
+        for name in ["test_batched_insert_batch", "test_batched_insert_single"] {
+            stores.push(
+                init_vector_store_in_memory(store_config(name))
+                    .await
+                    .unwrap(),
+            );
+        }
+        let (batched, single) = (&stores[0], &stores[1]);
+        let embs: Vec<VectorEmbedding> = (0..10)
+            .map(|i| {
+                let x = i as f32 / 10.0;
+                VectorEmbedding {
+                    raw_vec: Arc::new(batched.quantize(&[x, 1.0 - x, 0.5, 0.5])),
+                    hash_vec: VectorId::Int(i),
+                    namespace: None,
+                    exact: None,
+                }
+            })
+            .collect();
+
+        let txns_before = batched
+            .lmdb
+            .write_txns
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let results = insert_embeddings(batched, &embs).unwrap();
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(
+            batched
+                .lmdb
+                .write_txns
+                .load(std::sync::atomic::Ordering::Relaxed)
+                - txns_before,
+            1
+        );
+
+        let txns_before = single
+            .lmdb
+            .write_txns
+            .load(std::sync::atomic::Ordering::Relaxed);
+        for emb in &embs {
+            insert_embedding(single.clone(), emb).unwrap();
+        }
+        assert_eq!(
+            single
+                .lmdb
+                .write_txns
+                .load(std::sync::atomic::Ordering::Relaxed)
+                - txns_before,
+            embs.len()
+        );
+
+        let stored = |vec_store: &Arc<VectorStore>| -> HashMap<VectorId, Arc<Storage>> {
+            iter_vectors(vec_store.clone())
+                .map(|entry| entry.map(|(id, prop)| (id, prop.value.clone())))
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        assert_eq!(stored(batched), stored(single));
+        assert_eq!(vector_count(batched).unwrap(), 10);
+        assert_eq!(vector_count(single).unwrap(), 10);
+
+        // Ids already stored, or repeated within the batch, are reported one by one
+        let again = vec![embs[0].clone(), embs[0].clone()];
+        let results = insert_embeddings(batched, &again).unwrap();
+        assert!(matches!(results[0], Err(WaCustomError::DuplicateId(_))));
+        assert!(matches!(results[1], Err(WaCustomError::DuplicateId(_))));
+    }
+
+    #[tokio::test]
+    async fn test_list_stores_includes_created_stores() {
+        let names = ["test_list_stores_first", "test_list_stores_second"];
+        for name in names {
+            init_vector_store(store_config(name)).await.unwrap();
+        }
+        let stores = list_stores().unwrap();
+        for name in names {
+            assert!(stores.iter().any(|store| store == name));
+        }
+        assert!(stores.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[tokio::test]
+    async fn test_zero_vector_does_not_break_cosine_search() {
+        let name = "test_zero_vector_does_not_break_cosine_search";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        let mut vectors: Vec<_> = (1..8)
+            .map(|i| {
+                let x = i as f32 / 8.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, 0.5, 0.25])
+            })
+            .collect();
+        // Quantizes to all zeros, so it has no magnitude to divide by
+        vectors.push((VectorIdValue::IntValue(0), vec![0.0; 4]));
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.5, 0.5, 0.25])
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!results.is_empty());
+        assert!(results.iter().all(|result| !result.score.is_nan()));
+        assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
+        if let Some(zero) = results.iter().find(|r| r.id == VectorId::Int(0)) {
+            assert_eq!(zero.score, results.last().unwrap().score);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deferred_store_persists_only_on_commit() {
+        let query = vec![0.9, 0.1, 0.1, 0.9];
+        // Simulates a restart, the store is rebuilt from what's on disk
+        let reload = |name: &str| {
+            let ain_env = get_app_env().unwrap();
+            ain_env.vector_store_map.remove(name);
+            load_vector_stores().unwrap();
+            let vec_store = ain_env.vector_store_map.get(name).unwrap().clone();
+            vec_store
+        };
+        let finds_vector = |vec_store: Arc<VectorStore>| {
+            let query = query.clone();
+            async move {
+                ann_vector_query(vec_store, query)
+                    .await
+                    .unwrap()
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|result| result.id == VectorId::Int(1))
+            }
+        };
+
+        let mut stores = Vec::new();
+        for mode in [PersistMode::WriteThrough, PersistMode::Deferred] {
+            let name = format!("test_persist_mode_{:?}", mode).to_lowercase();
+            let vec_store = test_store(store_config(&name).persist_mode(mode)).await;
+            let created_len = std::fs::metadata(vec_store.index_file_path(0))
+                .unwrap()
+                .len();
+            let vectors = vec![
+                (VectorIdValue::IntValue(1), query.clone()),
+                (VectorIdValue::IntValue(2), vec![0.1, 0.9, 0.9, 0.1]),
+            ];
+            run_upload(vec_store.clone(), vectors, test_config(0));
+            stores.push((name, vec_store, created_len));
+        }
+        let (write_through_name, write_through, _) = &stores[0];
+        let (deferred_name, deferred, deferred_len) = &stores[1];
+
+        let version = write_through.get_current_version().unwrap();
+        assert_eq!(version.version, 1);
+        let reloaded = reload(write_through_name);
+        assert_eq!(reloaded.get_current_version().unwrap().version, 1);
+        assert!(finds_vector(reloaded).await);
+
+        // Searchable right away, but nothing was written out
+        assert!(finds_vector(deferred.clone()).await);
+        assert_eq!(deferred.get_current_version().unwrap().version, 0);
+        assert_eq!(
+            std::fs::metadata(deferred.index_file_path(0))
+                .unwrap()
+                .len(),
+            *deferred_len
+        );
+        assert!(!finds_vector(reload(deferred_name)).await);
+
+        let version = commit(deferred.clone(), test_config(0)).unwrap();
+        assert_eq!(version.version, 1);
+        let reloaded = reload(deferred_name);
+        assert_eq!(reloaded.get_current_version().unwrap().version, 1);
+        assert!(finds_vector(reloaded).await);
+    }
+
+    #[derive(Debug)]
+    struct FirstComponentDistance;
+
+    impl DistanceFunction for FirstComponentDistance {
+        fn calculate(
+            &self,
+            x: &Storage,
+            y: &Storage,
+        ) -> Result<f32, crate::distance::DistanceError> {
+            match (x, y) {
+                (
+                    Storage::UnsignedByte { quant_vec: x, .. },
+                    Storage::UnsignedByte { quant_vec: y, .. },
+                ) => Ok((x[0] as f32 - y[0] as f32).abs()),
+                _ => Err(crate::distance::DistanceError::StorageMismatch),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_metric_ranks_results() {
+        let metric = DistanceMetric::custom(
+            "first_component".to_string(),
+            false,
+            Arc::new(FirstComponentDistance),
+        );
+        let vec_store = init_vector_store_in_memory(
+            VectorStoreConfig::new("test_custom_metric_ranks_results".to_string(), 4)
+                .bounds(0.0, 1.0)
+                .max_cache_level(2)
+                .distance_metric(metric.clone()),
+        )
+        .await
+        .unwrap();
+        // Cosine would put 1 first, it points almost the same way as the query
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.9, 0.1, 0.1, 0.1]),
+            (VectorIdValue::IntValue(2), vec![0.5, 0.9, 0.9, 0.9]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let results = ann_vector_query(vec_store.clone(), vec![0.5, 0.1, 0.1, 0.1])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].id, VectorId::Int(2));
+        assert_eq!(results[0].score, 0.0);
+        assert_eq!(results[0].metric, metric);
+
+        // Saved by name, and read back as the same metric
+        let saved = serde_cbor::to_vec(&metric).unwrap();
+        let loaded: DistanceMetric = serde_cbor::from_slice(&saved).unwrap();
+        assert_eq!(loaded, metric);
+    }
+
+    #[tokio::test]
+    async fn test_index_file_outlives_removal_while_read() {
+        let name = "test_index_file_outlives_removal_while_read";
+        let vec_store = test_store(store_config(name)).await;
+        let query = vec![0.9, 0.1, 0.1, 0.9];
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), query.clone()),
+            (VectorIdValue::IntValue(2), vec![0.1, 0.9, 0.9, 0.1]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let version = vec_store.get_current_version().unwrap();
+        let version_root = retrieve_version_root(&vec_store.lmdb, name, &version).unwrap();
+        let path = vec_store.index_file_path(version_root.file_version);
+
+        let (opened_tx, opened_rx) = std::sync::mpsc::channel();
+        let (compacted_tx, compacted_rx) = std::sync::mpsc::channel();
+        let query_thread = {
+            let (vec_store, path) = (vec_store.clone(), path.clone());
+            std::thread::spawn(move || {
+                let index_file = IndexFileReader::open(&path).unwrap();
+                opened_tx.send(()).unwrap();
+                compacted_rx.recv().unwrap();
+                query_version_file(vec_store, &query, version_root, index_file)
+            })
+        };
+
+        opened_rx.recv().unwrap();
+        let compacted = compact_index(vec_store.clone()).unwrap();
+        assert!(compacted.version > version.version);
+        // The query still has the old file open, so it's only marked for removal
+        assert!(!remove_index_file(&path).unwrap());
+        assert!(path.exists());
+        compacted_tx.send(()).unwrap();
+
+        let results = query_thread.join().unwrap().unwrap().unwrap();
+        assert!(results.iter().any(|result| result.id == VectorId::Int(1)));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_top_k_heap_matches_full_sort() {
+        let name = "test_top_k_heap_matches_full_sort";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        // Every vector is stored twice under different ids, so scores tie
+        let vectors = (0..20)
+            .map(|i| {
+                let x = (i / 2) as f32 / 10.0;
+                (VectorIdValue::IntValue(i), vec![x, 1.0 - x, 0.5, x / 2.0])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let query = vec![0.3, 0.7, 0.5, 0.15];
+        let results = search_query(vec_store.clone(), &query).unwrap();
+        assert!(results.as_ref().is_some_and(|results| results.len() > 1));
+        for metric in [DistanceMetric::Cosine, DistanceMetric::Euclidean] {
+            for k in [0, 1, 3, 7, usize::MAX] {
+                let mut sorted = remove_duplicates_and_filter(results.clone(), &metric).unwrap();
+                sorted.truncate(k);
+                let bounded = remove_duplicates_and_filter_top_k(results.clone(), &metric, k);
+                assert_eq!(bounded.unwrap(), sorted, "{:?} with k = {}", metric, k);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_pair_links_both_ways() {
+        let name = "test_close_pair_links_both_ways";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        // Separate uploads, so the second insert finds the first and has to link back
+        for (id, vector) in [
+            (1, vec![0.8, 0.2, 0.1, 0.3]),
+            (2, vec![0.79, 0.21, 0.1, 0.31]),
+        ] {
+            run_upload(
+                vec_store.clone(),
+                vec![(VectorIdValue::IntValue(id), vector)],
+                test_config(0),
+            );
+        }
+
+        for (id, other) in [(1, 2), (2, 1)] {
+            let (_, neighbors) = fetch_vector_neighbors(vec_store.clone(), VectorId::Int(id))
+                .await
+                .unwrap();
+            assert!(
+                neighbors
+                    .iter()
+                    .any(|(nbr, _)| *nbr == VectorId::Int(other)),
+                "{} is missing {} in {:?}",
+                id,
+                other,
+                neighbors
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_explained_query_traces_visited_nodes() {
+        let name = "test_explained_query_traces_visited_nodes";
+        let vec_store = init_vector_store_in_memory(store_config(name))
+            .await
+            .unwrap();
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.9, 0.1, 0.1, 0.9]),
+            (VectorIdValue::IntValue(2), vec![0.1, 0.9, 0.9, 0.1]),
+            (VectorIdValue::IntValue(3), vec![0.5, 0.5, 0.5, 0.5]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+        let query = vec![0.8, 0.2, 0.1, 0.9];
+
+        let (plain, trace) = ann_vector_query_explained(vec_store.clone(), query.clone(), false)
+            .await
+            .unwrap();
+        assert!(trace.is_none());
+
+        let (explained, trace) = ann_vector_query_explained(vec_store.clone(), query, true)
+            .await
+            .unwrap();
+        assert_eq!(explained, plain);
+        let trace = trace.unwrap();
+        // A graph this small is fully connected on the bottom level
+        let visited = trace.visited_at(0);
+        for id in 1..=3 {
+            assert!(visited.contains(&&VectorId::Int(id)), "{} not visited", id);
+        }
+        // Every level the search passed through was entered somewhere
+        assert_eq!(trace.steps[0].decision, TraceDecision::Entry);
+        assert!(trace
+            .steps
+            .iter()
+            .any(|step| step.level == 0 && step.decision == TraceDecision::Entry));
+        let scored = trace
+            .steps
+            .iter()
+            .filter(|step| step.decision != TraceDecision::Entry)
+            .count();
+        assert!(scored > 0);
+    }
+
+    #[tokio::test]
+    async fn test_f64_upload_matches_f32_upload() {
+        let vectors: Vec<(i32, Vec<f64>)> = vec![
+            (1, vec![0.1, 0.2, 0.3, 0.4]),
+            (2, vec![0.123456789012, 0.9, 0.333333333333, 0.05]),
+            (3, vec![1.0 / 3.0, 2.0 / 3.0, 0.999999999, 0.0]),
+        ];
+        let mut stored = Vec::new();
+        for f64_input in [true, false] {
+            let vec_store = init_vector_store_in_memory(
+                VectorStoreConfig::new(format!("test_f64_upload_{}", f64_input), 4)
+                    .bounds(0.0, 1.0)
+                    .max_cache_level(2),
+            )
+            .await
+            .unwrap();
+            if f64_input {
+                let upload = vectors
+                    .iter()
+                    .map(|(id, vec)| (VectorIdValue::IntValue(*id), vec.clone()))
+                    .collect();
+                run_upload_f64(vec_store.clone(), upload, test_config(0));
+            } else {
+                let upload = vectors
+                    .iter()
+                    .map(|(id, vec)| {
+                        let vec = vec.iter().map(|value| *value as f32).collect();
+                        (VectorIdValue::IntValue(*id), vec)
+                    })
+                    .collect();
+                run_upload(vec_store.clone(), upload, test_config(0));
+            }
+            let mut props: Vec<(VectorId, Arc<Storage>)> =
+                ann_vector_query_with_props(vec_store, vec![0.5, 0.5, 0.5, 0.5])
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(prop, _)| (prop.id.clone(), prop.value.clone()))
+                    .collect();
+            props.sort_by_key(|(id, _)| format!("{:?}", id));
+            stored.push(props);
+        }
+
+        assert_eq!(stored[0].len(), vectors.len());
+        assert_eq!(stored[0], stored[1]);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_commits_get_distinct_versions() {
+        let name = "test_parallel_commits_get_distinct_versions";
+        let vec_store = test_store(store_config(name)).await;
+        let vectors = vec![
+            (VectorIdValue::IntValue(1), vec![0.9, 0.1, 0.1, 0.9]),
+            (VectorIdValue::IntValue(2), vec![0.1, 0.9, 0.9, 0.1]),
+        ];
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let barrier = std::sync::Barrier::new(2);
+        let versions: Vec<VersionHash> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let vec_store = vec_store.clone();
+                    let barrier = &barrier;
+                    scope.spawn(move || {
+                        barrier.wait();
+                        commit(vec_store, test_config(1000)).unwrap()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        assert_ne!(versions[0].version, versions[1].version);
+        for version in &versions {
+            assert!(retrieve_version_root(&vec_store.lmdb, name, version).is_ok());
+        }
+        let latest = versions
+            .iter()
+            .map(|version| version.version)
+            .max()
+            .unwrap();
+        assert_eq!(vec_store.get_current_version().unwrap().version, latest);
+    }
+
+    #[tokio::test]
+    async fn test_gc_old_versions_keeps_newest() {
+        let name = "test_gc_old_versions_keeps_newest".to_string();
+        let vec_store = test_store(store_config(&name)).await;
+
+        let query = vec![0.9, 0.1, 0.1, 0.9];
+        let mut versions = Vec::new();
+        for id in 1..=4 {
+            let vectors = vec![(VectorIdValue::IntValue(id), query.clone())];
+            run_upload(vec_store.clone(), vectors, test_config(0));
+            versions.push(vec_store.get_current_version().unwrap());
+        }
+        assert_eq!(versions.last().unwrap().version, 4);
+
+        let is_kept =
+            |version: &VersionHash| retrieve_version_root(&vec_store.lmdb, &name, version).is_ok();
+        assert_eq!(gc_old_versions(&vec_store, 2).unwrap(), vec![2, 1]);
+        assert!(!is_kept(&versions[0]) && !is_kept(&versions[1]));
+        assert!(is_kept(&versions[2]) && is_kept(&versions[3]));
+        // Every commit was appended to the file the store was created with
+        assert!(vec_store.index_file_path(0).exists());
+
+        assert!(
+            ann_vector_query_at(vec_store.clone(), query.clone(), versions[2].clone())
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            ann_vector_query_at(vec_store.clone(), query.clone(), versions[0].clone())
+                .await
+                .is_err()
+        );
+        // Nothing left to remove, and the current version stays even with nothing kept
+        assert!(gc_old_versions(&vec_store, 2).unwrap().is_empty());
+        assert_eq!(gc_old_versions(&vec_store, 0).unwrap(), vec![3]);
+        assert!(is_kept(&versions[3]));
+
+        // The old file goes along with the last version in it
+        let compacted = compact_index(vec_store.clone()).unwrap();
+        assert!(vec_store.index_file_path(0).exists());
+        assert_eq!(gc_old_versions(&vec_store, 1).unwrap(), vec![4]);
+        assert!(!vec_store.index_file_path(0).exists());
+        assert!(vec_store.index_file_path(compacted.version).exists());
+        let results = ann_vector_query(vec_store.clone(), query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(results.iter().any(|result| result.id == VectorId::Int(4)));
+    }
+
+    #[tokio::test]
+    async fn test_keep_versions_applies_on_commit() {
+        let name = "test_keep_versions_applies_on_commit".to_string();
+        let vec_store = test_store(store_config(&name).keep_versions(2)).await;
+
+        for id in 1..=3 {
+            let vectors = vec![(VectorIdValue::IntValue(id), vec![0.5, 0.1, 0.5, 0.1])];
+            run_upload(vec_store.clone(), vectors, test_config(0));
+        }
+        assert_eq!(vec_store.get_current_version().unwrap().version, 3);
+        let is_kept = |version| {
+            let version = VersionHasher::new().generate_hash("main", version, None, None);
+            retrieve_version_root(&vec_store.lmdb, &name, &version).is_ok()
+        };
+        assert!(!is_kept(1));
+        assert!(is_kept(2));
+        assert!(is_kept(3));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_vector_neighbors_batch_matches_sequential() {
+        let name = "test_fetch_vector_neighbors_batch_matches_sequential".to_string();
+        let vec_store = init_vector_store_in_memory(store_config(&name))
+            .await
+            .unwrap();
+        let vectors = (1..=8)
+            .map(|id| {
+                let x = id as f32 / 10.0;
+                (VectorIdValue::IntValue(id), vec![x, 1.0 - x, 0.5, x / 2.0])
+            })
+            .collect();
+        run_upload(vec_store.clone(), vectors, test_config(0));
+
+        let ids: Vec<VectorId> = [3, 99, 1, 8, 5].into_iter().map(VectorId::Int).collect();
+        let batch = fetch_vector_neighbors_batch(vec_store.clone(), ids.clone())
+            .await
+            .unwrap();
+        assert_eq!(batch.len(), ids.len());
+
+        for (id, (batch_id, batch_neighbors)) in ids.into_iter().zip(batch) {
+            assert_eq!(batch_id, id);
+            match fetch_vector_neighbors(vec_store.clone(), id).await {
+                Ok((_, mut neighbors)) => {
+                    let mut batch_neighbors = batch_neighbors.expect("missing neighbors");
+                    // Neighbor sets aren't kept in any particular order
+                    let by_id = |a: &(VectorId, f32), b: &(VectorId, f32)| {
+                        a.0.to_string().cmp(&b.0.to_string())
+                    };
+                    neighbors.sort_by(by_id);
+                    batch_neighbors.sort_by(by_id);
+                    assert_eq!(batch_neighbors, neighbors);
+                }
+                Err(WaCustomError::NotFound(_)) => assert!(batch_neighbors.is_none()),
+                Err(e) => panic!("Fetch failed: {}", e),
+            }
+        }
+    }
 }
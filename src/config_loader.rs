@@ -1,11 +1,26 @@
 use serde::Deserialize;
 use std::fs;
+use std::path::PathBuf;
 
 #[derive(Deserialize, Clone)]
 pub struct Config {
    pub server: Server,
    pub threshold: u32, 
-   pub batch_size: usize
+   pub batch_size: usize,
+   #[serde(default = "default_lmdb_map_size")]
+   pub lmdb_map_size: usize,
+   // Holds the LMDB environment and the stores' files, relative paths are taken
+   // from the working directory
+   #[serde(default = "default_data_dir")]
+   pub data_dir: PathBuf
+}
+
+fn default_lmdb_map_size() -> usize {
+    1073741824 // 1GB
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("./_mdb")
 }
 
 #[derive(Deserialize, Clone)]
@@ -14,10 +29,57 @@ pub struct Server {
    pub port: String
 }
 
+impl Config {
+    // `threshold` may be 0, which indexes on every upload
+    fn validate(&self) -> Result<(), String> {
+        if self.batch_size == 0 {
+            return Err("batch_size must be greater than 0".to_string());
+        }
+        if self.lmdb_map_size == 0 {
+            return Err("lmdb_map_size must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_config(contents: &str) -> Result<Config, String> {
+    let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+    config.validate()?;
+    Ok(config)
+}
+
 pub fn load_config() -> Config {
     let config_contents = fs::read_to_string("config.toml").expect("Failed to load config file");
-    let config: Config = toml::from_str(&config_contents).expect("Failed to parse config file contents!");
-    
-    config
+    match parse_config(&config_contents) {
+        Ok(config) => config,
+        Err(e) => panic!("Invalid config file: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+threshold = 100
+batch_size = 1000
+
+[server]
+host = "127.0.0.1"
+port = "8443"
+"#;
+
+    #[test]
+    fn test_zero_batch_size_rejected() {
+        assert!(parse_config(CONFIG).is_ok());
+
+        let zero_batch = CONFIG.replace("batch_size = 1000", "batch_size = 0");
+        let err = parse_config(&zero_batch).err().unwrap();
+        assert!(err.contains("batch_size"));
+
+        // Unsigned fields don't parse negative values in the first place
+        let negative_threshold = CONFIG.replace("threshold = 100", "threshold = -1");
+        assert!(parse_config(&negative_threshold).is_err());
+    }
 }
 
@@ -1,4 +1,9 @@
-use super::{DistanceError, DistanceFunction};
+use super::{
+    check_dimensions,
+    dotproduct::{dot_product_f16, dot_product_f32},
+    DistanceError, DistanceFunction, UNDEFINED_SIMILARITY,
+};
+use crate::models::dot_product::dot_product_u8;
 use crate::storage::Storage;
 #[derive(Debug)]
 pub struct CosineDistance;
@@ -6,90 +11,159 @@ pub struct CosineDistance;
 impl DistanceFunction for CosineDistance {
     // Implementation here
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError> {
+        check_dimensions(x, y)?;
         match (x, y) {
             (
                 Storage::UnsignedByte {
-                    mag: mag_x,
-                    quant_vec: vec_x,
+                    quant_vec: vec_x, ..
                 },
                 Storage::UnsignedByte {
-                    mag: mag_y,
-                    quant_vec: vec_y,
+                    quant_vec: vec_y, ..
                 },
-            ) => {
-                // Implement cosine similarity for UnsignedByte storage
-                //unimplemented!("Cosine similarity for UnsignedByte not implemented yet")
-                Ok(0.0)
-            }
+            ) => cosine_similarity_u8(vec_x, vec_y, x.norm(), y.norm()),
             (
                 Storage::SubByte {
                     mag: x_mag,
                     quant_vec: x_vec,
                     resolution: x_res,
+                    ..
                 },
                 Storage::SubByte {
                     mag: y_mag,
                     quant_vec: y_vec,
                     resolution: y_res,
+                    ..
                 },
             ) => {
                 if x_res != y_res {
                     return Err(DistanceError::StorageMismatch);
                 }
-                match *x_res {
-                    1 => {
-                        let dot_product = dot_product_binary(x_vec, y_vec, *x_res)
-                            .expect("Failed computing dot product");
-                        cosine_similarity_from_dot_product(dot_product, *x_mag, *y_mag)
-                    }
-                    2 => {
-                        let dot_product = dot_product_quaternary(x_vec, y_vec, *x_res);
-                        cosine_similarity_from_dot_product(dot_product, *x_mag, *y_mag)
-                    }
-                    _ => Err(DistanceError::CalculationError),
-                }
+                let code_dot_product = if *x_res == 2 {
+                    dot_product_quaternary(x_vec, y_vec, *x_res)
+                } else {
+                    dot_product_sub_byte(x_vec, y_vec)
+                };
+                cosine_similarity_sub_byte(
+                    code_dot_product,
+                    (*x_mag, x_vec),
+                    (*y_mag, y_vec),
+                    *x_res,
+                )
             }
-            (Storage::HalfPrecisionFP { .. }, Storage::HalfPrecisionFP { .. }) => {
-                // Implement cosine similarity for HalfPrecisionFP storage
-                unimplemented!("Cosine similarity for HalfPrecisionFP not implemented yet")
+            (
+                Storage::HalfPrecisionFP {
+                    quant_vec: vec_x, ..
+                },
+                Storage::HalfPrecisionFP {
+                    quant_vec: vec_y, ..
+                },
+            ) => {
+                // Values are widened back to f32 for the computation
+                cosine_similarity_from_dot_product(
+                    dot_product_f16(vec_x, vec_y),
+                    x.norm(),
+                    y.norm(),
+                )
             }
+            (
+                Storage::Float32 {
+                    quant_vec: vec_x, ..
+                },
+                Storage::Float32 {
+                    quant_vec: vec_y, ..
+                },
+            ) => cosine_similarity_from_dot_product(
+                dot_product_f32(vec_x, vec_y),
+                x.norm(),
+                y.norm(),
+            ),
             _ => Err(DistanceError::StorageMismatch),
         }
     }
 }
 
-fn dot_product_binary(
-    x_vec: &[Vec<u8>],
-    y_vec: &[Vec<u8>],
+// Sum of the products of the codes. Plane `i` of one vector against plane `j` of the
+// other adds `2^(i + j)` for every bit they share
+fn dot_product_sub_byte(x_vec: &[Vec<u8>], y_vec: &[Vec<u8>]) -> f32 {
+    let mut dot_product: u64 = 0;
+    for (i, x_plane) in x_vec.iter().enumerate() {
+        for (j, y_plane) in y_vec.iter().enumerate() {
+            let shared: u64 = x_plane
+                .iter()
+                .zip(y_plane)
+                .map(|(&x_item, &y_item)| (x_item & y_item).count_ones() as u64)
+                .sum();
+            dot_product += shared << (i + j);
+        }
+    }
+    dot_product as f32
+}
+
+// Sum of the codes, each plane weighted by its bit
+fn code_sum(planes: &[Vec<u8>]) -> f64 {
+    planes
+        .iter()
+        .enumerate()
+        .map(|(i, plane)| {
+            let ones: u64 = plane.iter().map(|&item| item.count_ones() as u64).sum();
+            ones << i
+        })
+        .sum::<u64>() as f64
+}
+
+// Code `n` stands for the level `(n - c) * step` with `c` the midpoint code, so over
+// the levels the dot product is `step^2 * (sum(nx * ny) - c * (sum(nx) + sum(ny)) +
+// dims * c^2)` and the squared norms follow from `mag` the same way. `step^2` cancels
+// out. The unused bits of the last byte decode as the lowest level, which tilts the
+// result a little when the dimension isn't a multiple of 8
+fn cosine_similarity_sub_byte(
+    code_dot_product: f32,
+    (mag_x, vec_x): (u32, &[Vec<u8>]),
+    (mag_y, vec_y): (u32, &[Vec<u8>]),
     resolution: u8,
 ) -> Result<f32, DistanceError> {
-    let parts = 2_usize.pow(resolution as u32);
-
-    let mut final_result: usize = 0;
+    let midpoint = ((1u32 << resolution) - 1) as f64 / 2.0;
+    let dims = vec_x.first().map_or(0, |plane| plane.len() * 8) as f64;
+    let offset = dims * midpoint * midpoint;
+    let (sum_x, sum_y) = (code_sum(vec_x), code_sum(vec_y));
+
+    let dot_product = code_dot_product as f64 - midpoint * (sum_x + sum_y) + offset;
+    let mag_x = mag_x as f64 - 2.0 * midpoint * sum_x + offset;
+    let mag_y = mag_y as f64 - 2.0 * midpoint * sum_y + offset;
+    cosine_similarity_from_dot_product(dot_product as f32, mag_x.sqrt() as f32, mag_y.sqrt() as f32)
+}
 
-    for index in 0..parts {
-        let sum: usize = x_vec[index]
-            .iter()
-            .zip(&y_vec[index])
-            .map(|(&x_item, &y_item)| (x_item & y_item).count_ones() as usize)
-            .sum();
-        final_result += sum << index;
+// Takes the AVX2/NEON dot product when the CPU supports it, and the scalar loop
+// otherwise. Both sum exact integer products, so they agree up to the final division
+fn cosine_similarity_u8(
+    vec_x: &[u8],
+    vec_y: &[u8],
+    norm_x: f32,
+    norm_y: f32,
+) -> Result<f32, DistanceError> {
+    if vec_x.len() != vec_y.len() {
+        return Err(DistanceError::DimensionMismatch {
+            left: vec_x.len(),
+            right: vec_y.len(),
+        });
     }
-
-    let dot_product = final_result;
-    Ok(dot_product as f32)
+    cosine_similarity_from_dot_product(dot_product_u8(vec_x, vec_y) as f32, norm_x, norm_y)
 }
 
+// Divides by the norms cached on the storage rather than deriving them from the
+// squared magnitudes on every comparison. A zero norm, or one that came out NaN, gives
+// `UNDEFINED_SIMILARITY`
 fn cosine_similarity_from_dot_product(
     dot_product: f32,
-    mag_x: u32,
-    mag_y: u32,
+    norm_x: f32,
+    norm_y: f32,
 ) -> Result<f32, DistanceError> {
-    let denominator = (mag_x as f32).sqrt() * (mag_y as f32).sqrt();
-    if denominator == 0.0 {
-        Err(DistanceError::CalculationError)
+    let denominator = norm_x * norm_y;
+    let similarity = dot_product / denominator;
+    if denominator > 0.0 && similarity.is_finite() {
+        Ok(similarity)
     } else {
-        Ok(dot_product / denominator)
+        Ok(UNDEFINED_SIMILARITY)
     }
 }
 
@@ -327,7 +401,169 @@ fn scalar_u6_count_ones(data: &[u8]) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::Rng;
+    use crate::models::common::cosine_similarity;
+    use crate::models::dot_product::dot_product_u8_simple;
+    use crate::models::types::VectorQt;
+    use crate::quantization::{scalar::ScalarQuantization, Quantization, StorageType};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_half_precision_cosine_matches_f32() {
+        let x = [0.12, -0.53, 0.91, 0.05, -0.27, 0.68, 0.33, -0.74];
+        let y = [0.44, -0.18, 0.72, -0.39, 0.11, 0.57, -0.06, -0.81];
+        let x_storage = ScalarQuantization.quantize(&x, StorageType::HalfPrecisionFP);
+        let y_storage = ScalarQuantization.quantize(&y, StorageType::HalfPrecisionFP);
+
+        let similarity = CosineDistance.calculate(&x_storage, &y_storage).unwrap();
+        assert!((similarity - cosine_similarity(&x, &y)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_float32_cosine_is_exact() {
+        let x = [0.12, -0.53, 0.91, 0.05, -0.27, 0.68, 0.33, -0.74];
+        let y = [0.44, -0.18, 0.72, -0.39, 0.11, 0.57, -0.06, -0.81];
+        let x_storage = ScalarQuantization.quantize(&x, StorageType::Float32);
+        let y_storage = ScalarQuantization.quantize(&y, StorageType::Float32);
+
+        let dot: f32 = x.iter().zip(&y).map(|(a, b)| a * b).sum();
+        let norm_x = x.iter().map(|a| a * a).sum::<f32>().sqrt();
+        let norm_y = y.iter().map(|b| b * b).sum::<f32>().sqrt();
+
+        let similarity = CosineDistance.calculate(&x_storage, &y_storage).unwrap();
+        assert_eq!(similarity, dot / (norm_x * norm_y));
+    }
+
+    #[test]
+    fn test_unsigned_byte_simd_cosine_matches_scalar() {
+        let mut rng = rand::thread_rng();
+
+        // Include lengths that leave a remainder after the 32-byte SIMD chunks
+        for &size in &[7, 32, 100, 1024, 4099] {
+            let x: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+            let y: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+            let mag_x: u32 = x.iter().map(|&v| v as u32 * v as u32).sum();
+            let mag_y: u32 = y.iter().map(|&v| v as u32 * v as u32).sum();
+            let x_storage = Storage::UnsignedByte {
+                mag: mag_x,
+                norm: (mag_x as f32).sqrt(),
+                quant_vec: x.clone(),
+            };
+            let y_storage = Storage::UnsignedByte {
+                mag: mag_y,
+                norm: (mag_y as f32).sqrt(),
+                quant_vec: y.clone(),
+            };
+
+            let simd = CosineDistance.calculate(&x_storage, &y_storage).unwrap();
+            let scalar = cosine_similarity_from_dot_product(
+                dot_product_u8_simple(&x, &y) as f32,
+                x_storage.norm(),
+                y_storage.norm(),
+            )
+            .unwrap();
+            assert!(
+                (simd - scalar).abs() < 1e-6,
+                "Mismatch for size {}: SIMD = {}, scalar = {}",
+                size,
+                simd,
+                scalar
+            );
+        }
+    }
+
+    #[test]
+    fn test_cached_norm_matches_fresh_computation() {
+        let x = [0.12, 0.53, 0.91, 0.05, 0.27, 0.68, 0.33, 0.74];
+        let y = [0.44, 0.18, 0.72, 0.39, 0.11, 0.57, 0.06, 0.81];
+
+        for storage_type in [StorageType::UnsignedByte, StorageType::HalfPrecisionFP] {
+            let x_storage = ScalarQuantization.quantize(&x, storage_type);
+            let y_storage = ScalarQuantization.quantize(&y, storage_type);
+
+            let fresh = match (&x_storage, &y_storage) {
+                (
+                    Storage::UnsignedByte {
+                        mag: mag_x,
+                        quant_vec: vec_x,
+                        ..
+                    },
+                    Storage::UnsignedByte {
+                        mag: mag_y,
+                        quant_vec: vec_y,
+                        ..
+                    },
+                ) => {
+                    dot_product_u8_simple(vec_x, vec_y) as f32
+                        / ((*mag_x as f32).sqrt() * (*mag_y as f32).sqrt())
+                }
+                (
+                    Storage::HalfPrecisionFP {
+                        mag: mag_x,
+                        quant_vec: vec_x,
+                        ..
+                    },
+                    Storage::HalfPrecisionFP {
+                        mag: mag_y,
+                        quant_vec: vec_y,
+                        ..
+                    },
+                ) => dot_product_f16(vec_x, vec_y) / (mag_x.sqrt() * mag_y.sqrt()),
+                _ => unreachable!(),
+            };
+
+            let cached = CosineDistance.calculate(&x_storage, &y_storage).unwrap();
+            assert_eq!(cached, fresh);
+        }
+    }
+
+    #[test]
+    fn test_sub_byte_self_cosine_similarity() {
+        let VectorQt::SubByte {
+            mag,
+            quant_vec,
+            resolution,
+        } = VectorQt::sub_byte(&[0.9, -0.3, 0.4, 0.1, -0.8, 0.6], 2).unwrap()
+        else {
+            panic!("Expected SubByte");
+        };
+        assert!(mag > 0);
+
+        let storage = Storage::SubByte {
+            mag,
+            norm: (mag as f32).sqrt(),
+            quant_vec,
+            resolution,
+        };
+        let similarity = CosineDistance.calculate(&storage, &storage).unwrap();
+        assert!((similarity - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sub_byte_cosine_tracks_full_precision() {
+        let mut rng = StdRng::seed_from_u64(46);
+        // Coarser levels get a wider margin
+        for (resolution, tolerance) in [(1, 0.4), (2, 0.25), (4, 0.08), (8, 0.01)] {
+            for _ in 0..20 {
+                let x: Vec<f32> = (0..64).map(|_| rng.gen_range(-1.0..1.0)).collect();
+                let y: Vec<f32> = (0..64).map(|_| rng.gen_range(-1.0..1.0)).collect();
+                let quantization = ScalarQuantization;
+                let x_storage = quantization.quantize(&x, StorageType::SubByte(resolution));
+                let y_storage = quantization.quantize(&y, StorageType::SubByte(resolution));
+
+                let expected = cosine_similarity(&x, &y);
+                let actual = CosineDistance.calculate(&x_storage, &y_storage).unwrap();
+                assert!(
+                    (actual - expected).abs() < tolerance,
+                    "resolution {}: {} vs {}",
+                    resolution,
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_dot_product_quaternary_vs_theoretical() {
         let mut rng = rand::thread_rng();
@@ -606,6 +842,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mismatched_storages_are_rejected() {
+        let x = Storage::UnsignedByte {
+            mag: 14,
+            norm: 14f32.sqrt(),
+            quant_vec: vec![1, 2, 3],
+        };
+        let y = Storage::UnsignedByte {
+            mag: 30,
+            norm: 30f32.sqrt(),
+            quant_vec: vec![1, 2, 3, 4],
+        };
+        assert!(matches!(
+            CosineDistance.calculate(&x, &y),
+            Err(DistanceError::DimensionMismatch { left: 3, right: 4 })
+        ));
+
+        let z = Storage::Float32 {
+            mag: 14.0,
+            norm: 14f32.sqrt(),
+            quant_vec: vec![1.0, 2.0, 3.0],
+        };
+        assert!(matches!(
+            CosineDistance.calculate(&x, &z),
+            Err(DistanceError::StorageMismatch)
+        ));
+    }
+
     #[test]
     fn test_senary_weighted_popcount() {
         let mut rng = rand::thread_rng();
@@ -625,7 +889,7 @@ mod tests {
         }
 
         // Test with edge cases
-        let edge_cases = vec![
+        let edge_cases = [
             vec![0u8; 32],                     // All zeros
             vec![255u8; 64],                   // All ones
             vec![0, 255, 0, 255, 0, 255],      // Alternating zeros and ones
@@ -643,6 +907,24 @@ mod tests {
 
         println!("All tests passed!");
     }
+
+    #[test]
+    fn test_zero_vector_gets_undefined_similarity() {
+        let zero = [0.0; 8];
+        let other = [0.12, -0.53, 0.91, 0.05, -0.27, 0.68, 0.33, -0.74];
+        for storage_type in [
+            StorageType::UnsignedByte,
+            StorageType::HalfPrecisionFP,
+            StorageType::Float32,
+        ] {
+            let zero = ScalarQuantization.quantize(&zero, storage_type);
+            let other = ScalarQuantization.quantize(&other, storage_type);
+            for (x, y) in [(&zero, &other), (&other, &zero), (&zero, &zero)] {
+                let similarity = CosineDistance.calculate(x, y).unwrap();
+                assert_eq!(similarity, UNDEFINED_SIMILARITY);
+            }
+        }
+    }
 }
 #[cfg(target_arch = "x86_64")]
 fn scalar_combinations(data: &[u8]) -> u64 {
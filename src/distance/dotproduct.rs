@@ -1,13 +1,35 @@
-use super::{DistanceError, DistanceFunction};
+use super::{check_dimensions, DistanceError, DistanceFunction, UNDEFINED_SIMILARITY};
 use crate::models::dot_product::dot_product_u8;
 use crate::storage::Storage;
 use half::f16;
 
-#[derive(Debug)]
-pub struct DotProductDistance;
+#[derive(Debug, Default)]
+pub struct DotProductDistance {
+    // Set when vectors are unit length before quantization. The quantized dot product
+    // is then divided by the norms cached on the storage, which undoes the scaling
+    // from quantization without summing the squares again
+    pub normalized: bool,
+}
 
 impl DistanceFunction for DotProductDistance {
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError> {
+        check_dimensions(x, y)?;
+        let dot_product = self.raw_dot_product(x, y)?;
+        if !self.normalized {
+            return Ok(dot_product);
+        }
+        let denominator = x.norm() * y.norm();
+        let similarity = dot_product / denominator;
+        if denominator > 0.0 && similarity.is_finite() {
+            Ok(similarity)
+        } else {
+            Ok(UNDEFINED_SIMILARITY)
+        }
+    }
+}
+
+impl DotProductDistance {
+    fn raw_dot_product(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError> {
         match (x, y) {
             (
                 Storage::UnsignedByte {
@@ -25,6 +47,14 @@ impl DistanceFunction for DotProductDistance {
                     quant_vec: vec_y, ..
                 },
             ) => Ok(dot_product_f16(vec_x, vec_y)),
+            (
+                Storage::Float32 {
+                    quant_vec: vec_x, ..
+                },
+                Storage::Float32 {
+                    quant_vec: vec_y, ..
+                },
+            ) => Ok(dot_product_f32(vec_x, vec_y)),
             (Storage::SubByte { .. }, Storage::SubByte { .. }) => {
                 Err(DistanceError::CalculationError) // Implement if needed
             }
@@ -39,3 +69,43 @@ pub fn dot_product_f16(x: &[f16], y: &[f16]) -> f32 {
         .map(|(&a, &b)| f32::from(a) * f32::from(b))
         .sum()
 }
+
+pub fn dot_product_f32(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y.iter()).map(|(&a, &b)| a * b).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantization::{scalar::ScalarQuantization, Quantization, StorageType};
+
+    fn normalize(vector: &[f32]) -> Vec<f32> {
+        let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        vector.iter().map(|v| v / norm).collect()
+    }
+
+    #[test]
+    fn test_normalized_dot_product_matches_naive() {
+        let x = normalize(&[0.12, 0.53, 0.91, 0.05, 0.27, 0.68, 0.33, 0.74]);
+        let y = normalize(&[0.44, 0.18, 0.72, 0.39, 0.11, 0.57, 0.06, 0.81]);
+        let naive: f32 = x.iter().zip(&y).map(|(a, b)| a * b).sum();
+
+        let distance = DotProductDistance { normalized: true };
+        for (storage_type, epsilon) in [
+            (StorageType::Float32, 1e-6),
+            (StorageType::HalfPrecisionFP, 1e-3),
+            (StorageType::UnsignedByte, 1e-2),
+        ] {
+            let x_storage = ScalarQuantization.quantize(&x, storage_type);
+            let y_storage = ScalarQuantization.quantize(&y, storage_type);
+            let optimized = distance.calculate(&x_storage, &y_storage).unwrap();
+            assert!(
+                (optimized - naive).abs() < epsilon,
+                "{:?}: optimized = {}, naive = {}",
+                storage_type,
+                optimized,
+                naive
+            );
+        }
+    }
+}
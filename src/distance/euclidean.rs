@@ -1,4 +1,4 @@
-use super::{DistanceError, DistanceFunction};
+use super::{check_dimensions, DistanceError, DistanceFunction};
 use crate::storage::Storage;
 use half::f16;
 
@@ -7,6 +7,7 @@ pub struct EuclideanDistance;
 
 impl DistanceFunction for EuclideanDistance {
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError> {
+        check_dimensions(x, y)?;
         match (x, y) {
             (
                 Storage::UnsignedByte {
@@ -24,6 +25,14 @@ impl DistanceFunction for EuclideanDistance {
                     quant_vec: vec_y, ..
                 },
             ) => Ok(euclidean_distance_f16(vec_x, vec_y)),
+            (
+                Storage::Float32 {
+                    quant_vec: vec_x, ..
+                },
+                Storage::Float32 {
+                    quant_vec: vec_y, ..
+                },
+            ) => Ok(euclidean_distance_f32(vec_x, vec_y)),
             (Storage::SubByte { .. }, Storage::SubByte { .. }) => {
                 Err(DistanceError::CalculationError) // Implement if needed
             }
@@ -35,7 +44,8 @@ pub fn euclidean_distance_u8(x: &[u8], y: &[u8]) -> f32 {
     x.iter()
         .zip(y.iter())
         .map(|(&a, &b)| {
-            let diff = (a as i16) - (b as i16);
+            // Squares reach 255², past what an i16 holds
+            let diff = (a as i32) - (b as i32);
             (diff * diff) as f32
         })
         .sum::<f32>()
@@ -52,3 +62,39 @@ pub fn euclidean_distance_f16(x: &[f16], y: &[f16]) -> f32 {
         .sum::<f32>()
         .sqrt()
 }
+
+pub fn euclidean_distance_f32(x: &[f32], y: &[f32]) -> f32 {
+    x.iter()
+        .zip(y.iter())
+        .map(|(&a, &b)| (a - b) * (a - b))
+        .sum::<f32>()
+        .sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatched_dimensions_are_rejected() {
+        let x = Storage::Float32 {
+            mag: 5.0,
+            norm: 5f32.sqrt(),
+            quant_vec: vec![1.0, 2.0],
+        };
+        let y = Storage::Float32 {
+            mag: 14.0,
+            norm: 14f32.sqrt(),
+            quant_vec: vec![1.0, 2.0, 3.0],
+        };
+        assert!(matches!(
+            EuclideanDistance.calculate(&x, &y),
+            Err(DistanceError::DimensionMismatch { left: 2, right: 3 })
+        ));
+        assert!(matches!(
+            EuclideanDistance.calculate(&y, &x),
+            Err(DistanceError::DimensionMismatch { left: 3, right: 2 })
+        ));
+        assert_eq!(EuclideanDistance.calculate(&y, &y).unwrap(), 0.0);
+    }
+}
@@ -1,4 +1,4 @@
-use super::{DistanceError, DistanceFunction};
+use super::{check_dimensions, DistanceError, DistanceFunction};
 use crate::storage::Storage;
 
 #[derive(Debug)]
@@ -7,15 +7,18 @@ pub struct HammingDistance;
 impl DistanceFunction for HammingDistance {
     // Implementation here
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError> {
+        check_dimensions(x, y)?;
         match (x, y) {
             (
                 Storage::UnsignedByte {
                     mag: mag_x,
                     quant_vec: vec_x,
+                    ..
                 },
                 Storage::UnsignedByte {
                     mag: mag_y,
                     quant_vec: vec_y,
+                    ..
                 },
             ) => {
                 // Implement hamming similarity for UnsignedByte storage
@@ -29,6 +32,10 @@ impl DistanceFunction for HammingDistance {
                 // Implement hamming similarity for HalfPrecisionFP storage
                 unimplemented!("Hamming similarity for HalfPrecisionFP not implemented yet")
             }
+            (Storage::Float32 { .. }, Storage::Float32 { .. }) => {
+                // Bit-level comparison isn't meaningful for unquantized floats
+                Err(DistanceError::CalculationError)
+            }
             _ => Err(DistanceError::StorageMismatch),
         }
     }
@@ -0,0 +1,101 @@
+use super::{check_dimensions, DistanceError, DistanceFunction};
+use crate::storage::Storage;
+use half::f16;
+
+#[derive(Debug)]
+pub struct ManhattanDistance;
+
+impl DistanceFunction for ManhattanDistance {
+    fn calculate(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError> {
+        check_dimensions(x, y)?;
+        match (x, y) {
+            (
+                Storage::UnsignedByte {
+                    quant_vec: vec_x, ..
+                },
+                Storage::UnsignedByte {
+                    quant_vec: vec_y, ..
+                },
+            ) => Ok(manhattan_distance_u8(vec_x, vec_y)),
+            (
+                Storage::HalfPrecisionFP {
+                    quant_vec: vec_x, ..
+                },
+                Storage::HalfPrecisionFP {
+                    quant_vec: vec_y, ..
+                },
+            ) => Ok(manhattan_distance_f16(vec_x, vec_y)),
+            (
+                Storage::Float32 {
+                    quant_vec: vec_x, ..
+                },
+                Storage::Float32 {
+                    quant_vec: vec_y, ..
+                },
+            ) => Ok(manhattan_distance_f32(vec_x, vec_y)),
+            (Storage::SubByte { .. }, Storage::SubByte { .. }) => {
+                Err(DistanceError::CalculationError) // Implement if needed
+            }
+            _ => Err(DistanceError::StorageMismatch),
+        }
+    }
+}
+
+pub fn manhattan_distance_u8(x: &[u8], y: &[u8]) -> f32 {
+    x.iter()
+        .zip(y.iter())
+        .map(|(&a, &b)| (a as i16 - b as i16).abs() as f32)
+        .sum()
+}
+
+pub fn manhattan_distance_f16(x: &[f16], y: &[f16]) -> f32 {
+    x.iter()
+        .zip(y.iter())
+        .map(|(&a, &b)| (f32::from(a) - f32::from(b)).abs())
+        .sum()
+}
+
+pub fn manhattan_distance_f32(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y.iter()).map(|(&a, &b)| (a - b).abs()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manhattan_distance_u8() {
+        let x = Storage::UnsignedByte {
+            mag: 0,
+            norm: 0.0,
+            quant_vec: vec![1, 5, 10, 200],
+        };
+        let y = Storage::UnsignedByte {
+            mag: 0,
+            norm: 0.0,
+            quant_vec: vec![4, 5, 2, 255],
+        };
+
+        // |1 - 4| + |5 - 5| + |10 - 2| + |200 - 255| = 3 + 0 + 8 + 55
+        let distance = ManhattanDistance.calculate(&x, &y).unwrap();
+        assert_eq!(distance, 66.0);
+    }
+
+    #[test]
+    fn test_manhattan_distance_f16() {
+        let x = Storage::HalfPrecisionFP {
+            mag: 0.0,
+            norm: 0.0,
+            quant_vec: vec![f16::from_f32(0.5), f16::from_f32(-1.0)],
+        };
+        let y = Storage::HalfPrecisionFP {
+            mag: 0.0,
+            norm: 0.0,
+            quant_vec: vec![f16::from_f32(-0.25), f16::from_f32(1.0)],
+        };
+
+        // |0.5 - (-0.25)| + |-1.0 - 1.0| = 0.75 + 2.0
+        let distance = ManhattanDistance.calculate(&x, &y).unwrap();
+        assert!((distance - 2.75).abs() < 1e-3);
+    }
+}
@@ -2,6 +2,7 @@ pub mod cosine;
 pub mod dotproduct;
 pub mod euclidean;
 pub mod hamming;
+pub mod manhattan;
 
 use crate::storage::Storage;
 
@@ -9,8 +10,27 @@ pub trait DistanceFunction: std::fmt::Debug + Send + Sync {
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<f32, DistanceError>;
 }
 
+// Similarity given to a pair that can't be scored, such as a zero vector under cosine,
+// which has no direction. It ranks with the least similar pairs instead of failing the
+// search or coming out as NaN
+pub(crate) const UNDEFINED_SIMILARITY: f32 = -1.0;
+
 #[derive(Debug)]
 pub enum DistanceError {
     StorageMismatch,
     CalculationError,
+    DimensionMismatch { left: usize, right: usize },
+}
+
+// Run by every `calculate` before touching the vectors, zipping operands of different
+// lengths would silently drop the tail of the longer one
+pub(crate) fn check_dimensions(x: &Storage, y: &Storage) -> Result<(), DistanceError> {
+    if x.storage_type() != y.storage_type() {
+        return Err(DistanceError::StorageMismatch);
+    }
+    let (left, right) = (x.dim(), y.dim());
+    if left != right {
+        return Err(DistanceError::DimensionMismatch { left, right });
+    }
+    Ok(())
 }
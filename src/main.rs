@@ -17,6 +17,13 @@ use crate::models::common::*;
 
 
 fn main() {
+    let config = cosdata::config_loader::load_config();
+    if let Err(e) = models::types::init_app_env(config.lmdb_map_size, &config.data_dir) {
+        eprintln!("Failed to open LMDB environment: {}", e);
+    }
+    if let Err(e) = api_service::load_vector_stores() {
+        eprintln!("Failed to restore vector stores: {}", e);
+    }
 
     let _ = run_actix_server();
     load_cache();
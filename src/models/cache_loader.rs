@@ -1,13 +1,16 @@
 use super::file_persist::*;
-use super::lazy_load::LazyItem;
+use super::lazy_load::{EagerLazyItem, LazyItem};
+use super::mmap_reader::MmapReader;
 use super::serializer::CustomSerialize;
 use super::types::*;
 use dashmap::DashMap;
 use probabilistic_collections::cuckoo::CuckooFilter;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io::Read;
 use std::io::Seek;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
 
@@ -15,9 +18,42 @@ pub struct NodeRegistry<R: Read + Seek> {
     cuckoo_filter: RwLock<CuckooFilter<FileOffset>>,
     registry: DashMap<FileOffset, LazyItem<MergedNode>>,
     reader: Arc<RwLock<R>>,
+    // Bumped on every access. Entries count their accesses in `decay_counter`
+    access_clock: AtomicUsize,
+    // Accesses between two automatic `decay` passes, 0 leaves decaying to the caller
+    decay_interval: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    // Where the copies of nodes that later commits replaced moved to, see
+    // `read_relocations`. Links holding an old offset load the latest copy
+    relocations: HashMap<FileOffset, FileOffset>,
+}
+
+pub const DEFAULT_DECAY_INTERVAL: usize = 1024;
+
+// Counts since the registry was created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegistryStats {
+    // Lookups served by a node already in the registry
+    pub hits: usize,
+    // Lookups that read the node from the file
+    pub misses: usize,
+}
+
+// Follows `offset` through `relocations` to the copy nothing replaced yet
+pub fn resolve_offset(
+    relocations: &HashMap<FileOffset, FileOffset>,
+    mut offset: FileOffset,
+) -> FileOffset {
+    while let Some(&next) = relocations.get(&offset) {
+        offset = next;
+    }
+    offset
 }
 
 impl<R: Read + Seek> NodeRegistry<R> {
+    // Every node loaded stays registered, the graph links them to each other so
+    // dropping one here would only leave a second copy to be loaded on its next access
     pub fn new(cuckoo_filter_capacity: usize, reader: R) -> Self {
         let cuckoo_filter = CuckooFilter::new(cuckoo_filter_capacity);
         let registry = DashMap::new();
@@ -25,9 +61,29 @@ impl<R: Read + Seek> NodeRegistry<R> {
             cuckoo_filter: RwLock::new(cuckoo_filter),
             registry,
             reader: Arc::new(RwLock::new(reader)),
+            access_clock: AtomicUsize::new(0),
+            decay_interval: DEFAULT_DECAY_INTERVAL,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            relocations: HashMap::new(),
         }
     }
 
+    pub fn with_decay_interval(mut self, decay_interval: usize) -> Self {
+        self.decay_interval = decay_interval;
+        self
+    }
+
+    pub fn with_relocations(mut self, relocations: HashMap<FileOffset, FileOffset>) -> Self {
+        self.relocations = relocations;
+        self
+    }
+
+    // The offset of the latest copy of the node first written at `offset`
+    pub fn resolve(&self, offset: FileOffset) -> FileOffset {
+        resolve_offset(&self.relocations, offset)
+    }
+
     pub fn get_object<F>(
         self: Arc<Self>,
         key: FileOffset,
@@ -45,6 +101,7 @@ impl<R: Read + Seek> NodeRegistry<R> {
             &mut HashSet<FileOffset>,
         ) -> std::io::Result<MergedNode>,
     {
+        let key = self.resolve(key);
         println!(
             "get_object called with key: {:?}, max_loads: {}",
             key, max_loads
@@ -57,9 +114,17 @@ impl<R: Read + Seek> NodeRegistry<R> {
             // Initial check with Cuckoo filter
             if cuckoo_filter.contains(&key) {
                 println!("Key found in cuckoo_filter");
-                if let Some(obj) = self.registry.get(&key) {
+                if let Some(mut obj) = self.registry.get_mut(&key) {
                     println!("Object found in registry, returning");
-                    return Ok(obj.clone());
+                    if let LazyItem::Valid { decay_counter, .. } = obj.value_mut() {
+                        *decay_counter = decay_counter.saturating_add(1);
+                    }
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    let item = obj.clone();
+                    // Decaying needs every entry, so the guard goes first
+                    drop(obj);
+                    self.touch();
+                    return Ok(item);
                 } else {
                     println!("Object not found in registry despite being in cuckoo_filter");
                 }
@@ -79,6 +144,7 @@ impl<R: Read + Seek> NodeRegistry<R> {
         }
 
         println!("Calling load_function");
+        self.misses.fetch_add(1, Ordering::Relaxed);
         let obj = load_function(reader, key, self.clone(), max_loads - 1, skipm)?;
         println!("load_function returned successfully");
 
@@ -91,7 +157,7 @@ impl<R: Read + Seek> NodeRegistry<R> {
         let item = LazyItem::Valid {
             data: Some(Item::new(obj)),
             offset: Item::new(Some(key)),
-            decay_counter: 0,
+            decay_counter: 1,
         };
 
         println!("Inserting key into cuckoo_filter");
@@ -99,11 +165,114 @@ impl<R: Read + Seek> NodeRegistry<R> {
 
         println!("Inserting item into registry");
         self.registry.insert(key, item.clone());
+        self.touch();
 
         println!("Returning newly created LazyItem");
         Ok(item)
     }
 
+    // Number of nodes loaded so far
+    pub fn len(&self) -> usize {
+        self.registry.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registry.is_empty()
+    }
+
+    pub fn stats(&self) -> RegistryStats {
+        RegistryStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn is_loaded(&self, key: FileOffset) -> bool {
+        self.registry
+            .get(&self.resolve(key))
+            .map_or(false, |obj| obj.get_data().is_some())
+    }
+
+    // Records an access, decaying every entry once per `decay_interval` accesses.
+    // Must not be called while holding a registry guard
+    fn touch(&self) {
+        let tick = self.access_clock.fetch_add(1, Ordering::Relaxed);
+        if self.decay_interval > 0 && (tick + 1) % self.decay_interval == 0 {
+            self.decay();
+        }
+    }
+
+    // Halves the access count of every entry, so a node that was hot a while ago
+    // keeps only part of its lead over recently used ones
+    pub fn decay(&self) {
+        for mut entry in self.registry.iter_mut() {
+            if let LazyItem::Valid { decay_counter, .. } = entry.value_mut() {
+                *decay_counter /= 2;
+            }
+        }
+    }
+
+    // A loop in the graph is cut where loading comes back around to a node still being
+    // read, leaving that link with only the node's offset. Points every such link among
+    // the nodes reachable from `root` at the copy loaded by then, and links to `root`'s
+    // own offset at `root`, which `load_item` reads without registering it
+    pub fn link_loaded(&self, root: &LazyItem<MergedNode>) {
+        let root_offset = root.get_offset();
+        let resolve = |item: &LazyItem<MergedNode>| -> Option<LazyItem<MergedNode>> {
+            let LazyItem::Valid { data, .. } = item else {
+                return None;
+            };
+            let offset = self.resolve(item.get_offset()?);
+            if Some(offset) == root_offset {
+                return Some(root.clone());
+            }
+            if data.is_some() {
+                return None;
+            }
+            self.registry
+                .get(&offset)
+                .filter(|loaded| loaded.get_data().is_some())
+                .map(|loaded| loaded.clone())
+        };
+
+        let mut visited = HashSet::new();
+        let mut queue = vec![root.clone()];
+        while let Some(lazy_node) = queue.pop() {
+            let Some(offset) = lazy_node.get_offset() else {
+                continue;
+            };
+            let Some(mut node_arc) = lazy_node.get_data() else {
+                continue;
+            };
+            if !visited.insert(offset) {
+                continue;
+            }
+            let node = node_arc.get();
+
+            let resolved: Vec<_> = node
+                .neighbors
+                .iter()
+                .filter_map(|nbr| resolve(&nbr.1).map(|item| EagerLazyItem(nbr.0, item)))
+                .collect();
+            if !resolved.is_empty() {
+                node.neighbors.retain(|nbr| resolve(&nbr.1).is_none());
+                for nbr in resolved {
+                    node.neighbors.insert(nbr);
+                }
+            }
+            if let Some(parent) = resolve(node.get_parent().item.clone().get()) {
+                node.set_parent(parent);
+            }
+            if let Some(child) = resolve(node.get_child().item.clone().get()) {
+                node.set_child(child);
+            }
+
+            queue.extend(node.neighbors.iter().map(|nbr| nbr.1));
+            queue.push(node.get_parent().item.clone().get().clone());
+            queue.push(node.get_child().item.clone().get().clone());
+        }
+    }
+
     pub fn load_item<T: CustomSerialize>(self: Arc<Self>, offset: u32) -> std::io::Result<T> {
         let mut reader_lock = self.reader.write().unwrap();
         let mut skipm: HashSet<FileOffset> = HashSet::new();
@@ -117,6 +286,13 @@ impl<R: Read + Seek> NodeRegistry<R> {
     }
 }
 
+impl NodeRegistry<MmapReader> {
+    // Reads nodes from a memory-mapped `.index` file instead of seeking through it
+    pub fn open_mmap(capacity: usize, path: &Path) -> std::io::Result<Self> {
+        Ok(Self::new(capacity, MmapReader::open(path)?))
+    }
+}
+
 pub fn load_cache() {
     use std::fs::OpenOptions;
 
@@ -132,3 +308,185 @@ pub fn load_cache() {
         Err(e) => println!("Failed to read node: {}", e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::lazy_load::LazyItemRef;
+    use std::io::Cursor;
+
+    fn get_cache(bytes: Vec<u8>) -> Arc<NodeRegistry<Cursor<Vec<u8>>>> {
+        Arc::new(NodeRegistry::new(1000, Cursor::new(bytes)))
+    }
+
+    fn load_node(
+        cache: &Arc<NodeRegistry<Cursor<Vec<u8>>>>,
+        reader: &mut Cursor<Vec<u8>>,
+        offset: FileOffset,
+    ) -> LazyItem<MergedNode> {
+        let mut skipm = HashSet::new();
+        cache
+            .clone()
+            .get_object(offset, reader, MergedNode::deserialize, 1000, &mut skipm)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_registry_keeps_one_copy_per_node() {
+        // More nodes than the registry's filter is sized for
+        let root = MergedNode::new(0, 0);
+        // Each with its own prop so they're told apart
+        for i in 1..=1200u32 {
+            let node = MergedNode::new(0, 0);
+            node.set_prop_pending((i * 64, 64));
+            root.add_ready_neighbor(LazyItem::from_data(node), 0.5);
+        }
+        let mut writer = Cursor::new(Vec::new());
+        let offset = LazyItemRef::new(root).serialize(&mut writer).unwrap();
+        let bytes = writer.into_inner();
+
+        let cache = get_cache(bytes.clone());
+        let root = cache.clone().load_item::<MergedNode>(offset).unwrap();
+        let neighbors: Vec<_> = root.neighbors.iter().map(|nbr| nbr.1).collect();
+        assert_eq!(neighbors.len(), 1200);
+        assert!(neighbors.iter().all(|nbr| nbr.get_data().is_some()));
+        assert_eq!(cache.len(), 1200);
+
+        // Looked up again, a node is the one the graph links to rather than a copy
+        let mut reader = Cursor::new(bytes);
+        for neighbor in &neighbors {
+            let offset = neighbor.get_offset().unwrap();
+            let mut node_arc = load_node(&cache, &mut reader, offset).get_data().unwrap();
+            let extra = MergedNode::new(0, 0);
+            extra.set_prop_pending((0, 64));
+            node_arc
+                .get()
+                .add_ready_neighbor(LazyItem::from_data(extra), 0.25);
+            let mut linked_arc = neighbor.get_data().unwrap();
+            assert_eq!(linked_arc.get().neighbors.len(), 1);
+        }
+        assert_eq!(cache.len(), 1200);
+        assert_eq!(cache.stats().misses, 1200);
+    }
+
+    #[test]
+    fn test_decay_halves_access_counts() {
+        let mut writer = Cursor::new(Vec::new());
+        let offset = MergedNode::new(1, 0).serialize(&mut writer).unwrap();
+        let bytes = writer.into_inner();
+
+        let cache =
+            Arc::new(NodeRegistry::new(1000, Cursor::new(bytes.clone())).with_decay_interval(0));
+        let mut reader = Cursor::new(bytes);
+        let accesses = |item: LazyItem<MergedNode>| match item {
+            LazyItem::Valid { decay_counter, .. } => decay_counter,
+            LazyItem::Invalid => panic!("node not loaded"),
+        };
+        for count in 1..=4 {
+            assert_eq!(accesses(load_node(&cache, &mut reader, offset)), count);
+        }
+        cache.decay();
+        assert_eq!(accesses(load_node(&cache, &mut reader, offset)), 3);
+    }
+
+    #[test]
+    fn test_link_loaded_closes_loops() {
+        // a <-> b <-> c, each with its own prop so they're told apart
+        let nodes: Vec<_> = (0..3u32)
+            .map(|i| {
+                let node = MergedNode::new(0, 0);
+                node.set_prop_pending((i * 64, 64));
+                LazyItem::from_data(node)
+            })
+            .collect();
+        let link = |from: usize, to: usize| {
+            let mut node_arc = nodes[from].get_data().unwrap();
+            node_arc.get().add_ready_neighbor(nodes[to].clone(), 0.5);
+        };
+        link(0, 1);
+        link(1, 0);
+        link(1, 2);
+        link(2, 1);
+
+        let mut writer = Cursor::new(Vec::new());
+        let offset = LazyItemRef::from_lazy(nodes[0].clone())
+            .serialize(&mut writer)
+            .unwrap();
+        let cache = get_cache(writer.into_inner());
+        let root = LazyItemRef::new(cache.clone().load_item::<MergedNode>(offset).unwrap());
+        root.set_offset(Some(offset));
+        let root = root.item.clone().get().clone();
+        cache.link_loaded(&root);
+
+        // Every link leads to a loaded node, the one back to `a` to the root itself
+        let neighbors = |item: &LazyItem<MergedNode>| -> Vec<LazyItem<MergedNode>> {
+            let mut node_arc = item.get_data().unwrap();
+            node_arc.get().neighbors.iter().map(|nbr| nbr.1).collect()
+        };
+        let b = neighbors(&root).pop().unwrap();
+        let b_neighbors = neighbors(&b);
+        assert_eq!(b_neighbors.len(), 2);
+        assert!(b_neighbors.iter().all(|nbr| nbr.get_data().is_some()));
+        assert!(b_neighbors
+            .iter()
+            .any(|nbr| nbr.get_offset() == Some(offset)));
+        let c = b_neighbors
+            .into_iter()
+            .find(|nbr| nbr.get_offset() != Some(offset))
+            .unwrap();
+        assert!(neighbors(&c).iter().all(|nbr| nbr.get_data().is_some()));
+    }
+
+    #[test]
+    fn test_stats_count_hits_and_misses() {
+        let mut writer = Cursor::new(Vec::new());
+        let offset = LazyItemRef::new(MergedNode::new(1, 0))
+            .serialize(&mut writer)
+            .unwrap();
+
+        let cache = get_cache(writer.into_inner());
+        let _: LazyItemRef<MergedNode> = cache.clone().load_item(offset).unwrap();
+        let _: LazyItemRef<MergedNode> = cache.clone().load_item(offset).unwrap();
+
+        assert_eq!(cache.stats(), RegistryStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn test_mmap_registry_matches_file_registry() {
+        let mut writer = Cursor::new(Vec::new());
+        let offsets: Vec<FileOffset> = (0..5)
+            .map(|version| {
+                let node = MergedNode::new(version, (version % 3) as u8);
+                node.add_ready_neighbor(
+                    LazyItem::from_data(MergedNode::new(100 + version, 0)),
+                    0.5,
+                );
+                LazyItemRef::new(node).serialize(&mut writer).unwrap()
+            })
+            .collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &writer.into_inner()).unwrap();
+
+        let file_cache = Arc::new(NodeRegistry::new(
+            1000,
+            std::fs::File::open(file.path()).unwrap(),
+        ));
+        let mmap_cache = Arc::new(NodeRegistry::open_mmap(1000, file.path()).unwrap());
+
+        let summary = |node: LazyItemRef<MergedNode>| {
+            let mut node_arc = node.get_data().expect("node not loaded");
+            let node = node_arc.get();
+            let neighbors: Vec<_> = node
+                .get_neighbors()
+                .iter()
+                .map(|nbr| (nbr.0, nbr.1.get_data().map(|mut n| n.get().version_id)))
+                .collect();
+            (node.version_id, node.hnsw_level, neighbors)
+        };
+        for offset in offsets {
+            let from_file = file_cache.clone().load_item(offset).unwrap();
+            let from_mmap = mmap_cache.clone().load_item(offset).unwrap();
+            assert_eq!(summary(from_file), summary(from_mmap));
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use super::dot_product::x86_64::dot_product_u8_avx2;
 use super::lazy_load::LazyItem;
 use super::rpc::VectorIdValue;
-use super::types::{MergedNode, VectorId};
+use super::types::{DistanceMetric, MergedNode, NamespacedId, ScoredResult, VectorId};
 use crate::distance::DistanceError;
 use crate::models::rpc::Vector;
 use crate::models::types::PropState;
@@ -10,9 +10,10 @@ use crate::quantization::QuantizationError;
 use futures::future::FutureExt;
 use sha2::{Digest, Sha256};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::sync::Arc;
 
 #[cfg(target_arch = "x86_64")]
@@ -279,15 +280,11 @@ pub fn cosine_similarity_qt(
 #[inline]
 
 fn to_float_flag(x: f32, bits_per_value: usize, step: f32) -> Vec<bool> {
-    let mut n = ((x + 1.0) / step).floor() as usize;
-    let mut result = vec![false; bits_per_value];
-    // Fill the vector with bits from the least significant to the most significant
-    for i in (0..bits_per_value).rev() {
-        result[i] = (n & 1) == 1;
-        n >>= 1;
-    }
-
-    result
+    // 1.0 would otherwise land one past the last level
+    let last_level = (1 << bits_per_value) - 1;
+    let n = (((x + 1.0) / step).floor() as usize).min(last_level);
+    // Bit `i` of the level goes to position `i`, so it ends up in plane `i`
+    (0..bits_per_value).map(|i| (n >> i) & 1 == 1).collect()
 }
 
 pub fn simp_quant(v: &[f32]) -> Vec<u8> {
@@ -298,7 +295,36 @@ pub fn mag_square_u8(vec: &[u8]) -> u32 {
     vec.iter().map(|&x| x as u32 * x as u32).sum()
 }
 
-pub fn quantize_to_u8_bits(fins: &[f32], resolution: u8) -> Vec<Vec<u8>> {
+// Reassembles each dimension from the bit planes (plane `i` weighted by `2^i`, the same
+// weighting the sub-byte dot products use) and sums the squares
+pub fn mag_square_sub_byte(quant_vec: &[Vec<u8>], resolution: u8) -> u32 {
+    let planes = &quant_vec[..quant_vec.len().min(resolution as usize)];
+    let bytes = planes.first().map_or(0, |plane| plane.len());
+
+    (0..bytes)
+        .flat_map(|byte| (0..8).map(move |bit| (byte, bit)))
+        .map(|(byte, bit)| {
+            let value: u32 = planes
+                .iter()
+                .enumerate()
+                .map(|(index, plane)| (((plane[byte] >> bit) & 1) as u32) << index)
+                .sum();
+            value * value
+        })
+        .sum()
+}
+
+pub const MAX_SUB_BYTE_RESOLUTION: u8 = 8;
+
+// Splits every value into `resolution` bit planes, plane `i` holding bit `i` of each
+// value packed eight to a byte. Resolutions outside `1..=8` are rejected
+pub fn quantize_to_u8_bits(
+    fins: &[f32],
+    resolution: u8,
+) -> Result<Vec<Vec<u8>>, QuantizationError> {
+    if resolution == 0 || resolution > MAX_SUB_BYTE_RESOLUTION {
+        return Err(QuantizationError::InvalidInput);
+    }
     let bits_per_value = resolution as usize;
     let parts = 2_usize.pow(bits_per_value as u32);
     let step = 2.0 / parts as f32;
@@ -318,7 +344,7 @@ pub fn quantize_to_u8_bits(fins: &[f32], resolution: u8) -> Vec<Vec<u8>> {
         }
         bit_index += 1;
 
-        if bit_index == 32 {
+        if bit_index == 8 {
             for bit_position in 0..bits_per_value {
                 quantized[bit_position].push(current_u32s[bit_position]);
                 current_u32s[bit_position] = 0;
@@ -333,7 +359,7 @@ pub fn quantize_to_u8_bits(fins: &[f32], resolution: u8) -> Vec<Vec<u8>> {
         }
     }
 
-    quantized
+    Ok(quantized)
 }
 
 #[derive(Debug, Clone)]
@@ -351,6 +377,16 @@ pub enum WaCustomError {
     CalculationError,
     FsError(String),
     DeserializationError(String),
+    SearchError(String),
+    DuplicateId(VectorId),
+    DimensionMismatch(usize, usize),
+    NotFound(VectorId),
+    AlreadyExists(String),
+    // Shared so the error stays cloneable
+    IoError(Arc<io::Error>),
+    QuantizationError(QuantizationError),
+    // An index file whose leading format version this build can't read
+    UnsupportedIndexFormat(u8),
 }
 
 impl fmt::Display for WaCustomError {
@@ -372,16 +408,33 @@ impl fmt::Display for WaCustomError {
             WaCustomError::CalculationError => write!(f, "Calculation error"),
             WaCustomError::FsError(err) => write!(f, "FS error: {}", err),
             WaCustomError::DeserializationError(err) => write!(f, "Deserialization error: {}", err),
+            WaCustomError::SearchError(msg) => write!(f, "Search error: {}", msg),
+            WaCustomError::DuplicateId(id) => write!(f, "Vector with ID {} already exists", id),
+            WaCustomError::DimensionMismatch(left, right) => {
+                write!(f, "Dimension mismatch: {} vs {}", left, right)
+            }
+            WaCustomError::NotFound(id) => write!(f, "Vector with ID {} not found", id),
+            WaCustomError::AlreadyExists(name) => {
+                write!(f, "Vector store {} already exists", name)
+            }
+            WaCustomError::IoError(err) => write!(f, "IO error: {}", err),
+            WaCustomError::QuantizationError(err) => write!(f, "Quantization error: {:?}", err),
+            WaCustomError::UnsupportedIndexFormat(version) => {
+                write!(f, "Unsupported index format version {}", version)
+            }
         }
     }
 }
 
 impl From<QuantizationError> for WaCustomError {
     fn from(value: QuantizationError) -> Self {
-        match value {
-            QuantizationError::InvalidInput => WaCustomError::InvalidParams,
-            QuantizationError::TrainingFailed => WaCustomError::TrainingFailed,
-        }
+        WaCustomError::QuantizationError(value)
+    }
+}
+
+impl From<io::Error> for WaCustomError {
+    fn from(value: io::Error) -> Self {
+        WaCustomError::IoError(Arc::new(value))
     }
 }
 
@@ -390,6 +443,9 @@ impl From<DistanceError> for WaCustomError {
         match value {
             DistanceError::StorageMismatch => WaCustomError::QuantizationMismatch,
             DistanceError::CalculationError => WaCustomError::CalculationError,
+            DistanceError::DimensionMismatch { left, right } => {
+                WaCustomError::DimensionMismatch(left, right)
+            }
         }
     }
 }
@@ -448,12 +504,10 @@ fn convert_id(id: VectorId) -> VectorIdValue {
 }
 
 // Function to convert the Option<Vec<(VectorId, _)>> to Option<Vec<(VectorIdValue, _)>>
-pub fn convert_option_vec(
-    input: Option<Vec<(VectorId, f32)>>,
-) -> Option<Vec<(VectorIdValue, f32)>> {
+pub fn convert_option_vec(input: Option<Vec<ScoredResult>>) -> Option<Vec<(VectorIdValue, f32)>> {
     input.map(|vec| {
         vec.into_iter()
-            .map(|(id, value)| (convert_id(id), value))
+            .map(|result| (convert_id(result.id), result.score))
             .collect()
     })
 }
@@ -466,38 +520,126 @@ pub fn convert_vectors(vectors: Vec<Vector>) -> Vec<(VectorIdValue, Vec<f32>)> {
         .collect()
 }
 
+// Drops the root and repeated ids, keeping each id's best score, and orders the
+// results best first according to `metric`
+// The match for a search candidate, or `None` for the root placeholder and nodes
+// whose data or prop isn't loaded
+fn scored_result(
+    lazy_item: LazyItem<MergedNode>,
+    similarity: f32,
+    metric: &DistanceMetric,
+) -> Option<(NamespacedId, ScoredResult)> {
+    let mut node = lazy_item.get_data()?;
+    let mut prop_arc = node.get().prop.clone();
+    let PropState::Ready(node_prop) = prop_arc.get() else {
+        return None;
+    };
+    if node_prop.id == VectorId::Int(-1) {
+        return None;
+    }
+    let result = ScoredResult {
+        id: node_prop.id.clone(),
+        score: similarity,
+        metric: metric.clone(),
+        namespace: node_prop.namespace.clone(),
+    };
+    Some((node_prop.namespaced_id(), result))
+}
+
 pub fn remove_duplicates_and_filter(
     input: Option<Vec<(LazyItem<MergedNode>, f32)>>,
-) -> Option<Vec<(VectorId, f32)>> {
-    input.map(|vec| {
+    metric: &DistanceMetric,
+) -> Option<Vec<ScoredResult>> {
+    input.map(|mut vec| {
+        vec.sort_by(|a, b| metric.compare_scores(a.1, b.1));
         let mut seen = HashSet::new();
         vec.into_iter()
-            .filter_map(|(lazy_item, similarity)| {
-                if let LazyItem::Valid {
-                    data: Some(mut node),
-                    ..
-                } = lazy_item
-                {
-                    let mut prop_arc = node.get().prop.clone();
-                    if let PropState::Ready(node_prop) = prop_arc.get() {
-                        let id = &node_prop.id;
-                        if let VectorId::Int(s) = id {
-                            if *s == -1 {
-                                return None;
-                            }
-                        }
-                        if seen.insert(id.clone()) {
-                            Some((id.clone(), similarity))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None // PropState is Pending
+            .filter_map(|(lazy_item, similarity)| scored_result(lazy_item, similarity, metric))
+            .filter_map(|(id, result)| seen.insert(id).then_some(result))
+            .collect()
+    })
+}
+
+// A match held by `remove_duplicates_and_filter_top_k`. The worse match compares
+// greater, so the heap's top is the first one to evict. Equal scores fall back to
+// the candidate order, like the stable sort of `remove_duplicates_and_filter`
+struct RankedResult<'a> {
+    id: NamespacedId,
+    result: ScoredResult,
+    index: usize,
+    metric: &'a DistanceMetric,
+}
+
+impl Ord for RankedResult<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.metric
+            .compare_scores(self.result.score, other.result.score)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+impl PartialOrd for RankedResult<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedResult<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for RankedResult<'_> {}
+
+// Same as `remove_duplicates_and_filter` followed by a truncate to `k`, but only ever
+// holds the best `k` matches instead of sorting every candidate
+pub fn remove_duplicates_and_filter_top_k(
+    input: Option<Vec<(LazyItem<MergedNode>, f32)>>,
+    metric: &DistanceMetric,
+    k: usize,
+) -> Option<Vec<ScoredResult>> {
+    input.map(|vec| {
+        let mut heap: BinaryHeap<RankedResult> = BinaryHeap::new();
+        // The ids in the heap, with the candidate index of their match
+        let mut held: HashMap<NamespacedId, usize> = HashMap::new();
+        for (index, (lazy_item, similarity)) in vec.into_iter().enumerate() {
+            let Some((id, result)) = scored_result(lazy_item, similarity, metric) else {
+                continue;
+            };
+            let candidate = RankedResult {
+                id,
+                result,
+                index,
+                metric,
+            };
+            // A node can turn up more than once, only its best match counts
+            if let Some(held_index) = held.get(&candidate.id).copied() {
+                let better = heap
+                    .iter()
+                    .find(|ranked| ranked.index == held_index)
+                    .is_some_and(|ranked| candidate < *ranked);
+                if !better {
+                    continue;
+                }
+                heap.retain(|ranked| ranked.index != held_index);
+                held.remove(&candidate.id);
+            }
+            if heap.len() >= k {
+                match heap.peek() {
+                    Some(worst) if candidate < *worst => {
+                        let evicted = heap.pop().unwrap();
+                        held.remove(&evicted.id);
                     }
-                } else {
-                    None // data is None
+                    _ => continue,
                 }
-            })
+            }
+            held.insert(candidate.id.clone(), candidate.index);
+            heap.push(candidate);
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|ranked| ranked.result)
             .collect()
     })
 }
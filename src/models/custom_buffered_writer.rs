@@ -5,11 +5,24 @@ use std::io::{self, Seek, SeekFrom, Write};
 use std::rc::Rc;
 
 pub const BUFFER_SIZE: usize = 8192; // 8 KB buffer, adjust as needed
-pub const FLUSH_THRESHOLD: usize = (BUFFER_SIZE as f32 * 0.7) as usize; // 70% of buffer size
+
+// For writing out a whole index at once, e.g. when a store is built or compacted
+pub const BULK_BUFFER_SIZE: usize = 1 << 20; // 1 MB
+
+// The buffer is written out once it's 70% full
+pub(crate) const fn flush_threshold(capacity: usize) -> usize {
+    let threshold = capacity * 7 / 10;
+    if threshold == 0 {
+        1
+    } else {
+        threshold
+    }
+}
 
 pub struct CustomBufferedWriter {
     file: Rc<RefCell<File>>,
-    buffer: [u8; BUFFER_SIZE],
+    buffer: Box<[u8]>,
+    flush_threshold: usize,
     buffer_position: usize,
     buffer_end: usize,
     file_position: u64,
@@ -17,10 +30,20 @@ pub struct CustomBufferedWriter {
 
 impl CustomBufferedWriter {
     pub fn new(file: Rc<RefCell<File>>) -> io::Result<Self> {
+        Self::with_capacity(file, BUFFER_SIZE)
+    }
+
+    // A larger buffer holds more writes per syscall and lets more seeks back to patch
+    // offsets land in memory, at the cost of keeping `capacity` bytes around for the
+    // writer's lifetime. The default suits incremental commits, bulk writes of a whole
+    // index do better with something like `BULK_BUFFER_SIZE`
+    pub fn with_capacity(file: Rc<RefCell<File>>, capacity: usize) -> io::Result<Self> {
+        let capacity = capacity.max(1);
         let file_position = file.borrow_mut().stream_position()?;
         Ok(CustomBufferedWriter {
             file,
-            buffer: [0; BUFFER_SIZE],
+            buffer: vec![0; capacity].into_boxed_slice(),
+            flush_threshold: flush_threshold(capacity),
             buffer_position: 0,
             buffer_end: 0,
             file_position,
@@ -50,11 +73,26 @@ impl CustomBufferedWriter {
     pub fn stream_position(&self) -> io::Result<u64> {
         Ok(self.file_position + self.buffer_position as u64)
     }
+
+    // Flushes whatever is still buffered, unlike dropping the writer this reports
+    // failures to the caller
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Drop for CustomBufferedWriter {
+    // Keeps writers abandoned on an early return from leaving the file short
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to flush buffered writer on drop: {}", e);
+        }
+    }
 }
 
 impl Write for CustomBufferedWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let available_space = BUFFER_SIZE - self.buffer_position;
+        let available_space = self.buffer.len() - self.buffer_position;
         let to_write = buf.len().min(available_space);
 
         self.buffer[self.buffer_position..self.buffer_position + to_write]
@@ -62,7 +100,7 @@ impl Write for CustomBufferedWriter {
         self.buffer_position += to_write;
         self.buffer_end = self.buffer_end.max(self.buffer_position);
 
-        if self.buffer_end >= FLUSH_THRESHOLD {
+        if self.buffer_end >= self.flush_threshold {
             self.flush_buffer()?;
         }
 
@@ -1,7 +1,7 @@
-#[cfg(tests)]
+#[cfg(test)]
 mod tests {
     use crate::models::custom_buffered_writer::{
-        CustomBufferedWriter, BUFFER_SIZE, FLUSH_THRESHOLD,
+        flush_threshold, CustomBufferedWriter, BUFFER_SIZE,
     };
     use std::{
         cell::RefCell,
@@ -39,7 +39,7 @@ mod tests {
     #[test]
     fn test_flush_on_threshold() {
         let (mut writer, file) = create_test_writer();
-        let threshold_data = vec![0; FLUSH_THRESHOLD - 1];
+        let threshold_data = vec![0; flush_threshold(BUFFER_SIZE) - 1];
         writer.write_all(&threshold_data).unwrap();
 
         // Check that the file is still empty (not flushed)
@@ -87,7 +87,7 @@ mod tests {
         writer.write_all(b"Initial data").unwrap();
         writer.seek(SeekFrom::Start(100)).unwrap();
         writer.write_all(b"More data").unwrap();
-        writer.flush_buffer();
+        writer.flush_buffer().unwrap();
 
         // Check that the seek caused a flush
         let contents = read_file_contents(&mut file.borrow_mut());
@@ -138,4 +138,46 @@ mod tests {
             b"Initial Appended"
         );
     }
+
+    #[test]
+    fn test_drop_flushes_buffer() {
+        let (mut writer, file) = create_test_writer();
+        writer.write_all(b"Never flushed").unwrap();
+        assert_eq!(read_file_contents(&mut file.borrow_mut()), Vec::<u8>::new());
+
+        drop(writer);
+
+        assert_eq!(read_file_contents(&mut file.borrow_mut()), b"Never flushed");
+    }
+
+    #[test]
+    fn test_finish_flushes_buffer() {
+        let (mut writer, file) = create_test_writer();
+        writer.write_all(b"Finished").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(read_file_contents(&mut file.borrow_mut()), b"Finished");
+    }
+
+    #[test]
+    fn test_with_capacity_writes_same_bytes() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        for capacity in [16, 1 << 20] {
+            let file = Rc::new(RefCell::new(tempfile().unwrap()));
+            let mut writer = CustomBufferedWriter::with_capacity(file.clone(), capacity).unwrap();
+            for chunk in data.chunks(100) {
+                writer.write_all(chunk).unwrap();
+            }
+            // Patch a byte written long before, which only the large buffer still holds
+            writer.seek(SeekFrom::Start(10)).unwrap();
+            writer.write_all(&[0xFF]).unwrap();
+            writer.seek(SeekFrom::Start(data.len() as u64)).unwrap();
+            assert_eq!(writer.stream_position().unwrap(), data.len() as u64);
+            writer.finish().unwrap();
+
+            let mut expected = data.clone();
+            expected[10] = 0xFF;
+            assert_eq!(read_file_contents(&mut file.borrow_mut()), expected);
+        }
+    }
 }
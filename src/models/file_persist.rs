@@ -1,13 +1,93 @@
 use super::cache_loader::NodeRegistry;
 use super::common::WaCustomError;
 use super::lazy_load::LazyItem;
-use super::types::{HNSWLevel, Item, MergedNode, NodeProp, VectorId};
+use super::types::{FileOffset, HNSWLevel, Item, MergedNode, NodeProp, PropPersistRef, VectorId};
 use crate::models::custom_buffered_writer::*;
 use crate::models::serializer::*;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
 
+// Index files start with this byte. Offsets and lengths after it are little-endian,
+// changing that or the layout of the nodes, or of the prop records they point to,
+// needs a new version so older builds refuse the file instead of misreading it.
+// Version 2 added the prop record header, version 3 dropped the neighbor delta slot
+// from nodes, version 4 added relocation tables
+pub const INDEX_FORMAT_VERSION: u8 = 4;
+
+// Every prop record starts with this byte and the length of the CBOR that follows it,
+// so reading from a stale or wrong location fails instead of decoding garbage
+const PROP_RECORD_MAGIC: u8 = 0xC7;
+const PROP_RECORD_HEADER_LEN: usize = 5;
+
+// Starts an index file that's still empty with its format version, one that already
+// has content is left alone
+pub fn write_index_header(file: &mut File) -> Result<(), WaCustomError> {
+    if file.metadata()?.len() == 0 {
+        file.write_all(&[INDEX_FORMAT_VERSION])?;
+    }
+    Ok(())
+}
+
+// Fails with `UnsupportedIndexFormat` unless the index file starts with the format
+// version written by this build
+pub fn check_index_header<R: Read + Seek>(reader: &mut R) -> Result<(), WaCustomError> {
+    let mut version = [0u8; 1];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut version)?;
+    if version[0] != INDEX_FORMAT_VERSION {
+        return Err(WaCustomError::UnsupportedIndexFormat(version[0]));
+    }
+    Ok(())
+}
+
+// A commit writes a new copy of each node it changed, and a relocation table pairing
+// the offset of every copy replaced with the offset of the one replacing it. Each table
+// points at the one written before it in the same index file, or `u32::MAX` for the
+// first. Returns where the table starts
+pub fn write_relocations<W: Write + Seek>(
+    writer: &mut W,
+    previous: Option<FileOffset>,
+    moved: &[(FileOffset, FileOffset)],
+) -> std::io::Result<FileOffset> {
+    let offset = writer.stream_position()? as FileOffset;
+    writer.write_all(&previous.unwrap_or(u32::MAX).to_le_bytes())?;
+    writer.write_all(&(moved.len() as u32).to_le_bytes())?;
+    for (from, to) in moved {
+        writer.write_all(&from.to_le_bytes())?;
+        writer.write_all(&to.to_le_bytes())?;
+    }
+    Ok(offset)
+}
+
+// Every relocation in the table at `last` and the ones written before it. A copy is
+// only ever replaced once, by the copy written after it
+pub fn read_relocations<R: Read + Seek>(
+    reader: &mut R,
+    last: Option<FileOffset>,
+) -> std::io::Result<HashMap<FileOffset, FileOffset>> {
+    fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+        let mut bytes = [0u8; 4];
+        reader.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    let mut moved = HashMap::new();
+    let mut table = last;
+    while let Some(offset) = table {
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        let previous = read_u32(reader)?;
+        let len = read_u32(reader)?;
+        for _ in 0..len {
+            let from = read_u32(reader)?;
+            moved.insert(from, read_u32(reader)?);
+        }
+        table = (previous != u32::MAX).then_some(previous);
+    }
+    Ok(moved)
+}
+
 // pub type FileOffset = u32;
 // pub type BytesToRead = u32;
 
@@ -93,15 +173,178 @@ pub fn load_vector_id_lsmdb(_level: HNSWLevel, _vector_id: VectorId) -> LazyItem
 pub fn load_neighbor_persist_ref(_level: HNSWLevel, _node_file_ref: u32) -> Option<MergedNode> {
     None
 }
-pub fn write_prop_to_file(prop: &NodeProp, mut file: &File) -> (u32, u32) {
+// `file` must be append-only, the prop is taken to end where the write left off. The
+// returned location covers the whole record, header included
+pub fn write_prop_to_file<W: Write + Seek>(prop: &NodeProp, mut file: W) -> (u32, u32) {
     let mut prop_bytes = Vec::new();
     //let result = encode(&prop);
     let result = serde_cbor::to_vec(&prop).unwrap();
 
+    prop_bytes.push(PROP_RECORD_MAGIC);
+    prop_bytes.extend_from_slice(&(result.len() as u32).to_le_bytes());
     prop_bytes.extend_from_slice(result.as_ref());
 
     file.write_all(&prop_bytes)
         .expect("Failed to write to file");
-    let offset = file.metadata().unwrap().len() - prop_bytes.len() as u64;
+    let offset = file.stream_position().unwrap() - prop_bytes.len() as u64;
     (offset as u32, prop_bytes.len() as u32)
 }
+
+pub fn read_prop_from_file<R: Read + Seek>(
+    (offset, length): PropPersistRef,
+    mut file: R,
+) -> Result<NodeProp, WaCustomError> {
+    let mut prop_bytes = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset as u64))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    file.read_exact(&mut prop_bytes)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    if prop_bytes.len() < PROP_RECORD_HEADER_LEN || prop_bytes[0] != PROP_RECORD_MAGIC {
+        return Err(WaCustomError::DeserializationError(format!(
+            "No prop record at offset {}",
+            offset
+        )));
+    }
+    let (header, body) = prop_bytes.split_at(PROP_RECORD_HEADER_LEN);
+    let body_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+    if body_len as usize != body.len() {
+        return Err(WaCustomError::DeserializationError(format!(
+            "Prop record at offset {} holds {} bytes, expected {}",
+            offset,
+            body_len,
+            body.len()
+        )));
+    }
+
+    let mut prop: NodeProp = serde_cbor::from_slice(body)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    prop.location = Some((offset, length));
+    Ok(prop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_prop_metadata_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(dir.path().join("prop.data"))
+            .unwrap();
+
+        let metadata = HashMap::from([
+            ("color".to_string(), "red".to_string()),
+            ("size".to_string(), "large".to_string()),
+        ]);
+        let prop = NodeProp {
+            id: VectorId::Int(7),
+            value: Arc::new(Storage::UnsignedByte {
+                mag: 5,
+                norm: 5f32.sqrt(),
+                quant_vec: vec![1, 2],
+            }),
+            location: None,
+            metadata: Some(metadata.clone()),
+            namespace: None,
+            exact: Some(vec![0.004, 0.0078]),
+        };
+        let plain = NodeProp {
+            id: VectorId::Int(8),
+            metadata: None,
+            exact: None,
+            ..prop.clone()
+        };
+
+        let location = write_prop_to_file(&prop, &file);
+        let plain_location = write_prop_to_file(&plain, &file);
+
+        let read = read_prop_from_file(location, &file).unwrap();
+        assert_eq!(read, prop);
+        assert_eq!(read.metadata, Some(metadata));
+        assert_eq!(read.exact, prop.exact);
+        let read_plain = read_prop_from_file(plain_location, &file).unwrap();
+        assert_eq!(read_plain.metadata, None);
+        assert_eq!(read_plain.exact, None);
+    }
+
+    #[test]
+    fn test_prop_records_are_validated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prop.data");
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let prop = |id: i32| NodeProp {
+            id: VectorId::Int(id),
+            value: Arc::new(Storage::UnsignedByte {
+                mag: 5,
+                norm: 5f32.sqrt(),
+                quant_vec: vec![1, 2],
+            }),
+            location: None,
+            metadata: None,
+            namespace: None,
+            exact: None,
+        };
+
+        let first = write_prop_to_file(&prop(1), &file);
+        let second = write_prop_to_file(&prop(2), &file);
+        assert_eq!(
+            read_prop_from_file(first, &file).unwrap().id,
+            VectorId::Int(1)
+        );
+        assert_eq!(
+            read_prop_from_file(second, &file).unwrap().id,
+            VectorId::Int(2)
+        );
+
+        // A location that doesn't start at a record
+        assert!(matches!(
+            read_prop_from_file((first.0 + 1, first.1), &file),
+            Err(WaCustomError::DeserializationError(_))
+        ));
+
+        // Overwrite the length in the first record's header
+        let mut writer = OpenOptions::new().write(true).open(&path).unwrap();
+        writer.seek(SeekFrom::Start(first.0 as u64 + 1)).unwrap();
+        writer.write_all(&(first.1 + 3).to_le_bytes()).unwrap();
+        assert!(matches!(
+            read_prop_from_file(first, &file),
+            Err(WaCustomError::DeserializationError(_))
+        ));
+        assert_eq!(
+            read_prop_from_file(second, &file).unwrap().id,
+            VectorId::Int(2)
+        );
+    }
+
+    #[test]
+    fn test_relocations_follow_earlier_tables() {
+        let mut file = std::io::Cursor::new(vec![INDEX_FORMAT_VERSION]);
+        file.seek(SeekFrom::End(0)).unwrap();
+
+        let first = write_relocations(&mut file, None, &[(1, 10), (2, 20)]).unwrap();
+        let second = write_relocations(&mut file, Some(first), &[(10, 30)]).unwrap();
+
+        assert_eq!(read_relocations(&mut file, None).unwrap(), HashMap::new());
+        assert_eq!(
+            read_relocations(&mut file, Some(first)).unwrap(),
+            HashMap::from([(1, 10), (2, 20)])
+        );
+        assert_eq!(
+            read_relocations(&mut file, Some(second)).unwrap(),
+            HashMap::from([(1, 10), (2, 20), (10, 30)])
+        );
+    }
+}
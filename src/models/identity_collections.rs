@@ -28,6 +28,14 @@ impl<T: Identifiable> IdentitySet<T> {
         self.map.insert(value.get_id(), value)
     }
 
+    pub fn remove(&mut self, id: &T::Id) -> Option<T> {
+        self.map.remove(id)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.map.retain(|_, value| keep(value))
+    }
+
     pub fn contains(&self, value: &T) -> bool {
         self.map.contains_key(&value.get_id())
     }
@@ -49,6 +57,8 @@ impl<T: Identifiable> IdentitySet<T> {
 pub enum IdentityMapKey {
     String(String),
     Int(u32),
+    // Binary keys such as UUIDs, kept apart from strings since they needn't be UTF-8
+    Bytes(Vec<u8>),
 }
 
 #[derive(Debug, Clone)]
@@ -73,10 +83,18 @@ impl<T> IdentityMap<T> {
         self.map.insert(key, value)
     }
 
+    pub fn get(&self, key: &IdentityMapKey) -> Option<&T> {
+        self.map.get(key)
+    }
+
     pub fn contains(&self, key: &IdentityMapKey) -> bool {
         self.map.contains_key(key)
     }
 
+    pub fn retain(&mut self, keep: impl FnMut(&IdentityMapKey, &mut T) -> bool) {
+        self.map.retain(keep)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&IdentityMapKey, &T)> {
         self.map.iter()
     }
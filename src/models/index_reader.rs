@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Default)]
+struct OpenIndexFile {
+    readers: usize,
+    // Set when the file was asked to be removed while still being read, the last
+    // reader to close it removes it
+    remove_when_unused: bool,
+}
+
+fn open_index_files() -> &'static Mutex<HashMap<PathBuf, OpenIndexFile>> {
+    static OPEN_INDEX_FILES: OnceLock<Mutex<HashMap<PathBuf, OpenIndexFile>>> = OnceLock::new();
+    OPEN_INDEX_FILES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// An index file opened for reading. While any is open for a path,
+// `remove_index_file` leaves the file in place and the last one to close removes it,
+// so a query that is still loading nodes never sees its file vanish
+#[derive(Debug)]
+pub struct IndexFileReader {
+    file: File,
+    path: PathBuf,
+}
+
+impl IndexFileReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut open_files = open_index_files().lock().unwrap();
+        let entry = open_files.get(path);
+        // Removal is already underway, the file is as good as gone for new readers
+        if entry.is_some_and(|entry| entry.remove_when_unused) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is being removed", path.display()),
+            ));
+        }
+        let file = File::open(path)?;
+        open_files.entry(path.to_path_buf()).or_default().readers += 1;
+        Ok(IndexFileReader {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for IndexFileReader {
+    fn drop(&mut self) {
+        let mut open_files = open_index_files().lock().unwrap();
+        let Some(entry) = open_files.get_mut(&self.path) else {
+            return;
+        };
+        entry.readers -= 1;
+        if entry.readers == 0 {
+            if entry.remove_when_unused {
+                if let Err(e) = std::fs::remove_file(&self.path) {
+                    eprintln!("Failed to remove {}: {}", self.path.display(), e);
+                }
+            }
+            open_files.remove(&self.path);
+        }
+    }
+}
+
+impl Read for IndexFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for IndexFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+// Removes an index file, or if an `IndexFileReader` still has it open, leaves that to
+// the last reader to close it. Returns whether the file was removed right away
+pub fn remove_index_file(path: &Path) -> io::Result<bool> {
+    let mut open_files = open_index_files().lock().unwrap();
+    if let Some(entry) = open_files.get_mut(path) {
+        entry.remove_when_unused = true;
+        return Ok(false);
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // How many readers have the file open
+    fn index_file_readers(path: &Path) -> usize {
+        let open_files = open_index_files().lock().unwrap();
+        open_files.get(path).map_or(0, |entry| entry.readers)
+    }
+
+    #[test]
+    fn test_removal_waits_for_the_last_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.index");
+        File::create(&path).unwrap().write_all(b"index").unwrap();
+
+        let mut first = IndexFileReader::open(&path).unwrap();
+        let second = IndexFileReader::open(&path).unwrap();
+        assert_eq!(index_file_readers(&path), 2);
+        assert!(!remove_index_file(&path).unwrap());
+        // No new readers once removal is pending
+        assert!(IndexFileReader::open(&path).is_err());
+
+        drop(second);
+        assert!(path.exists());
+        let mut contents = String::new();
+        first.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "index");
+
+        drop(first);
+        assert!(!path.exists());
+        assert_eq!(index_file_readers(&path), 0);
+    }
+
+    #[test]
+    fn test_unread_file_is_removed_right_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("2.index");
+        File::create(&path).unwrap();
+
+        assert!(remove_index_file(&path).unwrap());
+        assert!(!path.exists());
+    }
+}
@@ -26,7 +26,8 @@ pub struct EagerLazyItem<T: Clone + 'static, E: Clone + CustomSerialize + 'stati
     pub LazyItem<T>,
 );
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+// Ordered so sets can be written in a stable order, in-memory items first
+#[derive(Debug, Eq, PartialEq, Hash, Clone, PartialOrd, Ord)]
 pub enum LazyItemId {
     Memory(u64),
     Persist(u32),
@@ -38,16 +39,19 @@ where
 {
     type Id = LazyItemId;
 
+    // A loaded item goes by its data's id. Offsets are only unique within one index
+    // file while the live graph spans the files of several versions, so they're left
+    // for items that aren't loaded
     fn get_id(&self) -> Self::Id {
         if let LazyItem::Valid { data, offset, .. } = self {
-            if let Some(offset) = offset.clone().get().clone() {
-                return LazyItemId::Persist(offset);
-            }
-
             if let Some(data) = data {
                 let mut arc = data.clone();
                 return LazyItemId::Memory(arc.get().get_id());
             }
+
+            if let Some(offset) = offset.clone().get().clone() {
+                return LazyItemId::Persist(offset);
+            }
         }
 
         LazyItemId::Persist(u32::MAX)
@@ -277,6 +281,99 @@ where
         })
     }
 
+    // Inserts the item, then evicts the worst entries until the set holds at most
+    // `max_items`. `compare` orders eager values best first
+    pub fn insert_bounded(
+        &self,
+        item: EagerLazyItem<T, E>,
+        max_items: usize,
+        compare: impl Fn(&E, &E) -> std::cmp::Ordering,
+    ) {
+        self.insert_all_bounded(vec![item], max_items, compare)
+    }
+
+    // Like `insert_bounded` for several items, all applied in one rcu, so readers see
+    // the set from before or after the whole batch and never a part of it
+    pub fn insert_all_bounded(
+        &self,
+        items: Vec<EagerLazyItem<T, E>>,
+        max_items: usize,
+        compare: impl Fn(&E, &E) -> std::cmp::Ordering,
+    ) {
+        let mut arc = self.items.clone();
+
+        arc.rcu(|set| {
+            let mut set = set.clone();
+            for item in &items {
+                set.insert(item.clone());
+            }
+            Self::keep_best(&mut set, max_items, &compare);
+            set
+        })
+    }
+
+    // Swaps in a set made of `items`, trimmed to `max_items`, as a single update. Unlike
+    // a `clear` followed by inserts, readers never see the set empty or half filled
+    pub fn replace_bounded(
+        &self,
+        items: Vec<EagerLazyItem<T, E>>,
+        max_items: usize,
+        compare: impl Fn(&E, &E) -> std::cmp::Ordering,
+    ) {
+        let mut set = IdentitySet::from_iter(items.into_iter());
+        Self::keep_best(&mut set, max_items, &compare);
+        let mut arc = self.items.clone();
+        arc.update(set);
+    }
+
+    fn keep_best(
+        set: &mut IdentitySet<EagerLazyItem<T, E>>,
+        max_items: usize,
+        compare: &impl Fn(&E, &E) -> std::cmp::Ordering,
+    ) {
+        if set.len() <= max_items {
+            return;
+        }
+        let mut items: Vec<_> = set.iter().cloned().collect();
+        items.sort_by(|a, b| compare(&a.0, &b.0));
+        items.truncate(max_items);
+        // Rebuilt rather than removed from by id, an entry stays keyed by the id it had
+        // when inserted while its current id changes once its node's prop is loaded
+        *set = IdentitySet::from_iter(items.into_iter());
+    }
+
+    // Returns whether an entry was removed. Runs as a single rcu, so a concurrent
+    // insert is never lost
+    pub fn remove(&self, id: &LazyItemId) -> bool {
+        let mut arc = self.items.clone();
+        let mut removed = false;
+
+        arc.rcu(|set| {
+            let mut set = set.clone();
+            removed = set.remove(id).is_some();
+            set
+        });
+        removed
+    }
+
+    // Drops the entries `keep` rejects, as a single rcu. Matches on the entries
+    // themselves, so unlike `remove` it can't miss one whose id changed since it was
+    // inserted
+    pub fn retain(&self, keep: impl Fn(&EagerLazyItem<T, E>) -> bool) {
+        let mut arc = self.items.clone();
+
+        arc.rcu(|set| {
+            let mut set = set.clone();
+            set.retain(|item| keep(item));
+            set
+        })
+    }
+
+    pub fn clear(&self) {
+        let mut arc = self.items.clone();
+        arc.update(IdentitySet::new());
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = EagerLazyItem<T, E>> {
         let mut arc = self.items.clone();
         let vec: Vec<_> = arc.get().iter().map(Clone::clone).collect();
@@ -311,6 +408,19 @@ impl<T: Clone + Identifiable<Id = u64> + 'static> LazyItemSet<T> {
         })
     }
 
+    // Returns whether an entry was removed
+    pub fn remove(&self, id: &LazyItemId) -> bool {
+        let mut arc = self.items.clone();
+        let mut removed = false;
+
+        arc.rcu(|set| {
+            let mut set = set.clone();
+            removed = set.remove(id).is_some();
+            set
+        });
+        removed
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = LazyItem<T>> {
         let mut arc = self.items.clone();
         let vec: Vec<_> = arc.get().iter().map(Clone::clone).collect();
@@ -345,6 +455,27 @@ impl<T: Clone + 'static> LazyItemMap<T> {
         })
     }
 
+    pub fn get(&self, key: &IdentityMapKey) -> Option<LazyItem<T>> {
+        let mut arc = self.items.clone();
+        arc.get().get(key).cloned()
+    }
+
+    // Drops every entry whose key `keep` rejects, as a single rcu
+    pub fn retain(&self, mut keep: impl FnMut(&IdentityMapKey) -> bool) {
+        let mut arc = self.items.clone();
+
+        arc.rcu(|map| {
+            let mut map = map.clone();
+            map.retain(|key, _| keep(key));
+            map
+        })
+    }
+
+    pub fn keys(&self) -> Vec<IdentityMapKey> {
+        let mut arc = self.items.clone();
+        arc.get().iter().map(|(key, _)| key.clone()).collect()
+    }
+
     pub fn is_empty(&self) -> bool {
         let mut arc = self.items.clone();
         arc.get().is_empty()
@@ -355,3 +486,103 @@ impl<T: Clone + 'static> LazyItemMap<T> {
         arc.get().len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::types::{MergedNode, NodeProp, PropState, VectorId};
+    use crate::storage::Storage;
+    use std::sync::Arc;
+
+    fn node_with_id(id: i32) -> LazyItem<MergedNode> {
+        let node = MergedNode::new(0, 0);
+        node.set_prop_ready(Arc::new(NodeProp {
+            id: VectorId::Int(id),
+            value: Arc::new(Storage::UnsignedByte {
+                mag: 0,
+                norm: 0.0,
+                quant_vec: vec![],
+            }),
+            location: None,
+            metadata: None,
+            namespace: None,
+            exact: None,
+        }));
+        LazyItem::from_data(node)
+    }
+
+    fn node_id(item: &LazyItem<MergedNode>) -> VectorId {
+        let mut node = item.get_data().unwrap();
+        match node.get().get_prop() {
+            PropState::Ready(prop) => prop.id.clone(),
+            PropState::Pending(_) => panic!("Expected a ready prop"),
+        }
+    }
+
+    #[test]
+    fn test_lazy_item_set_remove() {
+        let set = LazyItemSet::new();
+        let items: Vec<_> = (0..3).map(node_with_id).collect();
+        for item in &items {
+            set.insert(item.clone());
+        }
+
+        assert!(set.remove(&items[1].get_id()));
+        assert!(!set.remove(&items[1].get_id()));
+
+        let mut remaining: Vec<_> = set.iter().map(|item| node_id(&item)).collect();
+        remaining.sort_by_key(|id| id.to_string());
+        assert_eq!(remaining, vec![VectorId::Int(0), VectorId::Int(2)]);
+    }
+
+    #[test]
+    fn test_lazy_item_map_get() {
+        let map = LazyItemMap::new();
+        map.insert(IdentityMapKey::Int(1), node_with_id(10));
+        map.insert(
+            IdentityMapKey::String("latest".to_string()),
+            node_with_id(20),
+        );
+
+        let first = map.get(&IdentityMapKey::Int(1)).unwrap();
+        let second = map
+            .get(&IdentityMapKey::String("latest".to_string()))
+            .unwrap();
+        assert_eq!(node_id(&first), VectorId::Int(10));
+        assert_eq!(node_id(&second), VectorId::Int(20));
+        assert!(map.get(&IdentityMapKey::Int(2)).is_none());
+    }
+
+    #[test]
+    fn test_eager_lazy_item_set_remove() {
+        let set = EagerLazyItemSet::new();
+        let items: Vec<_> = (0..3).map(node_with_id).collect();
+        for (i, item) in items.iter().enumerate() {
+            set.insert(EagerLazyItem(i as f32, item.clone()));
+        }
+
+        assert!(set.remove(&items[1].get_id()));
+
+        let mut remaining: Vec<_> = set.iter().map(|item| item.0).collect();
+        remaining.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(remaining, vec![0.0, 2.0]);
+    }
+
+    #[test]
+    fn test_eager_lazy_item_set_retain_after_id_change() {
+        let set = EagerLazyItemSet::new();
+        let item = LazyItem::from_data(MergedNode::new(0, 0));
+        item.get_data().unwrap().get().set_prop_pending((64, 64));
+        set.insert(EagerLazyItem(0.5, item.clone()));
+        set.insert(EagerLazyItem(0.7, node_with_id(1)));
+
+        // Its prop loading changes the item's id, so it can't be removed by id anymore
+        let loaded = node_with_id(2).get_data().unwrap().get().get_prop();
+        item.get_data().unwrap().get().prop.clone().update(loaded);
+        assert!(!set.remove(&item.get_id()));
+
+        set.retain(|nbr| node_id(&nbr.1) != VectorId::Int(2));
+        let remaining: Vec<_> = set.iter().map(|nbr| node_id(&nbr.1)).collect();
+        assert_eq!(remaining, vec![VectorId::Int(1)]);
+    }
+}
@@ -1,61 +1,387 @@
 use crate::models::common::*;
+use crate::models::meta_store::MetaStore;
 use crate::models::types::*;
 use crate::models::versioning::*;
-use lmdb::{Transaction, WriteFlags};
-use std::sync::Arc;
+use crate::quantization::StorageType;
+use serde::{Deserialize, Serialize};
+
+const STORE_META_PREFIX: &str = "store:";
+
+pub const DEFAULT_LEVELS_FACTOR: f64 = 10.0;
+
+fn default_levels_factor() -> f64 {
+    DEFAULT_LEVELS_FACTOR
+}
+
+// Range a store's values are expected in when none is given
+pub const DEFAULT_BOUNDS: (f32, f32) = (-1.0, 1.0);
+
+fn default_bounds() -> (f32, f32) {
+    DEFAULT_BOUNDS
+}
+
+// Everything needed to rebuild a `VectorStore` after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorStoreMeta {
+    pub name: String,
+    pub size: usize,
+    pub max_cache_level: u8,
+    pub max_neighbors: usize,
+    pub distance_metric: DistanceMetric,
+    pub quantization_metric: QuantizationMetric,
+    pub storage_type: StorageType,
+    // Offset of the root node in `0.index`, where a store that never committed a
+    // version is reloaded from
+    pub root_offset: FileOffset,
+    #[serde(default = "default_levels_factor")]
+    pub levels_factor: f64,
+    #[serde(default = "default_bounds")]
+    pub bounds: (f32, f32),
+    #[serde(default)]
+    pub min_explore_similarity: Option<f32>,
+    #[serde(default)]
+    pub neighbor_selection: NeighborSelection,
+    #[serde(default)]
+    pub persist_mode: PersistMode,
+    #[serde(default)]
+    pub keep_versions: Option<usize>,
+}
+
+fn current_version_key(store_name: &str) -> String {
+    format!("current_version:{}", store_name)
+}
 
 pub fn store_current_version(
-    vec_store: Arc<VectorStore>,
+    meta: &dyn MetaStore,
+    store_name: &str,
     branch: String,
     version: u32,
 ) -> Result<VersionHash, WaCustomError> {
     let mut hasher = VersionHasher::new();
     // Generate hashes for main branch
     let hash = hasher.generate_hash(&branch, version, None, None);
-    let env = vec_store.lmdb.env.clone();
-    let db = vec_store.lmdb.metadata_db.clone();
-
-    let mut txn = env
-        .begin_rw_txn()
-        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
     let serialized = rkyv::to_bytes::<_, 256>(&hash)
         .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
 
-    txn.put(
-        *db.as_ref(),
-        &"current_version".to_string(),
-        &serialized,
-        WriteFlags::empty(),
-    )
-    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
-
-    txn.commit().map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
-    })?;
+    meta.put(current_version_key(store_name).as_bytes(), &serialized)?;
 
     Ok(hash)
 }
 
-pub fn retrieve_current_version(vec_store: Arc<VectorStore>) -> Result<VersionHash, WaCustomError> {
-    let env = vec_store.lmdb.env.clone();
-    let db = vec_store.lmdb.metadata_db.clone();
-    let txn = env
-        .begin_ro_txn()
-        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
-
-    let serialized_hash = txn
-        .get(*db.as_ref(), &"current_version".to_string())
-        .map_err(|e| match e {
-            lmdb::Error::NotFound => {
-                WaCustomError::DatabaseError(format!("Record not found: {}", "current_version"))
-            }
-            _ => WaCustomError::DatabaseError(e.to_string()),
-        })?;
+pub fn retrieve_current_version(
+    meta: &dyn MetaStore,
+    store_name: &str,
+) -> Result<VersionHash, WaCustomError> {
+    let key = current_version_key(store_name);
+    let serialized_hash = meta
+        .get(key.as_bytes())?
+        .ok_or_else(|| WaCustomError::DatabaseError(format!("Record not found: {}", key)))?;
 
-    let version_hash = unsafe { rkyv::from_bytes_unchecked(serialized_hash) }.map_err(|e| {
+    let version_hash = unsafe { rkyv::from_bytes_unchecked(&serialized_hash) }.map_err(|e| {
         WaCustomError::SerializationError(format!("Failed to deserialize VersionHash: {}", e))
     })?;
 
     Ok(version_hash)
 }
+
+fn next_version_key(store_name: &str) -> String {
+    format!("next_version:{}", store_name)
+}
+
+// Hands out the version a new commit of the store writes. The read and increment
+// happen in one transaction, so commits running at once never get the same version.
+// Counting starts past `current`, the store's version when it was loaded
+pub fn reserve_version(
+    meta: &dyn MetaStore,
+    store_name: &str,
+    current: u32,
+) -> Result<u32, WaCustomError> {
+    let key = next_version_key(store_name);
+    let mut txn = meta.begin_txn()?;
+    let next = match txn.get(key.as_bytes())? {
+        Some(bytes) => u32::from_le_bytes(bytes.try_into().map_err(|_| {
+            WaCustomError::DeserializationError(format!("Invalid next version: {}", key))
+        })?),
+        None => 0,
+    };
+    let version = next.max(current + 1);
+    txn.put(key.as_bytes(), &(version + 1).to_le_bytes())?;
+    txn.commit()?;
+    Ok(version)
+}
+
+pub fn clear_reserved_versions(
+    meta: &dyn MetaStore,
+    store_name: &str,
+) -> Result<(), WaCustomError> {
+    meta.delete(next_version_key(store_name).as_bytes())
+}
+
+fn store_meta_key(store_name: &str) -> String {
+    format!("{}{}", STORE_META_PREFIX, store_name)
+}
+
+pub fn store_vector_store_meta(
+    meta: &dyn MetaStore,
+    store_meta: &VectorStoreMeta,
+) -> Result<(), WaCustomError> {
+    let serialized = serde_cbor::to_vec(store_meta)
+        .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
+
+    meta.put(store_meta_key(&store_meta.name).as_bytes(), &serialized)
+}
+
+pub fn vector_store_meta_exists(
+    meta: &dyn MetaStore,
+    store_name: &str,
+) -> Result<bool, WaCustomError> {
+    Ok(meta.get(store_meta_key(store_name).as_bytes())?.is_some())
+}
+
+pub fn retrieve_vector_store_metas(
+    meta: &dyn MetaStore,
+) -> Result<Vec<VectorStoreMeta>, WaCustomError> {
+    // A record that can't be read, such as one naming a custom metric that isn't
+    // built yet, is left out rather than holding back the others
+    let metas = meta
+        .scan_prefix(STORE_META_PREFIX.as_bytes())?
+        .into_iter()
+        .filter_map(|(key, value)| match serde_cbor::from_slice(&value) {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                eprintln!(
+                    "Failed to deserialize VectorStoreMeta {}: {}",
+                    String::from_utf8_lossy(&key),
+                    e
+                );
+                None
+            }
+        })
+        .collect();
+
+    Ok(metas)
+}
+
+// Where a committed version's graph is read from. Commits append to the index file
+// the last compaction started, `0.index` before any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRoot {
+    pub version: u32,
+    // The version that started the file, which is `{file_version}.index`
+    pub file_version: u32,
+    pub root_offset: FileOffset,
+    // The last relocation table written to the file by the time the version was
+    // committed, see `read_relocations`
+    pub relocations: Option<FileOffset>,
+}
+
+impl VersionRoot {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.file_version.to_le_bytes());
+        bytes.extend_from_slice(&self.root_offset.to_le_bytes());
+        bytes.extend_from_slice(&self.relocations.unwrap_or(u32::MAX).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 16 {
+            return None;
+        }
+        let field = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        Some(VersionRoot {
+            version: field(0),
+            file_version: field(1),
+            root_offset: field(2),
+            relocations: (field(3) != u32::MAX).then_some(field(3)),
+        })
+    }
+}
+
+fn version_roots_prefix(store_name: &str) -> String {
+    format!("version_root:{}:", store_name)
+}
+
+fn version_root_key(store_name: &str, version: &VersionHash) -> String {
+    format!("{}{}", version_roots_prefix(store_name), version.hash)
+}
+
+pub fn store_version_root(
+    meta: &dyn MetaStore,
+    store_name: &str,
+    version: &VersionHash,
+    root: VersionRoot,
+) -> Result<(), WaCustomError> {
+    meta.put(
+        version_root_key(store_name, version).as_bytes(),
+        &root.to_bytes(),
+    )
+}
+
+pub fn retrieve_version_root(
+    meta: &dyn MetaStore,
+    store_name: &str,
+    version: &VersionHash,
+) -> Result<VersionRoot, WaCustomError> {
+    let key = version_root_key(store_name, version);
+    let bytes = meta
+        .get(key.as_bytes())?
+        .ok_or_else(|| WaCustomError::DatabaseError(format!("Record not found: {}", key)))?;
+
+    VersionRoot::from_bytes(&bytes).ok_or_else(|| {
+        WaCustomError::DeserializationError(format!("Invalid version root: {}", key))
+    })
+}
+
+// Every version of the store that's still recorded, in no particular order
+pub fn retrieve_version_roots(
+    meta: &dyn MetaStore,
+    store_name: &str,
+) -> Result<Vec<VersionRoot>, WaCustomError> {
+    meta.scan_prefix(version_roots_prefix(store_name).as_bytes())?
+        .into_iter()
+        .map(|(key, value)| {
+            VersionRoot::from_bytes(&value).ok_or_else(|| {
+                WaCustomError::DeserializationError(format!(
+                    "Invalid version root: {}",
+                    String::from_utf8_lossy(&key)
+                ))
+            })
+        })
+        .collect()
+}
+
+// Forgets a version, which can't be searched or reloaded from after that
+pub fn clear_version_root(
+    meta: &dyn MetaStore,
+    store_name: &str,
+    version: &VersionHash,
+) -> Result<(), WaCustomError> {
+    meta.delete(version_root_key(store_name, version).as_bytes())
+}
+
+fn open_transaction_key(store_name: &str) -> String {
+    format!("open_transaction:{}", store_name)
+}
+
+// A commit in flight, and where its writes began
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenTransaction {
+    pub version: u32,
+    // The index file written to, `{file_version}.index`
+    pub file_version: u32,
+    // Length of the file before the commit wrote to it, 0 for a file it created
+    pub start_offset: u64,
+}
+
+// Marks a commit as in flight, so a crash before it finishes can be detected on the
+// next load
+pub fn store_open_transaction(
+    meta: &dyn MetaStore,
+    store_name: &str,
+    transaction: OpenTransaction,
+) -> Result<(), WaCustomError> {
+    let mut bytes = Vec::with_capacity(16);
+    bytes.extend_from_slice(&transaction.version.to_le_bytes());
+    bytes.extend_from_slice(&transaction.file_version.to_le_bytes());
+    bytes.extend_from_slice(&transaction.start_offset.to_le_bytes());
+
+    meta.put(open_transaction_key(store_name).as_bytes(), &bytes)
+}
+
+// Returns the commit left open, if any
+pub fn retrieve_open_transaction(
+    meta: &dyn MetaStore,
+    store_name: &str,
+) -> Result<Option<OpenTransaction>, WaCustomError> {
+    let key = open_transaction_key(store_name);
+    let Some(bytes) = meta.get(key.as_bytes())? else {
+        return Ok(None);
+    };
+    if bytes.len() != 16 {
+        return Err(WaCustomError::DeserializationError(format!(
+            "Invalid open transaction record: {}",
+            key
+        )));
+    }
+
+    Ok(Some(OpenTransaction {
+        version: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+        file_version: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        start_offset: u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+    }))
+}
+
+pub fn clear_open_transaction(meta: &dyn MetaStore, store_name: &str) -> Result<(), WaCustomError> {
+    meta.delete(open_transaction_key(store_name).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::meta_store::MemoryMetaStore;
+
+    #[test]
+    fn test_current_version_in_memory_meta_store() {
+        let meta = MemoryMetaStore::new();
+        assert!(retrieve_current_version(&meta, "a").is_err());
+
+        let stored = store_current_version(&meta, "a", "main".to_string(), 3).unwrap();
+        let retrieved = retrieve_current_version(&meta, "a").unwrap();
+        assert_eq!(retrieved.version, 3);
+        assert_eq!(retrieved.hash, stored.hash);
+
+        // Each store keeps its own version
+        store_current_version(&meta, "b", "main".to_string(), 0).unwrap();
+        assert_eq!(retrieve_current_version(&meta, "a").unwrap().version, 3);
+
+        // Store records sit under their own prefix, apart from the version
+        assert!(retrieve_vector_store_metas(&meta).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reserved_versions_are_unique() {
+        let meta = MemoryMetaStore::new();
+        assert_eq!(reserve_version(&meta, "a", 0).unwrap(), 1);
+        assert_eq!(reserve_version(&meta, "a", 0).unwrap(), 2);
+        // Stores count on their own
+        assert_eq!(reserve_version(&meta, "b", 4).unwrap(), 5);
+        // A store that moved on without allocating skips ahead
+        assert_eq!(reserve_version(&meta, "a", 6).unwrap(), 7);
+
+        clear_reserved_versions(&meta, "a").unwrap();
+        assert_eq!(reserve_version(&meta, "a", 0).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_version_roots_round_trip() {
+        let meta = MemoryMetaStore::new();
+        let hash = |version| VersionHasher::new().generate_hash("main", version, None, None);
+        let first = VersionRoot {
+            version: 1,
+            file_version: 0,
+            root_offset: 1,
+            relocations: None,
+        };
+        let second = VersionRoot {
+            version: 2,
+            file_version: 0,
+            root_offset: 90,
+            relocations: Some(80),
+        };
+        store_version_root(&meta, "a", &hash(1), first).unwrap();
+        store_version_root(&meta, "a", &hash(2), second).unwrap();
+        store_version_root(&meta, "b", &hash(1), second).unwrap();
+
+        assert_eq!(retrieve_version_root(&meta, "a", &hash(2)).unwrap(), second);
+        let mut roots = retrieve_version_roots(&meta, "a").unwrap();
+        roots.sort_by_key(|root| root.version);
+        assert_eq!(roots, vec![first, second]);
+
+        clear_version_root(&meta, "a", &hash(1)).unwrap();
+        assert!(retrieve_version_root(&meta, "a", &hash(1)).is_err());
+        assert_eq!(retrieve_version_roots(&meta, "a").unwrap(), vec![second]);
+    }
+}
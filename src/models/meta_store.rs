@@ -0,0 +1,286 @@
+use crate::models::common::WaCustomError;
+use crate::models::types::MetaDb;
+use lmdb::{Cursor, Database, Iter, RwTransaction, Transaction, WriteFlags};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+// A key and its value
+pub type MetaEntry = (Vec<u8>, Vec<u8>);
+// Values by key, in key order
+type MetaEntries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// The key-value store behind a store's metadata: its settings, counters and version
+// records. `MetaDb` keeps them in LMDB, `MemoryMetaStore` in memory for tests
+pub trait MetaStore: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, WaCustomError>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), WaCustomError>;
+
+    // Deleting a missing key is not an error
+    fn delete(&self, key: &[u8]) -> Result<(), WaCustomError>;
+
+    // Every entry whose key starts with `prefix`, in key order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<MetaEntry>, WaCustomError>;
+
+    // Groups reads and writes so they apply together or not at all. Dropping the
+    // transaction without committing discards its writes
+    fn begin_txn(&self) -> Result<Box<dyn MetaTxn + '_>, WaCustomError>;
+}
+
+pub trait MetaTxn {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, WaCustomError>;
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), WaCustomError>;
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), WaCustomError>;
+
+    fn commit(self: Box<Self>) -> Result<(), WaCustomError>;
+}
+
+// Reads a little endian `u32` record, 0 if it was never written
+pub fn get_u32(meta: &dyn MetaStore, key: &str) -> Result<u32, WaCustomError> {
+    let Some(bytes) = meta.get(key.as_bytes())? else {
+        return Ok(0);
+    };
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| WaCustomError::DeserializationError(format!("Invalid `{}` record", key)))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// The entries from the first key at or after `key` on, in key order. Unlike
+// `Cursor::iter_from`, which panics when every key sorts before `key`, this yields
+// nothing then
+pub fn iter_from<'txn, C: Cursor<'txn>>(
+    cursor: &mut C,
+    key: &[u8],
+) -> Result<std::iter::Flatten<std::option::IntoIter<Iter<'txn>>>, lmdb::Error> {
+    let iter = match cursor.get(Some(key), None, lmdb_sys::MDB_SET_RANGE) {
+        Ok(_) => Some(cursor.iter_from(key)),
+        Err(lmdb::Error::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+    Ok(iter.into_iter().flatten())
+}
+
+fn database_error(action: &str, e: lmdb::Error) -> WaCustomError {
+    WaCustomError::DatabaseError(format!("Failed to {}: {}", action, e))
+}
+
+// The LMDB backed store, on the metadata database
+impl MetaStore for MetaDb {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, WaCustomError> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| database_error("begin transaction", e))?;
+        match txn.get(*self.metadata_db, &key) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(database_error("get data", e)),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), WaCustomError> {
+        let mut txn = self.begin_txn()?;
+        txn.put(key, value)?;
+        txn.commit()
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), WaCustomError> {
+        let mut txn = self.begin_txn()?;
+        txn.delete(key)?;
+        txn.commit()
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<MetaEntry>, WaCustomError> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| database_error("begin transaction", e))?;
+        let mut cursor = txn
+            .open_ro_cursor(*self.metadata_db)
+            .map_err(|e| database_error("open cursor", e))?;
+        // Keys are sorted, so all matches sit next to each other
+        let entries = iter_from(&mut cursor, prefix)
+            .map_err(|e| database_error("scan keys", e))?
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+        Ok(entries)
+    }
+
+    fn begin_txn(&self) -> Result<Box<dyn MetaTxn + '_>, WaCustomError> {
+        let txn = self
+            .begin_rw_txn()
+            .map_err(|e| database_error("begin transaction", e))?;
+        Ok(Box::new(LmdbMetaTxn {
+            txn,
+            db: *self.metadata_db,
+        }))
+    }
+}
+
+struct LmdbMetaTxn<'env> {
+    txn: RwTransaction<'env>,
+    db: Database,
+}
+
+impl MetaTxn for LmdbMetaTxn<'_> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, WaCustomError> {
+        match self.txn.get(self.db, &key) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(database_error("get data", e)),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), WaCustomError> {
+        self.txn
+            .put(self.db, &key, &value, WriteFlags::empty())
+            .map_err(|e| database_error("put data", e))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), WaCustomError> {
+        match self.txn.del(self.db, &key, None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+            Err(e) => Err(database_error("delete data", e)),
+        }
+    }
+
+    fn commit(self: Box<Self>) -> Result<(), WaCustomError> {
+        self.txn
+            .commit()
+            .map_err(|e| database_error("commit transaction", e))
+    }
+}
+
+// Keeps everything in a map that's gone with the store. Transactions hold the map's
+// lock until they're committed or dropped, so they never interleave
+#[derive(Debug, Default)]
+pub struct MemoryMetaStore {
+    entries: Mutex<MetaEntries>,
+}
+
+impl MemoryMetaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> Result<MutexGuard<'_, MetaEntries>, WaCustomError> {
+        self.entries
+            .lock()
+            .map_err(|_| WaCustomError::LockError("Memory meta store lock poisoned".to_string()))
+    }
+}
+
+impl MetaStore for MemoryMetaStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, WaCustomError> {
+        Ok(self.entries()?.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), WaCustomError> {
+        self.entries()?.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), WaCustomError> {
+        self.entries()?.remove(key);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<MetaEntry>, WaCustomError> {
+        let entries = self.entries()?;
+        Ok(entries
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn begin_txn(&self) -> Result<Box<dyn MetaTxn + '_>, WaCustomError> {
+        Ok(Box::new(MemoryMetaTxn {
+            entries: self.entries()?,
+            writes: BTreeMap::new(),
+        }))
+    }
+}
+
+struct MemoryMetaTxn<'a> {
+    entries: MutexGuard<'a, MetaEntries>,
+    // Pending writes, `None` for a delete
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl MetaTxn for MemoryMetaTxn<'_> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, WaCustomError> {
+        match self.writes.get(key) {
+            Some(write) => Ok(write.clone()),
+            None => Ok(self.entries.get(key).cloned()),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), WaCustomError> {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), WaCustomError> {
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<(), WaCustomError> {
+        let writes = std::mem::take(&mut self.writes);
+        for (key, write) in writes {
+            match write {
+                Some(value) => self.entries.insert(key, value),
+                None => self.entries.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_txn_applies_on_commit_only() {
+        let meta = MemoryMetaStore::new();
+        meta.put(b"count", &1u32.to_le_bytes()).unwrap();
+
+        let mut txn = meta.begin_txn().unwrap();
+        txn.put(b"count", &2u32.to_le_bytes()).unwrap();
+        assert_eq!(
+            txn.get(b"count").unwrap(),
+            Some(2u32.to_le_bytes().to_vec())
+        );
+        drop(txn);
+        assert_eq!(get_u32(&meta, "count").unwrap(), 1);
+
+        let mut txn = meta.begin_txn().unwrap();
+        txn.put(b"count", &3u32.to_le_bytes()).unwrap();
+        txn.delete(b"missing").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(get_u32(&meta, "count").unwrap(), 3);
+        assert_eq!(get_u32(&meta, "missing").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_memory_scan_prefix_stays_in_prefix() {
+        let meta = MemoryMetaStore::new();
+        for key in ["store:a", "store:b", "storage", "version_root:a"] {
+            meta.put(key.as_bytes(), b"").unwrap();
+        }
+
+        let keys: Vec<Vec<u8>> = meta
+            .scan_prefix(b"store:")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"store:a".to_vec(), b"store:b".to_vec()]);
+    }
+}
@@ -0,0 +1,109 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+// Reads a file through a read-only memory mapping, so seeking is just moving an index
+// instead of a syscall. Clones share the mapping but keep their own position, hand
+// one to each thread that needs to read
+#[derive(Clone)]
+pub struct MmapReader {
+    mmap: Arc<Mmap>,
+    position: u64,
+}
+
+impl MmapReader {
+    // The file must not be truncated while mapped. Index files are only ever appended
+    // to, and appends past the mapped length just stay invisible to this reader
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only and the file is never truncated in place
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(MmapReader {
+            mmap: Arc::new(mmap),
+            position: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_empty()
+    }
+}
+
+impl Read for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = (self.position as usize).min(self.mmap.len());
+        let remaining = &self.mmap[start..];
+        let to_read = buf.len().min(remaining.len());
+        buf[..to_read].copy_from_slice(&remaining[..to_read]);
+        self.position += to_read as u64;
+        Ok(to_read)
+    }
+}
+
+impl Seek for MmapReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.mmap.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_threads_read_through_shared_mapping() {
+        let data: Vec<u8> = (0..4096u32).map(|i| (i % 253) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&data).unwrap();
+        file.flush().unwrap();
+
+        let reader = MmapReader::open(file.path()).unwrap();
+        assert_eq!(reader.len(), data.len());
+        let handles: Vec<_> = (0..4u64)
+            .map(|thread| {
+                let mut reader = reader.clone();
+                let data = data.clone();
+                std::thread::spawn(move || {
+                    for offset in (thread * 7..4000).step_by(97) {
+                        let mut buf = [0; 16];
+                        reader.seek(SeekFrom::Start(offset)).unwrap();
+                        reader.read_exact(&mut buf).unwrap();
+                        let offset = offset as usize;
+                        assert_eq!(&buf, &data[offset..offset + 16]);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Reads past the end come back short, like a file's
+        let mut reader = reader;
+        reader.seek(SeekFrom::End(-4)).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+}
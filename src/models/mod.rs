@@ -6,11 +6,16 @@ pub mod dry_run_writer;
 pub mod encoding_format;
 pub mod file_persist;
 pub mod identity_collections;
+pub mod index_reader;
 pub mod lazy_load;
 pub mod lookup_table;
 pub mod meta_persist;
+pub mod meta_store;
+pub mod mmap_reader;
+pub mod prop_cache;
 pub mod rpc;
 pub mod serializer;
+pub mod store_file;
 pub mod types;
 pub mod user;
 pub mod versioning;
@@ -0,0 +1,195 @@
+use super::common::WaCustomError;
+use super::file_persist::read_prop_from_file;
+use super::types::{NodeProp, PropPersistRef};
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_PROP_CACHE_CAPACITY: usize = 4096;
+
+// Props recently read from the prop file, keyed by where they live in it. Once full,
+// the least recently used prop makes room for the next one
+pub struct PropCache {
+    capacity: usize,
+    // Each prop is stored with the access clock reading of its last use
+    entries: Mutex<HashMap<PropPersistRef, (Arc<NodeProp>, usize)>>,
+    access_clock: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl PropCache {
+    pub fn new(capacity: usize) -> Self {
+        PropCache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            access_clock: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    // Returns the prop at `location`, reading it from `reader` only on a miss
+    pub fn get_or_load<R: Read + Seek>(
+        &self,
+        location: PropPersistRef,
+        reader: R,
+    ) -> Result<Arc<NodeProp>, WaCustomError> {
+        let tick = self.access_clock.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut entries = self
+                .entries
+                .lock()
+                .map_err(|e| WaCustomError::LockError(e.to_string()))?;
+            if let Some((prop, last_used)) = entries.get_mut(&location) {
+                *last_used = tick;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(prop.clone());
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Read without holding the lock, a racing miss on the same location only
+        // costs a duplicate read
+        let prop = Arc::new(read_prop_from_file(location, reader)?);
+
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|e| WaCustomError::LockError(e.to_string()))?;
+        entries.insert(location, (prop.clone(), tick));
+        if entries.len() > self.capacity {
+            let coldest = entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(location, _)| *location);
+            if let Some(coldest) = coldest {
+                entries.remove(&coldest);
+            }
+        }
+
+        Ok(prop)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map_or(0, |entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Share of lookups served without a read, 0 before the first lookup
+    pub fn hit_rate(&self) -> f32 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let lookups = hits + self.misses.load(Ordering::Relaxed);
+        if lookups == 0 {
+            0.0
+        } else {
+            hits as f32 / lookups as f32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::file_persist::write_prop_to_file;
+    use crate::models::types::VectorId;
+    use crate::storage::Storage;
+    use std::fs::{File, OpenOptions};
+    use std::io::SeekFrom;
+
+    // Counts the reads that reach the file
+    struct CountingReader<'a> {
+        file: &'a File,
+        reads: &'a AtomicUsize,
+    }
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.file.read(buf)
+        }
+    }
+
+    impl Seek for CountingReader<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    fn prop(id: i32) -> NodeProp {
+        NodeProp {
+            id: VectorId::Int(id),
+            value: Arc::new(Storage::UnsignedByte {
+                mag: 14,
+                norm: 14f32.sqrt(),
+                quant_vec: vec![1, 2, 3],
+            }),
+            location: None,
+            metadata: None,
+            namespace: None,
+            exact: None,
+        }
+    }
+
+    #[test]
+    fn test_repeated_loads_read_the_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(dir.path().join("prop.data"))
+            .unwrap();
+        let location = write_prop_to_file(&prop(7), &file);
+
+        let cache = PropCache::new(8);
+        let reads = AtomicUsize::new(0);
+        let reader = || CountingReader {
+            file: &file,
+            reads: &reads,
+        };
+
+        let first = cache.get_or_load(location, reader()).unwrap();
+        let reads_after_first = reads.load(Ordering::Relaxed);
+        assert!(reads_after_first > 0);
+
+        let second = cache.get_or_load(location, reader()).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), reads_after_first);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.id, VectorId::Int(7));
+        assert_eq!(cache.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn test_cache_stays_within_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(dir.path().join("prop.data"))
+            .unwrap();
+        let locations: Vec<_> = (0..10)
+            .map(|id| write_prop_to_file(&prop(id), &file))
+            .collect();
+
+        let cache = PropCache::new(4);
+        for location in &locations {
+            cache.get_or_load(*location, &file).unwrap();
+            assert!(cache.len() <= 4);
+        }
+        // The most recent ones are the ones kept
+        let reads = AtomicUsize::new(0);
+        let last = locations[9];
+        let reader = CountingReader {
+            file: &file,
+            reads: &reads,
+        };
+        cache.get_or_load(last, reader).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), 0);
+    }
+}
@@ -51,6 +51,10 @@ pub struct CreateVectorDb {
     pub dimensions: i32,
     pub max_val: Option<f32>,
     pub min_val: Option<f32>,
+    // Set both, along with training vectors, for a product quantized store
+    pub num_subspaces: Option<usize>,
+    pub num_centroids: Option<usize>,
+    pub training_vectors: Option<Vec<Vec<f32>>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -1,4 +1,4 @@
-use super::CustomSerialize;
+use super::{CustomSerialize, SerializedSize};
 use crate::models::{
     cache_loader::NodeRegistry,
     lazy_load::{EagerLazyItem, LazyItem},
@@ -9,6 +9,18 @@ use std::collections::HashSet;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
 
+impl<T, E> SerializedSize for EagerLazyItem<T, E>
+where
+    T: Clone + 'static,
+    LazyItem<T>: SerializedSize,
+    E: Clone + CustomSerialize + SerializedSize + 'static,
+{
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize {
+        // The eager data, then the offset of the item
+        self.0.serialized_size_with(counted) + 4 + self.1.serialized_size_with(counted)
+    }
+}
+
 impl<T, E> CustomSerialize for EagerLazyItem<T, E>
 where
     T: Clone + 'static,
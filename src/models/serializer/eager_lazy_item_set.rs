@@ -1,68 +1,98 @@
-use super::CustomSerialize;
-use crate::models::lazy_load::{EagerLazyItem, EagerLazyItemSet, LazyItem, CHUNK_SIZE};
+use super::{
+    chunk_size_for, chunked_size, read_chunk, read_chunk_header, write_chunk, write_chunk_header,
+    CustomSerialize, SerializedSize,
+};
+use crate::models::lazy_load::{EagerLazyItem, EagerLazyItemSet, LazyItem};
 use crate::models::types::FileOffset;
 use crate::models::{
     cache_loader::NodeRegistry,
     identity_collections::{Identifiable, IdentitySet},
     types::Item,
 };
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::HashSet;
 use std::{
     io::{Read, Seek, SeekFrom, Write},
     sync::Arc,
 };
 
-impl<T, E> CustomSerialize for EagerLazyItemSet<T, E>
+impl<T, E> EagerLazyItemSet<T, E>
 where
     LazyItem<T>: CustomSerialize,
     T: Clone + Identifiable<Id = u64> + 'static,
     E: Clone + CustomSerialize + 'static,
 {
-    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+    pub fn serialize_with_chunk_size<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        chunk_size: usize,
+    ) -> std::io::Result<u32> {
         if self.is_empty() {
             return Ok(u32::MAX);
         };
         let start_offset = writer.stream_position()? as u32;
+        let header = write_chunk_header(writer, chunk_size)?;
         let mut items_arc = self.items.clone();
-        let items: Vec<_> = items_arc.get().iter().map(Clone::clone).collect();
+        let mut items: Vec<_> = items_arc.get().iter().map(Clone::clone).collect();
+        // The backing set has no stable order, sorting makes equal sets write equal bytes
+        items.sort_by_cached_key(|item| item.get_id());
         let total_items = items.len();
 
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
+        for chunk_start in (0..total_items).step_by(chunk_size) {
+            let chunk_end = std::cmp::min(chunk_start + chunk_size, total_items);
             let is_last_chunk = chunk_end == total_items;
 
-            // Write placeholders for item offsets
-            let placeholder_start = writer.stream_position()? as u32;
-            for _ in 0..CHUNK_SIZE {
-                writer.write_u32::<LittleEndian>(u32::MAX)?;
-            }
-            // Write placeholder for next chunk link
-            let next_chunk_placeholder = writer.stream_position()? as u32;
-            writer.write_u32::<LittleEndian>(u32::MAX)?;
+            // Reserve space for the chunk, it's filled in once its items are written
+            let chunk_offset = writer.stream_position()?;
+            let mut offsets = vec![u32::MAX; chunk_size];
+            write_chunk(writer, &header, &offsets, u32::MAX)?;
 
-            // Serialize items and update placeholders
+            // Serialize items
             for i in chunk_start..chunk_end {
-                let item_offset = items[i].serialize(writer)?;
-                let placeholder_pos = placeholder_start as u64 + ((i - chunk_start) as u64 * 4);
-                let current_pos = writer.stream_position()?;
-                writer.seek(SeekFrom::Start(placeholder_pos))?;
-                writer.write_u32::<LittleEndian>(item_offset)?;
-                writer.seek(SeekFrom::Start(current_pos))?;
+                offsets[i - chunk_start] = items[i].serialize(writer)?;
             }
 
-            // Write next chunk link
+            // Write item offsets and next chunk link
             let next_chunk_start = writer.stream_position()? as u32;
-            writer.seek(SeekFrom::Start(next_chunk_placeholder as u64))?;
-            if is_last_chunk {
-                writer.write_u32::<LittleEndian>(u32::MAX)?; // Last chunk
+            let next_chunk = if is_last_chunk {
+                u32::MAX
             } else {
-                writer.write_u32::<LittleEndian>(next_chunk_start)?;
-            }
+                next_chunk_start
+            };
+            writer.seek(SeekFrom::Start(chunk_offset))?;
+            write_chunk(writer, &header, &offsets, next_chunk)?;
             writer.seek(SeekFrom::Start(next_chunk_start as u64))?;
         }
         Ok(start_offset)
     }
+}
+
+impl<T, E> SerializedSize for EagerLazyItemSet<T, E>
+where
+    EagerLazyItem<T, E>: SerializedSize,
+    T: Clone + Identifiable<Id = u64> + 'static,
+    E: Clone + CustomSerialize + 'static,
+{
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize {
+        let mut items_arc = self.items.clone();
+        let items = items_arc.get();
+        let len = items.len();
+        items
+            .iter()
+            .fold(chunked_size(len, chunk_size_for(len)), |size, item| {
+                size + item.serialized_size_with(counted)
+            })
+    }
+}
+
+impl<T, E> CustomSerialize for EagerLazyItemSet<T, E>
+where
+    LazyItem<T>: CustomSerialize,
+    T: Clone + Identifiable<Id = u64> + 'static,
+    E: Clone + CustomSerialize + 'static,
+{
+    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+        self.serialize_with_chunk_size(writer, chunk_size_for(self.len()))
+    }
 
     fn deserialize<R: Read + Seek>(
         reader: &mut R,
@@ -74,13 +104,12 @@ where
         if offset == u32::MAX {
             return Ok(EagerLazyItemSet::new());
         }
-        reader.seek(SeekFrom::Start(offset as u64))?;
+        let (header, first_chunk) = read_chunk_header(reader, offset)?;
         let mut items = Vec::new();
-        let mut current_chunk = offset;
+        let mut current_chunk = first_chunk;
         loop {
-            for i in 0..CHUNK_SIZE {
-                reader.seek(SeekFrom::Start(current_chunk as u64 + (i as u64 * 4)))?;
-                let item_offset = reader.read_u32::<LittleEndian>()?;
+            let (offsets, next_chunk) = read_chunk(reader, &header, current_chunk)?;
+            for item_offset in offsets {
                 if item_offset == u32::MAX {
                     continue;
                 }
@@ -93,11 +122,7 @@ where
                 )?;
                 items.push(item);
             }
-            reader.seek(SeekFrom::Start(
-                current_chunk as u64 + CHUNK_SIZE as u64 * 4,
-            ))?;
-            // Read next chunk link
-            current_chunk = reader.read_u32::<LittleEndian>()?;
+            current_chunk = next_chunk;
             if current_chunk == u32::MAX {
                 break;
             }
@@ -1,4 +1,4 @@
-use super::CustomSerialize;
+use super::{CustomSerialize, SerializedSize};
 use crate::models::lazy_load::SyncPersist;
 use crate::models::types::{FileOffset, Item};
 use crate::models::{
@@ -12,11 +12,32 @@ use std::{
     sync::Arc,
 };
 
+impl SerializedSize for LazyItem<MergedNode> {
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize {
+        let Self::Valid {
+            data: Some(data),
+            offset,
+            ..
+        } = self
+        else {
+            return 0;
+        };
+        let mut offset = offset.clone();
+        let offset = offset.get();
+        // Already in the file, nodes that changed since are rewritten in place
+        if offset.is_some() || !counted.insert(offset as *const _ as usize) {
+            return 0;
+        }
+        data.clone().get().serialized_size_with(counted)
+    }
+}
+
 impl CustomSerialize for LazyItem<MergedNode> {
     fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
         match self {
             Self::Valid { data, offset, .. } => {
                 if let Some(existing_offset) = offset.clone().get().clone() {
+                    // An item that isn't loaded is unchanged since it was written there
                     if let Some(data) = &data {
                         let mut arc = data.clone();
                         let data = arc.get();
@@ -25,13 +46,8 @@ impl CustomSerialize for LazyItem<MergedNode> {
                             data.set_persistence(false);
                             data.serialize(writer)?;
                         }
-                        Ok(existing_offset)
-                    } else {
-                        Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Attempting to serialize LazyItem with no data",
-                        ))
                     }
+                    Ok(existing_offset)
                 } else {
                     if let Some(data) = &data {
                         let mut arc = data.clone();
@@ -70,6 +86,12 @@ impl CustomSerialize for LazyItem<MergedNode> {
     }
 }
 
+impl SerializedSize for LazyItemRef<MergedNode> {
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize {
+        self.item.clone().get().serialized_size_with(counted)
+    }
+}
+
 impl CustomSerialize for LazyItemRef<MergedNode> {
     fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
         let mut arc = self.item.clone();
@@ -1,12 +1,11 @@
-use super::CustomSerialize;
+use super::{
+    chunk_size_for, chunked_size, read_chunk, read_chunk_header, write_chunk, write_chunk_header,
+    CustomSerialize, SerializedSize,
+};
 use crate::models::identity_collections::{IdentityMap, IdentityMapKey};
 use crate::models::lazy_load::LazyItemMap;
 use crate::models::types::FileOffset;
-use crate::models::{
-    cache_loader::NodeRegistry,
-    lazy_load::{LazyItem, CHUNK_SIZE},
-    types::Item,
-};
+use crate::models::{cache_loader::NodeRegistry, lazy_load::LazyItem, types::Item};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::HashSet;
 use std::{
@@ -14,18 +13,28 @@ use std::{
     sync::Arc,
 };
 
+// Keys start with a u32 word. Without the MSB it's an `Int` key, otherwise the low
+// bits hold the length of the payload that follows, and the next bit tells byte keys
+// apart from strings
 const MSB: u32 = 1 << 31;
+const BYTES_TAG: u32 = 1 << 30;
+const LEN_MASK: u32 = BYTES_TAG - 1;
 
-impl<T> CustomSerialize for LazyItemMap<T>
+impl<T> LazyItemMap<T>
 where
     LazyItem<T>: CustomSerialize,
     T: Clone + 'static,
 {
-    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+    pub fn serialize_with_chunk_size<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        chunk_size: usize,
+    ) -> std::io::Result<u32> {
         if self.is_empty() {
             return Ok(u32::MAX);
         };
         let start_offset = writer.stream_position()? as u32;
+        let header = write_chunk_header(writer, chunk_size)?;
         let mut items_arc = self.items.clone();
         let items: Vec<_> = items_arc
             .get()
@@ -34,47 +43,71 @@ where
             .collect();
         let total_items = items.len();
 
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
+        for chunk_start in (0..total_items).step_by(chunk_size) {
+            let chunk_end = std::cmp::min(chunk_start + chunk_size, total_items);
             let is_last_chunk = chunk_end == total_items;
 
-            // Write placeholders for item offsets
-            let placeholder_start = writer.stream_position()? as u32;
-            for _ in 0..CHUNK_SIZE {
-                writer.write_u32::<LittleEndian>(u32::MAX)?;
-            }
-            // Write placeholder for next chunk link
-            let next_chunk_placeholder = writer.stream_position()? as u32;
-            writer.write_u32::<LittleEndian>(u32::MAX)?;
+            // Reserve space for the chunk, it's filled in once its entries are written
+            let chunk_offset = writer.stream_position()?;
+            let mut offsets = vec![u32::MAX; chunk_size];
+            write_chunk(writer, &header, &offsets, u32::MAX)?;
 
-            // Serialize items and update placeholders
+            // Serialize entries, each one a key followed by the offset of its item
             for i in chunk_start..chunk_end {
                 let entry_offset = items[i].0.serialize(writer)?;
                 let item_placeholder_pos = writer.stream_position()?;
                 writer.write_u32::<LittleEndian>(0)?;
                 let item_offset = items[i].1.serialize(writer)?;
                 items[i].1.set_offset(Some(item_offset));
-                let placeholder_pos = placeholder_start as u64 + ((i - chunk_start) as u64 * 4);
                 let current_pos = writer.stream_position()?;
-                writer.seek(SeekFrom::Start(placeholder_pos))?;
-                writer.write_u32::<LittleEndian>(entry_offset)?;
                 writer.seek(SeekFrom::Start(item_placeholder_pos))?;
                 writer.write_u32::<LittleEndian>(item_offset)?;
                 writer.seek(SeekFrom::Start(current_pos))?;
+                offsets[i - chunk_start] = entry_offset;
             }
 
-            // Write next chunk link
+            // Write entry offsets and next chunk link
             let next_chunk_start = writer.stream_position()? as u32;
-            writer.seek(SeekFrom::Start(next_chunk_placeholder as u64))?;
-            if is_last_chunk {
-                writer.write_u32::<LittleEndian>(u32::MAX)?; // Last chunk
+            let next_chunk = if is_last_chunk {
+                u32::MAX
             } else {
-                writer.write_u32::<LittleEndian>(next_chunk_start)?;
-            }
+                next_chunk_start
+            };
+            writer.seek(SeekFrom::Start(chunk_offset))?;
+            write_chunk(writer, &header, &offsets, next_chunk)?;
             writer.seek(SeekFrom::Start(next_chunk_start as u64))?;
         }
         Ok(start_offset)
     }
+}
+
+impl<T> SerializedSize for LazyItemMap<T>
+where
+    LazyItem<T>: SerializedSize,
+    T: Clone + 'static,
+{
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize {
+        let mut items_arc = self.items.clone();
+        let items = items_arc.get();
+        let len = items.len();
+        // Every entry is its key followed by the offset of its item
+        items.iter().fold(
+            chunked_size(len, chunk_size_for(len)),
+            |size, (key, item)| {
+                size + key.serialized_size_with(counted) + 4 + item.serialized_size_with(counted)
+            },
+        )
+    }
+}
+
+impl<T> CustomSerialize for LazyItemMap<T>
+where
+    LazyItem<T>: CustomSerialize,
+    T: Clone + 'static,
+{
+    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+        self.serialize_with_chunk_size(writer, chunk_size_for(self.len()))
+    }
 
     fn deserialize<R: Read + Seek>(
         reader: &mut R,
@@ -86,13 +119,12 @@ where
         if offset == u32::MAX {
             return Ok(LazyItemMap::new());
         }
-        reader.seek(SeekFrom::Start(offset as u64))?;
+        let (header, first_chunk) = read_chunk_header(reader, offset)?;
         let mut items = Vec::new();
-        let mut current_chunk = offset;
+        let mut current_chunk = first_chunk;
         loop {
-            for i in 0..CHUNK_SIZE {
-                reader.seek(SeekFrom::Start(current_chunk as u64 + (i as u64 * 4)))?;
-                let entry_offset = reader.read_u32::<LittleEndian>()?;
+            let (offsets, next_chunk) = read_chunk(reader, &header, current_chunk)?;
+            for entry_offset in offsets {
                 if entry_offset == u32::MAX {
                     continue;
                 }
@@ -108,11 +140,7 @@ where
                     LazyItem::deserialize(reader, item_offset, cache.clone(), max_loads, skipm)?;
                 items.push((key, item));
             }
-            reader.seek(SeekFrom::Start(
-                current_chunk as u64 + CHUNK_SIZE as u64 * 4,
-            ))?;
-            // Read next chunk link
-            current_chunk = reader.read_u32::<LittleEndian>()?;
+            current_chunk = next_chunk;
             if current_chunk == u32::MAX {
                 break;
             }
@@ -123,6 +151,16 @@ where
     }
 }
 
+impl SerializedSize for IdentityMapKey {
+    fn serialized_size_with(&self, _counted: &mut HashSet<usize>) -> usize {
+        match self {
+            Self::String(str) => 4 + str.len(),
+            Self::Int(_) => 4,
+            Self::Bytes(bytes) => 4 + bytes.len(),
+        }
+    }
+}
+
 impl CustomSerialize for IdentityMapKey {
     fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
         let start = writer.stream_position()? as u32;
@@ -136,6 +174,11 @@ impl CustomSerialize for IdentityMapKey {
             Self::Int(int) => {
                 writer.write_u32::<LittleEndian>(*int)?;
             }
+            Self::Bytes(bytes) => {
+                let len = bytes.len() as u32;
+                writer.write_u32::<LittleEndian>(MSB | BYTES_TAG | len)?;
+                writer.write_all(bytes)?;
+            }
         }
         Ok(start)
     }
@@ -156,11 +199,15 @@ impl CustomSerialize for IdentityMapKey {
             return Ok(Self::Int(num));
         }
 
-        let len = (num << 1) >> 1;
+        let len = num & LEN_MASK;
         let mut bytes = vec![0; len as usize];
 
         reader.read_exact(&mut bytes)?;
 
+        if num & BYTES_TAG != 0 {
+            return Ok(Self::Bytes(bytes));
+        }
+
         let str = String::from_utf8(bytes).map_err(|e| {
             std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -5,12 +5,14 @@ mod lazy_item_map;
 mod lazy_item_set;
 mod neighbour;
 mod node;
-mod vector;
+mod node_prop;
+mod vector_qt;
 
 #[cfg(test)]
 mod tests;
 
 use super::cache_loader::NodeRegistry;
+use crate::models::lazy_load::CHUNK_SIZE;
 use crate::models::types::FileOffset;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::HashSet;
@@ -19,6 +21,19 @@ use std::{
     sync::Arc,
 };
 
+// Number of bytes `serialize` would append, worked out without writing anything.
+// Lazy items that already have an offset only cost the reference to them, ones that
+// don't are written in full the first time they come up, as `serialize` does
+pub trait SerializedSize {
+    // `counted` holds the lazy items already written in full, by the address of their
+    // shared offset, so clones of an item count once and separate items don't collide
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize;
+
+    fn serialized_size(&self) -> usize {
+        self.serialized_size_with(&mut HashSet::new())
+    }
+}
+
 pub trait CustomSerialize {
     fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32>;
     fn deserialize<R: Read + Seek>(
@@ -32,6 +47,129 @@ pub trait CustomSerialize {
         Self: Sized;
 }
 
+// Linked-chunk collections start with a header recording their chunk size. The tag
+// sits in the top byte, which a plain item offset never reaches in practice, so files
+// written before the header existed (always `CHUNK_SIZE`) are still readable
+const CHUNK_HEADER_TAG: u32 = 0xC5 << 24;
+const CHUNK_HEADER_MASK: u32 = 0xFF << 24;
+// Set when every chunk is followed by a CRC32 of its item offsets and next chunk link
+const CHUNK_FLAG_CHECKSUM: u32 = 1 << 8;
+const CHUNK_SIZE_MASK: u32 = 0xFF;
+pub const MAX_CHUNK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkHeader {
+    pub chunk_size: usize,
+    pub checksummed: bool,
+}
+
+// Small collections keep the default, larger ones fit in fewer, bigger chunks
+pub fn chunk_size_for(len: usize) -> usize {
+    len.clamp(CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+pub fn write_chunk_header<W: Write>(
+    writer: &mut W,
+    chunk_size: usize,
+) -> std::io::Result<ChunkHeader> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid chunk size: {}", chunk_size),
+        ));
+    }
+    writer.write_u32::<LittleEndian>(CHUNK_HEADER_TAG | CHUNK_FLAG_CHECKSUM | chunk_size as u32)?;
+    Ok(ChunkHeader {
+        chunk_size,
+        checksummed: true,
+    })
+}
+
+// Returns the header and the offset of the first chunk
+pub fn read_chunk_header<R: Read + Seek>(
+    reader: &mut R,
+    offset: u32,
+) -> std::io::Result<(ChunkHeader, u32)> {
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    let header = reader.read_u32::<LittleEndian>()?;
+    if header != u32::MAX && header & CHUNK_HEADER_MASK == CHUNK_HEADER_TAG {
+        let chunk_header = ChunkHeader {
+            chunk_size: (header & CHUNK_SIZE_MASK) as usize,
+            checksummed: header & CHUNK_FLAG_CHECKSUM != 0,
+        };
+        Ok((chunk_header, offset + 4))
+    } else {
+        let chunk_header = ChunkHeader {
+            chunk_size: CHUNK_SIZE,
+            checksummed: false,
+        };
+        Ok((chunk_header, offset))
+    }
+}
+
+pub fn write_chunk<W: Write>(
+    writer: &mut W,
+    header: &ChunkHeader,
+    offsets: &[u32],
+    next_chunk: u32,
+) -> std::io::Result<()> {
+    for offset in offsets {
+        writer.write_u32::<LittleEndian>(*offset)?;
+    }
+    writer.write_u32::<LittleEndian>(next_chunk)?;
+    if header.checksummed {
+        writer.write_u32::<LittleEndian>(chunk_checksum(offsets, next_chunk))?;
+    }
+    Ok(())
+}
+
+// Returns the item offsets of the chunk at `offset` and the link to the next chunk
+pub fn read_chunk<R: Read + Seek>(
+    reader: &mut R,
+    header: &ChunkHeader,
+    offset: u32,
+) -> std::io::Result<(Vec<u32>, u32)> {
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    let offsets = (0..header.chunk_size)
+        .map(|_| reader.read_u32::<LittleEndian>())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let next_chunk = reader.read_u32::<LittleEndian>()?;
+    if header.checksummed {
+        let checksum = reader.read_u32::<LittleEndian>()?;
+        if checksum != chunk_checksum(&offsets, next_chunk) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("chunk checksum mismatch at offset {}", offset),
+            ));
+        }
+    }
+    Ok((offsets, next_chunk))
+}
+
+// Size of the chunk header and chunks of a linked-chunk collection, without its items
+pub fn chunked_size(len: usize, chunk_size: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    // Every chunk holds its item offsets, the next chunk link and a checksum
+    4 + len.div_ceil(chunk_size) * (chunk_size * 4 + 8)
+}
+
+fn chunk_checksum(offsets: &[u32], next_chunk: u32) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for offset in offsets {
+        hasher.update(&offset.to_le_bytes());
+    }
+    hasher.update(&next_chunk.to_le_bytes());
+    hasher.finalize()
+}
+
+impl SerializedSize for f32 {
+    fn serialized_size_with(&self, _counted: &mut HashSet<usize>) -> usize {
+        4
+    }
+}
+
 impl CustomSerialize for f32 {
     fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
         let pos = writer.stream_position()? as u32;
@@ -53,3 +191,31 @@ impl CustomSerialize for f32 {
         reader.read_f32::<LittleEndian>()
     }
 }
+
+impl SerializedSize for f64 {
+    fn serialized_size_with(&self, _counted: &mut HashSet<usize>) -> usize {
+        8
+    }
+}
+
+impl CustomSerialize for f64 {
+    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+        let pos = writer.stream_position()? as u32;
+        writer.write_f64::<LittleEndian>(*self)?;
+        Ok(pos)
+    }
+
+    fn deserialize<R: Read + Seek>(
+        reader: &mut R,
+        offset: u32,
+        _cache: Arc<NodeRegistry<R>>,
+        _max_loads: u16,
+        _skipm: &mut HashSet<FileOffset>,
+    ) -> std::io::Result<Self>
+    where
+        Self: Sized,
+    {
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        reader.read_f64::<LittleEndian>()
+    }
+}
@@ -1,4 +1,4 @@
-use super::CustomSerialize;
+use super::{CustomSerialize, SerializedSize};
 use crate::models::{
     cache_loader::NodeRegistry,
     lazy_load::{EagerLazyItemSet, LazyItemMap, LazyItemRef},
@@ -12,6 +12,23 @@ use std::{
 
 use crate::models::types::FileOffset;
 use std::collections::HashSet;
+
+impl SerializedSize for MergedNode {
+    fn serialized_size_with(&self, counted: &mut HashSet<usize>) -> usize {
+        // version_id, hnsw_level, prop location and indicator byte
+        let mut size = 2 + 1 + 8 + 1;
+        for link in [&self.parent, &self.child] {
+            if link.is_valid() {
+                size += 4 + link.serialized_size_with(counted);
+            }
+        }
+        // Offsets of the neighbor set and the version map
+        size += 8;
+        size += self.neighbors.serialized_size_with(counted);
+        size + self.versions.serialized_size_with(counted)
+    }
+}
+
 impl CustomSerialize for MergedNode {
     fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
         let start_offset = writer.stream_position()? as u32;
@@ -0,0 +1,294 @@
+use super::CustomSerialize;
+use crate::models::{
+    cache_loader::NodeRegistry,
+    types::{FileOffset, NodeProp, VectorId},
+};
+use crate::storage::Storage;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use half::f16;
+use std::collections::{HashMap, HashSet};
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+const STR_ID_TAG: u8 = 0;
+const INT_ID_TAG: u8 = 1;
+
+const UNSIGNED_BYTE_TAG: u8 = 0;
+const SUB_BYTE_TAG: u8 = 1;
+const HALF_PRECISION_TAG: u8 = 2;
+const FLOAT32_TAG: u8 = 3;
+
+// Layout: id, value, an optional location, optional metadata, an optional namespace
+// and an optional exact vector. Optional fields start with a presence byte, strings
+// and vectors with a u32 length
+impl CustomSerialize for NodeProp {
+    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+        let offset = writer.stream_position()? as u32;
+
+        write_id(writer, &self.id)?;
+        write_storage(writer, &self.value)?;
+
+        match self.location {
+            Some((location_offset, length)) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<LittleEndian>(location_offset)?;
+                writer.write_u32::<LittleEndian>(length)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+
+        match &self.metadata {
+            Some(metadata) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<LittleEndian>(metadata.len() as u32)?;
+                for (key, value) in metadata {
+                    write_str(writer, key)?;
+                    write_str(writer, value)?;
+                }
+            }
+            None => writer.write_u8(0)?,
+        }
+
+        match &self.namespace {
+            Some(namespace) => {
+                writer.write_u8(1)?;
+                write_str(writer, namespace)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+
+        match &self.exact {
+            Some(exact) => {
+                writer.write_u8(1)?;
+                writer.write_u32::<LittleEndian>(exact.len() as u32)?;
+                for value in exact {
+                    writer.write_f32::<LittleEndian>(*value)?;
+                }
+            }
+            None => writer.write_u8(0)?,
+        }
+
+        Ok(offset)
+    }
+
+    fn deserialize<R: Read + Seek>(
+        reader: &mut R,
+        offset: u32,
+        _cache: Arc<NodeRegistry<R>>,
+        _max_loads: u16,
+        _skipm: &mut HashSet<FileOffset>,
+    ) -> std::io::Result<Self> {
+        reader.seek(SeekFrom::Start(offset as u64))?;
+
+        let id = read_id(reader)?;
+        let value = Arc::new(read_storage(reader)?);
+
+        let location = if reader.read_u8()? != 0 {
+            let location_offset = reader.read_u32::<LittleEndian>()?;
+            let length = reader.read_u32::<LittleEndian>()?;
+            Some((location_offset, length))
+        } else {
+            None
+        };
+
+        let metadata = if reader.read_u8()? != 0 {
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let mut metadata = HashMap::with_capacity(len);
+            for _ in 0..len {
+                let key = read_str(reader)?;
+                let value = read_str(reader)?;
+                metadata.insert(key, value);
+            }
+            Some(metadata)
+        } else {
+            None
+        };
+
+        let namespace = if reader.read_u8()? != 0 {
+            Some(read_str(reader)?)
+        } else {
+            None
+        };
+
+        let exact = if reader.read_u8()? != 0 {
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            Some(
+                (0..len)
+                    .map(|_| reader.read_f32::<LittleEndian>())
+                    .collect::<std::io::Result<_>>()?,
+            )
+        } else {
+            None
+        };
+
+        Ok(NodeProp {
+            id,
+            value,
+            location,
+            metadata,
+            namespace,
+            exact,
+        })
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn write_str<W: Write>(writer: &mut W, value: &str) -> std::io::Result<()> {
+    writer.write_u32::<LittleEndian>(value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_str<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    let len = reader.read_u32::<LittleEndian>()? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| invalid_data(e.to_string()))
+}
+
+fn write_id<W: Write>(writer: &mut W, id: &VectorId) -> std::io::Result<()> {
+    match id {
+        VectorId::Str(value) => {
+            writer.write_u8(STR_ID_TAG)?;
+            write_str(writer, value)
+        }
+        VectorId::Int(value) => {
+            writer.write_u8(INT_ID_TAG)?;
+            writer.write_i32::<LittleEndian>(*value)
+        }
+    }
+}
+
+fn read_id<R: Read>(reader: &mut R) -> std::io::Result<VectorId> {
+    match reader.read_u8()? {
+        STR_ID_TAG => Ok(VectorId::Str(read_str(reader)?)),
+        INT_ID_TAG => Ok(VectorId::Int(reader.read_i32::<LittleEndian>()?)),
+        tag => Err(invalid_data(format!("Invalid VectorId tag {}", tag))),
+    }
+}
+
+fn write_storage<W: Write>(writer: &mut W, storage: &Storage) -> std::io::Result<()> {
+    match storage {
+        Storage::UnsignedByte {
+            mag,
+            norm,
+            quant_vec,
+        } => {
+            writer.write_u8(UNSIGNED_BYTE_TAG)?;
+            writer.write_u32::<LittleEndian>(*mag)?;
+            writer.write_f32::<LittleEndian>(*norm)?;
+            writer.write_u32::<LittleEndian>(quant_vec.len() as u32)?;
+            writer.write_all(quant_vec)?;
+        }
+        Storage::SubByte {
+            mag,
+            norm,
+            quant_vec,
+            resolution,
+        } => {
+            writer.write_u8(SUB_BYTE_TAG)?;
+            writer.write_u32::<LittleEndian>(*mag)?;
+            writer.write_f32::<LittleEndian>(*norm)?;
+            writer.write_u8(*resolution)?;
+            writer.write_u32::<LittleEndian>(quant_vec.len() as u32)?;
+            for inner_vec in quant_vec {
+                writer.write_u32::<LittleEndian>(inner_vec.len() as u32)?;
+                writer.write_all(inner_vec)?;
+            }
+        }
+        Storage::HalfPrecisionFP {
+            mag,
+            norm,
+            quant_vec,
+        } => {
+            writer.write_u8(HALF_PRECISION_TAG)?;
+            writer.write_f32::<LittleEndian>(*mag)?;
+            writer.write_f32::<LittleEndian>(*norm)?;
+            writer.write_u32::<LittleEndian>(quant_vec.len() as u32)?;
+            for value in quant_vec {
+                writer.write_u16::<LittleEndian>(value.to_bits())?;
+            }
+        }
+        Storage::Float32 {
+            mag,
+            norm,
+            quant_vec,
+        } => {
+            writer.write_u8(FLOAT32_TAG)?;
+            writer.write_f32::<LittleEndian>(*mag)?;
+            writer.write_f32::<LittleEndian>(*norm)?;
+            writer.write_u32::<LittleEndian>(quant_vec.len() as u32)?;
+            for value in quant_vec {
+                writer.write_f32::<LittleEndian>(*value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_storage<R: Read>(reader: &mut R) -> std::io::Result<Storage> {
+    match reader.read_u8()? {
+        UNSIGNED_BYTE_TAG => {
+            let mag = reader.read_u32::<LittleEndian>()?;
+            let norm = reader.read_f32::<LittleEndian>()?;
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let mut quant_vec = vec![0u8; len];
+            reader.read_exact(&mut quant_vec)?;
+            Ok(Storage::UnsignedByte {
+                mag,
+                norm,
+                quant_vec,
+            })
+        }
+        SUB_BYTE_TAG => {
+            let mag = reader.read_u32::<LittleEndian>()?;
+            let norm = reader.read_f32::<LittleEndian>()?;
+            let resolution = reader.read_u8()?;
+            let outer_len = reader.read_u32::<LittleEndian>()? as usize;
+            let mut quant_vec = Vec::with_capacity(outer_len);
+            for _ in 0..outer_len {
+                let inner_len = reader.read_u32::<LittleEndian>()? as usize;
+                let mut inner_vec = vec![0u8; inner_len];
+                reader.read_exact(&mut inner_vec)?;
+                quant_vec.push(inner_vec);
+            }
+            Ok(Storage::SubByte {
+                mag,
+                norm,
+                quant_vec,
+                resolution,
+            })
+        }
+        HALF_PRECISION_TAG => {
+            let mag = reader.read_f32::<LittleEndian>()?;
+            let norm = reader.read_f32::<LittleEndian>()?;
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let quant_vec = (0..len)
+                .map(|_| Ok(f16::from_bits(reader.read_u16::<LittleEndian>()?)))
+                .collect::<std::io::Result<_>>()?;
+            Ok(Storage::HalfPrecisionFP {
+                mag,
+                norm,
+                quant_vec,
+            })
+        }
+        FLOAT32_TAG => {
+            let mag = reader.read_f32::<LittleEndian>()?;
+            let norm = reader.read_f32::<LittleEndian>()?;
+            let len = reader.read_u32::<LittleEndian>()? as usize;
+            let quant_vec = (0..len)
+                .map(|_| reader.read_f32::<LittleEndian>())
+                .collect::<std::io::Result<_>>()?;
+            Ok(Storage::Float32 {
+                mag,
+                norm,
+                quant_vec,
+            })
+        }
+        tag => Err(invalid_data(format!("Invalid Storage tag {}", tag))),
+    }
+}
@@ -1,5 +1,6 @@
 #[cfg(test)]
 mod tests {
+    use crate::models::identity_collections::IdentityMapKey;
     use crate::models::lazy_load::*;
     use crate::models::serializer::*;
     use crate::models::types::*;
@@ -85,6 +86,52 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_eager_lazy_item_f64_serialization() {
+        // A score that does not fit in an f32 without losing precision
+        let score = 0.1f64 + 1e-12;
+        let item = EagerLazyItem(score, LazyItem::new(simple_merged_node(1, 2)));
+
+        // Estimated before writing, which gives the node an offset
+        let estimate = item.serialized_size();
+        let mut writer = Cursor::new(Vec::new());
+        let offset = item.serialize(&mut writer).unwrap();
+        assert_eq!(writer.get_ref().len(), estimate);
+
+        let reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader);
+        let deserialized: EagerLazyItem<MergedNode, f64> = cache.load_item(offset).unwrap();
+
+        assert_eq!(deserialized.0, score);
+        let mut node_arc = deserialized.1.get_data().unwrap();
+        let node = node_arc.get();
+        assert_eq!(node.version_id, 1);
+        assert_eq!(node.hnsw_level, 2);
+    }
+
+    #[test]
+    fn test_eager_lazy_item_set_f64_serialization() {
+        let set: EagerLazyItemSet<MergedNode, f64> = EagerLazyItemSet::new();
+        for i in 1..13u32 {
+            // Distinct props give distinct ids, so the items don't collapse into one
+            let mut node = simple_merged_node(i as VersionId, 2);
+            node.prop = Item::new(PropState::Pending((i * 8, 8)));
+            set.insert(EagerLazyItem(i as f64 / 3.0, LazyItem::from_data(node)));
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        let offset = set.serialize(&mut writer).unwrap();
+
+        let reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader);
+        let deserialized: EagerLazyItemSet<MergedNode, f64> = cache.load_item(offset).unwrap();
+
+        let mut scores: Vec<f64> = deserialized.iter().map(|item| item.0).collect();
+        scores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected: Vec<f64> = (1..13u32).map(|i| i as f64 / 3.0).collect();
+        assert_eq!(scores, expected);
+    }
+
     #[test]
     fn test_lazy_item_set_serialization() {
         let lazy_items = LazyItemSet::new();
@@ -170,6 +217,52 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_set_serialization_is_deterministic() {
+        let node = |i: u32| {
+            let node = simple_merged_node(1, 2);
+            node.set_prop_pending((i * 16, 16));
+            LazyItem::from_data(node)
+        };
+        let lazy_set = |order: &[u32]| {
+            let set = LazyItemSet::new();
+            for &i in order {
+                set.insert(node(i));
+            }
+            set
+        };
+        let eager_set = |order: &[u32]| {
+            let set = EagerLazyItemSet::new();
+            for &i in order {
+                set.insert(EagerLazyItem(i as f32 / 10.0, node(i)));
+            }
+            set
+        };
+        let bytes = |serialize: &dyn Fn(&mut Cursor<Vec<u8>>) -> std::io::Result<u32>| {
+            let mut writer = Cursor::new(Vec::new());
+            serialize(&mut writer).unwrap();
+            writer.into_inner()
+        };
+
+        let forward: Vec<u32> = (0..20).collect();
+        let backward: Vec<u32> = (0..20).rev().collect();
+        assert_eq!(
+            bytes(&|writer| lazy_set(&forward).serialize(writer)),
+            bytes(&|writer| lazy_set(&backward).serialize(writer))
+        );
+        assert_eq!(
+            bytes(&|writer| eager_set(&forward).serialize(writer)),
+            bytes(&|writer| eager_set(&backward).serialize(writer))
+        );
+
+        // Reading back is unaffected by the order
+        let mut writer = Cursor::new(Vec::new());
+        let offset = eager_set(&backward).serialize(&mut writer).unwrap();
+        let cache = get_cache(Cursor::new(writer.into_inner()));
+        let deserialized: EagerLazyItemSet<MergedNode, f32> = cache.load_item(offset).unwrap();
+        assert_eq!(deserialized.len(), 20);
+    }
+
     #[test]
     fn test_merged_node_acyclic_serialization() {
         let node = simple_merged_node(1, 2);
@@ -285,6 +378,7 @@ mod tests {
         let cache = get_cache(reader);
         let deserialized: MergedNode = cache.load_item(offset).unwrap();
 
+        assert_eq!(node.get_versions().len(), 2);
         assert_eq!(node.get_versions().len(), deserialized.get_versions().len());
     }
 
@@ -477,4 +571,229 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_lazy_item_map_custom_chunk_size_serialization() {
+        let lazy_items = LazyItemMap::new();
+        for i in 1..21 {
+            lazy_items.insert(
+                IdentityMapKey::Int(i),
+                LazyItem::from_data(simple_merged_node(i as u16, 2)),
+            );
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        let offset = lazy_items
+            .serialize_with_chunk_size(&mut writer, 16)
+            .unwrap();
+
+        let reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader);
+        let deserialized: LazyItemMap<MergedNode> = cache.load_item(offset).unwrap();
+
+        assert_eq!(lazy_items.len(), deserialized.len());
+        let mut deserialized_arc = deserialized.items.clone();
+        for (key, item) in deserialized_arc.get().iter() {
+            let IdentityMapKey::Int(i) = key else {
+                panic!("Unexpected key {:?}", key);
+            };
+            let mut node_arc = item.get_data().expect("missing data");
+            assert_eq!(node_arc.get().version_id, *i as u16);
+        }
+    }
+
+    #[test]
+    fn test_identity_map_key_bytes_serialization() {
+        let uuid: Vec<u8> = (0..16).map(|i| i * 17).collect();
+        let keys = [
+            IdentityMapKey::Bytes(uuid.clone()),
+            IdentityMapKey::String("latest".to_string()),
+            IdentityMapKey::Int(7),
+        ];
+
+        let mut writer = Cursor::new(Vec::new());
+        let offsets: Vec<u32> = keys
+            .iter()
+            .map(|key| key.serialize(&mut writer).unwrap())
+            .collect();
+
+        let mut reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader.clone());
+        for (key, offset) in keys.iter().zip(offsets) {
+            let deserialized = IdentityMapKey::deserialize(
+                &mut reader,
+                offset,
+                cache.clone(),
+                1000,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+            assert_eq!(&deserialized, key);
+        }
+        // Same bytes under a different tag are a different key
+        assert_ne!(
+            IdentityMapKey::Bytes(b"latest".to_vec()),
+            IdentityMapKey::String("latest".to_string())
+        );
+    }
+
+    #[test]
+    fn test_corrupted_chunk_fails_checksum() {
+        let lazy_items = LazyItemSet::new();
+        for i in 1..13 {
+            lazy_items.insert(LazyItem::from_data(simple_merged_node(i, 2)));
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        let offset = lazy_items.serialize(&mut writer).unwrap();
+
+        // Flip a byte of the first item offset, just past the chunk header
+        let mut bytes = writer.into_inner();
+        bytes[offset as usize + 4] ^= 0xFF;
+
+        let reader = Cursor::new(bytes);
+        let cache = get_cache(reader);
+        let result: std::io::Result<LazyItemSet<MergedNode>> = cache.load_item(offset);
+
+        let err = result.err().expect("corruption went undetected");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("chunk checksum mismatch"));
+    }
+
+    #[test]
+    fn test_unsigned_byte_vector_qt_serialization() {
+        let vector = sample_vector_qt();
+
+        let mut writer = Cursor::new(Vec::new());
+        let offset = vector.serialize(&mut writer).unwrap();
+
+        let mut reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader.clone());
+        let deserialized =
+            VectorQt::deserialize(&mut reader, offset, cache, 1000, &mut HashSet::new()).unwrap();
+
+        assert_eq!(vector, deserialized);
+    }
+
+    #[test]
+    fn test_sub_byte_vector_qt_serialization() {
+        let vector = VectorQt::SubByte {
+            mag: 42,
+            quant_vec: vec![vec![1, 2, 3], vec![], vec![255, 0]],
+            resolution: 2,
+        };
+
+        let mut writer = Cursor::new(Vec::new());
+        // Start past zero so the returned offset is actually exercised
+        writer.write_all(&[0xAB; 7]).unwrap();
+        let offset = vector.serialize(&mut writer).unwrap();
+
+        let mut reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader.clone());
+        let deserialized =
+            VectorQt::deserialize(&mut reader, offset, cache, 1000, &mut HashSet::new()).unwrap();
+
+        assert_eq!(offset, 7);
+        assert_eq!(vector, deserialized);
+    }
+
+    #[test]
+    fn test_node_prop_serialization() {
+        use crate::storage::Storage;
+        use half::f16;
+        use std::collections::HashMap;
+
+        let props = vec![
+            NodeProp {
+                id: VectorId::Int(42),
+                value: Arc::new(Storage::UnsignedByte {
+                    mag: 14,
+                    norm: 14f32.sqrt(),
+                    quant_vec: vec![1, 2, 3],
+                }),
+                location: Some((128, 64)),
+                metadata: Some(HashMap::from([("tag".to_string(), "blue".to_string())])),
+                namespace: Some("docs".to_string()),
+                exact: Some(vec![0.004, 0.0078, 0.0118]),
+            },
+            NodeProp {
+                id: VectorId::Str("doc-7".to_string()),
+                value: Arc::new(Storage::HalfPrecisionFP {
+                    mag: 0.5,
+                    norm: 0.5f32.sqrt(),
+                    quant_vec: vec![f16::from_f32(0.5), f16::from_f32(-0.5)],
+                }),
+                location: None,
+                metadata: None,
+                namespace: None,
+                exact: None,
+            },
+            NodeProp {
+                id: VectorId::Int(-3),
+                value: Arc::new(Storage::SubByte {
+                    mag: 3,
+                    norm: 3f32.sqrt(),
+                    quant_vec: vec![vec![0b101], vec![0b011]],
+                    resolution: 1,
+                }),
+                location: None,
+                metadata: Some(HashMap::new()),
+                namespace: None,
+                exact: None,
+            },
+        ];
+
+        let mut writer = Cursor::new(Vec::new());
+        let offsets: Vec<_> = props
+            .iter()
+            .map(|prop| prop.serialize(&mut writer).unwrap())
+            .collect();
+
+        let mut reader = Cursor::new(writer.into_inner());
+        let cache = get_cache(reader.clone());
+        for (prop, offset) in props.iter().zip(offsets) {
+            let deserialized = NodeProp::deserialize(
+                &mut reader,
+                offset,
+                cache.clone(),
+                1000,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+            assert_eq!(*prop, deserialized);
+            assert_eq!(prop.location, deserialized.location);
+            assert_eq!(prop.metadata, deserialized.metadata);
+            assert_eq!(prop.exact, deserialized.exact);
+        }
+    }
+
+    #[test]
+    fn test_serialized_size_matches_bytes_written() {
+        // Distinct props give distinct ids, so the neighbors don't collapse into one
+        let node_at = |version_id: VersionId, hnsw_level: HNSWLevel, prop_offset: u32| {
+            let mut node = MergedNode::new(version_id, hnsw_level);
+            node.prop = Item::new(PropState::Pending((prop_offset, 8)));
+            node
+        };
+        let node = node_at(1, 2, 0);
+        node.set_parent(LazyItem::new(node_at(2, 3, 8)));
+        node.set_child(LazyItem::new(node_at(3, 1, 16)));
+        // More neighbors than fit in one chunk
+        for i in 0..MAX_CHUNK_SIZE as u32 + 6 {
+            node.add_ready_neighbor(LazyItem::from_data(node_at(1, 2, 24 + i * 8)), 0.5);
+        }
+        node.add_version(Item::new(node_at(4, 2, 0)));
+
+        let estimate = node.serialized_size();
+        let mut writer = Cursor::new(Vec::new());
+        node.serialize(&mut writer).unwrap();
+        assert_eq!(estimate, writer.get_ref().len());
+
+        // Everything the node links to has an offset now, so only the node is written
+        let estimate = node.serialized_size();
+        let start = writer.seek(SeekFrom::End(0)).unwrap() as usize;
+        node.serialize(&mut writer).unwrap();
+        assert_eq!(estimate, writer.get_ref().len() - start);
+        assert!(estimate < start);
+    }
 }
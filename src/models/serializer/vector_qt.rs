@@ -0,0 +1,83 @@
+use super::CustomSerialize;
+use crate::models::{cache_loader::NodeRegistry, types::FileOffset, types::VectorQt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashSet;
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+const UNSIGNED_BYTE_TAG: u8 = 0;
+const SUB_BYTE_TAG: u8 = 1;
+
+impl CustomSerialize for VectorQt {
+    fn serialize<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<u32> {
+        let offset = writer.stream_position()? as u32;
+
+        match self {
+            VectorQt::UnsignedByte { mag, quant_vec } => {
+                writer.write_u8(UNSIGNED_BYTE_TAG)?;
+                writer.write_u32::<LittleEndian>(*mag)?;
+                writer.write_u32::<LittleEndian>(quant_vec.len() as u32)?;
+                writer.write_all(quant_vec)?;
+            }
+            VectorQt::SubByte {
+                mag,
+                quant_vec,
+                resolution,
+            } => {
+                writer.write_u8(SUB_BYTE_TAG)?;
+                writer.write_u32::<LittleEndian>(*mag)?;
+                writer.write_u8(*resolution)?;
+                writer.write_u32::<LittleEndian>(quant_vec.len() as u32)?;
+                for inner_vec in quant_vec {
+                    writer.write_u32::<LittleEndian>(inner_vec.len() as u32)?;
+                    writer.write_all(inner_vec)?;
+                }
+            }
+        }
+
+        Ok(offset)
+    }
+
+    fn deserialize<R: Read + Seek>(
+        reader: &mut R,
+        offset: u32,
+        _cache: Arc<NodeRegistry<R>>,
+        _max_loads: u16,
+        _skipm: &mut HashSet<FileOffset>,
+    ) -> std::io::Result<Self> {
+        reader.seek(SeekFrom::Start(offset as u64))?;
+
+        match reader.read_u8()? {
+            UNSIGNED_BYTE_TAG => {
+                let mag = reader.read_u32::<LittleEndian>()?;
+                let len = reader.read_u32::<LittleEndian>()? as usize;
+                let mut quant_vec = vec![0u8; len];
+                reader.read_exact(&mut quant_vec)?;
+                Ok(VectorQt::UnsignedByte { mag, quant_vec })
+            }
+            SUB_BYTE_TAG => {
+                let mag = reader.read_u32::<LittleEndian>()?;
+                let resolution = reader.read_u8()?;
+                let outer_len = reader.read_u32::<LittleEndian>()? as usize;
+                let mut quant_vec = Vec::with_capacity(outer_len);
+                for _ in 0..outer_len {
+                    let inner_len = reader.read_u32::<LittleEndian>()? as usize;
+                    let mut inner_vec = vec![0u8; inner_len];
+                    reader.read_exact(&mut inner_vec)?;
+                    quant_vec.push(inner_vec);
+                }
+                Ok(VectorQt::SubByte {
+                    mag,
+                    quant_vec,
+                    resolution,
+                })
+            }
+            tag => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid VectorQt tag {}", tag),
+            )),
+        }
+    }
+}
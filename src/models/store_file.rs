@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, RwLock};
+
+// A file kept in memory. Clones share the bytes but keep their own position, like
+// separate handles on the same file. Writes always go to the end, as they would on
+// a file opened for appending
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFile {
+    bytes: Arc<RwLock<Vec<u8>>>,
+    position: u64,
+}
+
+impl MemoryFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.read().map_or(0, |bytes| bytes.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn poisoned<T>(_: T) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Memory file lock poisoned")
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.bytes.read().map_err(poisoned)?;
+        let start = (self.position as usize).min(bytes.len());
+        let remaining = &bytes[start..];
+        let to_read = buf.len().min(remaining.len());
+        buf[..to_read].copy_from_slice(&remaining[..to_read]);
+        self.position += to_read as u64;
+        Ok(to_read)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut bytes = self.bytes.write().map_err(poisoned)?;
+        bytes.extend_from_slice(buf);
+        self.position = bytes.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative position",
+            )),
+        }
+    }
+}
+
+// A file a store appends to and reads back from, such as its prop file. Stores
+// created with `init_vector_store_in_memory` keep theirs in memory
+#[derive(Debug)]
+pub enum StoreFile {
+    Disk(File),
+    Memory(MemoryFile),
+}
+
+impl StoreFile {
+    // A handle to read or append through. On disk every handle shares the file's
+    // position, in memory each gets its own
+    pub fn handle(&self) -> StoreFileHandle<'_> {
+        match self {
+            StoreFile::Disk(file) => StoreFileHandle::Disk(file),
+            StoreFile::Memory(file) => StoreFileHandle::Memory(file.clone()),
+        }
+    }
+
+    pub fn len(&self) -> io::Result<u64> {
+        match self {
+            StoreFile::Disk(file) => Ok(file.metadata()?.len()),
+            StoreFile::Memory(file) => Ok(file.len() as u64),
+        }
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+pub enum StoreFileHandle<'a> {
+    Disk(&'a File),
+    Memory(MemoryFile),
+}
+
+impl Read for StoreFileHandle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StoreFileHandle::Disk(file) => file.read(buf),
+            StoreFileHandle::Memory(file) => file.read(buf),
+        }
+    }
+}
+
+impl Write for StoreFileHandle<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StoreFileHandle::Disk(file) => file.write(buf),
+            StoreFileHandle::Memory(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StoreFileHandle::Disk(file) => file.flush(),
+            StoreFileHandle::Memory(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for StoreFileHandle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            StoreFileHandle::Disk(file) => file.seek(pos),
+            StoreFileHandle::Memory(file) => file.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_file_handles_keep_their_own_position() {
+        let file = StoreFile::Memory(MemoryFile::new());
+        let mut writer = file.handle();
+        writer.write_all(b"hello").unwrap();
+        let mut reader = file.handle();
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        // Writes land at the end wherever the handle is
+        writer.write_all(b" world").unwrap();
+        assert_eq!(writer.stream_position().unwrap(), 11);
+        assert_eq!(file.len().unwrap(), 11);
+
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "world");
+    }
+}
@@ -1,28 +1,33 @@
 use crate::distance::DistanceError;
 use crate::distance::{
     cosine::CosineDistance, dotproduct::DotProductDistance, euclidean::EuclideanDistance,
-    hamming::HammingDistance, DistanceFunction,
+    hamming::HammingDistance, manhattan::ManhattanDistance, DistanceFunction,
 };
 use crate::models::common::*;
 use crate::models::identity_collections::*;
 use crate::models::lazy_load::*;
+use crate::models::prop_cache::{PropCache, DEFAULT_PROP_CACHE_CAPACITY};
+use crate::models::store_file::{MemoryFile, StoreFile};
 use crate::models::versioning::VersionHash;
 use crate::quantization::product::ProductQuantization;
 use crate::quantization::scalar::ScalarQuantization;
-use crate::quantization::{Quantization, StorageType};
+use crate::quantization::{Quantization, QuantizationError, StorageType};
 use crate::storage::Storage;
 use arcshift::ArcShift;
 use dashmap::DashMap;
-use lmdb::{Database, Environment};
+use lmdb::{Database, Environment, RwTransaction};
 use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::*;
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, OnceLock,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, OnceLock,
 };
+use tempfile::TempDir;
 
 pub type HNSWLevel = u8;
 pub type FileOffset = u32;
@@ -52,20 +57,115 @@ impl Identifiable for MergedNode {
     fn get_id(&self) -> Self::Id {
         let mut prop_ref = self.prop.clone();
         let prop = prop_ref.get();
-        let mut hasher = DefaultHasher::new();
-        prop.hash(&mut hasher);
+        node_id::<SipNodeIds>(prop)
+    }
+}
+
+// Turns the bytes that identify a prop into a node id. Ids have to come out the same
+// in every process and every build, so a scheme can't be randomly seeded
+pub trait NodeIdScheme {
+    fn node_id(identity: &[u8]) -> u64;
+}
+
+// SipHash-2-4 under fixed keys
+pub struct SipNodeIds;
+
+impl SipNodeIds {
+    const KEYS: (u64, u64) = (
+        u64::from_le_bytes(*b"cosdata\0"),
+        u64::from_le_bytes(*b"node_id\0"),
+    );
+}
+
+impl NodeIdScheme for SipNodeIds {
+    fn node_id(identity: &[u8]) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(Self::KEYS.0, Self::KEYS.1);
+        hasher.write(identity);
         hasher.finish()
     }
 }
 
+pub fn node_id<S: NodeIdScheme>(prop: &PropState) -> u64 {
+    S::node_id(&prop_identity(prop))
+}
+
+// The bytes a prop is identified by, laid out explicitly rather than through `Hash`,
+// whose output isn't guaranteed to stay the same between Rust versions. A loaded prop
+// is identified by its namespace and id, the vector itself is left out so rewriting
+// it keeps the node's id. A prop not loaded yet only has its location to go on
+fn prop_identity(prop: &PropState) -> Vec<u8> {
+    fn write_str(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(s.as_bytes());
+    }
+
+    let mut bytes = Vec::new();
+    match prop {
+        PropState::Ready(prop) => {
+            bytes.push(0);
+            match &prop.namespace {
+                None => bytes.push(0),
+                Some(namespace) => {
+                    bytes.push(1);
+                    write_str(&mut bytes, namespace);
+                }
+            }
+            match &prop.id {
+                VectorId::Str(id) => {
+                    bytes.push(0);
+                    write_str(&mut bytes, id);
+                }
+                VectorId::Int(id) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&id.to_le_bytes());
+                }
+            }
+        }
+        PropState::Pending((offset, len)) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&len.to_le_bytes());
+        }
+    }
+    bytes
+}
+
 pub type PropPersistRef = (FileOffset, BytesToRead);
 pub type NodeFileRef = FileOffset;
 
+// A vector's id must be unique within its namespace, `insert_embedding` rejects an
+// id that's already taken there. Nodes are identified by hashing the namespace and
+// id, so two props sharing both would collide during search
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeProp {
     pub id: VectorId,
     pub value: Arc<Storage>,
     pub location: Option<PropPersistRef>,
+    // Arbitrary user tags. Props written before this existed load with `None`
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+    // `None` is the store's default namespace, which every vector inserted without
+    // one lives in
+    #[serde(default)]
+    pub namespace: Option<String>,
+    // The vector as it was given, which `value` only approximates once quantized.
+    // `None` for `Float32` stores and props written before this existed
+    #[serde(default)]
+    pub exact: Option<Vec<f32>>,
+}
+
+impl NodeProp {
+    pub fn namespaced_id(&self) -> NamespacedId {
+        (self.namespace.clone(), self.id.clone())
+    }
+}
+
+// Props are only equal when the namespace, id and vector all match, where they're
+// stored on disk doesn't matter
+impl PartialEq for NodeProp {
+    fn eq(&self, other: &Self) -> bool {
+        self.namespace == other.namespace && self.id == other.id && self.value == other.value
+    }
 }
 
 impl Hash for NodeProp {
@@ -73,10 +173,14 @@ impl Hash for NodeProp {
     where
         H: Hasher,
     {
+        self.namespace.hash(state);
         self.id.hash(state);
     }
 }
 
+// What a vector is told apart by, its id is only unique within the namespace
+pub type NamespacedId = (Option<String>, VectorId);
+
 #[derive(Debug, Clone, Hash)]
 pub enum PropState {
     Ready(Arc<NodeProp>),
@@ -112,12 +216,168 @@ pub struct MergedNode {
     pub persist_flag: Arc<AtomicBool>,
 }
 
-#[derive(Debug)]
+// When `run_upload` writes what it indexed to the store's files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PersistMode {
+    // Every upload is committed as a new version before it returns
+    #[default]
+    WriteThrough,
+    // Uploads are indexed and searchable, but only `commit` writes them out. Faster,
+    // though whatever wasn't committed is gone after a restart
+    Deferred,
+}
+
+// How a new node picks its neighbors out of the closest nodes found for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NeighborSelection {
+    // The `max_neighbors` best scoring ones
+    #[default]
+    Simple,
+    // Best first, skipping any candidate that scores better against a neighbor already
+    // picked than against the new node. Keeps links into other clusters that a cluster
+    // of near duplicates would otherwise crowd out
+    Heuristic,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DistanceMetric {
     Cosine,
     Euclidean,
     Hamming,
     DotProduct,
+    // Dot product for vectors that were unit length before quantization
+    NormalizedDotProduct,
+    Manhattan,
+    // Blend of cosine distance, `1 - cosine similarity`, and euclidean distance, build
+    // it with `DistanceMetric::weighted`
+    Weighted { cosine: f32, euclidean: f32 },
+    // Supplied by the user of the crate, build it with `DistanceMetric::custom`
+    Custom(CustomMetric),
+}
+
+// A distance function from outside the crate. Stores save it by name, so one on disk
+// only loads once a metric of that name has been built again with
+// `DistanceMetric::custom`
+#[derive(Debug, Clone)]
+pub struct CustomMetric {
+    pub name: String,
+    pub higher_is_better: bool,
+    pub function: Arc<dyn DistanceFunction>,
+}
+
+static CUSTOM_METRICS: OnceLock<DashMap<String, CustomMetric>> = OnceLock::new();
+
+impl PartialEq for CustomMetric {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Arc::ptr_eq(&self.function, &other.function)
+    }
+}
+
+impl Serialize for CustomMetric {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for CustomMetric {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        CUSTOM_METRICS
+            .get()
+            .and_then(|metrics| metrics.get(&name).map(|metric| metric.clone()))
+            .ok_or_else(|| {
+                serde::de::Error::custom(format!("Unknown custom distance metric {}", name))
+            })
+    }
+}
+
+impl DistanceMetric {
+    // `higher_is_better` tells whether the function returns similarities or distances.
+    // Building a metric with a name that's already taken replaces the earlier one for
+    // stores loaded afterwards
+    pub fn custom(
+        name: String,
+        higher_is_better: bool,
+        function: Arc<dyn DistanceFunction>,
+    ) -> Self {
+        let metric = CustomMetric {
+            name,
+            higher_is_better,
+            function,
+        };
+        CUSTOM_METRICS
+            .get_or_init(DashMap::new)
+            .insert(metric.name.clone(), metric.clone());
+        Self::Custom(metric)
+    }
+
+    pub fn weighted(cosine: f32, euclidean: f32) -> Result<Self, WaCustomError> {
+        let total = cosine + euclidean;
+        if !total.is_finite() || total == 0.0 {
+            return Err(WaCustomError::InvalidParams);
+        }
+        Ok(Self::Weighted { cosine, euclidean })
+    }
+
+    // Euclidean, manhattan and weighted scores are distances, where smaller means
+    // closer. The others are similarities where larger is better. Custom metrics say
+    // which they are
+    pub fn higher_is_better(&self) -> bool {
+        match self {
+            Self::Euclidean | Self::Manhattan | Self::Weighted { .. } => false,
+            Self::Custom(metric) => metric.higher_is_better,
+            _ => true,
+        }
+    }
+
+    // Whether scores can be computed for vectors stored as `storage_type`. Sub-byte
+    // vectors only have a cosine implementation, custom metrics are taken on trust
+    pub fn supports(&self, storage_type: StorageType) -> bool {
+        match storage_type {
+            StorageType::SubByte(_) => matches!(self, Self::Cosine | Self::Custom(_)),
+            _ => true,
+        }
+    }
+
+    // Orders two scores best first. NaN goes last whichever way scores run, so one
+    // can't leave the order inconsistent
+    pub fn compare_scores(&self, a: f32, b: f32) -> std::cmp::Ordering {
+        let ordering = match (a.is_nan(), b.is_nan()) {
+            (true, true) => return std::cmp::Ordering::Equal,
+            (true, false) => return std::cmp::Ordering::Greater,
+            (false, true) => return std::cmp::Ordering::Less,
+            (false, false) => b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal),
+        };
+        if self.higher_is_better() {
+            ordering
+        } else {
+            ordering.reverse()
+        }
+    }
+}
+
+// A query match. The score comes from `metric`, so whether a higher one is better
+// depends on `DistanceMetric::higher_is_better`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredResult {
+    pub id: VectorId,
+    pub score: f32,
+    pub metric: DistanceMetric,
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+// Health of a store's index, see `calculate_statistics`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub total_vectors: u64,
+    // Indexed nodes on each level, level 0 first. The root placeholder isn't counted
+    pub nodes_per_level: Vec<usize>,
+    // Mean neighbors per counted node, over all levels
+    pub average_degree: f32,
+    // Share of prop lookups served from the store's prop cache
+    pub cache_hit_rate: f32,
+    pub current_version: Option<VersionHash>,
 }
 
 impl DistanceFunction for DistanceMetric {
@@ -126,12 +386,21 @@ impl DistanceFunction for DistanceMetric {
             Self::Cosine => CosineDistance.calculate(x, y),
             Self::Euclidean => EuclideanDistance.calculate(x, y),
             Self::Hamming => HammingDistance.calculate(x, y),
-            Self::DotProduct => DotProductDistance.calculate(x, y),
+            Self::DotProduct => DotProductDistance::default().calculate(x, y),
+            Self::NormalizedDotProduct => DotProductDistance { normalized: true }.calculate(x, y),
+            Self::Manhattan => ManhattanDistance.calculate(x, y),
+            Self::Weighted { cosine, euclidean } => {
+                // Turned into a distance, so both terms grow as the vectors part
+                let cosine_distance = 1.0 - CosineDistance.calculate(x, y)?;
+                let euclidean_distance = EuclideanDistance.calculate(x, y)?;
+                Ok(cosine * cosine_distance + euclidean * euclidean_distance)
+            }
+            Self::Custom(metric) => metric.function.calculate(x, y),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum QuantizationMetric {
     Scalar,
     Product(ProductQuantization),
@@ -170,11 +439,39 @@ impl MergedNode {
         }
     }
 
+    // Neighbors on the node's own level, which is the only one it links to
+    pub fn neighbor_count(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    // How many versions the node has gone through, the current one included
+    pub fn version_chain_len(&self) -> usize {
+        self.versions.len() + 1
+    }
+
     pub fn add_ready_neighbor(&self, neighbor: LazyItem<MergedNode>, cosine_similarity: f32) {
         self.neighbors
             .insert(EagerLazyItem(cosine_similarity, neighbor));
     }
 
+    // Like `add_ready_neighbor`, but drops the least similar neighbor once the node has
+    // more than `max_neighbors` on its level. Returns whether `neighbor` is kept
+    pub fn add_ready_neighbor_bounded(
+        &self,
+        neighbor: LazyItem<MergedNode>,
+        cosine_similarity: f32,
+        max_neighbors: usize,
+        metric: &DistanceMetric,
+    ) -> bool {
+        let id = neighbor.get_id();
+        self.neighbors.insert_bounded(
+            EagerLazyItem(cosine_similarity, neighbor),
+            max_neighbors,
+            |a, b| metric.compare_scores(*a, *b),
+        );
+        self.neighbors.iter().any(|nbr| nbr.get_id() == id)
+    }
+
     pub fn set_parent(&self, parent: LazyItem<MergedNode>) {
         let mut arc = self.parent.item.clone();
         arc.update(parent);
@@ -191,6 +488,44 @@ impl MergedNode {
         }
     }
 
+    // The whole list lands in one step, a concurrent search sees all of it or none
+    pub fn add_ready_neighbors_bounded(
+        &self,
+        neighbors_list: Vec<(LazyItem<MergedNode>, f32)>,
+        max_neighbors: usize,
+        metric: &DistanceMetric,
+    ) {
+        self.neighbors.insert_all_bounded(
+            Self::neighbor_items(neighbors_list),
+            max_neighbors,
+            |a, b| metric.compare_scores(*a, *b),
+        );
+    }
+
+    // Replaces the node's neighbors in one step, so a concurrent search sees either the
+    // old or the new list
+    pub fn replace_neighbors_bounded(
+        &self,
+        neighbors_list: Vec<(LazyItem<MergedNode>, f32)>,
+        max_neighbors: usize,
+        metric: &DistanceMetric,
+    ) {
+        self.neighbors.replace_bounded(
+            Self::neighbor_items(neighbors_list),
+            max_neighbors,
+            |a, b| metric.compare_scores(*a, *b),
+        );
+    }
+
+    fn neighbor_items(
+        neighbors_list: Vec<(LazyItem<MergedNode>, f32)>,
+    ) -> Vec<EagerLazyItem<MergedNode, f32>> {
+        neighbors_list
+            .into_iter()
+            .map(|(neighbor, cosine_similarity)| EagerLazyItem(cosine_similarity, neighbor))
+            .collect()
+    }
+
     pub fn get_neighbors(&self) -> EagerLazyItemSet<MergedNode, f32> {
         self.neighbors.clone()
     }
@@ -200,10 +535,45 @@ impl MergedNode {
     //     arc.update(new_neighbors);
     // }
 
+    /// Adds a version keyed by its `version_id`; adding another version with
+    /// the same id replaces the previous one.
     pub fn add_version(&self, version: Item<MergedNode>) {
+        let version_id = version.clone().get().version_id;
         let lazy_item = LazyItem::from_item(version);
-        // TODO: look at the id
-        self.versions.insert(IdentityMapKey::Int(0), lazy_item);
+        self.versions
+            .insert(IdentityMapKey::Int(version_id as u32), lazy_item);
+    }
+
+    // Drops all but the `keep_latest` versions with the highest ids and returns how
+    // many were dropped. Versions are serialized with the node, so this keeps nodes
+    // that change often from growing without bound
+    pub fn prune_versions(&self, keep_latest: usize) -> usize {
+        let mut version_ids: Vec<u32> = self
+            .versions
+            .keys()
+            .into_iter()
+            .filter_map(|key| match key {
+                IdentityMapKey::Int(version_id) => Some(version_id),
+                _ => None,
+            })
+            .collect();
+        if version_ids.len() <= keep_latest {
+            return 0;
+        }
+        version_ids.sort_unstable_by(|a, b| b.cmp(a));
+        // Versions added while pruning are newer than the cutoff, so they stay
+        let oldest_kept = keep_latest.checked_sub(1).map(|index| version_ids[index]);
+        let before = self.versions.len();
+        self.versions.retain(|key| match (key, oldest_kept) {
+            (IdentityMapKey::Int(version_id), Some(oldest)) => *version_id >= oldest,
+            (IdentityMapKey::Int(_), None) => false,
+            _ => true,
+        });
+        before.saturating_sub(self.versions.len())
+    }
+
+    pub fn get_version(&self, version_id: VersionId) -> Option<LazyItem<MergedNode>> {
+        self.versions.get(&IdentityMapKey::Int(version_id as u32))
     }
 
     pub fn get_versions(&self) -> LazyItemMap<MergedNode> {
@@ -281,7 +651,7 @@ impl fmt::Display for MergedNode {
             PropState::Pending(_) => writeln!(f, "Pending")?,
         }
         // Display number of neighbors
-        writeln!(f, "  neighbors: {} items,", self.neighbors.len())?;
+        writeln!(f, "  neighbors: {} items,", self.neighbor_count())?;
 
         // Display parent and child status
         writeln!(
@@ -305,6 +675,7 @@ impl fmt::Display for MergedNode {
 
         // Display number of versions
         writeln!(f, "  versions: {} items,", self.versions.len())?;
+        writeln!(f, "  version_chain: {} long,", self.version_chain_len())?;
 
         // Display persist flag
         writeln!(
@@ -317,7 +688,7 @@ impl fmt::Display for MergedNode {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum VectorQt {
     UnsignedByte {
         mag: u32,
@@ -337,14 +708,14 @@ impl VectorQt {
         Self::UnsignedByte { mag, quant_vec }
     }
 
-    pub fn sub_byte(vec: &[f32], resolution: u8) -> Self {
-        let quant_vec = quantize_to_u8_bits(vec, resolution);
-        let mag = 0; //implement a proper magnitude calculation
-        Self::SubByte {
+    pub fn sub_byte(vec: &[f32], resolution: u8) -> Result<Self, QuantizationError> {
+        let quant_vec = quantize_to_u8_bits(vec, resolution)?;
+        let mag = mag_square_sub_byte(&quant_vec, resolution);
+        Ok(Self::SubByte {
             mag,
             quant_vec,
             resolution,
-        }
+        })
     }
 }
 
@@ -353,61 +724,185 @@ pub type SizeBytes = u32;
 // needed to flatten and get uniques
 pub type ExecQueueUpdate = Item<Vec<Item<LazyItem<MergedNode>>>>;
 
+// Nodes with their scores against a query, as searches return them
+pub type ScoredNodes = Vec<(LazyItem<MergedNode>, f32)>;
+
 #[derive(Debug, Clone)]
 pub struct MetaDb {
     pub env: Arc<Environment>,
     pub metadata_db: Arc<Database>,
     pub embeddings_db: Arc<Database>,
+    // Write transactions begun through `begin_rw_txn`
+    pub write_txns: Arc<AtomicUsize>,
+}
+
+impl MetaDb {
+    pub fn begin_rw_txn(&self) -> Result<RwTransaction<'_>, lmdb::Error> {
+        self.write_txns.fetch_add(1, Ordering::Relaxed);
+        self.env.begin_rw_txn()
+    }
 }
 
 #[derive(Clone)]
 pub struct VectorStore {
     pub exec_queue_nodes: ExecQueueUpdate,
     pub max_cache_level: u8,
+    pub max_neighbors: usize,
     pub database_name: String,
+    // Directory holding the store's prop and index files
+    pub base_path: PathBuf,
     pub root_vec: LazyItemRef<MergedNode>,
     pub levels_prob: Arc<Vec<(f64, i32)>>,
-    pub quant_dim: usize,
-    pub prop_file: Arc<File>,
+    pub prop_file: Arc<StoreFile>,
+    // Props already read from `prop_file`, shared by clones of the store
+    pub prop_cache: Arc<PropCache>,
     pub lmdb: MetaDb,
     pub current_version: Item<Option<VersionHash>>,
     pub current_open_transaction: Item<Option<VersionHash>>,
     pub quantization_metric: Arc<QuantizationMetric>,
-    pub distance_metric: Arc<DistanceMetric>,
+    // Swapped with `set_distance_metric`, read it through `get_distance_metric`
+    pub distance_metric: Item<DistanceMetric>,
     pub storage_type: StorageType,
+    // Lower and upper bound of the values the store holds, see `quantize`
+    pub bounds: (f32, f32),
+    // Searches don't expand neighbors scoring worse than this below the top level,
+    // see `SearchContext`
+    pub min_explore_similarity: Option<f32>,
+    pub neighbor_selection: NeighborSelection,
+    pub persist_mode: PersistMode,
+    // How many committed versions keep their index file, all of them if `None`. See
+    // `gc_old_versions`
+    pub keep_versions: Option<usize>,
+    // Set for stores that live in memory only, which write no index files
+    pub memory: Option<MemoryBacking>,
+    // Where commits are appended to. Held for the whole of a commit, so they're
+    // written one at a time and in the order of their versions
+    pub index_log: Arc<Mutex<IndexLog>>,
+    // The nodes of each vector, one per level it's on and top level first, so finding
+    // them by id doesn't walk the graph. Filled in as vectors are indexed and when the
+    // store is loaded
+    pub nodes_by_id: Arc<DashMap<NamespacedId, Vec<LazyItem<MergedNode>>>>,
+}
+
+// The index file a store's commits are appended to, and what they've written to it
+#[derive(Debug, Clone, Default)]
+pub struct IndexLog {
+    // The version that started the file, which is `{file_version}.index`
+    pub file_version: u32,
+    // The last relocation table in the file, see `write_relocations`
+    pub relocations: Option<FileOffset>,
+    // Every relocation in the file so far
+    pub moved: HashMap<FileOffset, FileOffset>,
+}
+
+// What a store created with `init_vector_store_in_memory` keeps instead of files
+#[derive(Clone)]
+pub struct MemoryBacking {
+    // Laid out like the `vec_raw.0` each store on disk keeps
+    pub raw_embeddings: MemoryFile,
+    // Holds the store's own metadata DB, removed once the last clone of the store
+    // is dropped
+    pub _lmdb_dir: Arc<TempDir>,
+}
+
+// What a `VectorStore` is built from, the fields of the same name it holds. The rest
+// start out empty
+pub struct VectorStoreParts {
+    pub exec_queue_nodes: ExecQueueUpdate,
+    pub max_cache_level: u8,
+    pub max_neighbors: usize,
+    pub database_name: String,
+    pub base_path: PathBuf,
+    pub root_vec: LazyItemRef<MergedNode>,
+    pub levels_prob: Arc<Vec<(f64, i32)>>,
+    pub prop_file: Arc<StoreFile>,
+    pub lmdb: MetaDb,
+    pub current_version: Item<Option<VersionHash>>,
+    pub quantization_metric: Arc<QuantizationMetric>,
+    pub distance_metric: DistanceMetric,
+    pub storage_type: StorageType,
+    pub bounds: (f32, f32),
+    pub min_explore_similarity: Option<f32>,
+    pub neighbor_selection: NeighborSelection,
+    pub persist_mode: PersistMode,
+    pub keep_versions: Option<usize>,
+    pub memory: Option<MemoryBacking>,
 }
 
 impl VectorStore {
-    pub fn new(
-        exec_queue_nodes: ExecQueueUpdate,
-        max_cache_level: u8,
-        database_name: String,
-        root_vec: LazyItemRef<MergedNode>,
-        levels_prob: Arc<Vec<(f64, i32)>>,
-        quant_dim: usize,
-        prop_file: Arc<File>,
-        lmdb: MetaDb,
-        current_version: Item<Option<VersionHash>>,
-        quantization_metric: Arc<QuantizationMetric>,
-        distance_metric: Arc<DistanceMetric>,
-        storage_type: StorageType,
-    ) -> Self {
+    pub fn new(parts: VectorStoreParts) -> Self {
+        let VectorStoreParts {
+            exec_queue_nodes,
+            max_cache_level,
+            max_neighbors,
+            database_name,
+            base_path,
+            root_vec,
+            levels_prob,
+            prop_file,
+            lmdb,
+            current_version,
+            quantization_metric,
+            distance_metric,
+            storage_type,
+            bounds,
+            min_explore_similarity,
+            neighbor_selection,
+            persist_mode,
+            keep_versions,
+            memory,
+        } = parts;
         VectorStore {
             exec_queue_nodes,
             max_cache_level,
+            max_neighbors,
             database_name,
+            base_path,
             root_vec,
             levels_prob,
-            quant_dim,
             prop_file,
+            prop_cache: Arc::new(PropCache::new(DEFAULT_PROP_CACHE_CAPACITY)),
             lmdb,
             current_version,
             current_open_transaction: Item::new(None),
             quantization_metric,
-            distance_metric,
+            distance_metric: Item::new(distance_metric),
             storage_type,
+            bounds,
+            min_explore_similarity,
+            neighbor_selection,
+            persist_mode,
+            keep_versions,
+            memory,
+            index_log: Arc::new(Mutex::new(IndexLog::default())),
+            nodes_by_id: Arc::new(DashMap::new()),
+        }
+    }
+
+    // For a store reloaded from an index file other commits were appended to before
+    pub fn with_index_log(self, index_log: IndexLog) -> Self {
+        Self {
+            index_log: Arc::new(Mutex::new(index_log)),
+            ..self
         }
     }
+
+    // Quantizes `vector` for this store. Values are clamped to the store's bounds
+    // first, so a query gets the same treatment, and so a comparable magnitude, as
+    // the vectors it's compared against
+    pub fn quantize(&self, vector: &[f32]) -> Storage {
+        let (lower, upper) = self.bounds;
+        let clamped: Vec<f32> = vector.iter().map(|x| x.clamp(lower, upper)).collect();
+        self.quantization_metric
+            .quantize(&clamped, self.storage_type)
+    }
+
+    // What's kept of `vector` next to its quantized value, so results can be reranked
+    // against it. A `Float32` store's values are exact already
+    pub fn exact_copy(&self, vector: &[f32]) -> Option<Vec<f32>> {
+        (self.storage_type != StorageType::Float32).then(|| vector.to_vec())
+    }
+
     // Get method
     pub fn get_current_version(&self) -> Option<VersionHash> {
         let mut arc = self.current_version.clone();
@@ -419,11 +914,59 @@ impl VectorStore {
         let mut arc = self.current_version.clone();
         arc.update(new_version);
     }
+
+    // Makes `version` the current one unless a later version already is, so commits
+    // finishing out of order never move the store back. Returns whether it moved
+    pub fn advance_current_version(&self, version: VersionHash) -> bool {
+        let mut arc = self.current_version.clone();
+        let mut advanced = false;
+        arc.rcu(|current| {
+            advanced = current
+                .as_ref()
+                .map_or(true, |current| current.version < version.version);
+            if advanced {
+                Some(version.clone())
+            } else {
+                current.clone()
+            }
+        });
+        advanced
+    }
+
+    pub fn get_distance_metric(&self) -> DistanceMetric {
+        let mut arc = self.distance_metric.clone();
+        arc.get().clone()
+    }
+
+    // Changes how searches score and rank candidates from now on. The graph was
+    // linked using the old metric and stays as it is, so recall may suffer if the
+    // two disagree much about which vectors are close. The change isn't saved, a
+    // reloaded store uses the metric it was created with
+    pub fn set_distance_metric(&self, metric: DistanceMetric) {
+        let mut arc = self.distance_metric.clone();
+        arc.update(metric);
+    }
+
+    pub fn prop_file_path(&self) -> PathBuf {
+        self.base_path.join("prop.data")
+    }
+
+    pub fn index_file_path(&self, version: u32) -> PathBuf {
+        index_file_path(&self.base_path, version)
+    }
+}
+
+// Where the store in `base_path` keeps what `version` committed
+pub fn index_file_path(base_path: &Path, version: u32) -> PathBuf {
+    base_path.join(format!("{}.index", version))
 }
 #[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, PartialEq)]
 pub struct VectorEmbedding {
     pub raw_vec: Arc<Storage>,
     pub hash_vec: VectorId,
+    pub namespace: Option<String>,
+    // Carried over to the prop of the vector's nodes, see `NodeProp::exact`
+    pub exact: Option<Vec<f32>>,
 }
 
 type VectorStoreMap = DashMap<String, Arc<VectorStore>>;
@@ -434,29 +977,358 @@ pub struct AppEnv {
     pub user_data_cache: UserDataCache,
     pub vector_store_map: VectorStoreMap,
     pub persist: Arc<Environment>,
+    // Holds the LMDB environment and a directory per store
+    pub data_dir: PathBuf,
 }
 
 static AIN_ENV: OnceLock<Result<Arc<AppEnv>, WaCustomError>> = OnceLock::new();
 
+// Where the data lives unless `Config::data_dir` says otherwise
+pub const DEFAULT_DATA_DIR: &str = "./_mdb";
+
+const DEFAULT_LMDB_MAP_SIZE: usize = 1073741824; // 1GB
+
 pub fn get_app_env() -> Result<Arc<AppEnv>, WaCustomError> {
+    init_app_env(DEFAULT_LMDB_MAP_SIZE, Path::new(DEFAULT_DATA_DIR))
+}
+
+// An LMDB environment in a fresh temporary directory, for stores that keep out of the
+// app's one. The directory is removed once the returned `TempDir` is dropped
+pub fn open_temp_env() -> Result<(Arc<Environment>, TempDir), WaCustomError> {
+    let dir = tempfile::tempdir()?;
+    let env = Environment::new()
+        .set_max_dbs(2)
+        .set_map_size(DEFAULT_LMDB_MAP_SIZE)
+        .open(dir.path())
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    Ok((Arc::new(env), dir))
+}
+
+// The map size and data directory only apply to the call that actually opens the
+// environment; once it's open, later calls return the existing one
+pub fn init_app_env(map_size: usize, data_dir: &Path) -> Result<Arc<AppEnv>, WaCustomError> {
     AIN_ENV
-        .get_or_init(|| {
-            let path = Path::new("./_mdb"); // TODO: prefix the customer & database name
-
-            // Ensure the directory exists
-            create_dir_all(&path).map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
-            // Initialize the environment
-            let env = Environment::new()
-                .set_max_dbs(2)
-                .set_map_size(10485760) // Set the maximum size of the database to 10MB
-                .open(&path)
-                .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
-
-            Ok(Arc::new(AppEnv {
-                user_data_cache: DashMap::new(),
-                vector_store_map: DashMap::new(),
-                persist: Arc::new(env),
-            }))
-        })
+        .get_or_init(|| open_app_env(data_dir, map_size).map(Arc::new))
         .clone()
 }
+
+fn open_app_env(data_dir: &Path, map_size: usize) -> Result<AppEnv, WaCustomError> {
+    // TODO: prefix the customer & database name
+    // Ensure the directory exists
+    create_dir_all(data_dir).map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    // Initialize the environment
+    let env = Environment::new()
+        .set_max_dbs(2)
+        .set_map_size(map_size)
+        .open(data_dir)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+    Ok(AppEnv {
+        user_data_cache: DashMap::new(),
+        vector_store_map: DashMap::new(),
+        persist: Arc::new(env),
+        data_dir: data_dir.to_path_buf(),
+    })
+}
+
+// Each store keeps its prop and index files in its own directory next to the
+// LMDB environment, so stores never write to each other's files
+pub fn vector_store_dir(database_name: &str) -> Result<PathBuf, WaCustomError> {
+    Ok(get_app_env()?.data_dir.join(database_name))
+}
+
+// Doubles the map size of an already open environment, which is equivalent to
+// reopening it with a larger size. LMDB only allows this while the process has no
+// active transactions, so the failed transaction must be dropped first
+pub fn grow_map_size(env: &Environment) -> Result<usize, WaCustomError> {
+    let mut info: lmdb_sys::MDB_envinfo = unsafe { std::mem::zeroed() };
+    let rc = unsafe { lmdb_sys::mdb_env_info(env.env(), &mut info) };
+    if rc != 0 {
+        return Err(WaCustomError::DatabaseError(
+            lmdb::Error::from_err_code(rc).to_string(),
+        ));
+    }
+
+    let new_size = info.me_mapsize * 2;
+    let rc = unsafe { lmdb_sys::mdb_env_set_mapsize(env.env(), new_size) };
+    if rc != 0 {
+        return Err(WaCustomError::DatabaseError(format!(
+            "Failed to grow map size to {}: {}",
+            new_size,
+            lmdb::Error::from_err_code(rc)
+        )));
+    }
+
+    Ok(new_size)
+}
+
+// Runs `op`, growing the map and trying again whenever LMDB reports it's full
+pub fn retry_on_map_full<T>(
+    env: &Environment,
+    mut op: impl FnMut() -> Result<T, lmdb::Error>,
+) -> Result<T, WaCustomError> {
+    loop {
+        match op() {
+            Err(lmdb::Error::MapFull) => {
+                grow_map_size(env)?;
+            }
+            result => return result.map_err(|e| WaCustomError::DatabaseError(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lmdb::{Transaction, WriteFlags};
+
+    fn node_with_id(id: i32) -> LazyItem<MergedNode> {
+        let node = MergedNode::new(0, 0);
+        node.set_prop_ready(Arc::new(NodeProp {
+            id: VectorId::Int(id),
+            value: Arc::new(Storage::UnsignedByte {
+                mag: 0,
+                norm: 0.0,
+                quant_vec: vec![],
+            }),
+            location: None,
+            metadata: None,
+            namespace: None,
+            exact: None,
+        }));
+        LazyItem::from_data(node)
+    }
+
+    #[test]
+    fn test_node_id_is_stable_across_runs() {
+        // Two separately built nodes stand in for the same prop seen by two processes
+        assert_eq!(node_with_id(42).get_id(), node_with_id(42).get_id());
+        assert_ne!(node_with_id(42).get_id(), node_with_id(43).get_id());
+
+        // Pinned so a change to the id scheme can't go unnoticed, ids computed by an
+        // older build would no longer match
+        let ready = |namespace: Option<&str>, id: VectorId| {
+            PropState::Ready(Arc::new(NodeProp {
+                id,
+                value: Arc::new(Storage::UnsignedByte {
+                    mag: 0,
+                    norm: 0.0,
+                    quant_vec: vec![],
+                }),
+                location: None,
+                metadata: None,
+                namespace: namespace.map(str::to_string),
+                exact: None,
+            }))
+        };
+        assert_eq!(
+            node_id::<SipNodeIds>(&ready(None, VectorId::Int(42))),
+            9591239744556126502
+        );
+        assert_eq!(
+            node_id::<SipNodeIds>(&ready(Some("docs"), VectorId::Str("a".to_string()))),
+            13900748082171819138
+        );
+        assert_eq!(
+            node_id::<SipNodeIds>(&PropState::Pending((5, 8))),
+            15336893748596415645
+        );
+    }
+
+    #[test]
+    fn test_sub_byte_rejects_invalid_resolution() {
+        for resolution in [0, 9] {
+            assert!(matches!(
+                VectorQt::sub_byte(&[0.5, -0.5], resolution),
+                Err(QuantizationError::InvalidInput)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sub_byte_bit_planes() {
+        let vec: Vec<f32> = (0..12).map(|i| i as f32 / 6.0 - 1.0).collect();
+        for resolution in [2, 4] {
+            let VectorQt::SubByte { quant_vec, .. } = VectorQt::sub_byte(&vec, resolution).unwrap()
+            else {
+                panic!("Expected SubByte");
+            };
+            assert_eq!(quant_vec.len(), resolution as usize);
+            // Eight values per byte, so twelve values take two bytes per plane
+            assert!(quant_vec.iter().all(|plane| plane.len() == 2));
+        }
+    }
+
+    #[test]
+    fn test_versions_keyed_by_version_id() {
+        let node = MergedNode::new(0, 0);
+        node.add_version(Item::new(MergedNode::new(1, 0)));
+        node.add_version(Item::new(MergedNode::new(2, 0)));
+
+        assert_eq!(node.get_versions().len(), 2);
+        let mut version = node.get_version(2).unwrap().get_data().unwrap();
+        assert_eq!(version.get().version_id, 2);
+        assert!(node.get_version(3).is_none());
+    }
+
+    #[test]
+    fn test_prune_versions_keeps_newest() {
+        let node = MergedNode::new(0, 0);
+        for version_id in [3, 1, 5, 2, 4] {
+            node.add_version(Item::new(MergedNode::new(version_id, 0)));
+        }
+
+        assert_eq!(node.prune_versions(2), 3);
+        assert_eq!(node.get_versions().len(), 2);
+        assert!(node.get_version(5).is_some());
+        assert!(node.get_version(4).is_some());
+        assert!(node.get_version(3).is_none());
+        assert_eq!(node.version_chain_len(), 3);
+        // Nothing left to drop
+        assert_eq!(node.prune_versions(2), 0);
+    }
+
+    #[test]
+    fn test_bounded_neighbors_keep_most_similar() {
+        let node = MergedNode::new(0, 0);
+        let similarities = [0.1, 0.9, 0.5, 0.7, 0.3, 0.8];
+        for (id, similarity) in similarities.iter().enumerate() {
+            node.add_ready_neighbor_bounded(
+                node_with_id(id as i32),
+                *similarity,
+                3,
+                &DistanceMetric::Cosine,
+            );
+        }
+
+        assert_eq!(node.neighbors.len(), 3);
+        let mut retained: Vec<f32> = node.neighbors.iter().map(|nbr| nbr.0).collect();
+        retained.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        assert_eq!(retained, vec![0.9, 0.8, 0.7]);
+    }
+
+    #[test]
+    fn test_bounded_neighbors_keep_closest_by_distance() {
+        let node = MergedNode::new(0, 0);
+        let distances = [0.1, 0.9, 0.5, 0.7, 0.3, 0.8];
+        for (id, distance) in distances.iter().enumerate() {
+            node.add_ready_neighbor_bounded(
+                node_with_id(id as i32),
+                *distance,
+                3,
+                &DistanceMetric::Euclidean,
+            );
+        }
+
+        assert_eq!(node.neighbors.len(), 3);
+        let mut retained: Vec<f32> = node.neighbors.iter().map(|nbr| nbr.0).collect();
+        retained.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(retained, vec![0.1, 0.3, 0.5]);
+    }
+
+    #[test]
+    fn test_bounded_neighbors_evict_loaded_entries() {
+        let node = MergedNode::new(0, 0);
+        // Read back from an index file, so their props are still pending
+        let neighbors: Vec<_> = (0..3)
+            .map(|id| {
+                let neighbor = MergedNode::new(0, 0);
+                neighbor.set_prop_pending((id * 64, 64));
+                LazyItem::from_data(neighbor)
+            })
+            .collect();
+        for (neighbor, similarity) in neighbors.iter().zip([0.2, 0.6, 0.4]) {
+            node.add_ready_neighbor_bounded(
+                neighbor.clone(),
+                similarity,
+                3,
+                &DistanceMetric::Cosine,
+            );
+        }
+        // Loading a prop changes its node's id after it was added
+        for (id, neighbor) in neighbors.iter().enumerate() {
+            let prop = node_with_id(id as i32).get_data().unwrap().get().get_prop();
+            let PropState::Ready(prop) = prop else {
+                unreachable!()
+            };
+            neighbor.get_data().unwrap().get().set_prop_ready(prop);
+        }
+
+        node.add_ready_neighbor_bounded(node_with_id(3), 0.8, 3, &DistanceMetric::Cosine);
+        let mut retained: Vec<f32> = node.neighbors.iter().map(|nbr| nbr.0).collect();
+        retained.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        assert_eq!(retained, vec![0.8, 0.6, 0.4]);
+    }
+
+    #[test]
+    fn test_app_env_opens_in_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        // Missing directories are created
+        let data_dir = dir.path().join("nested").join("data");
+        let app_env = open_app_env(&data_dir, DEFAULT_LMDB_MAP_SIZE).unwrap();
+
+        assert_eq!(app_env.data_dir, data_dir);
+        assert!(data_dir.join("data.mdb").exists());
+        assert!(!Path::new(DEFAULT_DATA_DIR).join("nested").exists());
+    }
+
+    #[test]
+    fn test_full_map_grows_instead_of_failing() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = Environment::new()
+            .set_map_size(32768)
+            .open(dir.path())
+            .unwrap();
+        let db = env.open_db(None).unwrap();
+
+        // Far more than the initial map can hold
+        let value = vec![7u8; 1024];
+        for i in 0..256u32 {
+            retry_on_map_full(&env, || {
+                let mut txn = env.begin_rw_txn()?;
+                txn.put(db, &i.to_le_bytes(), &value, WriteFlags::empty())?;
+                txn.commit()
+            })
+            .unwrap();
+        }
+
+        let txn = env.begin_ro_txn().unwrap();
+        assert_eq!(txn.get(db, &255u32.to_le_bytes()).unwrap(), &value[..]);
+    }
+
+    #[test]
+    fn test_weighted_metric_reduces_to_components() {
+        let x = ScalarQuantization.quantize(&[0.1, 0.7, -0.3, 0.5], StorageType::HalfPrecisionFP);
+        let y = ScalarQuantization.quantize(&[0.4, 0.2, 0.6, -0.1], StorageType::HalfPrecisionFP);
+
+        let cosine_only = DistanceMetric::weighted(1.0, 0.0).unwrap();
+        assert_eq!(
+            cosine_only.calculate(&x, &y).unwrap(),
+            1.0 - CosineDistance.calculate(&x, &y).unwrap()
+        );
+
+        let euclidean_only = DistanceMetric::weighted(0.0, 1.0).unwrap();
+        assert_eq!(
+            euclidean_only.calculate(&x, &y).unwrap(),
+            EuclideanDistance.calculate(&x, &y).unwrap()
+        );
+
+        assert!(matches!(
+            DistanceMetric::weighted(0.0, 0.0),
+            Err(WaCustomError::InvalidParams)
+        ));
+    }
+
+    #[test]
+    fn test_weighted_metric_ranks_nearer_first() {
+        // All three point the same way, so only the euclidean term tells them apart
+        let quantize = |value: f32| ScalarQuantization.quantize(&[value; 4], StorageType::Float32);
+        let (query, near, far) = (quantize(0.2), quantize(0.25), quantize(0.9));
+
+        let metric = DistanceMetric::weighted(1.0, 0.5).unwrap();
+        let near_score = metric.calculate(&query, &near).unwrap();
+        let far_score = metric.calculate(&query, &far).unwrap();
+        assert!(near_score < far_score);
+        assert!(metric.compare_scores(near_score, far_score).is_lt());
+    }
+}
@@ -2,20 +2,23 @@ pub mod product;
 pub mod scalar;
 
 use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
 
 pub trait Quantization: std::fmt::Debug + Send + Sync {
     fn quantize(&self, vector: &[f32], storage_type: StorageType) -> Storage;
     fn train(&mut self, vectors: &[Vec<f32>]) -> Result<(), QuantizationError>;
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum StorageType {
     UnsignedByte,
     SubByte(u8),
     HalfPrecisionFP,
+    // Keeps the vector as-is, for exact search at the cost of memory
+    Float32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum QuantizationError {
     InvalidInput,
     TrainingFailed,
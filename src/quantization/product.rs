@@ -1,27 +1,219 @@
-use super::{Quantization, QuantizationError, StorageType};
+use super::{scalar::ScalarQuantization, Quantization, QuantizationError, StorageType};
 use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+const KMEANS_ITERATIONS: usize = 25;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProductQuantization {
+    pub num_subspaces: usize,
+    pub num_centroids: usize,
     centroids: Option<Centroid>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Centroid {
     pub number_of_centroids: u16,
-    pub centroids: Vec<u16>,
+    // One codebook per subspace, each entry being a sub-vector
+    pub centroids: Vec<Vec<Vec<f32>>>,
+}
+
+impl ProductQuantization {
+    pub fn new(num_subspaces: usize, num_centroids: usize) -> Self {
+        ProductQuantization {
+            num_subspaces,
+            num_centroids,
+            centroids: None,
+        }
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.centroids.is_some()
+    }
+
+    // Index of the nearest codebook entry for each subspace of `vector`
+    pub fn encode(&self, vector: &[f32]) -> Option<Vec<u8>> {
+        let centroids = self.centroids.as_ref()?;
+        let sub_dim = vector.len() / self.num_subspaces;
+        let codes = centroids
+            .centroids
+            .iter()
+            .zip(vector.chunks(sub_dim))
+            .map(|(codebook, sub_vec)| nearest_centroid(codebook, sub_vec) as u8)
+            .collect();
+        Some(codes)
+    }
+
+    pub fn decode(&self, codes: &[u8]) -> Option<Vec<f32>> {
+        let centroids = self.centroids.as_ref()?;
+        let vector = centroids
+            .centroids
+            .iter()
+            .zip(codes)
+            .flat_map(|(codebook, &code)| codebook[code as usize].iter().copied())
+            .collect();
+        Some(vector)
+    }
 }
 
 impl Quantization for ProductQuantization {
-    // Implementation here
+    // The vector is snapped to its nearest codebook entries and the reconstruction is
+    // stored with the requested storage type, so the regular distance functions apply
     fn quantize(&self, vector: &[f32], storage_type: StorageType) -> Storage {
-        // Implement product quantization logic here
-        unimplemented!("Product quantization not implemented yet")
+        match self.encode(vector).and_then(|codes| self.decode(&codes)) {
+            Some(reconstructed) => ScalarQuantization.quantize(&reconstructed, storage_type),
+            None => ScalarQuantization.quantize(vector, storage_type),
+        }
     }
 
     fn train(&mut self, vectors: &[Vec<f32>]) -> Result<(), QuantizationError> {
-        // Implement k-means clustering for product quantization
-        // This is where you'd compute and store the centroids
-        unimplemented!("K-means clustering for product quantization not implemented yet")
+        let Some(first) = vectors.first() else {
+            return Err(QuantizationError::InvalidInput);
+        };
+        let dim = first.len();
+        if self.num_subspaces == 0
+            || self.num_centroids == 0
+            || self.num_centroids > u8::MAX as usize + 1
+            || dim % self.num_subspaces != 0
+            || vectors.iter().any(|vec| vec.len() != dim)
+        {
+            return Err(QuantizationError::InvalidInput);
+        }
+
+        let sub_dim = dim / self.num_subspaces;
+        let codebooks = (0..self.num_subspaces)
+            .map(|subspace| {
+                let sub_vectors: Vec<&[f32]> = vectors
+                    .iter()
+                    .map(|vec| &vec[subspace * sub_dim..(subspace + 1) * sub_dim])
+                    .collect();
+                kmeans(&sub_vectors, self.num_centroids)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.centroids = Some(Centroid {
+            number_of_centroids: self.num_centroids as u16,
+            centroids: codebooks,
+        });
+        Ok(())
+    }
+}
+
+fn squared_distance(x: &[f32], y: &[f32]) -> f32 {
+    x.iter().zip(y).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+fn nearest_centroid(codebook: &[Vec<f32>], sub_vec: &[f32]) -> usize {
+    codebook
+        .iter()
+        .enumerate()
+        .map(|(index, centroid)| (index, squared_distance(centroid, sub_vec)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+// Lloyd's algorithm, seeded with the first `k` training points
+fn kmeans(points: &[&[f32]], k: usize) -> Result<Vec<Vec<f32>>, QuantizationError> {
+    let k = k.min(points.len());
+    let mut centroids: Vec<Vec<f32>> = points.iter().take(k).map(|p| p.to_vec()).collect();
+    let dim = centroids
+        .first()
+        .map(|c| c.len())
+        .ok_or(QuantizationError::TrainingFailed)?;
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![vec![0.0f32; dim]; k];
+        let mut counts = vec![0usize; k];
+
+        for point in points {
+            let nearest = nearest_centroid(&centroids, point);
+            counts[nearest] += 1;
+            for (sum, value) in sums[nearest].iter_mut().zip(point.iter()) {
+                *sum += value;
+            }
+        }
+
+        let mut changed = false;
+        for (index, centroid) in centroids.iter_mut().enumerate() {
+            // Empty clusters keep their previous centroid
+            if counts[index] == 0 {
+                continue;
+            }
+            for (value, sum) in centroid.iter_mut().zip(&sums[index]) {
+                let mean = sum / counts[index] as f32;
+                changed |= *value != mean;
+                *value = mean;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    Ok(centroids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_product_quantization_uses_codebook() {
+        let training = vec![
+            vec![0.2, 0.2, 0.8, 0.8],
+            vec![0.8, 0.8, 0.2, 0.2],
+            vec![0.2, 0.2, 0.8, 0.8],
+            vec![0.8, 0.8, 0.2, 0.2],
+        ];
+        let mut pq = ProductQuantization::new(2, 2);
+        pq.train(&training).unwrap();
+
+        let codes = pq.encode(&[0.25, 0.15, 0.75, 0.85]).unwrap();
+        assert_eq!(pq.decode(&codes).unwrap(), vec![0.2, 0.2, 0.8, 0.8]);
+
+        let quantized = pq.quantize(&[0.25, 0.15, 0.75, 0.85], StorageType::UnsignedByte);
+        let expected = ScalarQuantization.quantize(&training[0], StorageType::UnsignedByte);
+        assert_eq!(quantized, expected);
+    }
+
+    #[test]
+    fn test_product_quantization_rejects_bad_input() {
+        let mut pq = ProductQuantization::new(3, 2);
+        assert!(pq.train(&[]).is_err());
+        assert!(pq.train(&[vec![0.1, 0.2, 0.3, 0.4]]).is_err());
+    }
+
+    #[test]
+    fn test_four_subspaces_round_trip() {
+        // Points scattered tightly around a few prototypes, so a small codebook per
+        // subspace can reproduce them closely
+        let prototypes = [
+            [0.9, 0.1, 0.4, 0.6, 0.2, 0.8, 0.5, 0.3],
+            [0.1, 0.9, 0.6, 0.4, 0.8, 0.2, 0.3, 0.5],
+            [0.5, 0.5, 0.9, 0.1, 0.3, 0.3, 0.9, 0.7],
+        ];
+        let vectors: Vec<Vec<f32>> = (0..90)
+            .map(|i| {
+                let jitter = (i / 3) as f32 * 0.001;
+                prototypes[i % 3].iter().map(|x| x + jitter).collect()
+            })
+            .collect();
+        let mut pq = ProductQuantization::new(4, 8);
+        pq.train(&vectors).unwrap();
+
+        for vector in &vectors {
+            let codes = pq.encode(vector).unwrap();
+            assert_eq!(codes.len(), 4);
+            let decoded = pq.decode(&codes).unwrap();
+            assert!(squared_distance(vector, &decoded) < 0.01);
+
+            let Storage::Float32 { quant_vec, .. } = pq.quantize(vector, StorageType::Float32)
+            else {
+                panic!("Expected Float32 storage");
+            };
+            assert_eq!(quant_vec, decoded);
+        }
     }
 }
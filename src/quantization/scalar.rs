@@ -1,5 +1,5 @@
 use super::{Quantization, QuantizationError, StorageType};
-use crate::models::common::quantize_to_u8_bits;
+use crate::models::common::{mag_square_sub_byte, mag_square_u8, quantize_to_u8_bits};
 use crate::storage::Storage;
 use half::f16;
 
@@ -11,22 +11,41 @@ impl Quantization for ScalarQuantization {
         match storage_type {
             StorageType::UnsignedByte => {
                 let quant_vec: Vec<_> = vector.iter().map(|&x| (x * 255.0).round() as u8).collect();
-                let mag = quant_vec.iter().map(|&x| x as u32 * x as u32).sum();
-                Storage::UnsignedByte { mag, quant_vec }
+                let mag = mag_square_u8(&quant_vec);
+                Storage::UnsignedByte {
+                    mag,
+                    norm: (mag as f32).sqrt(),
+                    quant_vec,
+                }
             }
             StorageType::SubByte(resolution) => {
-                let quant_vec: Vec<_> = quantize_to_u8_bits(vector, resolution);
-                let mag = 0; //implement todo
+                // The resolution is checked when the store is created
+                let quant_vec: Vec<_> = quantize_to_u8_bits(vector, resolution)
+                    .expect("Sub-byte resolution must be between 1 and 8");
+                let mag = mag_square_sub_byte(&quant_vec, resolution);
                 Storage::SubByte {
                     mag,
+                    norm: (mag as f32).sqrt(),
                     quant_vec,
                     resolution,
                 }
             }
             StorageType::HalfPrecisionFP => {
                 let quant_vec = vector.iter().map(|&x| f16::from_f32(x)).collect();
-                let mag = vector.iter().map(|&x| x * x).sum();
-                Storage::HalfPrecisionFP { mag, quant_vec }
+                let mag: f32 = vector.iter().map(|&x| x * x).sum();
+                Storage::HalfPrecisionFP {
+                    mag,
+                    norm: mag.sqrt(),
+                    quant_vec,
+                }
+            }
+            StorageType::Float32 => {
+                let mag: f32 = vector.iter().map(|&x| x * x).sum();
+                Storage::Float32 {
+                    mag,
+                    norm: mag.sqrt(),
+                    quant_vec: vector.to_vec(),
+                }
             }
         }
     }
@@ -1,3 +1,4 @@
+use crate::quantization::StorageType;
 use half::f16;
 use serde::{Deserialize, Serialize};
 
@@ -14,15 +15,101 @@ use serde::{Deserialize, Serialize};
 pub enum Storage {
     UnsignedByte {
         mag: u32,
+        // sqrt of `mag`, cached since cosine divides by it on every comparison
+        #[serde(default)]
+        norm: f32,
         quant_vec: Vec<u8>,
     },
     SubByte {
         mag: u32,
+        #[serde(default)]
+        norm: f32,
         quant_vec: Vec<Vec<u8>>,
         resolution: u8,
     },
     HalfPrecisionFP {
         mag: f32,
+        #[serde(default)]
+        norm: f32,
         quant_vec: Vec<f16>,
     },
+    Float32 {
+        mag: f32,
+        #[serde(default)]
+        norm: f32,
+        quant_vec: Vec<f32>,
+    },
+}
+
+impl Storage {
+    pub fn storage_type(&self) -> StorageType {
+        match self {
+            Storage::UnsignedByte { .. } => StorageType::UnsignedByte,
+            Storage::SubByte { resolution, .. } => StorageType::SubByte(*resolution),
+            Storage::HalfPrecisionFP { .. } => StorageType::HalfPrecisionFP,
+            Storage::Float32 { .. } => StorageType::Float32,
+        }
+    }
+
+    // Number of components stored. Sub-byte vectors are padded to a whole byte per plane,
+    // so theirs is a multiple of 8
+    pub fn dim(&self) -> usize {
+        match self {
+            Storage::UnsignedByte { quant_vec, .. } => quant_vec.len(),
+            Storage::SubByte { quant_vec, .. } => {
+                quant_vec.first().map_or(0, |plane| plane.len() * 8)
+            }
+            Storage::HalfPrecisionFP { quant_vec, .. } => quant_vec.len(),
+            Storage::Float32 { quant_vec, .. } => quant_vec.len(),
+        }
+    }
+
+    // Best estimate of the vector that was quantized. Byte and half precision values
+    // are scaled back, and sub-byte levels map to the middle of their bucket. Sub-byte
+    // vectors keep their padding, callers trim them to the dimension they expect
+    pub fn to_f32_vec(&self) -> Vec<f32> {
+        match self {
+            Storage::UnsignedByte { quant_vec, .. } => {
+                quant_vec.iter().map(|&x| x as f32 / 255.0).collect()
+            }
+            Storage::SubByte {
+                quant_vec,
+                resolution,
+                ..
+            } => {
+                let planes = &quant_vec[..quant_vec.len().min(*resolution as usize)];
+                let step = 2.0 / (1u32 << resolution) as f32;
+                (0..self.dim())
+                    .map(|i| {
+                        let level: u32 = planes
+                            .iter()
+                            .enumerate()
+                            .map(|(bit, plane)| (((plane[i / 8] >> (i % 8)) & 1) as u32) << bit)
+                            .sum();
+                        -1.0 + (level as f32 + 0.5) * step
+                    })
+                    .collect()
+            }
+            Storage::HalfPrecisionFP { quant_vec, .. } => {
+                quant_vec.iter().map(|&x| f32::from(x)).collect()
+            }
+            Storage::Float32 { quant_vec, .. } => quant_vec.clone(),
+        }
+    }
+
+    // Records written before the norm was cached read back with a zero norm, those
+    // fall back to computing it from `mag`
+    pub fn norm(&self) -> f32 {
+        let (mag, norm) = match self {
+            Storage::UnsignedByte { mag, norm, .. } => (*mag as f32, *norm),
+            Storage::SubByte { mag, norm, .. } => (*mag as f32, *norm),
+            Storage::HalfPrecisionFP { mag, norm, .. } => (*mag, *norm),
+            Storage::Float32 { mag, norm, .. } => (*mag, *norm),
+        };
+        if norm == 0.0 && mag != 0.0 {
+            mag.sqrt()
+        } else {
+            norm
+        }
+    }
 }
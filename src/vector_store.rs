@@ -1,10 +1,17 @@
 use crate::distance::DistanceFunction;
+use crate::models::cache_loader::resolve_offset;
 use crate::models::common::*;
-use crate::models::custom_buffered_writer::CustomBufferedWriter;
+use crate::models::custom_buffered_writer::{CustomBufferedWriter, BULK_BUFFER_SIZE};
 use crate::models::file_persist::*;
+use crate::models::identity_collections::Identifiable;
+use crate::models::index_reader::remove_index_file;
 use crate::models::lazy_load::*;
 use crate::models::meta_persist::*;
+use crate::models::meta_store::{get_u32, iter_from, MetaStore};
+use crate::models::serializer::CustomSerialize;
+use crate::models::store_file::StoreFile;
 use crate::models::types::*;
+use crate::models::versioning::{VersionHash, VersionHasher};
 use crate::storage::Storage;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use lmdb::Transaction;
@@ -13,29 +20,153 @@ use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 use smallvec::SmallVec;
 use std::array::TryFromSliceError;
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::sync::MutexGuard;
 use std::sync::RwLock;
 
+// Knobs and bookkeeping for one search, shared by every level of its descent
+#[derive(Debug, Clone, Default)]
+pub struct SearchContext {
+    // Below the level the search starts at, neighbors scoring worse than this
+    // aren't expanded further. A node's best neighbor is always expanded so the
+    // search can't strand itself
+    pub min_explore_similarity: Option<f32>,
+    // Once this many neighbors are scored the search stops expanding and settles for
+    // the best it has found, bounding how long a query can hold a worker
+    pub max_nodes_visited: Option<usize>,
+    // Neighbors scored so far
+    pub nodes_visited: usize,
+    // Set when `max_nodes_visited` cut the search short
+    pub truncated: bool,
+    // Set it to an empty trace to have the search record every node it scores
+    pub trace: Option<SearchTrace>,
+    // The level the search started at, filled in by the search itself
+    pub(crate) top_level: Option<i8>,
+}
+
+// What a search did with a node it scored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDecision {
+    // The search entered the level through it
+    Entry,
+    // Its neighbors were searched in turn
+    Expanded,
+    // Scored worse than `min_explore_similarity`, so left unexpanded
+    Pruned,
+    // Too many hops away from the level's entry to expand
+    OutOfHops,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    pub level: i8,
+    pub id: VectorId,
+    pub namespace: Option<String>,
+    pub score: f32,
+    pub decision: TraceDecision,
+}
+
+// The path a search took, in the order it scored nodes. Only recorded when asked for
+// through `SearchContext::trace`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchTrace {
+    pub steps: Vec<TraceStep>,
+}
+
+impl SearchTrace {
+    // Ids scored on `level`, in the order the search reached them
+    pub fn visited_at(&self, level: i8) -> Vec<&VectorId> {
+        self.steps
+            .iter()
+            .filter(|step| step.level == level)
+            .map(|step| &step.id)
+            .collect()
+    }
+
+    // A pruned node is still expanded when it was the best the level had
+    fn mark_expanded(&mut self, level: i8, id: &NamespacedId) {
+        if let Some(step) = self
+            .steps
+            .iter_mut()
+            .rev()
+            .find(|step| step.level == level && step.id == id.1 && step.namespace == id.0)
+        {
+            step.decision = TraceDecision::Expanded;
+        }
+    }
+}
+
+impl SearchContext {
+    pub fn for_store(vec_store: &VectorStore) -> Self {
+        SearchContext {
+            min_explore_similarity: vec_store.min_explore_similarity,
+            ..Default::default()
+        }
+    }
+
+    fn record(&mut self, level: i8, node_prop: &NodeProp, score: f32, decision: TraceDecision) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.steps.push(TraceStep {
+                level,
+                id: node_prop.id.clone(),
+                namespace: node_prop.namespace.clone(),
+                score,
+                decision,
+            });
+        }
+    }
+
+    fn out_of_budget(&self) -> bool {
+        self.max_nodes_visited
+            .map_or(false, |max| self.nodes_visited >= max)
+    }
+
+    // Whether a neighbor with this score is too weak to expand at `level`
+    fn prunes(&self, metric: &DistanceMetric, score: f32, level: i8) -> bool {
+        match (self.min_explore_similarity, self.top_level) {
+            (Some(floor), Some(top)) => level < top && metric.compare_scores(score, floor).is_gt(),
+            _ => false,
+        }
+    }
+}
+
 pub fn ann_search(
     vec_store: Arc<VectorStore>,
     vector_emb: VectorEmbedding,
     cur_entry: LazyItem<MergedNode>,
     cur_level: i8,
-) -> Result<Option<Vec<(LazyItem<MergedNode>, f32)>>, WaCustomError> {
+) -> Result<Option<ScoredNodes>, WaCustomError> {
+    let mut ctx = SearchContext::for_store(&vec_store);
+    ann_search_with_context(vec_store, vector_emb, cur_entry, cur_level, &mut ctx)
+}
+
+pub fn ann_search_with_context(
+    vec_store: Arc<VectorStore>,
+    vector_emb: VectorEmbedding,
+    cur_entry: LazyItem<MergedNode>,
+    cur_level: i8,
+    ctx: &mut SearchContext,
+) -> Result<Option<ScoredNodes>, WaCustomError> {
     if cur_level == -1 {
         return Ok(Some(vec![]));
     }
+    ctx.top_level.get_or_insert(cur_level);
+    let cur_entry = entry_at_level(cur_entry, cur_level);
 
     let fvec = vector_emb.raw_vec.clone();
     let mut skipm = HashSet::new();
-    skipm.insert(vector_emb.hash_vec.clone());
+    skipm.insert((vector_emb.namespace.clone(), vector_emb.hash_vec.clone()));
 
     let mut cur_node_arc = match cur_entry.clone() {
         LazyItem::Valid {
@@ -65,33 +196,20 @@ pub fn ann_search(
     };
 
     let cur_node = cur_node_arc.get();
-
-    let mut prop_arc = cur_node.prop.clone();
-    let prop_state = prop_arc.get();
-
-    let node_prop = match &*prop_state {
-        PropState::Ready(prop) => prop,
-        PropState::Pending(_) => {
-            return Err(WaCustomError::NodeError(
-                "Node prop is in pending state".to_string(),
-            ))
-        }
-    };
-
-    let z = traverse_find_nearest(
-        vec_store.clone(),
-        cur_entry.clone(),
-        fvec.clone(),
-        vector_emb.hash_vec.clone(),
-        0,
-        &mut skipm,
-        cur_level,
-        false,
-    )?;
+    let node_prop = get_or_load_prop(cur_node, &vec_store)?;
 
     let dist = vec_store
-        .distance_metric
+        .get_distance_metric()
         .calculate(&fvec, &node_prop.value)?;
+    ctx.record(cur_level, &node_prop, dist, TraceDecision::Entry);
+
+    let params = TraverseParams {
+        vec_store: &vec_store,
+        fvec: &fvec,
+        cur_level,
+        skip_hop: false,
+    };
+    let z = traverse_find_nearest(&params, cur_entry.clone(), 0, &mut skipm, ctx)?;
 
     let z = if z.is_empty() {
         vec![(cur_entry.clone(), dist)]
@@ -99,93 +217,45 @@ pub fn ann_search(
         z
     };
 
-    let result = ann_search(
+    let result = ann_search_with_context(
         vec_store.clone(),
         vector_emb.clone(),
         z[0].0.clone(),
         cur_level - 1,
+        ctx,
     )?;
 
     Ok(add_option_vecs(&result, &Some(z)))
 }
 
+// Returns the level 0 neighbors of `vector_id`, each with the similarity stored on
+// its link when the edge was made
 pub fn vector_fetch(
     vec_store: Arc<VectorStore>,
     vector_id: VectorId,
-) -> Result<Vec<Option<(VectorId, Vec<(VectorId, f32)>)>>, WaCustomError> {
-    let mut results = Vec::new();
-
-    for lev in 0..vec_store.max_cache_level {
-        let maybe_res = load_vector_id_lsmdb(lev, vector_id.clone());
-        let neighbors = match maybe_res {
-            LazyItem::Valid {
-                data: Some(vth), ..
-            } => {
-                let mut vth = vth.clone();
-                let nes: Vec<(VectorId, f32)> = vth
-                    .get()
-                    .neighbors
-                    .iter()
-                    .filter_map(|ne| match ne.1.clone() {
-                        LazyItem::Valid {
-                            data: Some(node), ..
-                        } => get_vector_id_from_node(node.clone().get()).map(|id| (id, ne.0)),
-                        LazyItem::Valid {
-                            data: None,
-                            mut offset,
-                            ..
-                        } => {
-                            if let Some(xloc) = offset.get() {
-                                match load_neighbor_from_db(*xloc, &vec_store) {
-                                    Ok(Some(info)) => Some(info),
-                                    Ok(None) => None,
-                                    Err(e) => {
-                                        eprintln!("Error loading neighbor: {}", e);
-                                        None
-                                    }
-                                }
-                            } else {
-                                None
-                            }
-                        }
-                        _ => None,
-                    })
-                    .collect();
-                Some((vector_id.clone(), nes))
-            }
-            LazyItem::Valid {
-                data: None,
-                mut offset,
-                ..
-            } => {
-                if let Some(xloc) = offset.get() {
-                    match load_node_from_persist(*xloc, &vec_store) {
-                        Ok(Some((id, neighbors))) => Some((id, neighbors)),
-                        Ok(None) => None,
-                        Err(e) => {
-                            eprintln!("Error loading vector: {}", e);
-                            None
-                        }
-                    }
-                } else {
-                    None
-                }
-            }
-            _ => None,
+) -> Result<(VectorId, Vec<(VectorId, f32)>), WaCustomError> {
+    let mut node_arc = find_nodes_by_id(&vec_store, &vector_id)?
+        .into_iter()
+        .filter_map(|lazy_node| lazy_node.get_data())
+        .find(|node_arc| node_arc.clone().get().hnsw_level == 0)
+        .ok_or_else(|| WaCustomError::NotFound(vector_id.clone()))?;
+    let node = node_arc.get();
+
+    let mut neighbors = Vec::new();
+    for nbr in node.neighbors.iter() {
+        let Some(mut neighbor_arc) = nbr.1.get_data() else {
+            return Err(WaCustomError::LazyLoadingError(format!(
+                "Neighbor of {} needs to be loaded",
+                vector_id
+            )));
         };
-        results.push(neighbors);
+        let neighbor_prop = get_or_load_prop(neighbor_arc.get(), &vec_store)?;
+        if neighbor_prop.id != VectorId::Int(-1) {
+            neighbors.push((neighbor_prop.id.clone(), nbr.0));
+        }
     }
-    Ok(results)
-}
-fn load_node_from_persist(
-    offset: FileOffset,
-    vec_store: &Arc<VectorStore>,
-) -> Result<Option<(VectorId, Vec<(VectorId, f32)>)>, WaCustomError> {
-    // Placeholder function to load vector from database
-    // TODO: Implement actual database loading logic
-    Err(WaCustomError::LazyLoadingError(
-        "Not implemented".to_string(),
-    ))
+
+    Ok((vector_id, neighbors))
 }
 
 // fn get_neighbor_info(nbr: &Neighbour) -> Option<(VectorId, f32)> {
@@ -212,25 +282,26 @@ fn load_node_from_persist(
 //     }
 // }
 
-fn get_vector_id_from_node(node: &MergedNode) -> Option<VectorId> {
+// Nodes read back from an index file only know where their prop lives in the prop
+// file, so it's loaded on first access. Props are shared through the store's cache,
+// so another node pointing at the same location skips the read
+pub fn get_or_load_prop(
+    node: &MergedNode,
+    vec_store: &VectorStore,
+) -> Result<Arc<NodeProp>, WaCustomError> {
     let mut prop_arc = node.prop.clone();
     match prop_arc.get() {
-        PropState::Ready(node_prop) => Some(node_prop.id.clone()),
-        PropState::Pending(_) => None,
+        PropState::Ready(prop) => Ok(prop.clone()),
+        PropState::Pending(location) => {
+            let prop = vec_store
+                .prop_cache
+                .get_or_load(*location, vec_store.prop_file.handle())?;
+            node.set_prop_ready(prop.clone());
+            Ok(prop)
+        }
     }
 }
 
-fn load_neighbor_from_db(
-    offset: FileOffset,
-    vec_store: &Arc<VectorStore>,
-) -> Result<Option<(VectorId, f32)>, WaCustomError> {
-    // Placeholder function to load neighbor from database
-    // TODO: Implement actual database loading logic
-    Err(WaCustomError::LazyLoadingError(
-        "Not implemented".to_string(),
-    ))
-}
-
 pub fn write_embedding<W: Write + Seek>(
     writer: &mut W,
     emb: &VectorEmbedding,
@@ -241,17 +312,11 @@ pub fn write_embedding<W: Write + Seek>(
 
     let len = serialized.len() as u32;
 
-    let start = writer
-        .stream_position()
-        .map_err(|e| WaCustomError::FsError(e.to_string()))? as u32;
+    let start = writer.stream_position()? as u32;
 
-    writer
-        .write_u32::<LittleEndian>(len)
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    writer.write_u32::<LittleEndian>(len)?;
 
-    writer
-        .write_all(&serialized)
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    writer.write_all(&serialized)?;
 
     Ok(start)
 }
@@ -260,143 +325,509 @@ fn read_embedding<R: Read + Seek>(
     reader: &mut R,
     offset: u32,
 ) -> Result<(VectorEmbedding, u32), WaCustomError> {
-    reader
-        .seek(SeekFrom::Start(offset as u64))
-        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    reader.seek(SeekFrom::Start(offset as u64))?;
 
-    let len = reader
-        .read_u32::<LittleEndian>()
-        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    let len = reader.read_u32::<LittleEndian>()?;
 
     let mut buf = vec![0; len as usize];
 
-    reader
-        .read_exact(&mut buf)
-        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    reader.read_exact(&mut buf)?;
 
     let emb = unsafe { rkyv::from_bytes_unchecked(&buf) }.map_err(|e| {
         WaCustomError::DeserializationError(format!("Failed to deserialize VectorEmbedding: {}", e))
     })?;
 
-    let next = reader
-        .stream_position()
-        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))? as u32;
+    let next = reader.stream_position()? as u32;
 
     Ok((emb, next))
 }
 
+// The raw embeddings `vec_store` appends to, its own buffer if it lives in memory
+// and otherwise the `vec_raw.0` in the store's own directory
+fn open_raw_embeddings(vec_store: &VectorStore, append: bool) -> Result<StoreFile, WaCustomError> {
+    let file = match &vec_store.memory {
+        Some(memory) => return Ok(StoreFile::Memory(memory.raw_embeddings.clone())),
+        None if append => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(vec_store.base_path.join("vec_raw.0"))?,
+        None => File::open(vec_store.base_path.join("vec_raw.0"))?,
+    };
+    Ok(StoreFile::Disk(file))
+}
+
+// Fails with `DuplicateId` if the store already has a vector with the same id, use
+// `update_embedding` to change an existing vector
 pub fn insert_embedding(
     vec_store: Arc<VectorStore>,
     emb: &VectorEmbedding,
 ) -> Result<(), WaCustomError> {
+    insert_embeddings(&vec_store, std::slice::from_ref(emb))?.remove(0)
+}
+
+// Inserts a batch of embeddings in a single LMDB write transaction. An error for the
+// whole batch is returned as such, otherwise there's one result per embedding, in
+// order, failing with `DuplicateId` for ids already taken, earlier ones in the batch
+// included
+pub fn insert_embeddings(
+    vec_store: &VectorStore,
+    embs: &[VectorEmbedding],
+) -> Result<Vec<Result<(), WaCustomError>>, WaCustomError> {
     let env = vec_store.lmdb.env.clone();
     let embedding_db = vec_store.lmdb.embeddings_db.clone();
     let metadata_db = vec_store.lmdb.metadata_db.clone();
 
-    let mut txn = env
-        .begin_rw_txn()
-        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let raw_file = open_raw_embeddings(vec_store, true)?;
+    let mut file = raw_file.handle();
+    // Appends land at the end whatever the position, it's moved there so the offset
+    // `write_embedding` reports is where the embedding ends up
+    file.seek(SeekFrom::End(0))?;
+
+    let embedding_keys: Vec<String> = embs
+        .iter()
+        .map(|emb| {
+            embedding_key(
+                &vec_store.database_name,
+                emb.namespace.as_deref(),
+                &emb.hash_vec,
+            )
+        })
+        .collect();
+    let count_total_key = count_total_key(&vec_store.database_name);
+    let count_unindexed_key = indexing_counter_key("count_unindexed", &vec_store.database_name);
+    let mut written_offsets = vec![None; embs.len()];
+
+    // A full map is grown and the whole transaction replayed. An embedding is only
+    // appended once its id is known to be free, and the write transaction is held
+    // meanwhile so a concurrent insert of the same id can't slip in between
+    retry_on_map_full(&env, || {
+        let mut txn = vec_store.lmdb.begin_rw_txn()?;
+        let mut results = Vec::with_capacity(embs.len());
+        let mut inserted = 0;
+
+        for ((emb, embedding_key), written_offset) in embs
+            .iter()
+            .zip(&embedding_keys)
+            .zip(written_offsets.iter_mut())
+        {
+            match txn.get(*embedding_db, embedding_key) {
+                Ok(_) => {
+                    results.push(Err(WaCustomError::DuplicateId(emb.hash_vec.clone())));
+                    continue;
+                }
+                Err(lmdb::Error::NotFound) => {}
+                Err(err) => return Err(err),
+            }
+
+            let offset = match written_offset {
+                Some(offset) => *offset,
+                None => match write_embedding(&mut file, emb) {
+                    Ok(offset) => *written_offset.insert(offset.to_le_bytes()),
+                    Err(err) => {
+                        results.push(Err(err));
+                        continue;
+                    }
+                },
+            };
+
+            txn.put(*embedding_db, embedding_key, &offset, WriteFlags::empty())?;
+            inserted += 1;
+            results.push(Ok(()));
+        }
 
-    let count_unindexed = match txn.get(*metadata_db, &"count_unindexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
+        if inserted == 0 {
+            txn.abort();
+            return Ok(results);
+        }
+
+        let count_unindexed = match txn.get(*metadata_db, &count_unindexed_key) {
+            Ok(bytes) => {
+                let bytes = bytes.try_into().map_err(|_| lmdb::Error::BadValSize)?;
+                u32::from_le_bytes(bytes)
+            }
+            Err(lmdb::Error::NotFound) => 0,
+            Err(err) => return Err(err),
+        };
+
+        let count_total = match txn.get(*metadata_db, &count_total_key) {
+            Ok(bytes) => {
+                let bytes = bytes.try_into().map_err(|_| lmdb::Error::BadValSize)?;
+                u64::from_le_bytes(bytes)
+            }
+            Err(lmdb::Error::NotFound) => 0,
+            Err(err) => return Err(err),
+        };
+
+        txn.put(
+            *metadata_db,
+            &count_unindexed_key,
+            &(count_unindexed + inserted as u32).to_le_bytes(),
+            WriteFlags::empty(),
+        )?;
+
+        txn.put(
+            *metadata_db,
+            &count_total_key,
+            &(count_total + inserted as u64).to_le_bytes(),
+            WriteFlags::empty(),
+        )?;
+
+        txn.commit()?;
+        Ok(results)
+    })
+}
+
+// Total number of vectors inserted into the store
+pub fn vector_count(vec_store: &VectorStore) -> Result<u64, WaCustomError> {
+    let count_total = match vec_store
+        .lmdb
+        .get(count_total_key(&vec_store.database_name).as_bytes())?
+    {
+        Some(bytes) => {
+            let bytes = bytes.try_into().map_err(|_| {
+                WaCustomError::DeserializationError("Invalid `count_total` record".to_string())
             })?;
-            u32::from_le_bytes(bytes)
+            u64::from_le_bytes(bytes)
         }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+        None => 0,
     };
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open("vec_raw.0")
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
-
-    let offset = write_embedding(&mut file, emb)?.to_le_bytes();
-
-    txn.put(
-        *embedding_db,
-        &emb.hash_vec.to_string(),
-        &offset,
-        WriteFlags::empty(),
-    )
-    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
-
-    txn.put(
-        *metadata_db,
-        &"count_unindexed",
-        &(count_unindexed + 1).to_le_bytes(),
-        WriteFlags::empty(),
-    )
-    .map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
-    })?;
+    Ok(count_total)
+}
 
-    txn.commit().map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
-    })?;
+// Resets the total to zero, for a store that starts over from empty files
+pub fn clear_vector_count(meta: &dyn MetaStore, database_name: &str) -> Result<(), WaCustomError> {
+    meta.delete(count_total_key(database_name).as_bytes())
+}
 
-    Ok(())
+// Ids read from LMDB per transaction by `iter_vectors`
+const ITER_BATCH_SIZE: usize = 256;
+
+// Yields every vector inserted into the store, in key order, read back from the raw
+// embedding file. Ids are fetched from LMDB a batch at a time and no transaction is
+// held between batches, so the store is never loaded whole
+pub fn iter_vectors(
+    vec_store: Arc<VectorStore>,
+) -> impl Iterator<Item = Result<(VectorId, Arc<NodeProp>), WaCustomError>> {
+    let prefix = format!("{}:", vec_store.database_name);
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut batch: VecDeque<u32> = VecDeque::new();
+    let mut raw_file: Option<StoreFile> = None;
+    let mut done = false;
+
+    std::iter::from_fn(move || {
+        if batch.is_empty() && !done {
+            let entries = match embedding_offsets_after(
+                &vec_store.lmdb,
+                &prefix,
+                last_key.as_deref(),
+                ITER_BATCH_SIZE,
+            ) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    done = true;
+                    return Some(Err(err));
+                }
+            };
+            done = entries.len() < ITER_BATCH_SIZE;
+            last_key = entries.last().map(|(key, _)| key.clone());
+            batch.extend(entries.into_iter().map(|(_, offset)| offset));
+        }
+        let offset = batch.pop_front()?;
+
+        if raw_file.is_none() {
+            match open_raw_embeddings(&vec_store, false) {
+                Ok(file) => raw_file = Some(file),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        let file = raw_file.as_ref()?;
+        Some(read_embedding(&mut file.handle(), offset).map(|(emb, _)| {
+            let prop = raw_embedding_prop(emb);
+            (prop.id.clone(), prop)
+        }))
+    })
 }
 
-pub fn index_embeddings(
+// Props of vectors read back from the raw embedding file, which keeps no metadata
+fn raw_embedding_prop(emb: VectorEmbedding) -> Arc<NodeProp> {
+    Arc::new(NodeProp {
+        id: emb.hash_vec,
+        value: emb.raw_vec,
+        location: None,
+        metadata: None,
+        namespace: emb.namespace,
+        exact: emb.exact,
+    })
+}
+
+// Returns the stored prop of `id`, or `None` if the store doesn't have it. Once the
+// vector is indexed its prop comes from the graph, which reflects updates and
+// metadata, otherwise it's read from the raw embedding file
+pub fn get_embedding(
     vec_store: Arc<VectorStore>,
-    batch_size: usize,
-) -> Result<(), WaCustomError> {
-    let env = vec_store.lmdb.env.clone();
-    let metadata_db = vec_store.lmdb.metadata_db.clone();
+    id: VectorId,
+) -> Result<Option<Arc<NodeProp>>, WaCustomError> {
+    let embedding_key = embedding_key(&vec_store.database_name, None, &id);
+    let Some(offset) = embedding_offset(&vec_store.lmdb, &embedding_key)? else {
+        return Ok(None);
+    };
 
-    let txn = env
+    for lazy_node in find_nodes_by_id(&vec_store, &id)? {
+        if let Some(mut node_arc) = lazy_node.get_data() {
+            return get_or_load_prop(node_arc.get(), &vec_store).map(Some);
+        }
+    }
+
+    let raw_file = open_raw_embeddings(&vec_store, false)?;
+    let (emb, _) = read_embedding(&mut raw_file.handle(), offset)?;
+    Ok(Some(raw_embedding_prop(emb)))
+}
+
+// Where the embedding stored under `embedding_key` starts in the raw file, `None` if
+// the store doesn't have it
+fn embedding_offset(lmdb: &MetaDb, embedding_key: &str) -> Result<Option<u32>, WaCustomError> {
+    let txn = lmdb
+        .env
         .begin_ro_txn()
         .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    match txn.get(*lmdb.embeddings_db, &embedding_key) {
+        Ok(bytes) => Ok(Some(u32::from_le_bytes(bytes.try_into().map_err(
+            |e: TryFromSliceError| WaCustomError::DeserializationError(e.to_string()),
+        )?))),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(err) => Err(WaCustomError::DatabaseError(err.to_string())),
+    }
+}
 
-    let mut count_indexed = match txn.get(*metadata_db, &"count_indexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
+// Up to `limit` of the store's embedding keys that sort after `after`, with the
+// offset of each embedding in the raw file
+fn embedding_offsets_after(
+    lmdb: &MetaDb,
+    prefix: &str,
+    after: Option<&[u8]>,
+    limit: usize,
+) -> Result<Vec<(Vec<u8>, u32)>, WaCustomError> {
+    let txn = lmdb
+        .env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let mut cursor = txn
+        .open_ro_cursor(*lmdb.embeddings_db)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+    let start = after.unwrap_or(prefix.as_bytes());
+    let mut entries = Vec::new();
+    for (key, value) in iter_from(&mut cursor, start)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?
+        .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+        .filter(|(key, _)| Some(*key) != after)
+        .take(limit)
+    {
+        let bytes = value
+            .try_into()
+            .map_err(|e: TryFromSliceError| WaCustomError::DeserializationError(e.to_string()))?;
+        entries.push((key.to_vec(), u32::from_le_bytes(bytes)));
+    }
+
+    Ok(entries)
+}
+
+// Calls `visit` once for every node reachable from the root, on every level. The
+// root placeholder is left out
+fn visit_nodes(
+    vec_store: &VectorStore,
+    mut visit: impl FnMut(&MergedNode),
+) -> Result<(), WaCustomError> {
+    let root = vec_store.root_vec.item.clone().get().clone();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([root]);
+
+    while let Some(lazy_node) = queue.pop_front() {
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+        let node_prop = get_or_load_prop(node, vec_store)?;
+        if !visited.insert((node_prop.namespaced_id(), node.hnsw_level)) {
+            continue;
         }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
+        queue.extend(node.neighbors.iter().map(|nbr| nbr.1));
+        queue.push_back(node.get_parent().item.clone().get().clone());
+        queue.push_back(node.get_child().item.clone().get().clone());
 
-    let mut count_unindexed = match txn.get(*metadata_db, &"count_unindexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
+        if node_prop.id != VectorId::Int(-1) {
+            visit(node);
         }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
+    }
 
-    let next_file_offset = match txn.get(*metadata_db, &"next_file_offset") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
+    Ok(())
+}
+
+// Walks every node reachable from the root and returns how many nodes each level has
+// along with how many neighbor links they hold, level 0 first. The root placeholder is
+// left out of both
+pub fn level_counts(vec_store: &VectorStore) -> Result<Vec<(usize, usize)>, WaCustomError> {
+    let mut counts = Vec::new();
+    visit_nodes(vec_store, |node| {
+        let level = node.hnsw_level as usize;
+        if counts.len() <= level {
+            counts.resize(level + 1, (0, 0));
         }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
+        counts[level].0 += 1;
+        counts[level].1 += node.neighbor_count();
+    })?;
+    Ok(counts)
+}
 
-    txn.abort();
+// How many nodes have each neighbor count, over all levels. A pile up at
+// `max_neighbors` or near zero points at an over or under connected graph
+pub fn graph_degree_histogram(
+    vec_store: &VectorStore,
+) -> Result<BTreeMap<usize, usize>, WaCustomError> {
+    let mut histogram = BTreeMap::new();
+    visit_nodes(vec_store, |node| {
+        *histogram.entry(node.neighbor_count()).or_insert(0) += 1;
+    })?;
+    Ok(histogram)
+}
 
-    let mut file = OpenOptions::new()
-        .read(true)
-        .open("vec_raw.0")
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+// Prunes the version history of every node reachable from the root down to the
+// `keep_latest` newest versions, returning how many versions were dropped. The
+// nodes shrink on disk the next time they're persisted
+pub fn prune_versions(vec_store: &VectorStore, keep_latest: usize) -> Result<usize, WaCustomError> {
+    let mut pruned = 0;
+    visit_nodes(vec_store, |node| {
+        pruned += node.prune_versions(keep_latest);
+    })?;
+    Ok(pruned)
+}
+
+// Forgets all but the `keep` newest committed versions, returning the versions
+// forgotten, then removes the index files no version left needs. The current version
+// is always kept, it's the one the store is reloaded from. Commits append to one file
+// until `compact_index` starts another, so disk space only comes back once every
+// version in a file older than that is forgotten. A file that's being searched is
+// removed once the search is done
+pub fn gc_old_versions(vec_store: &VectorStore, keep: usize) -> Result<Vec<u32>, WaCustomError> {
+    if vec_store.memory.is_some() {
+        return Ok(Vec::new());
+    }
+    // Keeps commits from adding a version or a file in the middle of it
+    let log = lock_index_log(vec_store)?;
+    forget_old_versions(vec_store, keep, log.file_version)
+}
+
+// `gc_old_versions` for a caller that holds the index log, whose file is `in_use`
+fn forget_old_versions(
+    vec_store: &VectorStore,
+    keep: usize,
+    in_use: u32,
+) -> Result<Vec<u32>, WaCustomError> {
+    let current = vec_store
+        .get_current_version()
+        .ok_or_else(|| WaCustomError::DatabaseError("No current version found".to_string()))?
+        .version;
+
+    let mut older = Vec::new();
+    let mut needed = HashSet::from([in_use]);
+    for root in retrieve_version_roots(&vec_store.lmdb, &vec_store.database_name)? {
+        if root.version < current {
+            older.push(root);
+        } else {
+            needed.insert(root.file_version);
+        }
+    }
+    // Newest first, the current version takes one of the `keep` places
+    older.sort_unstable_by(|a, b| b.version.cmp(&a.version));
+    let removed = older.split_off(keep.saturating_sub(1).min(older.len()));
+    needed.extend(older.iter().map(|root| root.file_version));
+
+    for root in &removed {
+        let vec_hash = VersionHasher::new().generate_hash("main", root.version, None, None);
+        clear_version_root(&vec_store.lmdb, &vec_store.database_name, &vec_hash)?;
+    }
+    for entry in std::fs::read_dir(&vec_store.base_path)? {
+        let file_name = entry?.file_name();
+        let file_version = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".index"))
+            .and_then(|version| version.parse::<u32>().ok());
+        if let Some(file_version) = file_version.filter(|version| !needed.contains(version)) {
+            remove_index_file(&vec_store.index_file_path(file_version))?;
+        }
+    }
+    Ok(removed.into_iter().map(|root| root.version).collect())
+}
+
+fn count_total_key(database_name: &str) -> String {
+    format!("count_total:{}", database_name)
+}
+
+// How far indexing got through the store's raw embeddings, each store keeps its own
+pub fn indexing_counter_key(counter: &str, database_name: &str) -> String {
+    format!("{}:{}", counter, database_name)
+}
+
+// Forgets how far indexing got, for a store that starts over from empty files
+pub fn clear_indexing_counters(
+    meta: &dyn MetaStore,
+    database_name: &str,
+) -> Result<(), WaCustomError> {
+    for counter in ["count_indexed", "count_unindexed", "next_file_offset"] {
+        meta.delete(indexing_counter_key(counter, database_name).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Ids are only unique within a store and namespace, so they're prefixed with both.
+// Store names can't hold a `:`, so one store's prefix never starts another's. The
+// default namespace keeps the plain `store:id` keys, others are set off from the id
+// by a NUL, which namespace names aren't expected to hold
+fn embedding_key(database_name: &str, namespace: Option<&str>, id: &VectorId) -> String {
+    match namespace {
+        Some(namespace) => format!("{}:{}\0{}", database_name, namespace, id),
+        None => format!("{}:{}", database_name, id),
+    }
+}
+
+// Forgets every id inserted into the store, so a freshly created store doesn't
+// inherit the ids of an earlier one with the same name
+pub fn clear_embedding_ids(lmdb: &MetaDb, database_name: &str) -> Result<(), WaCustomError> {
+    let env = lmdb.env.clone();
+    let embedding_db = lmdb.embeddings_db.clone();
+    let prefix = format!("{}:", database_name);
+
+    retry_on_map_full(&env, || {
+        let mut txn = env.begin_rw_txn()?;
+        let mut cursor = txn.open_ro_cursor(*embedding_db)?;
+        let keys: Vec<Vec<u8>> = iter_from(&mut cursor, prefix.as_bytes())?
+            .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        drop(cursor);
+        for key in keys {
+            txn.del(*embedding_db, &key, None)?;
+        }
+        txn.commit()
+    })
+}
 
-    let metadata = file
-        .metadata()
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
-    let len = metadata.len() as u32;
+pub fn index_embeddings(
+    vec_store: Arc<VectorStore>,
+    batch_size: usize,
+) -> Result<(), WaCustomError> {
+    let meta: &dyn MetaStore = &vec_store.lmdb;
+    let count_indexed_key = indexing_counter_key("count_indexed", &vec_store.database_name);
+    let count_unindexed_key = indexing_counter_key("count_unindexed", &vec_store.database_name);
+    let next_file_offset_key = indexing_counter_key("next_file_offset", &vec_store.database_name);
+    let mut count_indexed = get_u32(meta, &count_indexed_key)?;
+    let mut count_unindexed = get_u32(meta, &count_unindexed_key)?;
+    let next_file_offset = get_u32(meta, &next_file_offset_key)?;
+
+    let raw_file = open_raw_embeddings(&vec_store, false)?;
+    let len = raw_file.len()? as u32;
+    let mut file = raw_file.handle();
 
     let mut i = next_file_offset;
     let mut embeddings = Vec::new();
@@ -404,7 +835,15 @@ pub fn index_embeddings(
     // `file` is not thread safe, so we have to collect all the embeddings in the current thread
     while i < len {
         let (embedding, next) = read_embedding(&mut file, i)?;
-        embeddings.push(embedding);
+        // Deleted before it got here, or deleted and inserted again further on
+        let key = embedding_key(
+            &vec_store.database_name,
+            embedding.namespace.as_deref(),
+            &embedding.hash_vec,
+        );
+        if embedding_offset(&vec_store.lmdb, &key)? == Some(i) {
+            embeddings.push(embedding);
+        }
         i = next;
 
         if embeddings.len() == batch_size || i == len {
@@ -432,49 +871,25 @@ pub fn index_embeddings(
             count_indexed += batch_size;
             count_unindexed -= batch_size;
 
-            let mut txn = env.begin_rw_txn().map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
-            })?;
-
-            txn.put(
-                *metadata_db,
-                &"count_indexed",
-                &count_indexed.to_le_bytes(),
-                WriteFlags::empty(),
-            )
-            .map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to update `count_indexed`: {}", e))
-            })?;
-
+            let mut txn = meta.begin_txn()?;
+            txn.put(count_indexed_key.as_bytes(), &count_indexed.to_le_bytes())?;
             txn.put(
-                *metadata_db,
-                &"count_unindexed",
+                count_unindexed_key.as_bytes(),
                 &count_unindexed.to_le_bytes(),
-                WriteFlags::empty(),
-            )
-            .map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
-            })?;
-
-            txn.put(
-                *metadata_db,
-                &"next_file_offset",
-                &i.to_le_bytes(),
-                WriteFlags::empty(),
-            )
-            .map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to update `next_file_offset`: {}", e))
-            })?;
-
-            txn.commit().map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
-            })?;
+            )?;
+            txn.put(next_file_offset_key.as_bytes(), &i.to_le_bytes())?;
+            txn.commit()?;
         }
     }
 
     Ok(())
 }
 
+// Inserts the embedding on every level up to `max_insert_level`. The search starts
+// from the top level copy of `cur_entry` and each level below is entered through the
+// copy of the closest node found on the level above. On every level the embedding
+// lives on, a node is linked both ways with its nearest neighbors and to its own
+// copies on the adjacent levels
 pub fn index_embedding(
     vec_store: Arc<VectorStore>,
     vector_emb: VectorEmbedding,
@@ -482,15 +897,48 @@ pub fn index_embedding(
     cur_level: i8,
     max_insert_level: i8,
 ) -> Result<(), WaCustomError> {
-    if cur_level == -1 {
-        return Ok(());
+    let fvec = vector_emb.raw_vec.clone();
+    let key = (vector_emb.namespace.clone(), vector_emb.hash_vec.clone());
+    let mut entry = cur_entry;
+    // The copy inserted on the level above, linked once its lower copy exists
+    let mut upper: Option<LazyItem<MergedNode>> = None;
+
+    for level in (0..=cur_level).rev() {
+        entry = entry_at_level(entry, level);
+        let nbs = nearest_on_level(&vec_store, &entry, &fvec, &key, level)?;
+        let closest = nbs[0].0.clone();
+
+        if level <= max_insert_level {
+            let node = insert_node_create_edges(vec_store.clone(), &vector_emb, nbs, level)?;
+            if let Some(upper) = upper.take() {
+                if let (Some(mut upper_arc), Some(mut node_arc)) =
+                    (upper.get_data(), node.get_data())
+                {
+                    upper_arc.get().set_parent(node.clone());
+                    node_arc.get().set_child(upper);
+                }
+            }
+            upper = Some(node);
+        }
+        entry = closest;
     }
 
-    let fvec = vector_emb.raw_vec.clone();
+    Ok(())
+}
+
+// Nodes on `entry`'s level nearest to `fvec`, most similar first. Falls back to `entry`
+// alone while its level has nothing else to compare against
+fn nearest_on_level(
+    vec_store: &Arc<VectorStore>,
+    entry: &LazyItem<MergedNode>,
+    fvec: &Arc<Storage>,
+    key: &NamespacedId,
+    level: i8,
+) -> Result<Vec<(LazyItem<MergedNode>, f32)>, WaCustomError> {
     let mut skipm = HashSet::new();
-    skipm.insert(vector_emb.hash_vec.clone());
+    skipm.insert(key.clone());
 
-    let mut cur_node_arc = match cur_entry.clone() {
+    let mut cur_node_arc = match entry.clone() {
         LazyItem::Valid {
             data: Some(node), ..
         } => node,
@@ -518,75 +966,57 @@ pub fn index_embedding(
     };
 
     let cur_node = cur_node_arc.get();
+    let node_prop = get_or_load_prop(cur_node, vec_store)?;
 
-    let mut prop_arc = cur_node.prop.clone();
-    let prop_state = prop_arc.get();
-
-    let node_prop = match &*prop_state {
-        PropState::Ready(prop) => prop,
-        PropState::Pending(_) => {
-            return Err(WaCustomError::NodeError(
-                "Node prop is in pending state".to_string(),
-            ))
-        }
+    let params = TraverseParams {
+        vec_store,
+        fvec,
+        cur_level: level,
+        skip_hop: true,
     };
-
     let z = traverse_find_nearest(
-        vec_store.clone(),
-        cur_entry.clone(),
-        fvec.clone(),
-        vector_emb.hash_vec.clone(),
+        &params,
+        entry.clone(),
         0,
         &mut skipm,
-        cur_level,
-        true,
+        &mut SearchContext::default(),
     )?;
 
-    let dist = vec_store
-        .distance_metric
-        .calculate(&fvec, &node_prop.value)?;
-
-    let z = if z.is_empty() {
-        vec![(cur_entry.clone(), dist)]
-    } else {
-        z
-    };
-
-    let z_clone: Vec<_> = z.iter().map(|(first, _)| first.clone()).collect();
-
-    if cur_level <= max_insert_level {
-        index_embedding(
-            vec_store.clone(),
-            vector_emb.clone(),
-            z_clone[0].clone(),
-            cur_level - 1,
-            max_insert_level,
-        )?;
-        println!("here");
-        insert_node_create_edges(
-            vec_store.clone(),
-            fvec,
-            vector_emb.hash_vec.clone(),
-            z,
-            cur_level,
-        )
-        .expect("Failed insert_node_create_edges");
+    if z.is_empty() {
+        let dist = vec_store
+            .get_distance_metric()
+            .calculate(fvec, &node_prop.value)?;
+        Ok(vec![(entry.clone(), dist)])
     } else {
-        index_embedding(
-            vec_store.clone(),
-            vector_emb.clone(),
-            z_clone[0].clone(),
-            cur_level - 1,
-            max_insert_level,
-        )?;
+        Ok(z)
     }
+}
 
-    Ok(())
+// Moves from `entry` to its copy on `level`, following child links up and parent links
+// down. Stops at the last loaded copy on the way, which may leave it on another level
+pub fn entry_at_level(entry: LazyItem<MergedNode>, level: i8) -> LazyItem<MergedNode> {
+    let mut entry = entry;
+    loop {
+        let Some(mut node_arc) = entry.get_data() else {
+            return entry;
+        };
+        let node = node_arc.get();
+        let next = match (node.hnsw_level as i8).cmp(&level) {
+            std::cmp::Ordering::Less => node.get_child(),
+            std::cmp::Ordering::Greater => node.get_parent(),
+            std::cmp::Ordering::Equal => return entry,
+        };
+        let next = next.item.clone().get().clone();
+        if next.get_data().is_none() {
+            return entry;
+        }
+        entry = next;
+    }
 }
 
 pub fn queue_node_prop_exec(
     lznode: LazyItem<MergedNode>,
-    prop_file: Arc<File>,
+    prop_file: Arc<StoreFile>,
     vec_store: Arc<VectorStore>,
 ) -> Result<(), WaCustomError> {
     let (mut node_arc, location) = match &lznode {
@@ -616,7 +1046,7 @@ pub fn queue_node_prop_exec(
     let prop_state = prop_arc.get();
 
     if let PropState::Ready(node_prop) = &*prop_state {
-        let prop_location = write_prop_to_file(node_prop, &prop_file);
+        let prop_location = write_prop_to_file(node_prop, prop_file.handle());
         node.set_prop_location(prop_location);
     } else {
         return Err(WaCustomError::NodeError(
@@ -639,13 +1069,7 @@ pub fn queue_node_prop_exec(
     }
 
     // Add the node to exec_queue_nodes
-    let mut exec_queue = vec_store.exec_queue_nodes.clone();
-    println!("queue length {}", exec_queue.len());
-    exec_queue.rcu(|queue| {
-        let mut new_queue = queue.clone();
-        new_queue.push(Item::new(lznode.clone()));
-        new_queue
-    });
+    queue_changed_node(&vec_store, &lznode);
 
     Ok(())
 }
@@ -653,96 +1077,996 @@ pub fn queue_node_prop_exec(
 pub fn link_prev_version(prev_loc: Option<u32>, offset: u32) {
     // todo , needs to happen in file persist
 }
-pub fn auto_commit_transaction(
-    vec_store: Arc<VectorStore>,
-    buf_writer: &mut CustomBufferedWriter,
-) -> Result<(), WaCustomError> {
-    // Retrieve exec_queue_nodes from vec_store
-    let mut exec_queue_nodes_arc = vec_store.exec_queue_nodes.clone();
-    let exec_queue_nodes = exec_queue_nodes_arc.get();
 
-    // Iterate through the exec_queue_nodes and persist each node
-    for node in exec_queue_nodes.iter() {
-        println!("auto_commit_txn");
-        persist_node_update_loc(buf_writer, node.clone())?;
-    }
+// Queues a node whose links changed for the next commit to write
+fn queue_changed_node(vec_store: &VectorStore, lazy_node: &LazyItem<MergedNode>) {
+    let mut exec_queue = vec_store.exec_queue_nodes.clone();
+    exec_queue.rcu(|queue| {
+        let mut new_queue = queue.clone();
+        new_queue.push(Item::new(lazy_node.clone()));
+        new_queue
+    });
+}
+
+// Drops the first `count` queued nodes once a commit has written them. Nodes queued
+// while it ran are left for the next one
+fn forget_queued_nodes(vec_store: &VectorStore, count: usize) {
+    let mut exec_queue = vec_store.exec_queue_nodes.clone();
+    exec_queue.rcu(|queue| queue[count.min(queue.len())..].to_vec());
+}
+
+fn lock_index_log(vec_store: &VectorStore) -> Result<MutexGuard<'_, IndexLog>, WaCustomError> {
+    vec_store
+        .index_log
+        .lock()
+        .map_err(|e| WaCustomError::LockError(e.to_string()))
+}
+
+// Commits the nodes queued since the last commit as a new version. Each gets a new
+// copy appended to the store's index file, linking to nodes that didn't change by the
+// offsets they're already at, so a commit writes no more than what it changed. A
+// relocation table after the copies sends links to the copies they replace on to
+// the new ones, for this version and the ones after it
+pub fn auto_commit_transaction(vec_store: Arc<VectorStore>) -> Result<VersionHash, WaCustomError> {
+    let mut log = lock_index_log(&vec_store)?;
+    // Allocated under the lock, so the file holds versions in the order they're numbered
+    let new_ver = allocate_version(&vec_store)?;
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(vec_store.index_file_path(log.file_version))?;
+    let start = file.metadata()?.len();
+    store_open_transaction(
+        &vec_store.lmdb,
+        &vec_store.database_name,
+        OpenTransaction {
+            version: new_ver,
+            file_version: log.file_version,
+            start_offset: start,
+        },
+    )?;
+
+    // Serialized in memory as if positioned at the end of the file, since the
+    // serializer seeks back to patch offsets which an append-only handle can't do
+    let mut buffer = OffsetBuffer {
+        base: start,
+        inner: std::io::Cursor::new(Vec::new()),
+    };
+    let queued = vec_store.exec_queue_nodes.clone().get().clone();
+    let root = vec_store.root_vec.item.clone().get().clone();
+    let written = write_changed_nodes(&root, &queued, &log.moved, &mut buffer)?;
+    let relocations = if written.moved.is_empty() {
+        log.relocations
+    } else {
+        Some(write_relocations(
+            &mut buffer,
+            log.relocations,
+            &written.moved,
+        )?)
+    };
+    file.write_all(buffer.inner.get_ref())?;
+
+    let vec_hash = finish_version(
+        &vec_store,
+        VersionRoot {
+            version: new_ver,
+            file_version: log.file_version,
+            root_offset: written.root_offset,
+            relocations,
+        },
+    )?;
+
+    // The live graph only moves on to the new copies once they're committed
+    for (lazy_node, offset) in written.copies {
+        lazy_node.set_offset(Some(offset));
+    }
+    log.moved.extend(written.moved);
+    log.relocations = relocations;
+    forget_queued_nodes(&vec_store, queued.len());
+
+    Ok(vec_hash)
+}
+
+// What `write_changed_nodes` wrote
+struct ChangedNodes {
+    // Where the version's root is, whether it changed or not
+    root_offset: FileOffset,
+    // Every item queued along with the offset of its node's new copy
+    copies: Vec<(LazyItem<MergedNode>, FileOffset)>,
+    // The offset of each copy replaced, paired with the offset of its replacement
+    moved: Vec<(FileOffset, FileOffset)>,
+}
+
+// Writes a copy of the node of each item in `queued` as it is now. Links to other
+// queued nodes go to their copies, links to the rest to the offsets they're at
+fn write_changed_nodes<W: Write + Seek>(
+    root: &LazyItem<MergedNode>,
+    queued: &[Item<LazyItem<MergedNode>>],
+    moved: &HashMap<FileOffset, FileOffset>,
+    writer: &mut W,
+) -> Result<ChangedNodes, WaCustomError> {
+    // A node can be queued many times, through items that don't all share an offset
+    let mut order = Vec::new();
+    let mut changed: HashMap<NodeKey, (LazyItem<MergedNode>, Vec<LazyItem<MergedNode>>)> =
+        HashMap::new();
+    for item in queued {
+        let lazy_node = item.clone().get().clone();
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            return Err(not_loaded(&lazy_node));
+        };
+        let node = node_arc.get();
+        let key = (node.get_id(), node.hnsw_level);
+        let (_, items) = changed.entry(key).or_insert_with(|| {
+            order.push(key);
+            (LazyItem::from_data(unlinked_copy(node)), Vec::new())
+        });
+        items.push(lazy_node);
+    }
+
+    let link = |lazy_node: &LazyItem<MergedNode>| -> Result<_, WaCustomError> {
+        match lazy_node {
+            LazyItem::Invalid => Ok(None),
+            LazyItem::Valid { .. } => {
+                if let Some((copy, _)) = node_key(lazy_node).and_then(|key| changed.get(&key)) {
+                    return Ok(Some(copy.clone()));
+                }
+                let offset = lazy_node.get_offset().ok_or_else(|| {
+                    WaCustomError::NodeError("Linked to a node that was never written".to_string())
+                })?;
+                Ok(Some(LazyItem::Valid {
+                    data: None,
+                    offset: Item::new(Some(offset)),
+                    decay_counter: 0,
+                }))
+            }
+        }
+    };
+    for (copy, items) in changed.values() {
+        if let (Some(mut copy_arc), Some(mut node_arc)) = (copy.get_data(), items[0].get_data()) {
+            link_copy(node_arc.get(), copy_arc.get(), link)?;
+        }
+    }
+
+    let mut written = ChangedNodes {
+        root_offset: 0,
+        copies: Vec::new(),
+        moved: Vec::new(),
+    };
+    for key in &order {
+        let (copy, items) = &changed[key];
+        let offset = copy
+            .serialize(writer)
+            .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+        if let Some(previous) = items.iter().find_map(|item| item.get_offset()) {
+            written
+                .moved
+                .push((resolve_offset(moved, previous), offset));
+        }
+        written
+            .copies
+            .extend(items.iter().map(|item| (item.clone(), offset)));
+    }
+    written.root_offset = link(root)?
+        .and_then(|root| root.get_offset())
+        .map(|offset| resolve_offset(moved, offset))
+        .ok_or_else(|| WaCustomError::NodeError("Root node is invalid".to_string()))?;
+
+    Ok(written)
+}
+
+// A store kept in memory has no index files to write, committing only moves it on
+// to the next version. Its older versions can't be searched
+pub fn commit_in_memory(vec_store: Arc<VectorStore>) -> Result<VersionHash, WaCustomError> {
+    let queued = vec_store.exec_queue_nodes.clone().get().len();
+    let version = allocate_version(&vec_store)?;
+    let vec_hash = VersionHasher::new().generate_hash("main", version, None, None);
+    if vec_store.advance_current_version(vec_hash.clone()) {
+        store_current_version(
+            &vec_store.lmdb,
+            &vec_store.database_name,
+            "main".to_string(),
+            version,
+        )?;
+    }
+    forget_queued_nodes(&vec_store, queued);
+    Ok(vec_hash)
+}
 
-    // Update version
+// The next version number of the store, unique even among commits running at once
+pub fn allocate_version(vec_store: &VectorStore) -> Result<u32, WaCustomError> {
     let ver = vec_store
         .get_current_version()
-        .expect("No current version found");
-    let new_ver = ver.version + 1;
-    let vec_hash =
-        store_current_version(vec_store.clone(), "main".to_string(), new_ver).map_err(|e| {
+        .ok_or_else(|| WaCustomError::DatabaseError("No current version found".to_string()))?;
+    reserve_version(&vec_store.lmdb, &vec_store.database_name, ver.version)
+}
+
+// Records the version root, which is the commit point since recovery treats the
+// version as complete once it exists, then makes the version the current one unless
+// a later one already is
+fn finish_version(
+    vec_store: &VectorStore,
+    root: VersionRoot,
+) -> Result<VersionHash, WaCustomError> {
+    let vec_hash = VersionHasher::new().generate_hash("main", root.version, None, None);
+    store_version_root(&vec_store.lmdb, &vec_store.database_name, &vec_hash, root)?;
+    // A later commit that finished first stays current
+    if vec_store.advance_current_version(vec_hash.clone()) {
+        store_current_version(
+            &vec_store.lmdb,
+            &vec_store.database_name,
+            "main".to_string(),
+            root.version,
+        )
+        .map_err(|e| {
             WaCustomError::DatabaseError(format!("Failed to store current version: {:?}", e))
         })?;
+    }
+    clear_open_transaction(&vec_store.lmdb, &vec_store.database_name)?;
+
+    // The version is committed by now, failing to tidy up after it doesn't undo that
+    if let Some(keep) = vec_store.keep_versions {
+        if let Err(e) = forget_old_versions(vec_store, keep, root.file_version) {
+            eprintln!("Failed to remove old versions: {}", e);
+        }
+    }
+
+    Ok(vec_hash)
+}
+
+// Rewrites the live graph into a fresh `{version}.index` holding nothing else, and
+// commits it as a new version that later commits are appended to. Superseded node
+// copies, prior values kept as versions and anything no longer reachable from the
+// root are left behind in the older file, which goes once no version kept needs it
+pub fn compact_index(vec_store: Arc<VectorStore>) -> Result<VersionHash, WaCustomError> {
+    if vec_store.memory.is_some() {
+        return commit_in_memory(vec_store);
+    }
+    let mut log = lock_index_log(&vec_store)?;
+    // Everything queued so far is part of the copy
+    let queued = vec_store.exec_queue_nodes.clone().get().len();
+    let root = vec_store.root_vec.item.clone().get().clone();
+    let (root_copy, copies) = detached_copy(&root)?;
+
+    let new_ver = allocate_version(&vec_store)?;
+    store_open_transaction(
+        &vec_store.lmdb,
+        &vec_store.database_name,
+        OpenTransaction {
+            version: new_ver,
+            file_version: new_ver,
+            start_offset: 0,
+        },
+    )?;
+
+    // Not opened in append mode, the serializer seeks back to patch offsets
+    let mut index_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(vec_store.index_file_path(new_ver))?;
+    write_index_header(&mut index_file)?;
+    let mut writer =
+        CustomBufferedWriter::with_capacity(Rc::new(RefCell::new(index_file)), BULK_BUFFER_SIZE)?;
+
+    let root_offset = root_copy
+        .serialize(&mut writer)
+        .map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+    writer.flush()?;
+
+    let vec_hash = finish_version(
+        &vec_store,
+        VersionRoot {
+            version: new_ver,
+            file_version: new_ver,
+            root_offset,
+            relocations: None,
+        },
+    )?;
 
-    vec_store.set_current_version(Some(vec_hash));
+    for (lazy_node, copy) in copies {
+        lazy_node.set_offset(copy.get_offset());
+    }
+    *log = IndexLog {
+        file_version: new_ver,
+        ..IndexLog::default()
+    };
+    forget_queued_nodes(&vec_store, queued);
 
+    Ok(vec_hash)
+}
+
+// Settles a commit of the store in `base_path` that was interrupted by a crash. If
+// its version root was recorded the commit is completed by bumping the current
+// version, otherwise everything it wrote to its index file is truncated away. Runs
+// before the store is loaded, since it decides which version that loads
+pub fn recover_incomplete_transaction(
+    meta: &dyn MetaStore,
+    store_name: &str,
+    base_path: &Path,
+) -> Result<(), WaCustomError> {
+    let Some(transaction) = retrieve_open_transaction(meta, store_name)? else {
+        return Ok(());
+    };
+
+    let version = transaction.version;
+    let vec_hash = VersionHasher::new().generate_hash("main", version, None, None);
+    if retrieve_version_root(meta, store_name, &vec_hash).is_ok() {
+        let behind = retrieve_current_version(meta, store_name)
+            .map(|current| current.version < version)
+            .unwrap_or(true);
+        if behind {
+            store_current_version(meta, store_name, "main".to_string(), version)?;
+        }
+    } else {
+        let path = index_file_path(base_path, transaction.file_version);
+        if transaction.start_offset == 0 {
+            remove_index_file(&path)?;
+        } else if path.exists() {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(transaction.start_offset))?;
+        }
+    }
+
+    clear_open_transaction(meta, store_name)
+}
+
+// In-memory buffer whose positions start at `base` instead of zero
+struct OffsetBuffer {
+    base: u64,
+    inner: std::io::Cursor<Vec<u8>>,
+}
+
+impl Write for OffsetBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for OffsetBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => {
+                SeekFrom::Start(offset.checked_sub(self.base).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Seek before buffer start",
+                    )
+                })?)
+            }
+            relative => relative,
+        };
+        Ok(self.inner.seek(pos)? + self.base)
+    }
+}
+
+// A node on one level, the same node on another level is a different one
+type NodeKey = (u64, HNSWLevel);
+
+fn node_key(lazy_node: &LazyItem<MergedNode>) -> Option<NodeKey> {
+    let mut node_arc = lazy_node.get_data()?;
+    let node = node_arc.get();
+    Some((node.get_id(), node.hnsw_level))
+}
+
+fn not_loaded(lazy_node: &LazyItem<MergedNode>) -> WaCustomError {
+    match lazy_node.get_offset() {
+        Some(offset) => {
+            WaCustomError::LazyLoadingError(format!("Node at offset {} isn't loaded", offset))
+        }
+        None => WaCustomError::NodeError("Node is null".to_string()),
+    }
+}
+
+// A copy of `node` without its links or prior versions
+fn unlinked_copy(node: &MergedNode) -> MergedNode {
+    MergedNode {
+        version_id: node.version_id,
+        hnsw_level: node.hnsw_level,
+        prop: Item::new(node.get_prop()),
+        neighbors: EagerLazyItemSet::new(),
+        parent: LazyItemRef::new_invalid(),
+        child: LazyItemRef::new_invalid(),
+        versions: LazyItemMap::new(),
+        persist_flag: Arc::new(AtomicBool::new(true)),
+    }
+}
+
+// Gives `copy` the links `node` has, each to what `link` maps it to. A link mapped to
+// `None` is left out
+fn link_copy(
+    node: &MergedNode,
+    copy: &MergedNode,
+    link: impl Fn(&LazyItem<MergedNode>) -> Result<Option<LazyItem<MergedNode>>, WaCustomError>,
+) -> Result<(), WaCustomError> {
+    for nbr in node.neighbors.iter() {
+        if let Some(nbr_copy) = link(&nbr.1)? {
+            copy.add_ready_neighbor(nbr_copy, nbr.0);
+        }
+    }
+    if let Some(parent_copy) = link(node.get_parent().item.clone().get())? {
+        copy.set_parent(parent_copy);
+    }
+    if let Some(child_copy) = link(node.get_child().item.clone().get())? {
+        copy.set_child(child_copy);
+    }
     Ok(())
 }
 
+// Each item of a graph paired with the copy of its node
+type NodeCopies = Vec<(LazyItem<MergedNode>, LazyItem<MergedNode>)>;
+
+// Copies every node reachable from `root` into fresh items that share no file offsets
+// with the live graph, keeping the links between them. Returns the root's copy along
+// with every item reached. Fails on a node that isn't loaded rather than leave it out
+fn detached_copy(
+    root: &LazyItem<MergedNode>,
+) -> Result<(LazyItem<MergedNode>, NodeCopies), WaCustomError> {
+    let mut copies = HashMap::new();
+    let mut originals = Vec::new();
+    let mut reached = Vec::new();
+    let mut queue = VecDeque::from([root.clone()]);
+
+    while let Some(lazy_node) = queue.pop_front() {
+        if let LazyItem::Invalid = lazy_node {
+            continue;
+        }
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            return Err(not_loaded(&lazy_node));
+        };
+        let node = node_arc.get();
+        let key = (node.get_id(), node.hnsw_level);
+        reached.push((lazy_node.clone(), key));
+        if copies.contains_key(&key) {
+            continue;
+        }
+        copies.insert(key, LazyItem::from_data(unlinked_copy(node)));
+
+        queue.extend(node.neighbors.iter().map(|nbr| nbr.1));
+        queue.push_back(node.get_parent().item.clone().get().clone());
+        queue.push_back(node.get_child().item.clone().get().clone());
+        originals.push(lazy_node);
+    }
+
+    let copy_of = |lazy_node: &LazyItem<MergedNode>| -> Result<_, WaCustomError> {
+        Ok(node_key(lazy_node)
+            .and_then(|key| copies.get(&key))
+            .cloned())
+    };
+    for lazy_node in &originals {
+        if let (Some(Some(mut copy_arc)), Some(mut node_arc)) = (
+            copy_of(lazy_node)?.map(|copy| copy.get_data()),
+            lazy_node.get_data(),
+        ) {
+            link_copy(node_arc.get(), copy_arc.get(), copy_of)?;
+        }
+    }
+
+    let root_copy = copy_of(root)?.unwrap_or(LazyItem::Invalid);
+    let reached = reached
+        .into_iter()
+        .map(|(lazy_node, key)| (lazy_node, copies[&key].clone()))
+        .collect();
+    Ok((root_copy, reached))
+}
+
+// Replaces the value of an already indexed vector. The node on every level keeps the
+// prior value as a version and gets its neighbors re-linked as if freshly inserted
+pub fn update_embedding(
+    vec_store: Arc<VectorStore>,
+    id: VectorId,
+    new_vec: &[f32],
+) -> Result<(), WaCustomError> {
+    let nodes = find_nodes_by_id(&vec_store, &id)?;
+    if nodes.is_empty() {
+        return Err(WaCustomError::NodeError(format!("Vector {} not found", id)));
+    }
+
+    // Quantized from scratch rather than patched, so the magnitude and norm cosine
+    // divides by are the new vector's, not the ones of the value it replaces
+    let fvec = Arc::new(vec_store.quantize(new_vec));
+    let root = vec_store.root_vec.item.clone().get().clone();
+    let metric = vec_store.get_distance_metric();
+    // The value being replaced is the one the current version holds, so it's kept
+    // under that version's id
+    let current = vec_store
+        .get_current_version()
+        .ok_or_else(|| WaCustomError::DatabaseError("No current version found".to_string()))?;
+    let version_id = VersionId::try_from(current.version).map_err(|_| {
+        WaCustomError::NodeError(format!("Version {} is out of range", current.version))
+    })?;
+
+    for lazy_node in nodes {
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+
+        let previous_prop = get_or_load_prop(node, &vec_store)?;
+        // Updated again before a commit, the version keeps the value it committed
+        if node.get_version(version_id).is_none() {
+            let previous = MergedNode::new(version_id, node.hnsw_level);
+            previous.set_prop_ready(previous_prop.clone());
+            node.add_version(Item::new(previous));
+        }
+
+        // Only the vector changes, its metadata carries over
+        node.set_prop_ready(Arc::new(NodeProp {
+            id: id.clone(),
+            value: fvec.clone(),
+            location: None,
+            metadata: previous_prop.metadata.clone(),
+            namespace: previous_prop.namespace.clone(),
+            exact: vec_store.exact_copy(new_vec),
+        }));
+
+        // The old neighbors stay candidates, rescored against the new value. Dropping
+        // them outright could cut off nodes only reachable through this one
+        let mut old_nbs = Vec::new();
+        for nbr in node.neighbors.iter() {
+            let Some(mut nbr_arc) = nbr.1.get_data() else {
+                continue;
+            };
+            let nbr_prop = get_or_load_prop(nbr_arc.get(), &vec_store)?;
+            old_nbs.push((nbr.1, metric.calculate(&fvec, &nbr_prop.value)?));
+        }
+
+        let mut skipm = HashSet::new();
+        skipm.insert(previous_prop.namespaced_id());
+        let params = TraverseParams {
+            vec_store: &vec_store,
+            fvec: &fvec,
+            cur_level: node.hnsw_level as i8,
+            skip_hop: true,
+        };
+        let mut nbs = traverse_find_nearest(
+            &params,
+            entry_at_level(root.clone(), node.hnsw_level as i8),
+            0,
+            &mut skipm,
+            &mut SearchContext::default(),
+        )?;
+        nbs.extend(old_nbs);
+
+        node.replace_neighbors_bounded(nbs.clone(), vec_store.max_neighbors, &metric);
+        for (nbr, cs) in nbs {
+            let Some(mut nbr_node) = nbr.get_data() else {
+                continue;
+            };
+            let linked = nbr_node.get().add_ready_neighbor_bounded(
+                lazy_node.clone(),
+                cs,
+                vec_store.max_neighbors,
+                &metric,
+            );
+            if linked {
+                queue_changed_node(&vec_store, &nbr);
+            }
+        }
+
+        // Writes the new prop to the prop file and queues the node for persistence
+        queue_node_prop_exec(lazy_node, vec_store.prop_file.clone(), vec_store.clone())?;
+    }
+
+    Ok(())
+}
+
+// Removes the vector with `id` from the default namespace, see
+// `delete_embedding_in_namespace`
+pub fn delete_embedding(vec_store: Arc<VectorStore>, id: &VectorId) -> Result<(), WaCustomError> {
+    delete_embedding_in_namespace(vec_store, None, id)
+}
+
+// Removes the vector with `id` in `namespace` and frees its id for a later insert. One
+// still waiting to be indexed never makes it into the graph. Every link to an indexed
+// one's nodes is dropped, and the nodes it was linked with are offered to each other
+// as neighbors so they stay reachable. A node every one of them already has better
+// neighbors than can still be cut off. The removal is persisted by the next committed
+// version, as with `update_embedding`
+pub fn delete_embedding_in_namespace(
+    vec_store: Arc<VectorStore>,
+    namespace: Option<&str>,
+    id: &VectorId,
+) -> Result<(), WaCustomError> {
+    // The root placeholder holds the levels together
+    if *id == VectorId::Int(-1) {
+        return Err(WaCustomError::InvalidParams);
+    }
+
+    let embedding_key = embedding_key(&vec_store.database_name, namespace, id);
+    let Some(offset) = embedding_offset(&vec_store.lmdb, &embedding_key)? else {
+        return Err(WaCustomError::NotFound(id.clone()));
+    };
+    // Indexing skips embeddings whose id no longer leads back to them
+    let next_file_offset_key = indexing_counter_key("next_file_offset", &vec_store.database_name);
+    let pending = offset >= get_u32(&vec_store.lmdb, &next_file_offset_key)?;
+    if !pending {
+        unlink_embedding(&vec_store, namespace, id)?;
+    }
+
+    let env = vec_store.lmdb.env.clone();
+    let embedding_db = vec_store.lmdb.embeddings_db.clone();
+    let metadata_db = vec_store.lmdb.metadata_db.clone();
+    let count_total_key = count_total_key(&vec_store.database_name);
+    let count_unindexed_key = indexing_counter_key("count_unindexed", &vec_store.database_name);
+
+    retry_on_map_full(&env, || {
+        let mut txn = env.begin_rw_txn()?;
+        match txn.del(*embedding_db, &embedding_key, None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let count_total = match txn.get(*metadata_db, &count_total_key) {
+            Ok(bytes) => {
+                let bytes = bytes.try_into().map_err(|_| lmdb::Error::BadValSize)?;
+                u64::from_le_bytes(bytes)
+            }
+            Err(lmdb::Error::NotFound) => 0,
+            Err(err) => return Err(err),
+        };
+        txn.put(
+            *metadata_db,
+            &count_total_key,
+            &count_total.saturating_sub(1).to_le_bytes(),
+            WriteFlags::empty(),
+        )?;
+
+        if pending {
+            let count_unindexed = match txn.get(*metadata_db, &count_unindexed_key) {
+                Ok(bytes) => {
+                    let bytes = bytes.try_into().map_err(|_| lmdb::Error::BadValSize)?;
+                    u32::from_le_bytes(bytes)
+                }
+                Err(lmdb::Error::NotFound) => 0,
+                Err(err) => return Err(err),
+            };
+            txn.put(
+                *metadata_db,
+                &count_unindexed_key,
+                &count_unindexed.saturating_sub(1).to_le_bytes(),
+                WriteFlags::empty(),
+            )?;
+        }
+
+        txn.commit()
+    })
+}
+
+// Drops every link to the nodes of the vector with `id` in `namespace`, then links the
+// nodes that lost one among themselves, level by level
+fn unlink_embedding(
+    vec_store: &Arc<VectorStore>,
+    namespace: Option<&str>,
+    id: &VectorId,
+) -> Result<(), WaCustomError> {
+    let is_deleted = |prop: &NodeProp| prop.id == *id && prop.namespace.as_deref() == namespace;
+
+    // Insertion links both ways, but a bounded neighbor set may have dropped the link
+    // back, so every node is checked rather than just the deleted node's neighbors
+    let root = vec_store.root_vec.item.clone().get().clone();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([root]);
+    let mut found = Vec::new();
+    // Nodes that linked to the deleted ones or were linked from them, by level
+    let mut cut_off: HashMap<u8, HashMap<NamespacedId, LazyItem<MergedNode>>> = HashMap::new();
+    while let Some(lazy_node) = queue.pop_front() {
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+        let node_prop = get_or_load_prop(node, vec_store)?;
+        if !visited.insert((node_prop.namespaced_id(), node.hnsw_level)) {
+            continue;
+        }
+        queue.push_back(node.get_parent().item.clone().get().clone());
+        queue.push_back(node.get_child().item.clone().get().clone());
+        if is_deleted(&node_prop) {
+            queue.extend(node.neighbors.iter().map(|nbr| nbr.1));
+            found.push(lazy_node);
+            continue;
+        }
+
+        for nbr in node.neighbors.iter() {
+            queue.push_back(nbr.1.clone());
+            let Some(mut nbr_arc) = nbr.1.get_data() else {
+                continue;
+            };
+            get_or_load_prop(nbr_arc.get(), vec_store)?;
+        }
+        // The neighbors' props are all loaded by now
+        let linked = node.neighbors.len();
+        node.neighbors.retain(|nbr| {
+            let Some(mut nbr_arc) = nbr.1.get_data() else {
+                return true;
+            };
+            let PropState::Ready(nbr_prop) = nbr_arc.get().get_prop() else {
+                return true;
+            };
+            !is_deleted(&nbr_prop)
+        });
+        if node.neighbors.len() < linked {
+            cut_off
+                .entry(node.hnsw_level)
+                .or_default()
+                .insert(node_prop.namespaced_id(), lazy_node.clone());
+        }
+    }
+    if found.is_empty() {
+        return Ok(());
+    }
+    vec_store
+        .nodes_by_id
+        .remove(&(namespace.map(str::to_string), id.clone()));
+
+    for lazy_node in found {
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+        for nbr in node.neighbors.iter() {
+            let Some(mut nbr_arc) = nbr.1.get_data() else {
+                continue;
+            };
+            let nbr_prop = get_or_load_prop(nbr_arc.get(), vec_store)?;
+            cut_off
+                .entry(node.hnsw_level)
+                .or_default()
+                .insert(nbr_prop.namespaced_id(), nbr.1.clone());
+        }
+        node.neighbors.clear();
+        node.set_parent(LazyItem::Invalid);
+        node.set_child(LazyItem::Invalid);
+    }
+
+    let metric = vec_store.get_distance_metric();
+    for nodes in cut_off.into_values() {
+        let nodes: Vec<_> = nodes.into_values().collect();
+        for (i, lazy_node) in nodes.iter().enumerate() {
+            let Some(mut node_arc) = lazy_node.get_data() else {
+                continue;
+            };
+            let node = node_arc.get();
+            let node_prop = get_or_load_prop(node, vec_store)?;
+            for (j, other) in nodes.iter().enumerate() {
+                let Some(mut other_arc) = other.get_data() else {
+                    continue;
+                };
+                if i == j {
+                    continue;
+                }
+                let other_prop = get_or_load_prop(other_arc.get(), vec_store)?;
+                let score = metric.calculate(&node_prop.value, &other_prop.value)?;
+                node.add_ready_neighbor_bounded(
+                    other.clone(),
+                    score,
+                    vec_store.max_neighbors,
+                    &metric,
+                );
+            }
+            queue_changed_node(vec_store, lazy_node);
+        }
+    }
+
+    Ok(())
+}
+
+// Replaces the metadata of an already indexed vector on every level, leaving its
+// value and neighbors as they are
+pub fn set_embedding_metadata(
+    vec_store: Arc<VectorStore>,
+    id: VectorId,
+    metadata: HashMap<String, String>,
+) -> Result<(), WaCustomError> {
+    let nodes = find_nodes_by_id(&vec_store, &id)?;
+    if nodes.is_empty() {
+        return Err(WaCustomError::NodeError(format!("Vector {} not found", id)));
+    }
+
+    for lazy_node in nodes {
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+        let prop = get_or_load_prop(node, &vec_store)?;
+        node.set_prop_ready(Arc::new(NodeProp {
+            location: None,
+            metadata: Some(metadata.clone()),
+            ..(*prop).clone()
+        }));
+
+        queue_node_prop_exec(lazy_node, vec_store.prop_file.clone(), vec_store.clone())?;
+    }
+
+    Ok(())
+}
+
+// The node holding `id` in the default namespace on each level it's on, top level
+// first
+pub fn find_nodes_by_id(
+    vec_store: &Arc<VectorStore>,
+    id: &VectorId,
+) -> Result<Vec<LazyItem<MergedNode>>, WaCustomError> {
+    find_nodes_in_namespace(vec_store, None, id)
+}
+
+// Like `find_nodes_by_id`, for the vector with `id` in `namespace`
+pub fn find_nodes_in_namespace(
+    vec_store: &Arc<VectorStore>,
+    namespace: Option<&str>,
+    id: &VectorId,
+) -> Result<Vec<LazyItem<MergedNode>>, WaCustomError> {
+    let key = (namespace.map(str::to_string), id.clone());
+    Ok(vec_store
+        .nodes_by_id
+        .get(&key)
+        .map(|nodes| nodes.clone())
+        .unwrap_or_default())
+}
+
+// Fills in `nodes_by_id` for a graph read back from an index file, walking it once.
+// Nodes that aren't loaded can't be found by id either way
+pub fn register_loaded_nodes(vec_store: &VectorStore) -> Result<(), WaCustomError> {
+    let root = vec_store.root_vec.item.clone().get().clone();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([root]);
+    let mut found: HashMap<NamespacedId, Vec<(HNSWLevel, LazyItem<MergedNode>)>> = HashMap::new();
+
+    while let Some(lazy_node) = queue.pop_front() {
+        let Some(mut node_arc) = lazy_node.get_data() else {
+            continue;
+        };
+        let node = node_arc.get();
+        let node_prop = get_or_load_prop(node, vec_store)?;
+        if !visited.insert((node_prop.namespaced_id(), node.hnsw_level)) {
+            continue;
+        }
+        queue.extend(node.neighbors.iter().map(|nbr| nbr.1));
+        // Upper levels are only reachable through the copies linking the levels
+        queue.push_back(node.get_parent().item.clone().get().clone());
+        queue.push_back(node.get_child().item.clone().get().clone());
+
+        if node_prop.id != VectorId::Int(-1) {
+            found
+                .entry(node_prop.namespaced_id())
+                .or_default()
+                .push((node.hnsw_level, lazy_node.clone()));
+        }
+    }
+
+    for (key, mut nodes) in found {
+        nodes.sort_by(|a, b| b.0.cmp(&a.0));
+        vec_store
+            .nodes_by_id
+            .insert(key, nodes.into_iter().map(|(_, node)| node).collect());
+    }
+    Ok(())
+}
+
+// Picks the neighbors a new node links to out of `candidates`, which are scored
+// against the new node. Returned best first, at most `max_neighbors` of them
+pub fn select_neighbors(
+    vec_store: &VectorStore,
+    mut candidates: Vec<(LazyItem<MergedNode>, f32)>,
+) -> Result<Vec<(LazyItem<MergedNode>, f32)>, WaCustomError> {
+    let metric = vec_store.get_distance_metric();
+    candidates.sort_by(|a, b| metric.compare_scores(a.1, b.1));
+    if vec_store.neighbor_selection == NeighborSelection::Simple {
+        candidates.truncate(vec_store.max_neighbors);
+        return Ok(candidates);
+    }
+
+    let mut selected: Vec<(LazyItem<MergedNode>, f32, Arc<NodeProp>)> = Vec::new();
+    for (candidate, score) in candidates {
+        if selected.len() >= vec_store.max_neighbors {
+            break;
+        }
+        let Some(mut node_arc) = candidate.get_data() else {
+            continue;
+        };
+        let prop = get_or_load_prop(node_arc.get(), vec_store)?;
+        let mut covered = false;
+        for (_, _, picked) in &selected {
+            let to_picked = metric.calculate(&prop.value, &picked.value)?;
+            if metric.compare_scores(to_picked, score).is_lt() {
+                covered = true;
+                break;
+            }
+        }
+        if !covered {
+            selected.push((candidate, score, prop));
+        }
+    }
+    Ok(selected
+        .into_iter()
+        .map(|(candidate, score, _)| (candidate, score))
+        .collect())
+}
+
+// Adds a node for the embedding on `cur_level` and links it both ways with the
+// neighbors picked out of `nbs`, dropping the worst neighbors of anyone over
+// `max_neighbors`. Returns the node, already queued for persistence and found by id
 fn insert_node_create_edges(
     vec_store: Arc<VectorStore>,
-    fvec: Arc<Storage>,
-    hs: VectorId,
+    vector_emb: &VectorEmbedding,
     nbs: Vec<(LazyItem<MergedNode>, f32)>,
     cur_level: i8,
-) -> Result<(), WaCustomError> {
+) -> Result<LazyItem<MergedNode>, WaCustomError> {
     let node_prop = NodeProp {
-        id: hs.clone(),
-        value: fvec.clone(),
+        id: vector_emb.hash_vec.clone(),
+        value: vector_emb.raw_vec.clone(),
         location: None,
+        metadata: None,
+        namespace: vector_emb.namespace.clone(),
+        exact: vector_emb.exact.clone(),
     };
+    let nbs = select_neighbors(&vec_store, nbs)?;
     let mut nn = Item::new(MergedNode::new(0, cur_level as u8)); // Assuming MergedNode::new exists
     nn.get().set_prop_ready(Arc::new(node_prop));
 
-    nn.get().add_ready_neighbors(nbs.clone());
+    let metric = vec_store.get_distance_metric();
+    nn.get()
+        .add_ready_neighbors_bounded(nbs.clone(), vec_store.max_neighbors, &metric);
 
+    // Edges go both ways, each neighbor links back to the new node unless it already
+    // holds `max_neighbors` closer ones. The back links share `lazy_node`, and with it
+    // the offset the node is persisted at, so they never point at a stale copy
+    let lazy_node = LazyItem::from_item(nn);
     for (nbr1, cs) in nbs.into_iter() {
-        if let LazyItem::Valid {
-            data: Some(mut nbr1_node),
-            ..
-        } = nbr1.clone()
-        {
-            let mut neighbor_list: Vec<(LazyItem<MergedNode>, f32)> = nbr1_node
-                .get()
-                .neighbors
-                .iter()
-                .map(|nbr2| (nbr2.1, nbr2.0))
-                .collect();
-
-            neighbor_list.push((LazyItem::from_item(nn.clone()), cs));
-
-            neighbor_list
-                .sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-
-            neighbor_list.truncate(20);
-
-            nbr1_node.get().add_ready_neighbors(neighbor_list);
+        let Some(mut nbr1_node) = nbr1.get_data() else {
+            // The search only hands back loaded nodes
+            continue;
+        };
+        let linked = nbr1_node.get().add_ready_neighbor_bounded(
+            lazy_node.clone(),
+            cs,
+            vec_store.max_neighbors,
+            &metric,
+        );
+        if linked {
+            queue_changed_node(&vec_store, &nbr1);
         }
     }
+    // Levels are inserted top down, which keeps the id's nodes in that order
+    vec_store
+        .nodes_by_id
+        .entry((vector_emb.namespace.clone(), vector_emb.hash_vec.clone()))
+        .or_default()
+        .push(lazy_node.clone());
     println!("insert node create edges, queuing nodes");
-    queue_node_prop_exec(
-        LazyItem::from_item(nn),
-        vec_store.prop_file.clone(),
-        vec_store,
-    )?;
+    queue_node_prop_exec(lazy_node.clone(), vec_store.prop_file.clone(), vec_store)?;
 
-    Ok(())
+    Ok(lazy_node)
+}
+
+// What stays the same across the recursive calls of one `traverse_find_nearest` walk
+struct TraverseParams<'a> {
+    vec_store: &'a VectorStore,
+    fvec: &'a Storage,
+    cur_level: i8,
+    // Skips every other neighbor past the first few, for a cheaper but rougher walk
+    skip_hop: bool,
 }
 
 fn traverse_find_nearest(
-    vec_store: Arc<VectorStore>,
+    params: &TraverseParams,
     vtm: LazyItem<MergedNode>,
-    fvec: Arc<Storage>,
-    hs: VectorId,
     hops: u8,
-    skipm: &mut HashSet<VectorId>,
-    cur_level: i8,
-    skip_hop: bool,
-) -> Result<Vec<(LazyItem<MergedNode>, f32)>, WaCustomError> {
-    let mut tasks: SmallVec<[Vec<(LazyItem<MergedNode>, f32)>; 24]> = SmallVec::new();
+    skipm: &mut HashSet<NamespacedId>,
+    ctx: &mut SearchContext,
+) -> Result<ScoredNodes, WaCustomError> {
+    let TraverseParams {
+        vec_store,
+        fvec,
+        cur_level,
+        skip_hop,
+    } = *params;
+    let mut tasks: SmallVec<[ScoredNodes; 24]> = SmallVec::new();
 
     let mut node_arc = match vtm.clone() {
         LazyItem::Valid {
@@ -772,61 +2096,65 @@ fn traverse_find_nearest(
     };
 
     let node = node_arc.get();
+    let metric = vec_store.get_distance_metric();
+    // Best of the neighbors left unexpanded by `min_explore_similarity`
+    let mut pruned_best: Option<(LazyItem<MergedNode>, f32, NamespacedId)> = None;
+    let mut expanded_any = false;
 
     for (index, nref) in node.neighbors.iter().enumerate() {
         if let Some(mut neighbor_arc) = nref.1.get_data() {
             let neighbor = neighbor_arc.get();
-            let mut prop_arc = neighbor.prop.clone();
-            let prop_state = prop_arc.get();
+            let node_prop = get_or_load_prop(neighbor, vec_store)?;
 
-            let node_prop = match prop_state {
-                PropState::Ready(prop) => prop.clone(),
-                PropState::Pending(_) => {
-                    return Err(WaCustomError::NodeError(
-                        "Neighbor prop is in pending state".to_string(),
-                    ))
-                }
-            };
-
-            let nb = node_prop.id.clone();
+            let nb = node_prop.namespaced_id();
 
             if index % 2 != 0 && skip_hop && index > 4 {
                 continue;
             }
 
-            let vec_store = vec_store.clone();
-            let fvec = fvec.clone();
-            let hs = hs.clone();
-
+            if !skipm.contains(&nb) && ctx.out_of_budget() {
+                ctx.truncated = true;
+                break;
+            }
             if skipm.insert(nb.clone()) {
-                let dist = vec_store
-                    .distance_metric
-                    .calculate(&fvec, &node_prop.value)?;
+                let dist = metric.calculate(fvec, &node_prop.value)?;
+                ctx.nodes_visited += 1;
 
                 let full_hops = 30;
-                if hops <= tapered_total_hops(full_hops, cur_level as u8, vec_store.max_cache_level)
-                {
-                    let mut z = traverse_find_nearest(
-                        vec_store.clone(),
-                        nref.1.clone(),
-                        fvec.clone(),
-                        hs.clone(),
-                        hops + 1,
-                        skipm,
-                        cur_level,
-                        skip_hop,
-                    )?;
+                let within_hops = hops
+                    <= tapered_total_hops(full_hops, cur_level as u8, vec_store.max_cache_level);
+                if within_hops && ctx.prunes(&metric, dist, cur_level) {
+                    ctx.record(cur_level, &node_prop, dist, TraceDecision::Pruned);
+                    tasks.push(vec![(nref.1.clone(), dist)]);
+                    if pruned_best.as_ref().map_or(true, |(_, best, _)| {
+                        metric.compare_scores(dist, *best).is_lt()
+                    }) {
+                        pruned_best = Some((nref.1.clone(), dist, nb));
+                    }
+                } else if within_hops {
+                    ctx.record(cur_level, &node_prop, dist, TraceDecision::Expanded);
+                    expanded_any = true;
+                    let mut z =
+                        traverse_find_nearest(params, nref.1.clone(), hops + 1, skipm, ctx)?;
                     z.push((nref.1.clone(), dist));
                     tasks.push(z);
                 } else {
+                    ctx.record(cur_level, &node_prop, dist, TraceDecision::OutOfHops);
                     tasks.push(vec![(nref.1.clone(), dist)]);
                 }
             }
         }
     }
 
+    if let (false, Some((best, _, best_id))) = (expanded_any, pruned_best) {
+        if let Some(trace) = ctx.trace.as_mut() {
+            trace.mark_expanded(cur_level, &best_id);
+        }
+        tasks.push(traverse_find_nearest(params, best, hops + 1, skipm, ctx)?);
+    }
+
     let mut nn: Vec<_> = tasks.into_iter().flatten().collect();
-    nn.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    nn.sort_by(|a, b| metric.compare_scores(a.1, b.1));
     let mut seen = HashSet::new();
     nn.retain(|(lazy_node, _)| {
         if let LazyItem::Valid {
@@ -839,7 +2167,7 @@ fn traverse_find_nearest(
             let mut prop_arc = node.prop.clone();
             let prop_state = prop_arc.get();
             if let PropState::Ready(node_prop) = &*prop_state {
-                seen.insert(node_prop.id.clone())
+                seen.insert(node_prop.namespaced_id())
             } else {
                 false
             }
@@ -858,7 +2186,10 @@ mod tests {
     use rand::{distributions::Uniform, rngs::ThreadRng, thread_rng, Rng};
 
     use crate::{
-        models::types::{VectorEmbedding, VectorId},
+        models::{
+            common::WaCustomError,
+            types::{VectorEmbedding, VectorId},
+        },
         quantization::{scalar::ScalarQuantization, Quantization, StorageType},
     };
 
@@ -876,6 +2207,8 @@ mod tests {
         VectorEmbedding {
             raw_vec,
             hash_vec: VectorId::Int(rng.gen()),
+            namespace: None,
+            exact: None,
         }
     }
 
@@ -915,4 +2248,24 @@ mod tests {
             assert_eq!(embedding, deserialized);
         }
     }
+
+    #[test]
+    fn test_write_failure_is_io_error() {
+        // Too small for even the length prefix
+        let mut buf = [0u8; 2];
+        let mut writer = Cursor::new(&mut buf[..]);
+        let embedding = VectorEmbedding {
+            raw_vec: Arc::new(ScalarQuantization.quantize(&[0.1, 0.2], StorageType::UnsignedByte)),
+            hash_vec: VectorId::Int(1),
+            namespace: None,
+            exact: None,
+        };
+
+        let err = write_embedding(&mut writer, &embedding).unwrap_err();
+        assert!(
+            matches!(&err, WaCustomError::IoError(io_err) if io_err.kind() == std::io::ErrorKind::WriteZero),
+            "{}",
+            err
+        );
+    }
 }